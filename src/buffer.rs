@@ -0,0 +1,467 @@
+use std::cmp;
+use std::ffi::CString;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use nix::libc;
+
+use config::{Config, LineEnding};
+use editorconfig;
+use filter::Filter;
+use follow::Follow;
+use loader::Loading;
+use prompt::{self, PathCompleter, Prompt};
+use syntax::Syntax;
+use undo::Undo;
+use vi::Mode;
+use window::View;
+use {display_path, Cursor, Editor, Line, Pos};
+
+/// An open file, along with where it's scrolled to and the cursor in it.
+pub struct Buffer {
+    // Note that this does not always report the actual position of the cursor.
+    // Instead, it reflects the _desired_ position, i.e. what user sets. It may
+    // be that for rendering purposes the cursor is temporarily relocated but
+    // then set back to this position. This also means that when it's
+    // temporarily relocated, this field shall not be updated.
+    pub cursor: Cursor,
+    // Note that there is a distinction between rows and lines. A line is the
+    // string of text until the new-line character, as stored in the file, while
+    // a row is the rendered string that fits into a single row in the window.
+    // Thus a line may wrap several rows.
+    pub lines: Vec<Line>,
+    // The zero-based index into `lines` of the first line to show.
+    pub line_offset: usize,
+    // The first row of the line at `line_offset` that should be drawn, as the
+    // line may begin above the window if it's wrapped. Also zero-based.
+    pub line_offset_row: usize,
+    // In no-wrap mode, the first column of each line that should be drawn.
+    // Always 0 when wrapping.
+    pub col_offset: usize,
+    // The settings for this file.
+    pub config: Config,
+    // The path of the file being edited.
+    pub path: PathBuf,
+    // Whether there are changes not yet written to disk.
+    pub is_dirty: bool,
+    // Whether the file doesn't exist yet, and is to be created when saved.
+    pub is_new: bool,
+    // Whether the file is kept from being edited and saved.
+    pub is_readonly: bool,
+    // Whether keys page and search through the file as in less, rather than
+    // type text. Viewed files are read-only.
+    pub is_viewed: bool,
+    // The file as it's read while it grows, if it's being followed.
+    pub follow: Option<Follow>,
+    // The filters that hide lines, which are stacked in the order applied.
+    pub filters: Vec<Filter>,
+    // Whether the file is at least `large_file_size`, so that it's loaded
+    // in the background and its lines are rendered only once they're shown.
+    pub is_large: bool,
+    // The file as it's split into lines, until it's been loaded.
+    pub loading: Option<Loading>,
+    // The file's character encoding, as shown in the status bar.
+    pub encoding: &'static str,
+    // How to highlight the file, if its language is known.
+    pub syntax: Option<Syntax>,
+    // The lines before this one are highlighted from the state the lines
+    // above them leave the highlighter in. The lines from here on may not be,
+    // as lines above them have changed since.
+    pub hl_frontier: usize,
+    // The line ending the file was read with.
+    pub line_ending: LineEnding,
+    // The changes made to the file that can be undone or redone.
+    pub undo: Undo,
+}
+
+impl Buffer {
+    pub fn new(config: Config, path: PathBuf) -> Buffer {
+        Buffer {
+            cursor: Cursor { pos: Pos { row: 0, col: 0 }, line: 0, byte: 0, is_at_eol: false },
+            lines: vec![],
+            line_offset: 0,
+            line_offset_row: 0,
+            col_offset: 0,
+            config,
+            path,
+            is_dirty: false,
+            is_new: false,
+            is_readonly: false,
+            is_viewed: false,
+            follow: None,
+            filters: vec![],
+            is_large: false,
+            loading: None,
+            encoding: "utf-8",
+            syntax: None,
+            hl_frontier: 0,
+            line_ending: LineEnding::Lf,
+            undo: Undo::default(),
+        }
+    }
+
+    /// Reads the file at `path`, or if there is none, starts a new file
+    /// there. Its lines are yet to be rendered, since that depends on the
+    /// settings for the file, which may depend on its contents. A file that
+    /// can't be written to is opened read-only. A large file is mapped into
+    /// memory and split into lines in the background.
+    pub fn open(config: Config, path: &Path) -> io::Result<Buffer> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                let mut buffer = Buffer::new(config, path.to_path_buf());
+                buffer.is_new = true;
+                return Ok(buffer);
+            }
+            Err(e) => return Err(e),
+        };
+        let len = file.metadata()?.len();
+        let mut buffer = if config.is_large_file(len) {
+            let mut buffer = Buffer::new(config, path.to_path_buf());
+            let loading = Loading::start(file, len as usize)?;
+            buffer.line_ending = loading.line_ending();
+            buffer.loading = Some(loading);
+            buffer.is_large = true;
+            buffer
+        } else {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            Buffer::from_bytes(config, path.to_path_buf(), &buf)
+        };
+        buffer.is_readonly = !is_writable(path);
+        Ok(buffer)
+    }
+
+    /// Reads the text piped to standard input into a buffer that is yet to
+    /// be named.
+    pub fn from_stdin(config: Config) -> io::Result<Buffer> {
+        let mut buf = vec![];
+        io::stdin().read_to_end(&mut buf)?;
+        Ok(Buffer::from_bytes(config, PathBuf::new(), &buf))
+    }
+
+    /// Splits the contents of a file into lines.
+    fn from_bytes(config: Config, path: PathBuf, buf: &[u8]) -> Buffer {
+        let mut buffer = Buffer::new(config, path);
+
+        // FIXME there's an extra empty space at the end even if there shouldn't be
+        buffer.line_ending = LineEnding::detect(buf);
+        // Bytes are edited as they are, so all this says is whether the text
+        // can be shown as it is.
+        if ::std::str::from_utf8(buf).is_err() {
+            buffer.encoding = "unknown";
+        }
+        let separator = if buffer.line_ending == LineEnding::Cr { b'\r' } else { b'\n' };
+        let is_crlf = buffer.line_ending == LineEnding::CrLf;
+        let lines = buf.split(|b| *b == separator);
+
+        // Try to get an esimate of the number of lines in file.
+        let size_hint = {
+            let (lower, upper) = lines.size_hint();
+            if let Some(upper) = upper { upper } else { lower }
+        };
+
+        if size_hint > 0 {
+            buffer.lines.reserve(size_hint);
+        }
+
+        buffer.lines = lines
+            .map(|line| {
+                let line = if is_crlf { line.strip_suffix(b"\r").unwrap_or(line) } else { line };
+                Line::new(line.to_vec())
+            })
+            .collect();
+        buffer
+    }
+
+    /// Puts the cursor on the one-based `line` at the one-based `col`,
+    /// counted in characters, or as close to them as the text goes.
+    pub fn go_to(&mut self, line: usize, col: usize) {
+        self.cursor.line = cmp::min(line.saturating_sub(1), self.lines.len().saturating_sub(1));
+        let orig = self.lines.get(self.cursor.line).map_or(&[][..], |line| &line.orig[..]);
+        // Skip the bytes that continue a character.
+        self.cursor.byte = orig.iter()
+            .enumerate()
+            .filter(|(_, b)| **b & 0xc0 != 0x80)
+            .map(|(i, _)| i)
+            .nth(col.saturating_sub(1))
+            .unwrap_or(orig.len());
+    }
+}
+
+impl Editor {
+    pub fn buffer_at(&self, idx: usize) -> &Buffer {
+        if idx == self.current { &self.buffer } else { &self.buffers[idx] }
+    }
+
+    /// Returns the name the buffer at `idx` is listed by.
+    pub fn buffer_name(&self, idx: usize) -> String {
+        let buffer = self.buffer_at(idx);
+        let modified = if buffer.is_dirty { " [+]" } else { "" };
+        format!("{}{}", display_path(&buffer.path), modified)
+    }
+
+    /// Switches to the file at `path`, opening it in a new buffer unless it's
+    /// open already.
+    pub fn open_buffer(&mut self, path: &Path) {
+        let abs_path = editorconfig::absolute(path);
+        if let Some(idx) = (0..self.buffers.len()).find(|&i| editorconfig::absolute(&self.buffer_at(i).path) == abs_path) {
+            self.switch_buffer(idx);
+            return;
+        }
+        match Buffer::open(self.base_config.clone(), path) {
+            Ok(buffer) => {
+                self.buffers.push(buffer);
+                self.switch_buffer(self.buffers.len() - 1);
+                self.configure_buffer();
+            }
+            Err(e) => self.new_status_msg(&format!("Can't open {}: {}", path.display(), e), Duration::from_secs(5)),
+        }
+    }
+
+    /// Shows the buffer at `idx` in the focused window.
+    pub fn switch_buffer(&mut self, idx: usize) {
+        if idx == self.current {
+            return;
+        }
+        self.swap_buffer(idx);
+        self.windows[self.focus].buffer = idx;
+        self.forget_positions();
+    }
+
+    /// Makes the buffer at `idx` the one being edited, putting the current
+    /// one back in its place.
+    pub fn swap_buffer(&mut self, idx: usize) {
+        if idx == self.current {
+            return;
+        }
+        mem::swap(&mut self.buffer, &mut self.buffers[self.current]);
+        mem::swap(&mut self.buffer, &mut self.buffers[idx]);
+        self.current = idx;
+    }
+
+    /// Drops what refers to positions in the buffer that was being edited.
+    pub fn forget_positions(&mut self) {
+        self.mark = None;
+        self.yanked = None;
+        self.isearch = None;
+        if self.vi.mode == Mode::Visual || self.vi.mode == Mode::VisualLine {
+            self.vi.mode = Mode::Normal;
+        }
+    }
+
+    pub fn next_buffer(&mut self) {
+        let idx = (self.current + 1) % self.buffers.len();
+        self.switch_buffer(idx);
+    }
+
+    pub fn previous_buffer(&mut self) {
+        let n = self.buffers.len();
+        self.switch_buffer((self.current + n - 1) % n);
+    }
+
+    /// Opens a picker that lists the open buffers, those after the current one
+    /// first, to switch to one by typing part of its name.
+    pub fn pick_buffer(&mut self) {
+        let n = self.buffers.len();
+        let order: Vec<usize> = (1..=n).map(|i| (self.current + i) % n).collect();
+        let names: Vec<String> = order.iter().map(|&i| self.buffer_name(i)).collect();
+        let choices = names.clone();
+        let prompt = Prompt::new("Buffer: ", "buffer", move |editor: &mut Editor, name: String| {
+            match names.iter().position(|n| *n == name) {
+                Some(i) => editor.switch_buffer(order[i]),
+                None => editor.new_status_msg(&format!("No buffer named {}", name), Duration::from_secs(3)),
+            }
+        });
+        self.open_prompt(prompt.with_menu(choices));
+    }
+
+    /// Saves the buffer under a path that is prompted for.
+    pub fn save_as(&mut self) {
+        let prompt = Prompt::new("Save as: ", "path", |editor: &mut Editor, path: String| {
+            let path = prompt::expand_home(path.trim());
+            if !path.as_os_str().is_empty() {
+                editor.save_as_path(path);
+            }
+        });
+        self.open_prompt(prompt.with_completer(PathCompleter));
+    }
+
+    /// Saves the buffer at `path`, which becomes its path, asking first if
+    /// that would overwrite another file.
+    pub fn save_as_path(&mut self, path: PathBuf) {
+        let is_other = editorconfig::absolute(&path) != editorconfig::absolute(&self.buffer.path);
+        if !is_other || !path.exists() {
+            self.save_to(path);
+            return;
+        }
+        let label = format!("{} exists, overwrite it? ", display_path(&path));
+        let choices = vec!["overwrite".to_string(), "cancel".to_string()];
+        let prompt = Prompt::new(&label, "overwrite", move |editor: &mut Editor, choice: String| {
+            if choice == "overwrite" {
+                editor.save_to(path);
+            }
+        });
+        self.open_prompt(prompt.with_menu(choices));
+    }
+
+    /// Moves the buffer to `path` and saves it there. The file's settings
+    /// and syntax are derived again, as its type may have changed. A file
+    /// that was read-only may well be saved elsewhere, so the buffer no
+    /// longer is once it's moved.
+    fn save_to(&mut self, path: PathBuf) {
+        if self.buffer.loading.is_some() {
+            self.new_status_msg("The file is still loading", Duration::from_secs(3));
+            return;
+        }
+        if self.buffer.is_readonly {
+            self.toggle_readonly();
+        }
+        self.buffer.path = path;
+        self.configure_buffer();
+        self.save();
+    }
+
+    /// Closes the current buffer, asking what to do with unsaved changes
+    /// first. Closing the last buffer quits.
+    pub fn close_buffer(&mut self) {
+        if !self.buffer.is_dirty {
+            self.remove_buffer();
+            return;
+        }
+        let label = format!("{} has unsaved changes: ", display_path(&self.buffer.path));
+        let choices = vec!["save and close".to_string(), "close without saving".to_string(), "cancel".to_string()];
+        let prompt = Prompt::new(&label, "close-buffer", |editor: &mut Editor, choice: String| {
+            match choice.as_str() {
+                "save and close" => {
+                    editor.save();
+                    if !editor.buffer.is_dirty {
+                        editor.remove_buffer();
+                    }
+                }
+                "close without saving" => editor.remove_buffer(),
+                _ => {}
+            }
+        });
+        self.open_prompt(prompt.with_menu(choices));
+    }
+
+    /// Returns the indices of the buffers with unsaved changes.
+    pub fn dirty_buffers(&self) -> Vec<usize> {
+        (0..self.buffers.len()).filter(|&idx| self.buffer_at(idx).is_dirty).collect()
+    }
+
+    /// Quits, asking what to do with the unsaved changes of any buffer
+    /// first.
+    pub fn quit(&mut self) {
+        let dirty = self.dirty_buffers();
+        let label = match dirty.len() {
+            0 => {
+                self.should_quit = true;
+                return;
+            }
+            1 => format!("{} has unsaved changes: ", display_path(&self.buffer_at(dirty[0]).path)),
+            n => format!("{} files have unsaved changes: ", n),
+        };
+        let choices = vec!["save and quit".to_string(), "quit without saving".to_string(), "cancel".to_string()];
+        let prompt = Prompt::new(&label, "quit", move |editor: &mut Editor, choice: String| {
+            match choice.as_str() {
+                "save and quit" => {
+                    // Stop at the first buffer that can't be saved, which is
+                    // left showing along with why.
+                    for &idx in &dirty {
+                        editor.switch_buffer(idx);
+                        editor.save();
+                        if editor.buffer.is_dirty {
+                            return;
+                        }
+                    }
+                    editor.should_quit = true;
+                }
+                "quit without saving" => editor.should_quit = true,
+                _ => {}
+            }
+        });
+        self.open_prompt(prompt.with_menu(choices));
+    }
+
+    fn remove_buffer(&mut self) {
+        if self.buffers.len() == 1 {
+            self.should_quit = true;
+            return;
+        }
+        let removed = self.current;
+        self.buffers.remove(removed);
+        let idx = cmp::min(removed, self.buffers.len() - 1);
+        mem::swap(&mut self.buffer, &mut self.buffers[idx]);
+        // Drop the closed buffer, which was swapped into the placeholder's
+        // slot, along with any file it holds open.
+        self.buffers[idx] = Buffer::new(self.base_config.clone(), PathBuf::new());
+        self.current = idx;
+        // The windows that showed the buffer show the one taking its place.
+        for window in &mut self.windows {
+            if window.buffer == removed {
+                window.buffer = idx;
+                window.view = View::of(&self.buffer);
+            } else if window.buffer > removed {
+                window.buffer -= 1;
+            }
+        }
+        self.forget_positions();
+    }
+}
+
+/// Returns whether the file at `path` may be written to. Opening it to find
+/// out could block, or take the place of a reader, if it's a FIFO.
+fn is_writable(path: &Path) -> bool {
+    match CString::new(path.as_os_str().as_bytes()) {
+        Ok(path) => unsafe { libc::access(path.as_ptr(), libc::W_OK) == 0 },
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::Buffer;
+    use config::Config;
+    use tests::{confirm, editor};
+    use Editor;
+
+    /// Returns an editor of two buffers, the second of which, not the one
+    /// shown, has unsaved changes.
+    fn editor_with_changes() -> Editor {
+        let mut editor = editor("shown");
+        let mut other = Buffer::new(Config::default(), PathBuf::from("other.txt"));
+        other.is_dirty = true;
+        editor.buffers.push(other);
+        editor
+    }
+
+    #[test]
+    fn asks_before_quitting_with_unsaved_changes() {
+        let mut editor = editor_with_changes();
+        editor.quit();
+        assert!(!editor.should_quit);
+        assert_eq!(editor.prompt.as_ref().unwrap().label, "other.txt has unsaved changes: ");
+        confirm(&mut editor, "cancel");
+        assert!(!editor.should_quit);
+        editor.quit();
+        confirm(&mut editor, "quit without saving");
+        assert!(editor.should_quit);
+    }
+
+    #[test]
+    fn quits_at_once_without_unsaved_changes() {
+        let mut editor = editor("text");
+        editor.quit();
+        assert!(editor.should_quit);
+        assert!(editor.prompt.is_none());
+    }
+}
@@ -0,0 +1,216 @@
+use std::path::{Path, PathBuf};
+
+use config::{valid_tab_width, Overrides, WrapMode};
+
+pub const USAGE: &str = "\
+Usage: kilo-rust [OPTION]... [FILE]...
+Edit the FILEs, each in a buffer of its own, or an empty buffer.
+
+  FILE:LINE[:COL]     open FILE with the cursor at LINE (and COL)
+  +LINE FILE          open FILE with the cursor at LINE
+  -                   read the text from standard input
+
+  -R, --readonly      open the files read-only
+  -v, --view          page through the files read-only, with less's keys
+  -f, --follow        view the files, showing what's added to them as they
+                      grow, as tail -f does
+      --tab-width N   show tabs N columns wide
+      --no-wrap       don't wrap lines wider than the window
+      --config PATH   read the settings from PATH rather than the config
+                      directory
+  -h, --help          show this help and exit
+  -V, --version       show the version and exit
+";
+
+/// A file given on the command line, and where to put the cursor in it.
+pub struct FileArg {
+    // `-` for standard input.
+    pub path: PathBuf,
+    // One-based, as given.
+    pub line: Option<usize>,
+    pub col: Option<usize>,
+}
+
+/// What the command line asks for.
+pub enum Command {
+    Edit(Args),
+    Help,
+    Version,
+}
+
+pub struct Args {
+    pub files: Vec<FileArg>,
+    pub is_readonly: bool,
+    pub is_viewed: bool,
+    pub is_followed: bool,
+    pub config_path: Option<PathBuf>,
+    // The settings given as options, which override those for any file.
+    pub overrides: Overrides,
+}
+
+/// Parses the arguments the editor was run with, without the program name.
+/// The error says which argument is wrong.
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut parsed = Args {
+        files: vec![],
+        is_readonly: false,
+        is_viewed: false,
+        is_followed: false,
+        config_path: None,
+        overrides: Overrides::default(),
+    };
+    // The line given by a `+LINE` argument, for the file that follows it.
+    let mut line = None;
+    let mut is_after_options = false;
+    while let Some(arg) = args.next() {
+        if is_after_options || arg == "-" || !(arg.starts_with('-') || arg.starts_with('+')) {
+            if arg == "-" && parsed.files.iter().any(|file| file.path == Path::new("-")) {
+                return Err("standard input can only be read once".to_string());
+            }
+            parsed.files.push(file_arg(&arg, line.take()));
+            continue;
+        }
+        // Options that take a value may also be given it after a '='.
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (&arg[..], None),
+        };
+        match name {
+            "--tab-width" => {
+                let value = value.or_else(|| args.next()).ok_or("option '--tab-width' needs a number")?;
+                match value.parse().ok().and_then(valid_tab_width) {
+                    Some(n) => parsed.overrides.tab_width = Some(n),
+                    None => return Err(format!("invalid tab width '{}', it must be between 1 and 32", value)),
+                }
+            }
+            "--config" => {
+                let value = value.or_else(|| args.next()).ok_or("option '--config' needs a path")?;
+                parsed.config_path = Some(PathBuf::from(value));
+            }
+            _ => match &arg[..] {
+                "--" => is_after_options = true,
+                "-h" | "--help" => return Ok(Command::Help),
+                "-V" | "--version" => return Ok(Command::Version),
+                "-R" | "--readonly" => parsed.is_readonly = true,
+                "-v" | "--view" => parsed.is_viewed = true,
+                "-f" | "--follow" => parsed.is_followed = true,
+                "--no-wrap" => parsed.overrides.wrap_mode = Some(WrapMode::NoWrap),
+                _ if arg.starts_with('+') => match arg[1..].parse() {
+                    Ok(n) => line = Some(n),
+                    Err(_) => return Err(format!("invalid line number '{}'", &arg[1..])),
+                },
+                _ => return Err(format!("unknown option '{}'", arg)),
+            },
+        }
+    }
+    if line.is_some() {
+        return Err("a +LINE argument must be followed by a file".to_string());
+    }
+    Ok(Command::Edit(parsed))
+}
+
+/// Makes a file argument of `arg`, which may end in `:LINE` or
+/// `:LINE:COL`, as in compilers' and grep's messages, unless a file is
+/// named that.
+fn file_arg(arg: &str, line: Option<usize>) -> FileArg {
+    let literal = FileArg { path: PathBuf::from(arg), line, col: None };
+    if line.is_some() || arg == "-" || Path::new(arg).exists() {
+        return literal;
+    }
+    let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    let trimmed = arg.strip_suffix(':').unwrap_or(arg);
+    let (rest, last) = match trimmed.rsplit_once(':') {
+        Some((rest, n)) if !rest.is_empty() && is_number(n) => (rest, n.parse().ok()),
+        _ => return literal,
+    };
+    match rest.rsplit_once(':') {
+        Some((path, n)) if !path.is_empty() && is_number(n) => {
+            FileArg { path: PathBuf::from(path), line: n.parse().ok(), col: last }
+        }
+        _ => FileArg { path: PathBuf::from(rest), line: last, col: None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::{file_arg, parse, Args, Command};
+    use config::WrapMode;
+
+    fn parse_args(args: &[&str]) -> Result<Args, String> {
+        match parse(args.iter().map(|arg| arg.to_string()))? {
+            Command::Edit(args) => Ok(args),
+            Command::Help => Err("help".to_string()),
+            Command::Version => Err("version".to_string()),
+        }
+    }
+
+    fn error(args: &[&str]) -> String {
+        parse_args(args).err().unwrap()
+    }
+
+    /// Returns the path, line and column of a file argument.
+    fn file(arg: &str) -> (String, Option<usize>, Option<usize>) {
+        let file = file_arg(arg, None);
+        (file.path.to_string_lossy().to_string(), file.line, file.col)
+    }
+
+    #[test]
+    fn parses_options_and_files() {
+        let args = parse_args(&["-R", "--tab-width", "4", "a.rs", "--no-wrap", "+7", "b.rs", "--", "-f"]).unwrap();
+        assert!(args.is_readonly && !args.is_viewed && !args.is_followed);
+        assert_eq!(args.overrides.tab_width, Some(4));
+        assert_eq!(args.overrides.wrap_mode, Some(WrapMode::NoWrap));
+        let files: Vec<_> = args.files.iter().map(|f| (f.path.clone(), f.line)).collect();
+        assert_eq!(files, vec![
+            (PathBuf::from("a.rs"), None),
+            (PathBuf::from("b.rs"), Some(7)),
+            (PathBuf::from("-f"), None),
+        ]);
+        let args = parse_args(&["--config=kilo.toml", "--tab-width=8", "-v", "-"]).unwrap();
+        assert_eq!(args.config_path, Some(PathBuf::from("kilo.toml")));
+        assert_eq!(args.overrides.tab_width, Some(8));
+        assert!(args.is_viewed);
+        assert_eq!(args.files[0].path, PathBuf::from("-"));
+        assert_eq!(error(&["a.rs", "-h"]), "help");
+        assert_eq!(error(&["--version"]), "version");
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(error(&["--tab-width"]), "option '--tab-width' needs a number");
+        assert_eq!(error(&["--tab-width", "0"]), "invalid tab width '0', it must be between 1 and 32");
+        assert_eq!(error(&["--tab-width=2000000000"]), "invalid tab width '2000000000', it must be between 1 and 32");
+        assert_eq!(error(&["--config"]), "option '--config' needs a path");
+        assert_eq!(error(&["+x", "a.rs"]), "invalid line number 'x'");
+        assert_eq!(error(&["a.rs", "+3"]), "a +LINE argument must be followed by a file");
+        assert_eq!(error(&["-", "-"]), "standard input can only be read once");
+        assert_eq!(error(&["--wrap"]), "unknown option '--wrap'");
+    }
+
+    #[test]
+    fn splits_lines_and_columns_off_file_names() {
+        assert_eq!(file("no/such/a.rs"), ("no/such/a.rs".to_string(), None, None));
+        assert_eq!(file("no/such/a.rs:12"), ("no/such/a.rs".to_string(), Some(12), None));
+        assert_eq!(file("no/such/a.rs:12:3"), ("no/such/a.rs".to_string(), Some(12), Some(3)));
+        assert_eq!(file("no/such/a.rs:12:3:"), ("no/such/a.rs".to_string(), Some(12), Some(3)));
+        assert_eq!(file("no/such/a.rs:x"), ("no/such/a.rs:x".to_string(), None, None));
+        assert_eq!(file(":12"), (":12".to_string(), None, None));
+        assert_eq!(file("12:3"), ("12".to_string(), Some(3), None));
+        let file = file_arg("no/such/a.rs:12", Some(5));
+        assert_eq!((file.path, file.line), (PathBuf::from("no/such/a.rs:12"), Some(5)));
+    }
+
+    #[test]
+    fn keeps_the_names_of_files_that_exist() {
+        let path = env::temp_dir().join(format!("kilo-rust-test-{}:4", std::process::id()));
+        fs::write(&path, "").unwrap();
+        let arg = path.to_string_lossy().to_string();
+        let parsed = file(&arg);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(parsed, (arg, None, None));
+    }
+}
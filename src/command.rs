@@ -0,0 +1,89 @@
+use Editor;
+
+/// An action of the editor that keys can be bound to and that can be run by
+/// name from the command palette.
+pub struct Command {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub run: fn(&mut Editor),
+}
+
+/// All commands, in the order the command palette lists them when nothing
+/// has been typed.
+pub const COMMANDS: &[Command] = &[
+    Command { name: "save", description: "Save the file", run: Editor::save },
+    Command { name: "save-as", description: "Save the file under another name", run: Editor::save_as },
+    Command { name: "quit", description: "Quit the editor", run: Editor::quit },
+    Command { name: "open-file", description: "Open another file", run: Editor::prompt_open_file },
+    Command { name: "switch-buffer", description: "Switch to an open file by name", run: Editor::pick_buffer },
+    Command { name: "next-buffer", description: "Switch to the next open file", run: Editor::next_buffer },
+    Command { name: "previous-buffer", description: "Switch to the previous open file", run: Editor::previous_buffer },
+    Command { name: "move-tab-left", description: "Move the file's tab to the left", run: Editor::move_tab_left },
+    Command { name: "move-tab-right", description: "Move the file's tab to the right", run: Editor::move_tab_right },
+    Command { name: "close-buffer", description: "Close the file, or quit if it's the last one", run: Editor::close_buffer },
+    Command { name: "split-below", description: "Split the window into one above the other", run: Editor::split_below },
+    Command { name: "split-right", description: "Split the window into two side by side", run: Editor::split_right },
+    Command { name: "close-window", description: "Close the window, leaving its file open", run: Editor::close_window },
+    Command { name: "close-other-windows", description: "Close all windows but this one", run: Editor::close_other_windows },
+    Command { name: "next-window", description: "Move to the next window", run: Editor::next_window },
+    Command { name: "focus-up", description: "Move to the window above", run: Editor::focus_up },
+    Command { name: "focus-down", description: "Move to the window below", run: Editor::focus_down },
+    Command { name: "focus-left", description: "Move to the window on the left", run: Editor::focus_left },
+    Command { name: "focus-right", description: "Move to the window on the right", run: Editor::focus_right },
+    Command { name: "grow-window", description: "Make the window a row or column bigger", run: Editor::grow_window },
+    Command { name: "shrink-window", description: "Make the window a row or column smaller", run: Editor::shrink_window },
+    Command { name: "toggle-readonly", description: "Make the file read-only, or editable again", run: Editor::toggle_readonly },
+    Command { name: "filter-lines", description: "Show only the lines that match a regular expression", run: Editor::filter_lines },
+    Command { name: "filter-lines-literal", description: "Show only the lines that contain some text", run: Editor::filter_lines_literal },
+    Command { name: "invert-filter", description: "Show the lines the last filter hides, and hide the rest", run: Editor::invert_filter },
+    Command { name: "pop-filter", description: "Remove the last filter", run: Editor::pop_filter },
+    Command { name: "clear-filters", description: "Remove all filters, showing every line", run: Editor::clear_filters },
+    Command { name: "follow", description: "Keep to the end of the file as it grows, as tail -f does", run: Editor::follow },
+    Command { name: "search-forward", description: "Search forward for a regular expression", run: Editor::search_forward },
+    Command { name: "search-backward", description: "Search backward for a regular expression", run: Editor::search_backward },
+    Command { name: "search-next", description: "Go to the next match of the last search", run: Editor::search_next },
+    Command { name: "search-previous", description: "Go to the previous match of the last search", run: Editor::search_previous },
+    Command { name: "goto-line", description: "Go to a line by number", run: Editor::prompt_goto_line },
+    Command { name: "command-palette", description: "Run a command by name", run: Editor::open_command_palette },
+    Command { name: "toggle-wrap", description: "Switch between char, word and no wrapping", run: Editor::toggle_wrap_mode },
+    Command { name: "toggle-line-numbers", description: "Show or hide line numbers", run: Editor::toggle_line_numbers },
+    Command { name: "toggle-comment", description: "Comment or uncomment the line", run: Editor::toggle_comment },
+    Command { name: "reload-config", description: "Reread the config file", run: Editor::reload_config },
+    Command { name: "describe-key", description: "Show what a key is bound to", run: Editor::describe_key },
+    Command { name: "describe-syntax", description: "Show which syntax highlights the file", run: Editor::describe_syntax },
+    Command { name: "cursor-up", description: "Move the cursor up a row", run: Editor::cursor_up },
+    Command { name: "cursor-down", description: "Move the cursor down a row", run: Editor::cursor_down },
+    Command { name: "cursor-left", description: "Move the cursor left a character", run: Editor::cursor_left },
+    Command { name: "cursor-right", description: "Move the cursor right a character", run: Editor::cursor_right },
+    Command { name: "page-up", description: "Move the cursor up a window", run: Editor::page_up },
+    Command { name: "page-down", description: "Move the cursor down a window", run: Editor::page_down },
+    Command { name: "line-start", description: "Move the cursor to the start of the line", run: Editor::cursor_line_home },
+    Command { name: "line-end", description: "Move the cursor to the end of the line", run: Editor::cursor_line_end },
+    Command { name: "file-start", description: "Move the cursor to the start of the file", run: Editor::cursor_file_home },
+    Command { name: "file-end", description: "Move the cursor to the end of the file", run: Editor::cursor_file_end },
+    Command { name: "insert-newline", description: "Break the line at the cursor", run: Editor::insert_newline },
+    Command { name: "insert-tab", description: "Insert a tab, or spaces if tabs are expanded", run: Editor::insert_tab },
+    Command { name: "delete-back", description: "Delete the character before the cursor", run: Editor::delete_back },
+    Command { name: "delete-forward", description: "Delete the character under the cursor", run: Editor::delete_forward },
+    Command { name: "undo", description: "Undo the last change", run: Editor::undo },
+    Command { name: "redo", description: "Redo the last change undone", run: Editor::redo },
+    Command { name: "forward-word", description: "Move the cursor to the end of the word", run: Editor::forward_word },
+    Command { name: "backward-word", description: "Move the cursor to the start of the word", run: Editor::backward_word },
+    Command { name: "set-mark", description: "Set the mark at the cursor, to start a region", run: Editor::set_mark },
+    Command { name: "keyboard-quit", description: "Clear the mark", run: Editor::keyboard_quit },
+    Command { name: "kill-line", description: "Cut to the end of the line", run: Editor::kill_line },
+    Command { name: "kill-region", description: "Cut the text between the mark and the cursor", run: Editor::kill_region },
+    Command { name: "copy-region", description: "Copy the text between the mark and the cursor", run: Editor::copy_region },
+    Command { name: "yank", description: "Paste the latest cut or copied text", run: Editor::yank },
+    Command { name: "yank-pop", description: "Replace the pasted text with the text cut before it", run: Editor::yank_pop },
+    Command { name: "isearch-forward", description: "Search forward as you type", run: Editor::isearch_forward },
+    Command { name: "isearch-backward", description: "Search backward as you type", run: Editor::isearch_backward },
+    Command { name: "record-macro", description: "Start or stop recording keys into a register", run: Editor::record_macro },
+    Command { name: "play-macro", description: "Play the keys recorded into a register", run: Editor::play_macro },
+    Command { name: "save-macros", description: "Save the recorded macros to the config directory", run: Editor::save_macros },
+];
+
+/// Returns the command with the given name.
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
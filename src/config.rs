@@ -0,0 +1,1103 @@
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use command;
+use editorconfig;
+use keymap::{self, Keymap};
+use macros;
+use filetype;
+use status;
+use syntax;
+use theme::{self, ColorDepth, Theme};
+
+/// The set of key bindings and editing model the editor starts with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Profile {
+    Default,
+    /// Modal editing, with vi's normal, insert, visual and command-line
+    /// modes.
+    Vi,
+    /// Emacs's key bindings, on top of the default ones.
+    Emacs,
+}
+
+impl Profile {
+    fn from_name(name: &str) -> Option<Profile> {
+        match name {
+            "default" => Some(Profile::Default),
+            "vi" => Some(Profile::Vi),
+            "emacs" => Some(Profile::Emacs),
+            _ => None,
+        }
+    }
+}
+
+/// Determines how lines that are wider than the window are laid out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    /// Lines are split into rows of exactly `window_width` bytes.
+    Char,
+    /// Lines are split after whitespace or punctuation so that words are kept
+    /// whole, unless a word doesn't fit in a row by itself.
+    Word,
+    /// Lines are never split. Instead, the window is scrolled horizontally to
+    /// follow the cursor.
+    NoWrap,
+}
+
+impl WrapMode {
+    fn from_name(name: &str) -> Option<WrapMode> {
+        match name {
+            "char" => Some(WrapMode::Char),
+            "word" => Some(WrapMode::Word),
+            "none" => Some(WrapMode::NoWrap),
+            _ => None,
+        }
+    }
+}
+
+/// When the tab bar, which lists the open files, is shown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TabBar {
+    /// While more than one file is open.
+    Auto,
+    Always,
+    Never,
+}
+
+impl TabBar {
+    fn from_name(name: &str) -> Option<TabBar> {
+        match name {
+            "auto" => Some(TabBar::Auto),
+            "always" => Some(TabBar::Always),
+            "never" => Some(TabBar::Never),
+            _ => None,
+        }
+    }
+}
+
+/// The sequence of bytes that ends lines in a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+    Cr,
+}
+
+impl LineEnding {
+    pub fn from_name(name: &str) -> Option<LineEnding> {
+        match name {
+            "lf" => Some(LineEnding::Lf),
+            "crlf" => Some(LineEnding::CrLf),
+            "cr" => Some(LineEnding::Cr),
+            _ => None,
+        }
+    }
+
+    /// Returns the line ending used by the file with contents `buf`, going by
+    /// its first line. Files without line breaks are assumed to use `Lf`.
+    pub fn detect(buf: &[u8]) -> LineEnding {
+        match buf.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            Some(i) if buf[i] == b'\r' => {
+                if buf.get(i + 1) == Some(&b'\n') { LineEnding::CrLf } else { LineEnding::Cr }
+            }
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+            LineEnding::Cr => b"\r",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub tab_width: i32,
+    // Whether pressing Tab inserts spaces up to the next tab stop instead of
+    // a tab character.
+    pub expand_tabs: bool,
+    pub wrap_mode: WrapMode,
+    // In no-wrap mode, the number of columns to keep between the cursor and
+    // the left and right edges of the window when scrolling horizontally.
+    pub h_scroll_margin: usize,
+    // In word-wrap mode, whether to indent continuation rows to match the
+    // leading indentation of their line.
+    pub wrap_indent: bool,
+    // In word-wrap mode, drawn at the start of each continuation row (after
+    // the indentation). May be empty.
+    pub wrap_indicator: String,
+    pub line_numbers: bool,
+    pub profile: Profile,
+    pub theme: String,
+    // How many colours to use, or `None` to go by what the terminal says it
+    // supports.
+    pub color_depth: Option<ColorDepth>,
+    pub tab_bar: TabBar,
+    // Whether the terminal reports mouse clicks, which keeps it from
+    // selecting text itself.
+    pub mouse: bool,
+    // The size in megabytes from which files are loaded in the background
+    // and aren't highlighted.
+    pub large_file_size: usize,
+    // Maps key names, such as "ctrl-s", to the names of the commands they run.
+    pub keymap: Keymap,
+    // Keyboard macros, by register, as the names of their keys.
+    pub macros: Vec<(char, Vec<String>)>,
+    // Describes what the status bar shows. See `status::parse`.
+    pub status_format: Vec<status::Piece>,
+    // The name of the language of the file, if it was recognized.
+    pub filetype: Option<String>,
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+    // The line ending to save the file with, or `None` to keep the one the
+    // file was read with.
+    pub end_of_line: Option<LineEnding>,
+    pub trim_trailing_whitespace: bool,
+    // Whether to make sure the file ends with a new line when saving it (or
+    // that it doesn't, if false), or `None` to leave it as it is.
+    pub insert_final_newline: Option<bool>,
+    // Settings that override the above for files in a language, keyed by the
+    // language's name.
+    pub languages: Vec<(String, Overrides)>,
+    // Settings that override the above for files whose name matches a glob
+    // pattern. Later entries take precedence.
+    pub filetypes: Vec<(String, Overrides)>,
+    // The syntax definitions loaded from the config directory.
+    pub syntaxes: Vec<syntax::Definition>,
+    // The themes loaded from the config directory.
+    pub themes: Vec<Theme>,
+    // The themes in the config directory that couldn't be loaded, by name,
+    // with where and why.
+    pub theme_errors: Vec<(String, String)>,
+    // Settings given on the command line, which override those for any
+    // file.
+    pub command_line: Overrides,
+}
+
+/// The subset of settings that may be set per file, whether by language,
+/// file type, EditorConfig or modeline. `None` means the setting is not
+/// overridden.
+#[derive(Clone, Default)]
+pub struct Overrides {
+    pub tab_width: Option<i32>,
+    pub expand_tabs: Option<bool>,
+    pub wrap_mode: Option<WrapMode>,
+    pub line_numbers: Option<bool>,
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+    pub end_of_line: Option<LineEnding>,
+    pub trim_trailing_whitespace: Option<bool>,
+    pub insert_final_newline: Option<bool>,
+}
+
+const DEFAULT_STATUS_FORMAT: &str = "{path}{modified}{=}{mode} {filetype} | {line}:{col} | {percent}";
+
+/// The key bindings that apply unless the config file changes them.
+const DEFAULT_KEYS: &[(&str, &str)] = &[
+    ("ctrl-c", "quit"),
+    ("ctrl-s", "save"),
+    ("ctrl-x ctrl-w", "save-as"),
+    ("ctrl-x ctrl-q", "toggle-readonly"),
+    ("ctrl-x f", "filter-lines"),
+    ("ctrl-w", "toggle-wrap"),
+    ("ctrl-r", "reload-config"),
+    ("ctrl-_", "toggle-comment"),
+    ("ctrl-z", "undo"),
+    ("ctrl-y", "redo"),
+    ("ctrl-t", "describe-syntax"),
+    ("ctrl-p", "command-palette"),
+    ("alt-x", "command-palette"),
+    ("ctrl-x ?", "describe-key"),
+    ("ctrl-x (", "record-macro"),
+    ("ctrl-x )", "record-macro"),
+    ("ctrl-x e", "play-macro"),
+    ("ctrl-g", "goto-line"),
+    ("ctrl-o", "open-file"),
+    ("ctrl-x b", "switch-buffer"),
+    ("ctrl-x n", "next-buffer"),
+    ("ctrl-x p", "previous-buffer"),
+    ("ctrl-x k", "close-buffer"),
+    ("ctrl-x <", "move-tab-left"),
+    ("ctrl-x >", "move-tab-right"),
+    ("ctrl-x 2", "split-below"),
+    ("ctrl-x 3", "split-right"),
+    ("ctrl-x 0", "close-window"),
+    ("ctrl-x 1", "close-other-windows"),
+    ("ctrl-x o", "next-window"),
+    ("ctrl-x up", "focus-up"),
+    ("ctrl-x down", "focus-down"),
+    ("ctrl-x left", "focus-left"),
+    ("ctrl-x right", "focus-right"),
+    ("ctrl-x +", "grow-window"),
+    ("ctrl-x -", "shrink-window"),
+    ("up", "cursor-up"),
+    ("down", "cursor-down"),
+    ("left", "cursor-left"),
+    ("right", "cursor-right"),
+    ("pageup", "page-up"),
+    ("pagedown", "page-down"),
+    ("home", "line-start"),
+    ("end", "line-end"),
+    ("ctrl-home", "file-start"),
+    ("ctrl-end", "file-end"),
+    ("enter", "insert-newline"),
+    ("tab", "insert-tab"),
+    ("backspace", "delete-back"),
+    ("delete", "delete-forward"),
+];
+
+/// The key bindings of the Emacs profile, which replace the default ones
+/// that clash with them. Ctrl-C only starts chords in Emacs.
+const EMACS_KEYS: &[(&str, &str)] = &[
+    ("ctrl-c", ""),
+    ("ctrl-o", ""),
+    ("ctrl-t", ""),
+    ("ctrl-f", "cursor-right"),
+    ("ctrl-b", "cursor-left"),
+    ("ctrl-n", "cursor-down"),
+    ("ctrl-p", "cursor-up"),
+    ("ctrl-a", "line-start"),
+    ("ctrl-e", "line-end"),
+    ("alt-f", "forward-word"),
+    ("alt-b", "backward-word"),
+    ("alt-<", "file-start"),
+    ("alt->", "file-end"),
+    ("ctrl-v", "page-down"),
+    ("alt-v", "page-up"),
+    ("ctrl-d", "delete-forward"),
+    ("ctrl-k", "kill-line"),
+    ("ctrl-w", "kill-region"),
+    ("alt-w", "copy-region"),
+    ("ctrl-y", "yank"),
+    ("alt-y", "yank-pop"),
+    ("ctrl-@", "set-mark"),
+    ("ctrl-g", "keyboard-quit"),
+    ("ctrl-s", "isearch-forward"),
+    ("ctrl-r", "isearch-backward"),
+    ("alt-g g", "goto-line"),
+    ("alt-;", "toggle-comment"),
+    ("ctrl-_", "undo"),
+    ("ctrl-x u", "undo"),
+    ("alt-_", "redo"),
+    ("ctrl-x ctrl-s", "save"),
+    ("ctrl-x ctrl-c", "quit"),
+    ("ctrl-x ctrl-f", "open-file"),
+    ("ctrl-x right", "next-buffer"),
+    ("ctrl-x left", "previous-buffer"),
+    ("ctrl-x ^", "grow-window"),
+];
+
+fn default_keymap() -> Keymap {
+    let mut keymap = Keymap::default();
+    for (keys, command) in DEFAULT_KEYS {
+        keymap.bind(keys, command).unwrap();
+    }
+    keymap
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            tab_width: 4,
+            expand_tabs: false,
+            wrap_mode: WrapMode::Char,
+            h_scroll_margin: 4,
+            wrap_indent: true,
+            wrap_indicator: "↪".to_string(),
+            line_numbers: false,
+            profile: Profile::Default,
+            theme: "default".to_string(),
+            color_depth: None,
+            tab_bar: TabBar::Auto,
+            mouse: true,
+            large_file_size: 64,
+            keymap: default_keymap(),
+            macros: vec![],
+            status_format: status::parse(DEFAULT_STATUS_FORMAT).unwrap(),
+            filetype: None,
+            line_comment: None,
+            block_comment: None,
+            end_of_line: None,
+            trim_trailing_whitespace: false,
+            insert_final_newline: None,
+            languages: vec![],
+            filetypes: vec![],
+            syntaxes: vec![],
+            themes: vec![],
+            theme_errors: vec![],
+            command_line: Overrides::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Returns the directory the config file, syntax definitions and themes
+    /// are looked for in: `$XDG_CONFIG_HOME/kilo-rust`, falling back to
+    /// `~/.config/kilo-rust`.
+    pub fn dir() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_CONFIG_HOME") {
+            Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(dir.join("kilo-rust"))
+    }
+
+    /// Loads the config file at `path`, or the one in the config directory,
+    /// or returns the default config if there is none, along with the syntax
+    /// definitions in the `syntax` directory and the themes in the `themes`
+    /// directory. The error describes where and why the config file could
+    /// not be parsed. Errors in syntax definitions are kept with the
+    /// definitions instead, and themes that can't be loaded are left out and
+    /// kept in `theme_errors`.
+    pub fn load(path: Option<&Path>) -> Result<Config, String> {
+        let dir = Config::dir();
+        let (syntaxes, (themes, theme_errors)) = match dir {
+            Some(ref dir) => (syntax::load_dir(&dir.join("syntax")), theme::load_dir(&dir.join("themes"))),
+            None => (vec![], (vec![], vec![])),
+        };
+        let path = match (path, &dir) {
+            (Some(path), _) => {
+                // Unlike the usual one, a config file that is asked for must
+                // exist.
+                if !path.is_file() {
+                    return Err(format!("{}: no such file", path.display()));
+                }
+                path.to_path_buf()
+            }
+            (None, Some(dir)) => dir.join("config.toml"),
+            (None, None) => return Ok(Config::default()),
+        };
+        let mut config = Config::parse(&read_config_file(&path)?, syntaxes, themes, theme_errors)
+            .map_err(|e| format!("{}:{}", path.display(), e))?;
+        // Macros saved from the editor go in a file of their own, so that
+        // saving them leaves the config file alone.
+        if let Some(dir) = dir {
+            let path = dir.join("macros.toml");
+            config.apply(&read_config_file(&path)?).map_err(|e| format!("{}:{}", path.display(), e))?;
+        }
+        Ok(config)
+    }
+
+    /// Builds a config from the contents of a config file. Settings missing
+    /// from the file keep their default values.
+    pub fn parse(
+        text: &str,
+        syntaxes: Vec<syntax::Definition>,
+        themes: Vec<Theme>,
+        theme_errors: Vec<(String, String)>,
+    ) -> Result<Config, String> {
+        let mut config = Config { syntaxes, themes, theme_errors, ..Config::default() };
+        config.apply(text)?;
+        Ok(config)
+    }
+
+    fn apply(&mut self, text: &str) -> Result<(), String> {
+        for entry in toml::parse(text)? {
+            self.apply_entry(&entry).map_err(|e| format!("{}: {}", entry.line, e))?;
+        }
+        Ok(())
+    }
+
+    fn apply_entry(&mut self, entry: &toml::Entry) -> Result<(), String> {
+        let (key, value) = (entry.key.as_str(), &entry.value);
+        let table: Vec<&str> = entry.table.iter().map(|s| s.as_str()).collect();
+        match table.as_slice() {
+            [] => match key {
+                "tab_width" => self.tab_width = tab_width(key, value)?,
+                "expand_tabs" => self.expand_tabs = value.bool(key)?,
+                "wrap_mode" => self.wrap_mode = wrap_mode(key, value)?,
+                "h_scroll_margin" => self.h_scroll_margin = value.uint(key)?,
+                "wrap_indent" => self.wrap_indent = value.bool(key)?,
+                "wrap_indicator" => self.wrap_indicator = value.str(key)?,
+                "line_numbers" => self.line_numbers = value.bool(key)?,
+                "profile" => {
+                    self.profile = Profile::from_name(&value.str(key)?)
+                        .ok_or_else(|| format!("{} must be one of \"default\", \"vi\" or \"emacs\"", key))?;
+                    // Settings come before tables, so bindings under `[keys]`
+                    // still override these.
+                    if self.profile == Profile::Emacs {
+                        for (keys, command) in EMACS_KEYS {
+                            self.keymap.bind(keys, command).unwrap();
+                        }
+                    }
+                }
+                "theme" => {
+                    let theme = value.str(key)?;
+                    // A theme that couldn't be loaded gives way to the default
+                    // one, as the reason is reported along with the config.
+                    let is_known = theme::is_builtin(&theme)
+                        || self.themes.iter().any(|t| t.name == theme)
+                        || self.theme_errors.iter().any(|(name, _)| *name == theme);
+                    if !is_known {
+                        return Err(format!("unknown theme '{}'", theme));
+                    }
+                    self.theme = theme;
+                }
+                "color_depth" => {
+                    let depth = value.str(key)?;
+                    self.color_depth = match depth.as_str() {
+                        "auto" => None,
+                        _ => Some(ColorDepth::from_name(&depth).ok_or_else(|| {
+                            format!("{} must be one of \"auto\", \"16\", \"256\" or \"truecolor\"", key)
+                        })?),
+                    };
+                }
+                "tab_bar" => {
+                    self.tab_bar = TabBar::from_name(&value.str(key)?)
+                        .ok_or_else(|| format!("{} must be one of \"auto\", \"always\" or \"never\"", key))?;
+                }
+                "mouse" => self.mouse = value.bool(key)?,
+                "large_file_size" => self.large_file_size = value.uint(key)?,
+                "status_format" => self.status_format = status::parse(&value.str(key)?)?,
+                "end_of_line" => self.end_of_line = Some(end_of_line(key, value)?),
+                "trim_trailing_whitespace" => self.trim_trailing_whitespace = value.bool(key)?,
+                "insert_final_newline" => self.insert_final_newline = Some(value.bool(key)?),
+                _ => return Err(format!("unknown setting '{}'", key)),
+            },
+            ["keys"] => {
+                let command = value.str(key)?;
+                if !command.is_empty() && command::find(&command).is_none() {
+                    return Err(format!("unknown command '{}'", command));
+                }
+                self.keymap.bind(key, &command)?;
+            }
+            ["macros"] => {
+                let mut chars = key.chars();
+                let register = match (chars.next(), chars.next()) {
+                    (Some(c), None) if macros::is_register(c) => c,
+                    _ => return Err(format!("macro register '{}' isn't a letter or digit", key)),
+                };
+                let keys: Vec<String> = value.str(key)?.split_whitespace().map(|k| k.to_string()).collect();
+                if let Some(k) = keys.iter().find(|k| !keymap::is_key_name(k)) {
+                    return Err(format!("unknown key '{}'", k));
+                }
+                macros::set(&mut self.macros, register, keys);
+            }
+            ["filetype", pattern] => {
+                // A bare extension, such as `rs`, is short for `*.rs`.
+                let pattern = if pattern.contains(['*', '?', '/']) {
+                    pattern.to_string()
+                } else {
+                    format!("*.{}", pattern)
+                };
+                overrides_entry(&mut self.filetypes, pattern).apply_entry(key, value)?;
+            }
+            ["language", name] => {
+                let name = match filetype::find(name) {
+                    Some(lang) => lang.name.to_string(),
+                    None => match self.find_syntax(name) {
+                        Some(def) => def.name.clone(),
+                        None => return Err(format!("unknown language '{}'", name)),
+                    },
+                };
+                overrides_entry(&mut self.languages, name).apply_entry(key, value)?;
+            }
+            _ => return Err(format!("unknown table [{}]", entry.table.join("."))),
+        }
+        Ok(())
+    }
+
+    /// Returns whether a file of `len` bytes is large enough to be loaded in
+    /// the background.
+    pub fn is_large_file(&self, len: u64) -> bool {
+        len > 0 && len >= (self.large_file_size as u64).saturating_mul(1024 * 1024)
+    }
+
+    /// Returns the config for the file at `path`, given its first and last
+    /// few lines. It detects the file's language and then applies, in order
+    /// of increasing precedence, the language's built-in settings, those of
+    /// its syntax definition, the overrides for the language and for file
+    /// types matching `path`, the EditorConfig files above `path`, and the
+    /// file's modelines.
+    pub fn for_file(&self, path: &Path, head: &[&[u8]], tail: &[&[u8]]) -> Config {
+        let mut config = self.clone();
+        let modeline = filetype::parse_modelines(head.iter().chain(tail.iter()).cloned());
+        // Syntax definitions take precedence over the built-in languages,
+        // which they may also replace by taking their name.
+        let (def, language) = match modeline.filetype {
+            Some(ref name) => (self.find_syntax(name), filetype::find(name)),
+            None => match self.syntaxes.iter().find(|def| def.matches(path)) {
+                Some(def) => (Some(def), filetype::find(&def.name)),
+                None => (None, filetype::detect(path, head.first().cloned().unwrap_or(b""))),
+            },
+        };
+        let def = def.or_else(|| self.find_syntax(language?.name));
+
+        if let Some(lang) = language {
+            config.filetype = Some(lang.name.to_string());
+            config.apply_overrides(&lang.overrides());
+        }
+        if let Some(def) = def {
+            config.filetype = Some(def.name.clone());
+            config.apply_overrides(&Overrides {
+                line_comment: def.syntax.line_comment.clone(),
+                block_comment: def.syntax.block_comment.clone(),
+                ..Overrides::default()
+            });
+        }
+        if let Some(filetype) = config.filetype.clone() {
+            for (name, overrides) in &self.languages {
+                if *name == filetype {
+                    config.apply_overrides(overrides);
+                }
+            }
+        }
+
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let full = path.to_string_lossy();
+        for (pattern, overrides) in &self.filetypes {
+            // Patterns with a slash are matched against the whole path.
+            let subject = if pattern.contains('/') { &full[..] } else { &name[..] };
+            if glob_match(pattern.as_bytes(), subject.as_bytes()) {
+                config.apply_overrides(overrides);
+            }
+        }
+
+        config.apply_overrides(&editorconfig::overrides_for(&editorconfig::absolute(path)));
+        config.apply_overrides(&modeline.overrides);
+        config.apply_overrides(&self.command_line);
+        config
+    }
+
+    fn apply_overrides(&mut self, overrides: &Overrides) {
+        self.tab_width = overrides.tab_width.unwrap_or(self.tab_width);
+        self.expand_tabs = overrides.expand_tabs.unwrap_or(self.expand_tabs);
+        self.wrap_mode = overrides.wrap_mode.unwrap_or(self.wrap_mode);
+        self.line_numbers = overrides.line_numbers.unwrap_or(self.line_numbers);
+        if overrides.line_comment.is_some() {
+            self.line_comment = overrides.line_comment.clone();
+        }
+        if overrides.block_comment.is_some() {
+            self.block_comment = overrides.block_comment.clone();
+        }
+        self.end_of_line = overrides.end_of_line.or(self.end_of_line);
+        self.trim_trailing_whitespace = overrides.trim_trailing_whitespace
+            .unwrap_or(self.trim_trailing_whitespace);
+        self.insert_final_newline = overrides.insert_final_newline.or(self.insert_final_newline);
+    }
+
+    /// Returns the theme the config selects, preferring themes from the config
+    /// directory over the built-in ones with the same name.
+    pub fn theme(&self) -> Theme {
+        self.themes.iter().find(|t| t.name == self.theme).cloned()
+            .or_else(|| Theme::builtin(&self.theme))
+            .unwrap_or_else(|| Theme::builtin("default").unwrap())
+    }
+
+    /// Describes the themes that couldn't be loaded, if any.
+    pub fn theme_error(&self) -> Option<String> {
+        if self.theme_errors.is_empty() {
+            return None;
+        }
+        let errors: Vec<&str> = self.theme_errors.iter().map(|(_, e)| e.as_str()).collect();
+        Some(format!("Skipped themes with errors: {}", errors.join("; ")))
+    }
+
+    /// Returns the syntax definition with the given name, ignoring case.
+    pub fn find_syntax(&self, name: &str) -> Option<&syntax::Definition> {
+        let name = name.to_lowercase();
+        self.syntaxes.iter().find(|def| def.name == name)
+    }
+}
+
+impl Overrides {
+    fn apply_entry(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        match key {
+            "tab_width" => self.tab_width = Some(tab_width(key, value)?),
+            "expand_tabs" => self.expand_tabs = Some(value.bool(key)?),
+            "wrap_mode" => self.wrap_mode = Some(wrap_mode(key, value)?),
+            "line_numbers" => self.line_numbers = Some(value.bool(key)?),
+            "comment" => self.line_comment = Some(value.str(key)?),
+            "end_of_line" => self.end_of_line = Some(end_of_line(key, value)?),
+            "trim_trailing_whitespace" => self.trim_trailing_whitespace = Some(value.bool(key)?),
+            "insert_final_newline" => self.insert_final_newline = Some(value.bool(key)?),
+            _ => return Err(format!("setting '{}' cannot be overridden per file type", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Returns the contents of a file in the config directory, which are empty
+/// if there's no such file.
+fn read_config_file(path: &Path) -> Result<String, String> {
+    let mut text = String::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            if let Err(e) = file.read_to_string(&mut text) {
+                return Err(format!("{}: {}", path.display(), e));
+            }
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    }
+    Ok(text)
+}
+
+/// Returns the overrides keyed by `name`, adding empty ones if there are none.
+fn overrides_entry(list: &mut Vec<(String, Overrides)>, name: String) -> &mut Overrides {
+    match list.iter().position(|(n, _)| *n == name) {
+        Some(i) => &mut list[i].1,
+        None => {
+            list.push((name, Overrides::default()));
+            &mut list.last_mut().unwrap().1
+        }
+    }
+}
+
+fn tab_width(key: &str, value: &toml::Value) -> Result<i32, String> {
+    valid_tab_width(value.uint(key)?).ok_or_else(|| format!("{} must be between 1 and {}", key, MAX_TAB_WIDTH))
+}
+
+const MAX_TAB_WIDTH: usize = 32;
+
+/// Returns `n` as a tab width if it's between 1 and 32, wherever the width
+/// comes from: a tab is drawn as that many spaces, so a file mustn't be able
+/// to ask for billions of them.
+pub fn valid_tab_width(n: usize) -> Option<i32> {
+    if (1..=MAX_TAB_WIDTH).contains(&n) { Some(n as i32) } else { None }
+}
+
+fn wrap_mode(key: &str, value: &toml::Value) -> Result<WrapMode, String> {
+    WrapMode::from_name(&value.str(key)?)
+        .ok_or_else(|| format!("{} must be one of \"char\", \"word\" or \"none\"", key))
+}
+
+fn end_of_line(key: &str, value: &toml::Value) -> Result<LineEnding, String> {
+    LineEnding::from_name(&value.str(key)?)
+        .ok_or_else(|| format!("{} must be one of \"lf\", \"crlf\" or \"cr\"", key))
+}
+
+/// Matches `text` against a glob pattern in which `*` matches any sequence of
+/// bytes and `?` matches any single byte.
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..text.len() + 1).any(|i| glob_match(rest, &text[i..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// A parser for the subset of TOML used by the config file: tables, and keys
+/// with string, integer, boolean or array values.
+pub mod toml {
+    pub enum Value {
+        Str(String),
+        Int(i64),
+        Bool(bool),
+        Array(Vec<Value>),
+    }
+
+    impl Value {
+        fn type_name(&self) -> &'static str {
+            match *self {
+                Value::Str(_) => "a string",
+                Value::Int(_) => "an integer",
+                Value::Bool(_) => "a boolean",
+                Value::Array(_) => "an array",
+            }
+        }
+
+        pub fn str(&self, key: &str) -> Result<String, String> {
+            match *self {
+                Value::Str(ref s) => Ok(s.clone()),
+                _ => Err(format!("{} must be a string, not {}", key, self.type_name())),
+            }
+        }
+
+        /// Returns the strings in an array of strings.
+        pub fn strs(&self, key: &str) -> Result<Vec<String>, String> {
+            match *self {
+                Value::Array(ref values) => values.iter().map(|v| v.str(key)).collect(),
+                _ => Err(format!("{} must be an array of strings, not {}", key, self.type_name())),
+            }
+        }
+
+        pub fn bool(&self, key: &str) -> Result<bool, String> {
+            match *self {
+                Value::Bool(b) => Ok(b),
+                _ => Err(format!("{} must be a boolean, not {}", key, self.type_name())),
+            }
+        }
+
+        pub fn uint(&self, key: &str) -> Result<usize, String> {
+            match *self {
+                Value::Int(n) if n >= 0 => Ok(n as usize),
+                Value::Int(_) => Err(format!("{} must not be negative", key)),
+                _ => Err(format!("{} must be an integer, not {}", key, self.type_name())),
+            }
+        }
+    }
+
+    /// A key-value pair along with the table it's in and the (1-based) line
+    /// it's on, for error reporting.
+    pub struct Entry {
+        pub table: Vec<String>,
+        pub key: String,
+        pub value: Value,
+        pub line: usize,
+    }
+
+    const UNTERMINATED_ARRAY: &str = "unterminated array";
+
+    /// Parses `text` into its entries, in the order they appear. The error
+    /// is prefixed with the number of the offending line.
+    pub fn parse(text: &str) -> Result<Vec<Entry>, String> {
+        let mut entries = vec![];
+        let mut table = vec![];
+        let lines: Vec<&str> = text.lines().collect();
+        let mut i = 0;
+        while i < lines.len() {
+            // Arrays may span several lines, in which case the following
+            // lines are parsed along with the one the array starts on.
+            let start = i;
+            let mut line = lines[i].to_string();
+            let kv = loop {
+                let mut p = Parser { s: line.as_bytes(), pos: 0 };
+                match p.line(&mut table) {
+                    Err(ref e) if e == UNTERMINATED_ARRAY && i + 1 < lines.len() => {
+                        i += 1;
+                        line.push('\n');
+                        line.push_str(lines[i]);
+                    }
+                    Err(e) => return Err(format!("{}: {}", start + 1, e)),
+                    Ok(kv) => break kv,
+                }
+            };
+            if let Some((key, value)) = kv {
+                entries.push(Entry { table: table.clone(), key, value, line: start + 1 });
+            }
+            i += 1;
+        }
+        Ok(entries)
+    }
+
+    struct Parser<'a> {
+        s: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Parser<'a> {
+        fn peek(&self) -> Option<u8> {
+            self.s.get(self.pos).cloned()
+        }
+
+        fn skip_ws(&mut self) {
+            while let Some(b' ') | Some(b'\t') = self.peek() {
+                self.pos += 1;
+            }
+        }
+
+        fn expect(&mut self, c: u8) -> Result<(), String> {
+            self.skip_ws();
+            if self.peek() == Some(c) {
+                self.pos += 1;
+                Ok(())
+            } else {
+                Err(format!("expected '{}'", c as char))
+            }
+        }
+
+        /// Parses a whole line, which may be a table header, a key-value
+        /// pair, or nothing but whitespace and comments.
+        fn line(&mut self, table: &mut Vec<String>) -> Result<Option<(String, Value)>, String> {
+            self.skip_ws();
+            let kv = match self.peek() {
+                None | Some(b'#') => None,
+                Some(b'[') => {
+                    self.pos += 1;
+                    table.clear();
+                    loop {
+                        table.push(self.key()?);
+                        self.skip_ws();
+                        match self.peek() {
+                            Some(b'.') => self.pos += 1,
+                            Some(b']') => { self.pos += 1; break; }
+                            _ => return Err("expected '.' or ']' in table name".to_string()),
+                        }
+                    }
+                    None
+                }
+                Some(_) => {
+                    let key = self.key()?;
+                    self.expect(b'=')?;
+                    Some((key, self.value()?))
+                }
+            };
+            self.skip_ws();
+            match self.peek() {
+                None | Some(b'#') => Ok(kv),
+                Some(_) => Err("unexpected text at end of line".to_string()),
+            }
+        }
+
+        fn key(&mut self) -> Result<String, String> {
+            self.skip_ws();
+            match self.peek() {
+                Some(b'"') | Some(b'\'') => self.string(),
+                _ => {
+                    let start = self.pos;
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    if start == self.pos {
+                        Err("expected a key".to_string())
+                    } else {
+                        Ok(String::from_utf8_lossy(&self.s[start..self.pos]).to_string())
+                    }
+                }
+            }
+        }
+
+        /// Skips whitespace, line breaks and comments between array items.
+        fn skip_ws_and_comments(&mut self) {
+            loop {
+                match self.peek() {
+                    Some(b' ') | Some(b'\t') | Some(b'\n') => self.pos += 1,
+                    Some(b'#') => {
+                        while self.peek().is_some_and(|c| c != b'\n') {
+                            self.pos += 1;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+
+        fn array(&mut self) -> Result<Value, String> {
+            self.pos += 1;
+            let mut values = vec![];
+            loop {
+                self.skip_ws_and_comments();
+                match self.peek() {
+                    None => return Err(UNTERMINATED_ARRAY.to_string()),
+                    Some(b']') => break,
+                    Some(_) => values.push(self.value()?),
+                }
+                self.skip_ws_and_comments();
+                match self.peek() {
+                    None => return Err(UNTERMINATED_ARRAY.to_string()),
+                    Some(b',') => self.pos += 1,
+                    Some(b']') => break,
+                    Some(_) => return Err("expected ',' or ']' in array".to_string()),
+                }
+            }
+            self.pos += 1;
+            Ok(Value::Array(values))
+        }
+
+        fn value(&mut self) -> Result<Value, String> {
+            self.skip_ws();
+            match self.peek() {
+                Some(b'"') | Some(b'\'') => Ok(Value::Str(self.string()?)),
+                Some(b'[') => self.array(),
+                _ => {
+                    let start = self.pos;
+                    while let Some(c) = self.peek() {
+                        if c.is_ascii_alphanumeric() || c == b'-' || c == b'+' || c == b'_' {
+                            self.pos += 1;
+                        } else {
+                            break;
+                        }
+                    }
+                    let word = String::from_utf8_lossy(&self.s[start..self.pos]).replace('_', "");
+                    match word.as_str() {
+                        "true" => Ok(Value::Bool(true)),
+                        "false" => Ok(Value::Bool(false)),
+                        "" => Err("expected a value".to_string()),
+                        _ => word.parse().map(Value::Int)
+                            .map_err(|_| format!("invalid value '{}'", word)),
+                    }
+                }
+            }
+        }
+
+        /// Parses a basic ("...") or literal ('...') string. Only basic
+        /// strings have escape sequences.
+        fn string(&mut self) -> Result<String, String> {
+            let quote = self.peek().unwrap();
+            self.pos += 1;
+            let mut buf = vec![];
+            loop {
+                match self.peek() {
+                    None => return Err("unterminated string".to_string()),
+                    Some(c) if c == quote => {
+                        self.pos += 1;
+                        break;
+                    }
+                    Some(b'\\') if quote == b'"' => {
+                        self.pos += 1;
+                        let c = match self.peek() {
+                            Some(b'n') => b'\n',
+                            Some(b't') => b'\t',
+                            Some(b'\\') => b'\\',
+                            Some(b'"') => b'"',
+                            _ => return Err("invalid escape sequence in string".to_string()),
+                        };
+                        buf.push(c);
+                        self.pos += 1;
+                    }
+                    Some(c) => {
+                        buf.push(c);
+                        self.pos += 1;
+                    }
+                }
+            }
+            String::from_utf8(buf).map_err(|_| "string is not valid UTF-8".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{glob_match, toml, valid_tab_width, Config};
+    use theme;
+
+    /// Returns the entries of `text` as "table.key" and a rendering of the
+    /// value, or the error.
+    fn parse(text: &str) -> Result<Vec<(String, String)>, String> {
+        fn show(value: &toml::Value) -> String {
+            match *value {
+                toml::Value::Str(ref s) => format!("{:?}", s),
+                toml::Value::Int(n) => n.to_string(),
+                toml::Value::Bool(b) => b.to_string(),
+                toml::Value::Array(ref values) => {
+                    format!("[{}]", values.iter().map(show).collect::<Vec<_>>().join(", "))
+                }
+            }
+        }
+        let entries = toml::parse(text)?;
+        Ok(entries.iter().map(|e| {
+            let mut path = e.table.clone();
+            path.push(e.key.clone());
+            (path.join("."), show(&e.value))
+        }).collect())
+    }
+
+    fn entry(path: &str, value: &str) -> (String, String) {
+        (path.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn parses_keys_in_tables() {
+        let text = "# comment\nsoft_wrap = \"word\"\n\n[keys] # keys\nctrl-s = 'save'\n[\"a b\".c]\nd-e_f = 1_000";
+        assert_eq!(parse(text), Ok(vec![
+            entry("soft_wrap", "\"word\""),
+            entry("keys.ctrl-s", "\"save\""),
+            entry("a b.c.d-e_f", "1000"),
+        ]));
+    }
+
+    #[test]
+    fn parses_values() {
+        let text = "a = true\nb = false\nc = -3\nd = \"x\\ty\\\\\\\"\\n\"\ne = 'x\\t'\nf = []\ng = [1, [\"s\"], false,]";
+        assert_eq!(parse(text), Ok(vec![
+            entry("a", "true"),
+            entry("b", "false"),
+            entry("c", "-3"),
+            entry("d", "\"x\\ty\\\\\\\"\\n\""),
+            entry("e", "\"x\\\\t\""),
+            entry("f", "[]"),
+            entry("g", "[1, [\"s\"], false]"),
+        ]));
+    }
+
+    #[test]
+    fn parses_arrays_over_several_lines() {
+        let entries = toml::parse("a = [\n  1, # one\n  2,\n]\nb = 3").unwrap();
+        assert_eq!(entries.iter().map(|e| (e.key.as_str(), e.line)).collect::<Vec<_>>(), vec![("a", 1), ("b", 5)]);
+        assert_eq!(parse("a = [1, 2"), Err("1: unterminated array".to_string()));
+    }
+
+    #[test]
+    fn reports_errors_with_their_line() {
+        assert_eq!(parse("a = 1\nb"), Err("2: expected '='".to_string()));
+        assert_eq!(parse("a = 1 2"), Err("1: unexpected text at end of line".to_string()));
+        assert_eq!(parse("a ="), Err("1: expected a value".to_string()));
+        assert_eq!(parse("a = yes"), Err("1: invalid value 'yes'".to_string()));
+        assert_eq!(parse("a = \"x"), Err("1: unterminated string".to_string()));
+        assert_eq!(parse("a = \"\\q\""), Err("1: invalid escape sequence in string".to_string()));
+        assert_eq!(parse("[a b]"), Err("1: expected '.' or ']' in table name".to_string()));
+        assert_eq!(parse("= 1"), Err("1: expected a key".to_string()));
+        assert_eq!(parse("a = [1 2]"), Err("1: expected ',' or ']' in array".to_string()));
+    }
+
+    #[test]
+    fn checks_value_types() {
+        let entries = toml::parse("a = -1\nb = [\"x\", 2]").unwrap();
+        assert_eq!(entries[0].value.uint("a"), Err("a must not be negative".to_string()));
+        assert_eq!(entries[0].value.str("a"), Err("a must be a string, not an integer".to_string()));
+        assert_eq!(entries[1].value.strs("b"), Err("b must be a string, not an integer".to_string()));
+        assert_eq!(entries[1].value.bool("b"), Err("b must be a boolean, not an array".to_string()));
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match(b"*.rs", b"main.rs"));
+        assert!(glob_match(b"*.rs", b".rs"));
+        assert!(!glob_match(b"*.rs", b"main.rsx"));
+        assert!(glob_match(b"Makefile", b"Makefile"));
+        assert!(!glob_match(b"Makefile", b"makefile"));
+        assert!(glob_match(b"?.c", b"a.c"));
+        assert!(!glob_match(b"?.c", b".c"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"", b""));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"a*b*c", b"axxbyybc"));
+        assert!(!glob_match(b"a*b*c", b"axxbyyb"));
+    }
+
+    #[test]
+    fn limits_tab_widths() {
+        assert_eq!(valid_tab_width(0), None);
+        assert_eq!(valid_tab_width(1), Some(1));
+        assert_eq!(valid_tab_width(32), Some(32));
+        assert_eq!(valid_tab_width(33), None);
+        assert_eq!(valid_tab_width(2_000_000_000), None);
+    }
+
+    #[test]
+    fn caps_the_large_file_size() {
+        let config = Config { large_file_size: usize::MAX, ..Config::default() };
+        assert!(!config.is_large_file(u64::MAX - 1));
+        assert!(!Config::default().is_large_file(0));
+        assert!(!Config::default().is_large_file((64 << 20) - 1));
+        assert!(Config::default().is_large_file(64 << 20));
+    }
+
+    #[test]
+    fn skips_themes_that_cant_be_loaded() {
+        let dir = env::temp_dir().join(format!("kilo-rust-themes-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a-good.toml"), "[keyword]\nfg = \"red\"\n").unwrap();
+        fs::write(dir.join("b-bad.toml"), "[keyword]\nfg = \"reddish\"\n").unwrap();
+        fs::write(dir.join("c-child.toml"), "inherits = \"a-good\"\n").unwrap();
+        let (themes, errors) = theme::load_dir(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(themes.iter().map(|t| t.name.as_str()).collect::<Vec<_>>(), vec!["a-good", "c-child"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, "b-bad");
+        assert!(errors[0].1.contains("b-bad.toml:"), "{}", errors[0].1);
+
+        // The config may still select the theme, which falls back to the
+        // default one.
+        let config = Config::parse("theme = \"b-bad\"", vec![], themes, errors).ok().unwrap();
+        assert_eq!(config.theme().name, "default");
+        assert!(config.theme_error().unwrap().starts_with("Skipped themes with errors: "));
+        assert!(Config::parse("theme = \"nope\"", vec![], vec![], vec![]).is_err());
+    }
+}
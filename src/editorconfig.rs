@@ -0,0 +1,325 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use config::{valid_tab_width, LineEnding, Overrides};
+
+/// Returns the settings that the `.editorconfig` files in the directories
+/// above the file at `path` (which should be absolute) give for it. The
+/// search stops at the first file that declares `root = true`, and files
+/// closer to `path` take precedence. As the EditorConfig spec asks, lines and
+/// values that can't be understood are ignored.
+pub fn overrides_for(path: &Path) -> Overrides {
+    let mut files = vec![];
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        let candidate = d.join(".editorconfig");
+        let mut text = String::new();
+        if let Ok(mut file) = File::open(&candidate) {
+            if file.read_to_string(&mut text).is_ok() {
+                let is_root = is_root(&text);
+                files.push((d.to_path_buf(), text));
+                if is_root {
+                    break;
+                }
+            }
+        }
+        dir = d.parent();
+    }
+
+    let mut overrides = Overrides::default();
+    // Apply the files farthest from `path` first so that closer ones win.
+    for (dir, text) in files.iter().rev() {
+        apply_file(&mut overrides, dir, text, path);
+    }
+    overrides
+}
+
+/// Returns whether the preamble (the part before the first section) of the
+/// file declares it to be the root one.
+fn is_root(text: &str) -> bool {
+    text.lines()
+        .map(|line| line.trim())
+        .take_while(|line| !line.starts_with('['))
+        .filter_map(parse_pair)
+        .any(|(key, value)| key == "root" && value == "true")
+}
+
+/// Parses a `key = value` (or `key: value`) line, lowercasing both sides.
+fn parse_pair(line: &str) -> Option<(String, String)> {
+    if line.starts_with('#') || line.starts_with(';') {
+        return None;
+    }
+    let i = line.find(['=', ':'])?;
+    Some((line[..i].trim().to_lowercase(), line[i + 1..].trim().to_lowercase()))
+}
+
+fn apply_file(overrides: &mut Overrides, dir: &Path, text: &str, path: &Path) {
+    let rel_path = match path.strip_prefix(dir) {
+        Ok(rel_path) => rel_path.to_string_lossy().to_string(),
+        Err(_) => return,
+    };
+    // Properties before the first section don't apply to any file.
+    let mut in_matching_section = false;
+    let mut indent_size = None;
+    for line in text.lines().map(|line| line.trim()) {
+        if line.starts_with('[') && line.ends_with(']') {
+            in_matching_section = section_matches(&line[1..line.len() - 1], &rel_path);
+            continue;
+        }
+        if !in_matching_section {
+            continue;
+        }
+        let (key, value) = match parse_pair(line) {
+            Some(pair) => pair,
+            None => continue,
+        };
+        match key.as_str() {
+            "indent_style" => match value.as_str() {
+                "tab" => overrides.expand_tabs = Some(false),
+                "space" => overrides.expand_tabs = Some(true),
+                _ => overrides.expand_tabs = None,
+            },
+            "indent_size" => indent_size = value.parse().ok().and_then(valid_tab_width),
+            "tab_width" => overrides.tab_width = value.parse().ok().and_then(valid_tab_width),
+            "end_of_line" => overrides.end_of_line = LineEnding::from_name(&value),
+            "trim_trailing_whitespace" => overrides.trim_trailing_whitespace = parse_bool(&value),
+            "insert_final_newline" => overrides.insert_final_newline = parse_bool(&value),
+            _ => {}
+        }
+    }
+    // There is only one width setting, so the indentation size stands in for
+    // the tab width if the latter isn't given.
+    if overrides.tab_width.is_none() {
+        overrides.tab_width = indent_size;
+    }
+}
+
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// Returns whether the section glob matches the file at `rel_path`, which is
+/// relative to the directory of the `.editorconfig` file. Globs without a
+/// slash match the file name in any directory.
+fn section_matches(glob: &str, rel_path: &str) -> bool {
+    let glob = if glob.contains('/') {
+        glob.trim_start_matches('/').to_string()
+    } else {
+        format!("**/{}", glob)
+    };
+    expand_braces(&glob).iter().any(|glob| {
+        // `**/` may also match no directory at all.
+        glob_match(glob.as_bytes(), rel_path.as_bytes())
+            || (glob.starts_with("**/") && glob_match(&glob.as_bytes()[3..], rel_path.as_bytes()))
+    })
+}
+
+/// Expands `{a,b}` alternatives and `{1..3}` numeric ranges into all the
+/// globs they stand for.
+fn expand_braces(glob: &str) -> Vec<String> {
+    let open = match glob.find('{') {
+        Some(open) => open,
+        None => return vec![glob.to_string()],
+    };
+    // Find the matching closing brace, skipping nested ones.
+    let mut depth = 0;
+    let mut close = None;
+    for (i, c) in glob[open..].char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(open + i);
+                    break;
+                }
+            }
+            _ => {}
+        }
+    }
+    let close = match close {
+        Some(close) => close,
+        // An unmatched brace is taken literally.
+        None => return vec![glob.to_string()],
+    };
+
+    let (prefix, inner, suffix) = (&glob[..open], &glob[open + 1..close], &glob[close + 1..]);
+    let alternatives: Vec<String> = match numeric_range(inner) {
+        Some((lo, hi)) => (lo..hi + 1).map(|n| n.to_string()).collect(),
+        None => split_top_level(inner),
+    };
+    alternatives.iter()
+        .flat_map(|alt| expand_braces(&format!("{}{}{}", prefix, alt, suffix)))
+        .collect()
+}
+
+fn numeric_range(s: &str) -> Option<(i64, i64)> {
+    let i = s.find("..")?;
+    let (lo, hi) = (s[..i].parse().ok()?, s[i + 2..].parse().ok()?);
+    // Don't blow up on huge ranges.
+    if lo <= hi && hi - lo <= 1000 { Some((lo, hi)) } else { None }
+}
+
+/// Splits on the commas that are not inside nested braces.
+fn split_top_level(s: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                parts.push(s[start..i].to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(s[start..].to_string());
+    parts
+}
+
+/// Matches an EditorConfig glob without braces: `*` matches anything but a
+/// slash, `**` matches anything, `?` matches a single byte, and `[abc]`,
+/// `[a-z]` and `[!abc]` match a single byte from (or not from) a set.
+fn glob_match(glob: &[u8], text: &[u8]) -> bool {
+    match glob.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => {
+            if rest.first() == Some(&b'*') {
+                let rest = &rest[1..];
+                (0..text.len() + 1).any(|i| glob_match(rest, &text[i..]))
+            } else {
+                (0..text.len() + 1)
+                    .take_while(|i| *i == 0 || text[i - 1] != b'/')
+                    .any(|i| glob_match(rest, &text[i..]))
+            }
+        }
+        Some((b'?', rest)) => !text.is_empty() && text[0] != b'/' && glob_match(rest, &text[1..]),
+        Some((b'[', rest)) => {
+            let close = match rest.iter().skip(1).position(|b| *b == b']') {
+                Some(i) => i + 1,
+                None => return text.first() == Some(&b'[') && glob_match(rest, &text[1..]),
+            };
+            let (set, rest) = (&rest[..close], &rest[close + 1..]);
+            let (negated, set) = match set.split_first() {
+                Some((b'!', set)) | Some((b'^', set)) => (true, set),
+                _ => (false, set),
+            };
+            match text.split_first() {
+                Some((c, text)) => in_set(set, *c) != negated && glob_match(rest, text),
+                None => false,
+            }
+        }
+        Some((b'\\', rest)) if !rest.is_empty() => {
+            text.first() == Some(&rest[0]) && glob_match(&rest[1..], &text[1..])
+        }
+        Some((c, rest)) => text.first() == Some(c) && glob_match(rest, &text[1..]),
+    }
+}
+
+fn in_set(set: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            if set[i] <= c && c <= set[i + 2] {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+/// Makes `path` absolute without requiring it to exist, unlike
+/// `fs::canonicalize`.
+pub fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        ::std::env::current_dir().map(|dir| dir.join(path)).unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{apply_file, expand_braces, glob_match, section_matches};
+    use config::Overrides;
+
+    fn matches(glob: &str, text: &str) -> bool {
+        glob_match(glob.as_bytes(), text.as_bytes())
+    }
+
+    #[test]
+    fn expands_braces() {
+        assert_eq!(expand_braces("*.rs"), vec!["*.rs"]);
+        assert_eq!(expand_braces("*.{c,h}"), vec!["*.c", "*.h"]);
+        assert_eq!(expand_braces("{a,{b,c}d}x"), vec!["ax", "bdx", "cdx"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), vec!["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("f{8..10}"), vec!["f8", "f9", "f10"]);
+        assert_eq!(expand_braces("{a,}b"), vec!["ab", "b"]);
+        assert_eq!(expand_braces("{a,b"), vec!["{a,b"]);
+        // Huge and backwards ranges are taken as alternatives instead.
+        assert_eq!(expand_braces("{1..100000}"), vec!["1..100000"]);
+        assert_eq!(expand_braces("{3..1}"), vec!["3..1"]);
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "src/main.rs"));
+        assert!(matches("**.rs", "src/main.rs"));
+        assert!(matches("src/**/*.rs", "src/a/b/main.rs"));
+        assert!(matches("?.c", "a.c"));
+        assert!(!matches("?c", "/c"));
+        assert!(matches("[abc].c", "b.c"));
+        assert!(matches("[a-c].c", "c.c"));
+        assert!(!matches("[!a-c].c", "c.c"));
+        assert!(matches("[!a-c].c", "d.c"));
+        assert!(matches("[a", "[a"));
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+    }
+
+    #[test]
+    fn matches_sections_in_any_directory_unless_anchored() {
+        assert!(section_matches("*.{js,ts}", "web/app.ts"));
+        assert!(section_matches("Makefile", "Makefile"));
+        assert!(section_matches("Makefile", "sub/Makefile"));
+        assert!(section_matches("/lib/*.rs", "lib/a.rs"));
+        assert!(!section_matches("/lib/*.rs", "src/lib/a.rs"));
+        assert!(section_matches("src/**.rs", "src/a/b.rs"));
+    }
+
+    #[test]
+    fn applies_matching_sections() {
+        let text = "root = true\nindent_size = 2\n\n[*]\nend_of_line = lf\n\n[*.py]\nindent_style = space\nindent_size = 4\n\n[*.md]\ntrim_trailing_whitespace = false\n";
+        let mut overrides = Overrides::default();
+        apply_file(&mut overrides, Path::new("/p"), text, Path::new("/p/src/a.py"));
+        assert_eq!((overrides.expand_tabs, overrides.tab_width), (Some(true), Some(4)));
+        assert!(overrides.end_of_line.is_some());
+        assert_eq!(overrides.trim_trailing_whitespace, None);
+    }
+
+    #[test]
+    fn ignores_bad_tab_widths() {
+        for text in &["[*]\ntab_width = 2000000000", "[*]\nindent_size = 0", "[*]\nindent_size = tab"] {
+            let mut overrides = Overrides::default();
+            apply_file(&mut overrides, Path::new("/p"), text, Path::new("/p/a.c"));
+            assert_eq!(overrides.tab_width, None, "{}", text);
+        }
+    }
+}
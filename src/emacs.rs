@@ -0,0 +1,352 @@
+use std::cmp;
+use std::time::Duration;
+
+use keymap;
+use {Editor, TextPos};
+
+/// How many kills the kill ring keeps.
+const KILL_RING_LEN: usize = 60;
+
+/// The state of an incremental search.
+pub struct Isearch {
+    query: Vec<u8>,
+    is_backward: bool,
+    // Where the cursor was when the search started, to go back to if it's
+    // cancelled.
+    origin: TextPos,
+    // The start and end of the match the cursor is at.
+    pub found: Option<(TextPos, TextPos)>,
+    is_wrapped: bool,
+}
+
+fn is_word(b: Option<u8>) -> bool {
+    b.is_some_and(|b| b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80)
+}
+
+impl Editor {
+    fn set_cursor(&mut self, (line, byte): TextPos) {
+        self.buffer.cursor.line = line;
+        self.buffer.cursor.byte = byte;
+        self.buffer.cursor.is_at_eol = false;
+    }
+
+    fn cursor_text_pos(&self) -> TextPos {
+        (self.buffer.cursor.line, self.buffer.cursor.byte)
+    }
+
+    /// Moves the cursor to the end of the word it's in, or of the next one.
+    pub fn forward_word(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let mut pos = self.cursor_text_pos();
+        for is_in_word in [false, true] {
+            while is_word(self.byte_at(pos)) == is_in_word {
+                match self.next_pos(pos) {
+                    Some(next) => pos = next,
+                    None => break,
+                }
+            }
+        }
+        self.set_cursor(pos);
+    }
+
+    /// Moves the cursor to the start of the word it's in, or of the previous
+    /// one.
+    pub fn backward_word(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let mut pos = self.cursor_text_pos();
+        for is_in_word in [false, true] {
+            while let Some(prev) = self.prev_pos(pos) {
+                if is_word(self.byte_at(prev)) != is_in_word {
+                    break;
+                }
+                pos = prev;
+            }
+        }
+        self.set_cursor(pos);
+    }
+
+    /// Adds killed text to the kill ring, or to its latest entry if the last
+    /// command was a kill too, so that consecutive kills are yanked together.
+    fn kill(&mut self, text: Vec<u8>) {
+        let is_consecutive = self.last_command == "kill-line" || self.last_command == "kill-region";
+        match self.kill_ring.last_mut() {
+            Some(last) if is_consecutive => last.extend(text),
+            _ => {
+                self.kill_ring.push(text);
+                if self.kill_ring.len() > KILL_RING_LEN {
+                    self.kill_ring.remove(0);
+                }
+            }
+        }
+    }
+
+    /// Kills the rest of the line, or the line break if the cursor is at the
+    /// end of the line.
+    pub fn kill_line(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let start = self.cursor_text_pos();
+        let len = self.buffer.lines[start.0].orig.len();
+        let end = if start.1 < len {
+            (start.0, len)
+        } else if start.0 + 1 < self.buffer.lines.len() {
+            (start.0 + 1, 0)
+        } else {
+            return;
+        };
+        let text = self.text_range(start, end);
+        self.delete_range(start, end);
+        self.kill(text);
+    }
+
+    /// Returns the text between the mark and the cursor, if the mark is set.
+    /// The mark is kept within the text, which may have shrunk since it was
+    /// set.
+    pub fn region(&self) -> Option<(TextPos, TextPos)> {
+        let (line, byte) = self.mark?;
+        if self.buffer.lines.is_empty() {
+            return None;
+        }
+        let line = cmp::min(line, self.buffer.lines.len() - 1);
+        let mark = (line, cmp::min(byte, self.buffer.lines[line].orig.len()));
+        let cursor = self.cursor_text_pos();
+        Some((cmp::min(mark, cursor), cmp::max(mark, cursor)))
+    }
+
+    pub fn kill_region(&mut self) {
+        match self.region() {
+            Some((start, end)) => {
+                let text = self.text_range(start, end);
+                self.delete_range(start, end);
+                self.kill(text);
+                self.mark = None;
+            }
+            None => self.new_status_msg("The mark is not set now", Duration::from_secs(3)),
+        }
+    }
+
+    pub fn copy_region(&mut self) {
+        match self.region() {
+            Some((start, end)) => {
+                let text = self.text_range(start, end);
+                // Copying after a kill doesn't add to it.
+                self.last_command.clear();
+                self.kill(text);
+                self.mark = None;
+            }
+            None => self.new_status_msg("The mark is not set now", Duration::from_secs(3)),
+        }
+    }
+
+    /// Inserts the latest kill at the cursor.
+    pub fn yank(&mut self) {
+        let idx = match self.kill_ring.len() {
+            0 => {
+                self.new_status_msg("Kill ring is empty", Duration::from_secs(3));
+                return;
+            }
+            n => n - 1,
+        };
+        self.yank_entry(idx);
+    }
+
+    /// Replaces the text just yanked with the kill before it in the ring.
+    pub fn yank_pop(&mut self) {
+        let is_after_yank = self.last_command == "yank" || self.last_command == "yank-pop";
+        match self.yanked {
+            Some((start, end, idx)) if is_after_yank => {
+                self.delete_range(start, end);
+                let n = self.kill_ring.len();
+                self.yank_entry((idx + n - 1) % n);
+            }
+            _ => self.new_status_msg("Previous command was not a yank", Duration::from_secs(3)),
+        }
+    }
+
+    fn yank_entry(&mut self, idx: usize) {
+        let start = self.cursor_text_pos();
+        let text = self.kill_ring[idx].clone();
+        self.insert_text(&text);
+        self.yanked = Some((start, self.cursor_text_pos(), idx));
+    }
+
+    pub fn set_mark(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        self.mark = Some(self.cursor_text_pos());
+        self.new_status_msg("Mark set", Duration::from_secs(2));
+    }
+
+    pub fn keyboard_quit(&mut self) {
+        self.mark = None;
+        self.new_status_msg("Quit", Duration::from_secs(2));
+    }
+
+    pub fn isearch_forward(&mut self) {
+        self.start_isearch(false);
+    }
+
+    pub fn isearch_backward(&mut self) {
+        self.start_isearch(true);
+    }
+
+    fn start_isearch(&mut self, is_backward: bool) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        self.isearch = Some(Isearch {
+            query: vec![],
+            is_backward,
+            origin: self.cursor_text_pos(),
+            found: None,
+            is_wrapped: false,
+        });
+    }
+
+    /// Returns what the message bar shows during an incremental search.
+    pub fn isearch_msg(&self) -> Option<String> {
+        let isearch = self.isearch.as_ref()?;
+        let failing = if !isearch.query.is_empty() && isearch.found.is_none() { "Failing " } else { "" };
+        let wrapped = if isearch.is_wrapped { "Wrapped " } else { "" };
+        let direction = if isearch.is_backward { " backward" } else { "" };
+        Some(format!(
+            "{}{}I-search{}: {}",
+            failing, wrapped, direction, String::from_utf8_lossy(&isearch.query)
+        ))
+    }
+
+    /// Handles a key during an incremental search, returning false if the
+    /// key ends the search and should then be handled as usual.
+    pub fn handle_isearch_key(&mut self, key: &str) -> bool {
+        let mut isearch = match self.isearch.take() {
+            Some(isearch) => isearch,
+            None => return false,
+        };
+        match key {
+            "ctrl-s" | "ctrl-r" => {
+                let origin = isearch.origin;
+                let is_backward = key == "ctrl-r";
+                if isearch.query.is_empty() {
+                    // Search for the previous query again.
+                    isearch.query = self.last_search.clone();
+                    isearch.is_backward = is_backward;
+                    self.isearch_from(&mut isearch, origin, false);
+                } else if isearch.found.is_none() {
+                    // After failing, start over from the other end.
+                    isearch.is_backward = is_backward;
+                    isearch.is_wrapped = true;
+                    let from = if is_backward {
+                        let last = self.buffer.lines.len() - 1;
+                        (last, self.buffer.lines[last].orig.len())
+                    } else {
+                        (0, 0)
+                    };
+                    self.isearch_from(&mut isearch, from, false);
+                } else {
+                    isearch.is_backward = is_backward;
+                    let (start, _) = isearch.found.unwrap();
+                    self.isearch_from(&mut isearch, start, true);
+                }
+            }
+            "backspace" => {
+                isearch.query.pop();
+                let origin = isearch.origin;
+                self.isearch_from(&mut isearch, origin, false);
+            }
+            "ctrl-g" | "esc" => {
+                let origin = isearch.origin;
+                self.set_cursor(origin);
+                return true;
+            }
+            "enter" => {
+                self.last_search = isearch.query;
+                return true;
+            }
+            _ => match keymap::key_byte(key) {
+                Some(b) => {
+                    isearch.query.push(b);
+                    // Extend the current match if it still matches.
+                    let from = isearch.found.map_or(isearch.origin, |(start, _)| start);
+                    self.isearch_from(&mut isearch, from, false);
+                }
+                None => {
+                    if !isearch.query.is_empty() {
+                        self.last_search = isearch.query;
+                    }
+                    return false;
+                }
+            },
+        }
+        self.isearch = Some(isearch);
+        true
+    }
+
+    /// Looks for the query from `from` in the search's direction, skipping a
+    /// match at `from` itself if `is_next`, and moves the cursor to the match:
+    /// to its end searching forward, and its start searching backward.
+    fn isearch_from(&mut self, isearch: &mut Isearch, from: TextPos, is_next: bool) {
+        if isearch.query.is_empty() {
+            isearch.found = None;
+            self.set_cursor(isearch.origin);
+            return;
+        }
+        let start = if isearch.is_backward {
+            let from = if is_next { self.prev_pos(from) } else { Some(from) };
+            from.and_then(|from| self.find_backward(&isearch.query, from))
+        } else {
+            let from = if is_next { self.next_pos(from) } else { Some(from) };
+            from.and_then(|from| self.find_forward(&isearch.query, from))
+        };
+        match start {
+            Some(start) => {
+                let end = (start.0, start.1 + isearch.query.len());
+                isearch.found = Some((start, end));
+                self.set_cursor(if isearch.is_backward { start } else { end });
+            }
+            // Keep the cursor at the last match.
+            None => isearch.found = None,
+        }
+    }
+
+    /// Returns whether `query` is found in the line at `line_idx` at `byte`,
+    /// ignoring case unless the query has upper case letters.
+    fn matches_at(&self, query: &[u8], (line_idx, byte): TextPos) -> bool {
+        let orig = &self.buffer.lines[line_idx].orig;
+        if byte + query.len() > orig.len() {
+            return false;
+        }
+        let text = &orig[byte..byte + query.len()];
+        if query.iter().any(|b| b.is_ascii_uppercase()) {
+            text == query
+        } else {
+            text.eq_ignore_ascii_case(query)
+        }
+    }
+
+    /// Returns the start of the first match of `query` at or after `from`.
+    fn find_forward(&self, query: &[u8], from: TextPos) -> Option<TextPos> {
+        (from.0..self.buffer.lines.len()).find_map(|line_idx| {
+            let start = if line_idx == from.0 { from.1 } else { 0 };
+            (start..=self.buffer.lines[line_idx].orig.len())
+                .map(|byte| (line_idx, byte))
+                .find(|&pos| self.matches_at(query, pos))
+        })
+    }
+
+    /// Returns the start of the last match of `query` at or before `from`.
+    fn find_backward(&self, query: &[u8], from: TextPos) -> Option<TextPos> {
+        (0..=from.0).rev().find_map(|line_idx| {
+            let end = if line_idx == from.0 { from.1 } else { self.buffer.lines[line_idx].orig.len() };
+            (0..=end)
+                .rev()
+                .map(|byte| (line_idx, byte))
+                .find(|&pos| self.matches_at(query, pos))
+        })
+    }
+}
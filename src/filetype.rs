@@ -0,0 +1,326 @@
+use std::path::Path;
+
+use config::{glob_match, valid_tab_width, Overrides};
+
+/// What the editor knows about a language: how to recognize its files and
+/// the settings its files are edited with, unless overridden.
+pub struct Language {
+    pub name: &'static str,
+    // Other names the language goes by in modelines, lowercased.
+    pub aliases: &'static [&'static str],
+    // Glob patterns matched against the file name.
+    pub patterns: &'static [&'static str],
+    // The interpreters named on shebang lines of scripts in this language.
+    pub interpreters: &'static [&'static str],
+    pub line_comment: Option<&'static str>,
+    pub block_comment: Option<(&'static str, &'static str)>,
+    pub tab_width: Option<i32>,
+    pub expand_tabs: Option<bool>,
+}
+
+pub const LANGUAGES: &[Language] = &[
+    Language {
+        name: "rust",
+        aliases: &["rs", "rust-mode"],
+        patterns: &["*.rs"],
+        interpreters: &[],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        tab_width: Some(4),
+        expand_tabs: Some(true),
+    },
+    Language {
+        name: "c",
+        aliases: &["cpp", "c++", "h"],
+        patterns: &["*.c", "*.h", "*.cc", "*.cpp", "*.hpp"],
+        interpreters: &[],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        tab_width: None,
+        expand_tabs: None,
+    },
+    Language {
+        name: "python",
+        aliases: &["py", "python3"],
+        patterns: &["*.py", "*.pyw"],
+        interpreters: &["python", "python2", "python3"],
+        line_comment: Some("#"),
+        block_comment: None,
+        tab_width: Some(4),
+        expand_tabs: Some(true),
+    },
+    Language {
+        name: "sh",
+        aliases: &["bash", "zsh", "shell", "shell-script"],
+        patterns: &["*.sh", "*.bash", "*.zsh", ".bashrc", ".bash_profile", ".profile", ".zshrc"],
+        interpreters: &["sh", "bash", "zsh", "dash", "ksh"],
+        line_comment: Some("#"),
+        block_comment: None,
+        tab_width: None,
+        expand_tabs: None,
+    },
+    Language {
+        name: "json",
+        aliases: &["js-json"],
+        patterns: &["*.json"],
+        interpreters: &[],
+        line_comment: None,
+        block_comment: None,
+        tab_width: None,
+        expand_tabs: Some(true),
+    },
+    Language {
+        name: "toml",
+        aliases: &[],
+        patterns: &["*.toml", "Cargo.lock"],
+        interpreters: &[],
+        line_comment: Some("#"),
+        block_comment: None,
+        tab_width: None,
+        expand_tabs: None,
+    },
+    Language {
+        name: "markdown",
+        aliases: &["md", "gfm"],
+        patterns: &["*.md", "*.markdown"],
+        interpreters: &[],
+        line_comment: None,
+        block_comment: Some(("<!--", "-->")),
+        tab_width: None,
+        expand_tabs: Some(true),
+    },
+    Language {
+        name: "make",
+        aliases: &["makefile", "makefile-gmake"],
+        patterns: &["Makefile", "makefile", "GNUmakefile", "*.mk"],
+        interpreters: &["make"],
+        line_comment: Some("#"),
+        block_comment: None,
+        // Recipes must be indented with tabs.
+        tab_width: Some(8),
+        expand_tabs: Some(false),
+    },
+    Language {
+        name: "go",
+        aliases: &["golang"],
+        patterns: &["*.go"],
+        interpreters: &[],
+        line_comment: Some("//"),
+        block_comment: Some(("/*", "*/")),
+        tab_width: Some(4),
+        expand_tabs: Some(false),
+    },
+    Language {
+        name: "yaml",
+        aliases: &["yml"],
+        patterns: &["*.yaml", "*.yml"],
+        interpreters: &[],
+        line_comment: Some("#"),
+        block_comment: None,
+        tab_width: Some(2),
+        expand_tabs: Some(true),
+    },
+];
+
+impl Language {
+    /// Returns the overrides that apply the language's settings.
+    pub fn overrides(&self) -> Overrides {
+        Overrides {
+            tab_width: self.tab_width,
+            expand_tabs: self.expand_tabs,
+            line_comment: self.line_comment.map(|c| c.to_string()),
+            block_comment: self.block_comment.map(|(start, end)| (start.to_string(), end.to_string())),
+            ..Overrides::default()
+        }
+    }
+}
+
+/// Returns the language with the given name or alias, ignoring case.
+pub fn find(name: &str) -> Option<&'static Language> {
+    let name = name.to_lowercase();
+    LANGUAGES.iter().find(|lang| lang.name == name || lang.aliases.contains(&name.as_str()))
+}
+
+/// Detects the language of the file at `path` from its name, or failing
+/// that, from the shebang on its first line.
+pub fn detect(path: &Path, first_line: &[u8]) -> Option<&'static Language> {
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let by_name = LANGUAGES.iter()
+        .find(|lang| lang.patterns.iter().any(|p| glob_match(p.as_bytes(), name.as_bytes())));
+    by_name.or_else(|| {
+        let interpreter = shebang_interpreter(first_line)?;
+        LANGUAGES.iter().find(|lang| lang.interpreters.contains(&interpreter.as_str()))
+    })
+}
+
+/// Returns the name of the interpreter on a shebang line, skipping `env`
+/// and any minor version (so `#!/usr/bin/env python3.8` yields `python3`).
+fn shebang_interpreter(line: &[u8]) -> Option<String> {
+    if !line.starts_with(b"#!") {
+        return None;
+    }
+    let line = String::from_utf8_lossy(&line[2..]);
+    let mut words = line.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        // Skip any options given to env, such as -S.
+        program = words.find(|w| !w.starts_with('-'))?;
+    }
+    // Drop any minor version, e.g. python3.8 is just python3.
+    Some(program.split('.').next().unwrap_or(program).to_string())
+}
+
+/// Settings read from vim or emacs modelines.
+#[derive(Default)]
+pub struct Modeline {
+    pub filetype: Option<String>,
+    pub overrides: Overrides,
+}
+
+/// Looks for vim (`vim: set ts=4 et ft=python:`) and emacs
+/// (`-*- mode: python; tab-width: 4; indent-tabs-mode: nil -*-`) modelines
+/// in the given lines, which should be the first and last few lines of the
+/// file. Later modelines take precedence.
+pub fn parse_modelines<'a, I: Iterator<Item = &'a [u8]>>(lines: I) -> Modeline {
+    let mut modeline = Modeline::default();
+    for line in lines {
+        let line = String::from_utf8_lossy(line);
+        if let Some(opts) = vim_modeline_opts(&line) {
+            for opt in opts.split(|c: char| c == ':' || c.is_whitespace()).filter(|o| !o.is_empty()) {
+                let (name, value) = match opt.find('=') {
+                    Some(i) => (&opt[..i], Some(&opt[i + 1..])),
+                    None => (opt, None),
+                };
+                match (name, value) {
+                    ("ts", Some(n)) | ("tabstop", Some(n)) => {
+                        modeline.overrides.tab_width = n.parse().ok().and_then(valid_tab_width);
+                    }
+                    ("et", None) | ("expandtab", None) => modeline.overrides.expand_tabs = Some(true),
+                    ("noet", None) | ("noexpandtab", None) => modeline.overrides.expand_tabs = Some(false),
+                    ("ft", Some(ft)) | ("filetype", Some(ft)) | ("syntax", Some(ft)) => {
+                        modeline.filetype = Some(ft.to_string());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(vars) = emacs_modeline_vars(&line) {
+            if !vars.contains(':') {
+                // The short form, -*- python -*-, just names the mode.
+                modeline.filetype = Some(vars.trim().to_string());
+                continue;
+            }
+            for var in vars.split(';') {
+                let mut parts = var.splitn(2, ':');
+                let (name, value) = match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+                    _ => continue,
+                };
+                match name.as_str() {
+                    "mode" => modeline.filetype = Some(value.to_string()),
+                    "tab-width" => modeline.overrides.tab_width = value.parse().ok().and_then(valid_tab_width),
+                    "indent-tabs-mode" => modeline.overrides.expand_tabs = Some(value == "nil"),
+                    _ => {}
+                }
+            }
+        }
+    }
+    modeline
+}
+
+/// Returns the options of a vim modeline, i.e. what follows `vi:`, `vim:` or
+/// `ex:` (and an optional `set`) up to the closing colon, if any.
+fn vim_modeline_opts(line: &str) -> Option<&str> {
+    let start = ["vim:", "vi:", "ex:"].iter()
+        .filter_map(|marker| {
+            let i = line.find(marker)?;
+            // The marker must be preceded by whitespace unless it starts the line.
+            if i == 0 || line[..i].ends_with(char::is_whitespace) {
+                Some(i + marker.len())
+            } else {
+                None
+            }
+        })
+        .min()?;
+    let opts = line[start..].trim_start();
+    for set in &["set ", "se "] {
+        if let Some(rest) = opts.strip_prefix(set) {
+            // In this form the options end at the next colon.
+            return Some(rest.split(':').next().unwrap_or(""));
+        }
+    }
+    Some(opts)
+}
+
+/// Returns what is between the `-*-` markers of an emacs modeline.
+fn emacs_modeline_vars(line: &str) -> Option<&str> {
+    let start = line.find("-*-")? + 3;
+    let end = line[start..].find("-*-")? + start;
+    Some(&line[start..end])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{detect, parse_modelines, shebang_interpreter, Modeline};
+
+    fn modeline(lines: &[&str]) -> Modeline {
+        parse_modelines(lines.iter().map(|line| line.as_bytes()))
+    }
+
+    fn interpreter(line: &str) -> Option<String> {
+        shebang_interpreter(line.as_bytes())
+    }
+
+    #[test]
+    fn reads_vim_modelines() {
+        let m = modeline(&["# vim: set ts=8 noet ft=make:", "ignored"]);
+        assert_eq!((m.filetype.as_deref(), m.overrides.tab_width, m.overrides.expand_tabs), (Some("make"), Some(8), Some(false)));
+        let m = modeline(&["/* vi:ts=2:et */"]);
+        assert_eq!((m.overrides.tab_width, m.overrides.expand_tabs), (Some(2), Some(true)));
+        // The marker must start a word.
+        assert_eq!(modeline(&["nvim: ts=2"]).overrides.tab_width, None);
+    }
+
+    #[test]
+    fn reads_emacs_modelines() {
+        let m = modeline(&["# -*- mode: Python; tab-width: 4; indent-tabs-mode: nil -*-"]);
+        assert_eq!((m.filetype.as_deref(), m.overrides.tab_width, m.overrides.expand_tabs), (Some("Python"), Some(4), Some(true)));
+        assert_eq!(modeline(&[";; -*- lisp -*-"]).filetype.as_deref(), Some("lisp"));
+    }
+
+    #[test]
+    fn lets_later_modelines_win() {
+        let m = modeline(&["# vim: ts=2 et", "# vim: ts=4"]);
+        assert_eq!((m.overrides.tab_width, m.overrides.expand_tabs), (Some(4), Some(true)));
+    }
+
+    #[test]
+    fn ignores_bad_tab_widths() {
+        assert_eq!(modeline(&["vim: ts=2000000000"]).overrides.tab_width, None);
+        assert_eq!(modeline(&["vim: ts=0"]).overrides.tab_width, None);
+        assert_eq!(modeline(&["vim: ts=-1"]).overrides.tab_width, None);
+        assert_eq!(modeline(&["-*- tab-width: 33 -*-"]).overrides.tab_width, None);
+    }
+
+    #[test]
+    fn finds_shebang_interpreters() {
+        assert_eq!(interpreter("#!/bin/sh"), Some("sh".to_string()));
+        assert_eq!(interpreter("#! /usr/bin/python3.8 -u"), Some("python3".to_string()));
+        assert_eq!(interpreter("#!/usr/bin/env bash"), Some("bash".to_string()));
+        assert_eq!(interpreter("#!/usr/bin/env -S python3 -u"), Some("python3".to_string()));
+        assert_eq!(interpreter("#!/usr/bin/env"), None);
+        assert_eq!(interpreter("#!"), None);
+        assert_eq!(interpreter("# not a shebang"), None);
+    }
+
+    #[test]
+    fn detects_languages_by_name_then_shebang() {
+        let name = |path: &str, first_line: &str| detect(Path::new(path), first_line.as_bytes()).map(|lang| lang.name);
+        assert_eq!(name("src/main.rs", "#!/bin/sh"), Some("rust"));
+        assert_eq!(name("/home/u/.bashrc", ""), Some("sh"));
+        assert_eq!(name("bin/tool", "#!/usr/bin/env python3"), Some("python"));
+        assert_eq!(name("notes", "hello"), None);
+    }
+}
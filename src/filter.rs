@@ -0,0 +1,266 @@
+use std::time::Duration;
+
+use prompt::Prompt;
+use regex::Regex;
+use Editor;
+
+/// A filter that hides the lines of a buffer that don't match it, or with
+/// `is_inverted`, those that do. A buffer's filters are stacked, so that only
+/// the lines that pass all of them are shown.
+pub struct Filter {
+    pattern: String,
+    regex: Regex,
+    is_inverted: bool,
+}
+
+impl Filter {
+    fn passes(&self, text: &[u8]) -> bool {
+        self.regex.find(text, 0).is_some() != self.is_inverted
+    }
+}
+
+impl Editor {
+    /// Asks for a regular expression and shows only the lines that match it,
+    /// as well as the filters already applied. A leading `!` shows the lines
+    /// that don't match instead, and nothing clears the filters.
+    pub fn filter_lines(&mut self) {
+        self.prompt_filter("Filter: ", false);
+    }
+
+    /// Like `filter_lines`, but matches the text typed as it is.
+    pub fn filter_lines_literal(&mut self) {
+        self.prompt_filter("Filter text: ", true);
+    }
+
+    fn prompt_filter(&mut self, label: &str, is_literal: bool) {
+        let prompt = Prompt::new(label, "filter", move |editor: &mut Editor, text: String| {
+            editor.add_filter(text, is_literal);
+        });
+        self.open_prompt(prompt);
+    }
+
+    /// Applies the filter that `text` was typed for at a filter prompt.
+    pub fn add_filter(&mut self, text: String, is_literal: bool) {
+        if text.is_empty() {
+            return self.clear_filters();
+        }
+        let (pattern, is_inverted) = match text.strip_prefix('!') {
+            Some(rest) => (rest.to_string(), true),
+            None => (text, false),
+        };
+        let regex = if is_literal { Ok(Regex::literal(pattern.as_bytes())) } else { Regex::new(&pattern) };
+        match regex {
+            Ok(regex) => {
+                self.buffer.filters.push(Filter { pattern, regex, is_inverted });
+                self.refilter();
+            }
+            Err(e) => self.new_status_msg(&e, Duration::from_secs(5)),
+        }
+    }
+
+    /// Swaps the lines the last filter shows for those it hides.
+    pub fn invert_filter(&mut self) {
+        match self.buffer.filters.last_mut() {
+            Some(filter) => filter.is_inverted = !filter.is_inverted,
+            None => return self.new_status_msg("No filter to invert", Duration::from_secs(3)),
+        }
+        self.refilter();
+    }
+
+    /// Removes the last filter, showing the lines it hid.
+    pub fn pop_filter(&mut self) {
+        if self.buffer.filters.pop().is_none() {
+            return self.new_status_msg("No filter to remove", Duration::from_secs(3));
+        }
+        self.refilter();
+    }
+
+    pub fn clear_filters(&mut self) {
+        self.buffer.filters.clear();
+        self.refilter();
+    }
+
+    /// Hides the lines that don't pass the filters, keeping the cursor on a
+    /// line that is shown, and says how many are.
+    fn refilter(&mut self) {
+        for line_idx in 0..self.buffer.lines.len() {
+            self.apply_filters(line_idx);
+        }
+        let line = self.buffer.cursor.line;
+        if line < self.buffer.lines.len() && self.buffer.lines[line].is_hidden {
+            if let Some(line) = self.shown_line_after(line).or_else(|| self.shown_line_before(line)) {
+                self.buffer.cursor.line = line;
+                self.cursor_line_home();
+            }
+        }
+        let n_shown = self.buffer.lines.iter().filter(|line| !line.is_hidden).count();
+        let msg = if self.buffer.filters.is_empty() {
+            "Showing all lines".to_string()
+        } else {
+            let patterns: Vec<String> = self.buffer.filters.iter()
+                .map(|filter| format!("{}{}", if filter.is_inverted { "!" } else { "" }, filter.pattern))
+                .collect();
+            format!("Showing {} of {} lines: {}", n_shown, self.buffer.lines.len(), patterns.join(" & "))
+        };
+        self.new_status_msg(&msg, Duration::from_secs(5));
+    }
+
+    /// Hides the line at `line_idx` if it doesn't pass the filters.
+    pub fn apply_filters(&mut self, line_idx: usize) {
+        let line = &self.buffer.lines[line_idx];
+        let is_hidden = !self.buffer.filters.iter().all(|filter| filter.passes(&line.orig));
+        self.buffer.lines[line_idx].is_hidden = is_hidden;
+    }
+
+    /// Returns whether the line at `line_idx` is shown. The cursor's line is
+    /// shown even if it no longer passes the filters after an edit.
+    pub fn is_line_shown(&self, line_idx: usize) -> bool {
+        !self.buffer.lines[line_idx].is_hidden || line_idx == self.buffer.cursor.line
+    }
+
+    /// Returns the first line shown after the line at `line_idx`.
+    pub fn shown_line_after(&self, line_idx: usize) -> Option<usize> {
+        (line_idx + 1..self.buffer.lines.len()).find(|&i| self.is_line_shown(i))
+    }
+
+    /// Returns the last line shown before the line at `line_idx`.
+    pub fn shown_line_before(&self, line_idx: usize) -> Option<usize> {
+        (0..line_idx).rev().find(|&i| self.is_line_shown(i))
+    }
+
+    /// Returns the line shown `n` shown lines below the line at `line_idx`,
+    /// or the last shown line if there are fewer.
+    pub fn shown_lines_down(&self, line_idx: usize, n: usize) -> usize {
+        (0..n).try_fold(line_idx, |line, _| self.shown_line_after(line)).unwrap_or_else(|| self.last_shown_line())
+    }
+
+    /// Returns the line shown `n` shown lines above the line at `line_idx`,
+    /// or the first shown line if there are fewer.
+    pub fn shown_lines_up(&self, line_idx: usize, n: usize) -> usize {
+        (0..n).try_fold(line_idx, |line, _| self.shown_line_before(line)).unwrap_or_else(|| self.first_shown_line())
+    }
+
+    pub fn first_shown_line(&self) -> usize {
+        (0..self.buffer.lines.len()).find(|&i| self.is_line_shown(i)).unwrap_or(0)
+    }
+
+    pub fn last_shown_line(&self) -> usize {
+        let n = self.buffer.lines.len();
+        (0..n).rev().find(|&i| self.is_line_shown(i)).unwrap_or(n.saturating_sub(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tests::editor;
+    use Editor;
+
+    fn shown(editor: &Editor) -> Vec<usize> {
+        (0..editor.buffer.lines.len()).filter(|&i| editor.is_line_shown(i)).collect()
+    }
+
+    #[test]
+    fn stacks_filters() {
+        let mut editor = editor("apple\nbanana\navocado\ncherry");
+        editor.add_filter("a".to_string(), false);
+        assert_eq!(shown(&editor), vec![0, 1, 2]);
+        editor.add_filter("^a".to_string(), false);
+        assert_eq!(shown(&editor), vec![0, 2]);
+        assert_eq!(editor.status_msg.data, "Showing 2 of 4 lines: a & ^a");
+        editor.add_filter("!v".to_string(), false);
+        assert_eq!(shown(&editor), vec![0]);
+        assert_eq!(editor.status_msg.data, "Showing 1 of 4 lines: a & ^a & !v");
+    }
+
+    #[test]
+    fn matches_literal_text() {
+        let mut editor = editor("a.c\nabc");
+        editor.add_filter("a.c".to_string(), true);
+        assert_eq!(shown(&editor), vec![0]);
+        editor.add_filter("!.".to_string(), true);
+        assert_eq!(shown(&editor), vec![0]);
+        assert!(editor.buffer.lines[0].is_hidden);
+    }
+
+    #[test]
+    fn inverts_and_pops_the_last_filter() {
+        let mut editor = editor("one\ntwo\nthree\nfour");
+        editor.add_filter("o".to_string(), false);
+        editor.add_filter("t".to_string(), false);
+        assert_eq!(shown(&editor), vec![1]);
+        editor.invert_filter();
+        assert_eq!(shown(&editor), vec![0, 3]);
+        assert_eq!(editor.status_msg.data, "Showing 2 of 4 lines: o & !t");
+        editor.invert_filter();
+        assert_eq!(shown(&editor), vec![1]);
+        editor.pop_filter();
+        assert_eq!(shown(&editor), vec![0, 1, 3]);
+        editor.pop_filter();
+        assert_eq!(shown(&editor), vec![0, 1, 2, 3]);
+        assert_eq!(editor.status_msg.data, "Showing all lines");
+        editor.pop_filter();
+        assert_eq!(editor.status_msg.data, "No filter to remove");
+        editor.invert_filter();
+        assert_eq!(editor.status_msg.data, "No filter to invert");
+    }
+
+    #[test]
+    fn clears_the_filters_for_nothing() {
+        let mut editor = editor("a\nb\na");
+        editor.add_filter("a".to_string(), false);
+        editor.add_filter("!a".to_string(), false);
+        assert_eq!(shown(&editor), vec![0]);
+        editor.add_filter(String::new(), false);
+        assert!(editor.buffer.filters.is_empty());
+        assert_eq!(shown(&editor), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn keeps_a_bad_pattern_from_filtering() {
+        let mut editor = editor("a\nb");
+        editor.add_filter("(".to_string(), false);
+        assert!(editor.buffer.filters.is_empty());
+        assert_eq!(shown(&editor), vec![0, 1]);
+    }
+
+    #[test]
+    fn moves_the_cursor_onto_a_shown_line() {
+        let mut editor = editor("a\nb\nb\na\nb");
+        editor.buffer.cursor.line = 1;
+        editor.add_filter("a".to_string(), false);
+        assert_eq!(editor.buffer.cursor.line, 3);
+        editor.pop_filter();
+        editor.buffer.cursor.line = 4;
+        editor.buffer.cursor.byte = 1;
+        editor.add_filter("a".to_string(), false);
+        assert_eq!(editor.buffer.cursor.line, 3);
+        assert_eq!(editor.buffer.cursor.byte, 0);
+    }
+
+    #[test]
+    fn keeps_showing_the_cursors_line_after_an_edit() {
+        let mut editor = editor("ab\nab\nab");
+        editor.add_filter("ab".to_string(), false);
+        editor.buffer.cursor.line = 1;
+        editor.buffer.cursor.byte = 1;
+        editor.insert_text(b"x");
+        assert!(editor.buffer.lines[1].is_hidden);
+        assert_eq!(shown(&editor), vec![0, 1, 2]);
+        assert_eq!(editor.shown_line_after(0), Some(1));
+        editor.buffer.cursor.line = 2;
+        assert_eq!(shown(&editor), vec![0, 2]);
+        assert_eq!(editor.shown_line_after(0), Some(2));
+        assert_eq!(editor.shown_line_before(2), Some(0));
+    }
+
+    #[test]
+    fn counts_shown_lines() {
+        let mut editor = editor("a\nb\na\nb\na");
+        editor.add_filter("a".to_string(), false);
+        assert_eq!(editor.shown_lines_down(0, 1), 2);
+        assert_eq!(editor.shown_lines_down(0, 5), 4);
+        assert_eq!(editor.shown_lines_up(4, 2), 0);
+        assert_eq!(editor.shown_lines_up(4, 9), 0);
+        assert_eq!((editor.first_shown_line(), editor.last_shown_line()), (0, 4));
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use nix::errno::Errno;
+use nix::poll::{poll, EventFlags, PollFd};
+
+use config::LineEnding;
+use loader::Loading;
+use window::View;
+use {display_path, Editor, Line};
+
+/// How often followed files are checked for more text, in milliseconds.
+const POLL_INTERVAL_MS: i32 = 250;
+
+/// The file a buffer is following, as `tail -f` does.
+pub struct Follow {
+    file: File,
+    // How much of the file has been read into the buffer.
+    len: u64,
+    // Whether the view was scrolled away from the end, where it's otherwise
+    // kept as text is added.
+    pub is_paused: bool,
+}
+
+impl Editor {
+    /// Keeps the view at the end of the file as text is added to it, or if
+    /// the file is already being followed, goes back to the end after
+    /// scrolling away from it. A followed file is viewed read-only.
+    pub fn follow(&mut self) {
+        if let Some(ref mut follow) = self.buffer.follow {
+            follow.is_paused = false;
+            self.cursor_file_end();
+            return;
+        }
+        if self.buffer.path.as_os_str().is_empty() || self.buffer.is_new {
+            return self.new_status_msg("Only files can be followed", Duration::from_secs(3));
+        }
+        if self.buffer.is_dirty {
+            return self.new_status_msg("Save the file before following it", Duration::from_secs(3));
+        }
+        // A file that is still being loaded is followed from where its
+        // mapping ends.
+        let result = if self.buffer.loading.is_some() { self.follow_mapped() } else { self.reopen() };
+        if let Err(e) = result {
+            let msg = format!("Can't follow {}: {}", display_path(&self.buffer.path), e);
+            return self.new_status_msg(&msg, Duration::from_secs(5));
+        }
+        self.buffer.is_readonly = true;
+        self.buffer.is_viewed = true;
+        self.cursor_file_end();
+    }
+
+    fn follow_mapped(&mut self) -> io::Result<()> {
+        let (mut file, len) = match self.buffer.loading {
+            Some(ref loading) => (loading.file().try_clone()?, loading.len() as u64),
+            None => return Ok(()),
+        };
+        file.seek(SeekFrom::Start(len))?;
+        self.buffer.follow = Some(Follow { file, len, is_paused: false });
+        self.clear_undo();
+        Ok(())
+    }
+
+    /// Rereads the file being followed from the start, as it was truncated
+    /// or replaced, or is only starting to be followed. A large file is
+    /// loaded in the background, as when it's opened, and what is added to
+    /// it meanwhile is read once it has been.
+    fn reopen(&mut self) -> io::Result<()> {
+        let mut file = File::open(&self.buffer.path)?;
+        let len = file.metadata()?.len();
+        let is_paused = self.buffer.follow.as_ref().is_some_and(|follow| follow.is_paused);
+        // Keep the cursor where it was, as far as the new text goes, and let
+        // the window scroll to it from the top.
+        let view = View::of(&self.buffer);
+        let is_large = self.buffer.config.is_large_file(len);
+        self.clear_undo();
+        if is_large {
+            let loading = Loading::start(file.try_clone()?, len as usize)?;
+            file.seek(SeekFrom::Start(len))?;
+            self.buffer.follow = Some(Follow { file, len, is_paused });
+            self.buffer.line_ending = loading.line_ending();
+            self.buffer.lines = vec![];
+            self.buffer.loading = Some(loading);
+        } else {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)?;
+            self.buffer.follow = Some(Follow { file, len: buf.len() as u64, is_paused });
+            self.buffer.line_ending = LineEnding::detect(&buf);
+            self.buffer.lines = vec![Line::new(vec![])];
+            self.buffer.loading = None;
+            self.append_text(&buf);
+        }
+        // Whether the file is highlighted depends on its size.
+        if is_large != self.buffer.is_large {
+            self.buffer.is_large = is_large;
+            self.configure_buffer();
+        }
+        view.restore(&mut self.buffer);
+        self.buffer.line_offset = 0;
+        self.buffer.line_offset_row = 0;
+        Ok(())
+    }
+
+    /// Adds text read from the end of the file to the buffer. The last line
+    /// is the one still being written, if it hasn't ended yet.
+    fn append_text(&mut self, text: &[u8]) {
+        let separator = if self.buffer.line_ending == LineEnding::Cr { b'\r' } else { b'\n' };
+        let is_crlf = self.buffer.line_ending == LineEnding::CrLf;
+        let mut last = self.buffer.lines.pop().map_or(vec![], |line| line.orig.to_vec());
+        last.extend_from_slice(text);
+        let first = self.buffer.lines.len();
+        let mut parts = last.split(|b| *b == separator).peekable();
+        while let Some(part) = parts.next() {
+            // A `\r` at the end of the last line may yet be followed by `\n`.
+            let part = if is_crlf && parts.peek().is_some() { part.strip_suffix(b"\r").unwrap_or(part) } else { part };
+            self.buffer.lines.push(Line::new(part.to_vec()));
+        }
+        for line_idx in first..self.buffer.lines.len() {
+            self.update_render(line_idx);
+        }
+    }
+
+    /// Reads what was added to the file being followed since it was last
+    /// read, and returns whether anything was. A file that shrank was
+    /// truncated, and a different file at its path replaced it when it was
+    /// rotated, so either is read again from the start.
+    fn read_follow(&mut self) -> io::Result<bool> {
+        // The file is read on from where it was mapped once it's loaded.
+        if self.buffer.loading.is_some() {
+            return Ok(false);
+        }
+        let (is_truncated, is_replaced) = match self.buffer.follow {
+            Some(ref follow) => {
+                let meta = follow.file.metadata()?;
+                let is_replaced = match fs::metadata(&self.buffer.path) {
+                    Ok(path_meta) => (path_meta.dev(), path_meta.ino()) != (meta.dev(), meta.ino()),
+                    // The old file is read until a new one takes its place.
+                    Err(_) => false,
+                };
+                (meta.len() < follow.len, is_replaced)
+            }
+            None => return Ok(false),
+        };
+        if is_truncated || is_replaced {
+            let what = if is_truncated { "truncated" } else { "replaced" };
+            let msg = format!("{} was {}", display_path(&self.buffer.path), what);
+            self.new_status_msg(&msg, Duration::from_secs(3));
+            self.reopen()?;
+        } else {
+            let mut buf = vec![];
+            if let Some(ref mut follow) = self.buffer.follow {
+                follow.file.read_to_end(&mut buf)?;
+                follow.len += buf.len() as u64;
+            }
+            if buf.is_empty() {
+                return Ok(false);
+            }
+            self.append_text(&buf);
+        }
+        self.pin_to_end();
+        Ok(true)
+    }
+
+    /// Moves the cursor to the end of a followed file, unless following it
+    /// is paused.
+    pub fn pin_to_end(&mut self) {
+        if self.buffer.follow.as_ref().is_some_and(|follow| !follow.is_paused) {
+            self.cursor_file_end();
+        }
+    }
+
+    /// Pauses following the file once the cursor was moved off its last
+    /// shown line.
+    pub fn check_follow_paused(&mut self) {
+        let is_at_end = self.buffer.cursor.line >= self.last_shown_line();
+        if let Some(ref mut follow) = self.buffer.follow {
+            if !follow.is_paused && !is_at_end {
+                follow.is_paused = true;
+                self.new_status_msg("Following paused, press F to resume", Duration::from_secs(3));
+            }
+        }
+    }
+
+    /// Waits for a key to be typed, and meanwhile loads more of large files
+    /// and reads more of the files being followed, redrawing the screen as
+    /// they grow.
+    pub fn wait_for_key(&mut self) {
+        loop {
+            let loading_fds = self.loading_fds();
+            let is_loading = !loading_fds.is_empty();
+            if !is_loading && !self.is_following() {
+                return;
+            }
+            // Files being loaded tell when they have more lines, while
+            // followed ones are checked every so often.
+            let mut fds = vec![PollFd::new(self.tty.as_raw_fd(), EventFlags::POLLIN)];
+            fds.extend(loading_fds.into_iter().map(|fd| PollFd::new(fd, EventFlags::POLLIN)));
+            let timeout = if self.is_following() { POLL_INTERVAL_MS } else { -1 };
+            match poll(&mut fds, timeout) {
+                Ok(_) if fds[0].revents().is_some_and(|events| !events.is_empty()) => return,
+                Ok(_) | Err(nix::Error::Sys(Errno::EINTR)) => {}
+                Err(_) => return,
+            }
+            let is_loaded = is_loading && self.load_more();
+            if is_loaded | self.check_truncated() {
+                self.refresh_screen();
+            }
+            if !self.is_following() {
+                continue;
+            }
+            let current = self.current;
+            let mut is_changed = false;
+            for idx in 0..self.buffers.len() {
+                self.swap_buffer(idx);
+                match self.read_follow() {
+                    Ok(is_read) => is_changed |= is_read,
+                    Err(e) => {
+                        let msg = format!("Stopped following {}: {}", display_path(&self.buffer.path), e);
+                        self.new_status_msg(&msg, Duration::from_secs(5));
+                        self.buffer.follow = None;
+                        is_changed = true;
+                    }
+                }
+                self.swap_buffer(current);
+            }
+            if is_changed {
+                self.refresh_screen();
+            }
+        }
+    }
+
+    fn is_following(&self) -> bool {
+        (0..self.buffers.len()).any(|idx| {
+            let buffer = if idx == self.current { &self.buffer } else { &self.buffers[idx] };
+            buffer.follow.is_some()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::prelude::*;
+
+    use buffer::Buffer;
+    use config::Config;
+    use tests::editor;
+
+    #[test]
+    fn follows_the_lines_a_filter_shows() {
+        let path = env::temp_dir().join(format!("kilo-rust-follow-{}", ::std::process::id()));
+        fs::write(&path, "a 1\nb 1\n").unwrap();
+        let mut editor = editor("");
+        editor.buffer = Buffer::open(Config::default(), &path).unwrap();
+        editor.configure_buffer();
+        editor.add_filter("a".to_string(), false);
+        editor.follow();
+        editor.check_follow_paused();
+        assert_eq!(editor.buffer.cursor.line, 0);
+        assert!(!editor.buffer.follow.as_ref().unwrap().is_paused);
+
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"a 2\nb 2\n").unwrap();
+        let has_read = editor.read_follow();
+        fs::remove_file(&path).unwrap();
+        assert!(has_read.unwrap());
+        editor.check_follow_paused();
+        assert_eq!(editor.buffer.cursor.line, 2);
+        assert!(!editor.buffer.follow.as_ref().unwrap().is_paused);
+
+        editor.buffer.cursor.line = 0;
+        editor.check_follow_paused();
+        assert!(editor.buffer.follow.as_ref().unwrap().is_paused);
+    }
+}
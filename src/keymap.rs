@@ -0,0 +1,196 @@
+/// The keys that have names of their own, besides printable characters,
+/// `ctrl-` letters and `alt-` keys.
+const NAMED_KEYS: &[&str] = &[
+    "enter", "tab", "backspace", "space", "esc", "delete", "up", "down", "left", "right",
+    "home", "end", "ctrl-home", "ctrl-end", "pageup", "pagedown",
+];
+
+/// Returns the name of the key that sends the byte `c`.
+pub fn byte_key_name(c: char) -> String {
+    match c {
+        '\r' => "enter".to_string(),
+        '\t' => "tab".to_string(),
+        '\x7f' | '\x08' => "backspace".to_string(),
+        ' ' => "space".to_string(),
+        // Turn the control character back into the key it was typed with: a
+        // letter, or one of @[\]^_.
+        '\0' | '\x1b'..='\x1f' => format!("ctrl-{}", ((c as u8) | 0x40) as char),
+        c if c < ' ' => format!("ctrl-{}", ((c as u8) | 0x60) as char),
+        c => c.to_string(),
+    }
+}
+
+/// Returns the byte that the key with the given name inserts, if it's a
+/// printable character. This is the inverse of `byte_key_name`.
+pub fn key_byte(name: &str) -> Option<u8> {
+    if name == "space" {
+        return Some(b' ');
+    }
+    let mut chars = name.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) if (c as u32) < 0x100 && c >= ' ' && c != '\x7f' => Some(c as u8),
+        _ => None,
+    }
+}
+
+pub fn is_key_name(name: &str) -> bool {
+    if NAMED_KEYS.contains(&name) || name.chars().count() == 1 {
+        return true;
+    }
+    if let Some(key) = name.strip_prefix("alt-") {
+        return is_key_name(key);
+    }
+    match name.strip_prefix("ctrl-") {
+        Some(key) => key.len() == 1 && (key.as_bytes()[0].is_ascii_lowercase() || "@[\\]^_".contains(key)),
+        None => false,
+    }
+}
+
+/// What a sequence of keys is bound to.
+pub enum Lookup<'a> {
+    Command(&'a str),
+    /// The keys start a longer sequence that is bound.
+    Prefix,
+    Unbound,
+}
+
+/// Maps keys, or sequences of keys pressed one after the other (chords such
+/// as `ctrl-x ctrl-s`), to the names of commands.
+#[derive(Clone, Default)]
+pub struct Keymap {
+    bindings: Vec<(Vec<String>, String)>,
+}
+
+impl Keymap {
+    /// Binds the space-separated key names in `keys` to `command`, or unbinds
+    /// them if `command` is empty. Bindings that the new one would shadow or
+    /// be shadowed by, by starting with the same keys, are removed.
+    pub fn bind(&mut self, keys: &str, command: &str) -> Result<(), String> {
+        let keys: Vec<String> = keys.split_whitespace().map(|k| k.to_lowercase()).collect();
+        if keys.is_empty() {
+            return Err("a key binding needs at least one key".to_string());
+        }
+        if let Some(key) = keys.iter().find(|k| !is_key_name(k)) {
+            return Err(format!("unknown key '{}'", key));
+        }
+        self.bindings.retain(|(k, _)| !(k.starts_with(&keys) || keys.starts_with(k)));
+        if !command.is_empty() {
+            self.bindings.push((keys, command.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn lookup(&self, keys: &[String]) -> Lookup<'_> {
+        let mut lookup = Lookup::Unbound;
+        for (k, command) in &self.bindings {
+            if k.as_slice() == keys {
+                return Lookup::Command(command);
+            } else if k.starts_with(keys) {
+                lookup = Lookup::Prefix;
+            }
+        }
+        lookup
+    }
+
+    /// Returns the key sequences bound to the command with the given name,
+    /// with their keys separated by spaces.
+    pub fn keys_for_command(&self, command: &str) -> Vec<String> {
+        self.bindings.iter()
+            .filter(|(_, c)| c == command)
+            .map(|(keys, _)| keys.join(" "))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byte_key_name, is_key_name, key_byte, Keymap, Lookup};
+
+    fn lookup(keymap: &Keymap, keys: &str) -> String {
+        let keys: Vec<String> = keys.split(' ').map(|key| key.to_string()).collect();
+        match keymap.lookup(&keys) {
+            Lookup::Command(command) => command.to_string(),
+            Lookup::Prefix => "prefix".to_string(),
+            Lookup::Unbound => "unbound".to_string(),
+        }
+    }
+
+    #[test]
+    fn names_the_keys_bytes_are_sent_by() {
+        assert_eq!(byte_key_name('\r'), "enter");
+        assert_eq!(byte_key_name('\x7f'), "backspace");
+        assert_eq!(byte_key_name('\x01'), "ctrl-a");
+        assert_eq!(byte_key_name('\x1a'), "ctrl-z");
+        assert_eq!(byte_key_name('\0'), "ctrl-@");
+        assert_eq!(byte_key_name('\x1f'), "ctrl-_");
+        assert_eq!(byte_key_name('é'), "é");
+        for c in (0u8..0x80).map(|b| b as char) {
+            assert!(is_key_name(&byte_key_name(c)), "{:?}", c);
+        }
+    }
+
+    #[test]
+    fn turns_printable_keys_back_into_bytes() {
+        for b in 0x20u8..0x7f {
+            assert_eq!(key_byte(&byte_key_name(b as char)), Some(b));
+        }
+        for b in (0u8..0x20).chain(Some(0x7f)) {
+            assert_eq!(key_byte(&byte_key_name(b as char)), None, "{}", b);
+        }
+        assert_eq!(key_byte("ctrl-a"), None);
+        assert_eq!(key_byte("é"), Some(0xe9));
+        assert_eq!(key_byte("€"), None);
+    }
+
+    #[test]
+    fn checks_key_names() {
+        assert!(is_key_name("pagedown"));
+        assert!(is_key_name("alt-ctrl-x"));
+        assert!(is_key_name("ctrl-]"));
+        assert!(!is_key_name("ctrl-A"));
+        assert!(!is_key_name("ctrl-1"));
+        assert!(!is_key_name("hyper-x"));
+        assert!(!is_key_name("ctrl-"));
+    }
+
+    #[test]
+    fn looks_up_chords_and_their_prefixes() {
+        let mut keymap = Keymap::default();
+        keymap.bind("ctrl-x ctrl-s", "save").unwrap();
+        keymap.bind("ctrl-x ctrl-c", "quit").unwrap();
+        keymap.bind("ctrl-s", "search").unwrap();
+        assert_eq!(lookup(&keymap, "ctrl-x"), "prefix");
+        assert_eq!(lookup(&keymap, "ctrl-x ctrl-s"), "save");
+        assert_eq!(lookup(&keymap, "ctrl-s"), "search");
+        assert_eq!(lookup(&keymap, "ctrl-x ctrl-s ctrl-s"), "unbound");
+        assert_eq!(lookup(&keymap, "ctrl-y"), "unbound");
+        assert_eq!(keymap.keys_for_command("quit"), vec!["ctrl-x ctrl-c"]);
+    }
+
+    #[test]
+    fn replaces_bindings_that_shadow_each_other() {
+        let mut keymap = Keymap::default();
+        keymap.bind("ctrl-x ctrl-s", "save").unwrap();
+        keymap.bind("ctrl-x ctrl-c", "quit").unwrap();
+        // A key that starts chords takes the place of all of them.
+        keymap.bind("ctrl-x", "cut").unwrap();
+        assert_eq!(lookup(&keymap, "ctrl-x"), "cut");
+        assert_eq!(lookup(&keymap, "ctrl-x ctrl-s"), "unbound");
+        // And a chord takes the place of the key it starts with.
+        keymap.bind("ctrl-x  u", "undo").unwrap();
+        assert_eq!(lookup(&keymap, "ctrl-x"), "prefix");
+        assert_eq!(lookup(&keymap, "ctrl-x u"), "undo");
+        assert!(keymap.keys_for_command("cut").is_empty());
+        // Binding nothing unbinds.
+        keymap.bind("ctrl-x u", "").unwrap();
+        assert_eq!(lookup(&keymap, "ctrl-x"), "unbound");
+    }
+
+    #[test]
+    fn rejects_bad_bindings() {
+        let mut keymap = Keymap::default();
+        assert_eq!(keymap.bind("", "save"), Err("a key binding needs at least one key".to_string()));
+        assert_eq!(keymap.bind("ctrl-x hyper-s", "save"), Err("unknown key 'hyper-s'".to_string()));
+        assert_eq!(lookup(&keymap, "ctrl-x"), "unbound");
+    }
+}
@@ -0,0 +1,475 @@
+use std::cmp;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::io;
+use std::mem;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Once};
+use std::thread;
+use std::time::Duration;
+
+use nix::libc::{self, c_int, c_void};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use nix::unistd::pipe;
+
+use config::LineEnding;
+use {display_path, Editor, Line};
+
+/// How many line breaks the indexer finds before handing them over.
+const BATCH_SIZE: usize = 1 << 16;
+
+/// How many files can be mapped at once.
+const MAX_MAPPINGS: usize = 64;
+
+// Where each mapping starts and ends, for the SIGBUS handler to look up
+// without taking a lock, and whether the handler has found its file to have
+// been truncated. A start of 0 marks a free slot.
+static MAPPING_STARTS: [AtomicUsize; MAX_MAPPINGS] = [const { AtomicUsize::new(0) }; MAX_MAPPINGS];
+static MAPPING_ENDS: [AtomicUsize; MAX_MAPPINGS] = [const { AtomicUsize::new(0) }; MAX_MAPPINGS];
+static MAPPING_TRUNCATED: [AtomicBool; MAX_MAPPINGS] = [const { AtomicBool::new(false) }; MAX_MAPPINGS];
+// Whether any of the mappings has been truncated since this was last checked.
+static IS_TRUNCATED: AtomicBool = AtomicBool::new(false);
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+static INSTALL_HANDLER: Once = Once::new();
+
+/// A file mapped read-only into memory. If the file shrinks while it's
+/// mapped, reading past its new end raises SIGBUS, which `handle_sigbus`
+/// handles by mapping blank pages over what is gone, so that the editor
+/// carries on and can tell that the file has been cut short.
+pub struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+    // The mapping's slot in `MAPPING_STARTS` and the like.
+    slot: usize,
+}
+
+// The mapping is only ever read, from whichever thread.
+unsafe impl Send for Mapping {}
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize) -> io::Result<Mapping> {
+        INSTALL_HANDLER.call_once(|| {
+            PAGE_SIZE.store(unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize, Ordering::SeqCst);
+            let action = SigAction::new(SigHandler::SigAction(handle_sigbus), SaFlags::SA_SIGINFO, SigSet::empty());
+            let _ = unsafe { sigaction(Signal::SIGBUS, &action) };
+        });
+        let slot = (0..MAX_MAPPINGS)
+            .find(|&slot| MAPPING_STARTS[slot].compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_ok())
+            .ok_or_else(|| io::Error::other("too many large files are open"))?;
+        let ptr = unsafe {
+            mmap(ptr::null_mut(), len, ProtFlags::PROT_READ, MapFlags::MAP_PRIVATE, file.as_raw_fd(), 0)
+        };
+        match ptr {
+            Ok(ptr) => {
+                MAPPING_TRUNCATED[slot].store(false, Ordering::SeqCst);
+                MAPPING_ENDS[slot].store(ptr as usize + len, Ordering::SeqCst);
+                MAPPING_STARTS[slot].store(ptr as usize, Ordering::SeqCst);
+                Ok(Mapping { ptr, len, slot })
+            }
+            Err(_) => {
+                let error = io::Error::last_os_error();
+                MAPPING_STARTS[slot].store(0, Ordering::SeqCst);
+                Err(error)
+            }
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+    }
+
+    /// Returns whether the file has been found to have been truncated.
+    fn is_truncated(&self) -> bool {
+        MAPPING_TRUNCATED[self.slot].load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = munmap(self.ptr, self.len);
+        }
+        MAPPING_ENDS[self.slot].store(0, Ordering::SeqCst);
+        MAPPING_STARTS[self.slot].store(0, Ordering::SeqCst);
+    }
+}
+
+/// Handles a SIGBUS raised by reading a page of a mapped file that is past
+/// the file's end, since the file has shrunk, by mapping a page of zeros in
+/// its place. Any other SIGBUS is left to crash the editor as it would have.
+extern "C" fn handle_sigbus(_: c_int, info: *mut libc::siginfo_t, _: *mut c_void) {
+    let addr = unsafe { fault_addr(info) };
+    let slot = (0..MAX_MAPPINGS).find(|&slot| {
+        let start = MAPPING_STARTS[slot].load(Ordering::SeqCst);
+        start > 1 && start <= addr && addr < MAPPING_ENDS[slot].load(Ordering::SeqCst)
+    });
+    if let Some(slot) = slot {
+        let page_size = PAGE_SIZE.load(Ordering::SeqCst);
+        let page = (addr & !(page_size - 1)) as *mut c_void;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED;
+        let ptr = unsafe { libc::mmap(page, page_size, libc::PROT_READ, flags, -1, 0) };
+        if ptr != libc::MAP_FAILED {
+            MAPPING_TRUNCATED[slot].store(true, Ordering::SeqCst);
+            IS_TRUNCATED.store(true, Ordering::SeqCst);
+            return;
+        }
+    }
+    // Returning faults again, this time to the default action.
+    unsafe {
+        libc::signal(libc::SIGBUS, libc::SIG_DFL);
+    }
+}
+
+/// Returns the address whose access raised the signal that `info` is about.
+/// This version of libc has no field for it: it comes after the three ints
+/// that `siginfo_t` starts with, aligned for a pointer.
+unsafe fn fault_addr(info: *const libc::siginfo_t) -> usize {
+    let offset = (3 * mem::size_of::<c_int>()).next_multiple_of(mem::align_of::<usize>());
+    ptr::read_unaligned((info as *const u8).add(offset) as *const usize)
+}
+
+/// The bytes of a line. The lines of a large file are pieces of its mapping
+/// until they're edited, and only then are they copied into memory, so
+/// opening the file doesn't read all of it: the buffer is a piece table of
+/// the mapped file and of the lines that have been changed.
+#[derive(Clone)]
+pub enum Text {
+    Mapped(Arc<Mapping>, usize, usize),
+    Owned(Vec<u8>),
+}
+
+impl Text {
+    /// Returns the bytes to be edited, copying them out of the mapping if
+    /// they're still in it.
+    pub fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let Text::Mapped(..) = *self {
+            *self = Text::Owned(self.to_vec());
+        }
+        match *self {
+            Text::Owned(ref mut bytes) => bytes,
+            Text::Mapped(..) => unreachable!(),
+        }
+    }
+}
+
+impl Deref for Text {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Text::Mapped(ref mapping, start, end) => &mapping.bytes()[start..end],
+            Text::Owned(ref bytes) => bytes,
+        }
+    }
+}
+
+impl From<Vec<u8>> for Text {
+    fn from(bytes: Vec<u8>) -> Text {
+        Text::Owned(bytes)
+    }
+}
+
+/// A large file that is being split into lines in the background, so that
+/// it can be shown before it's all been read.
+pub struct Loading {
+    // The file that is mapped.
+    file: File,
+    mapping: Arc<Mapping>,
+    // The offsets of the line breaks found so far, in batches.
+    breaks: Receiver<Vec<usize>>,
+    // A byte is written to the other end of this pipe after each batch, so
+    // that waiting for keys can wait for batches as well.
+    ready: File,
+    // Where the line after those added to the buffer starts.
+    start: usize,
+    line_ending: LineEnding,
+}
+
+impl Loading {
+    /// Maps `file`, which is `len` bytes long, into memory, and starts
+    /// finding its line breaks on another thread.
+    pub fn start(file: File, len: usize) -> io::Result<Loading> {
+        let mapping = Arc::new(Mapping::new(&file, len)?);
+        let line_ending = LineEnding::detect(&mapping.bytes()[..cmp::min(len, 1 << 16)]);
+        let separator = if line_ending == LineEnding::Cr { b'\r' } else { b'\n' };
+        let (sender, breaks) = mpsc::channel();
+        let (ready, mut notify) = match pipe() {
+            Ok((read, write)) => unsafe { (File::from_raw_fd(read), File::from_raw_fd(write)) },
+            Err(_) => return Err(io::Error::last_os_error()),
+        };
+        let indexed = Arc::clone(&mapping);
+        thread::spawn(move || {
+            let mut batch = Vec::with_capacity(BATCH_SIZE);
+            for (i, b) in indexed.bytes().iter().enumerate() {
+                if *b == separator {
+                    batch.push(i);
+                    if batch.len() == BATCH_SIZE {
+                        // Stop once the buffer is closed.
+                        if sender.send(mem::take(&mut batch)).is_err() {
+                            return;
+                        }
+                        let _ = notify.write_all(&[0]);
+                    }
+                }
+            }
+            let _ = sender.send(batch);
+            // The pipe is closed as the thread ends, which tells that the
+            // channel will be too.
+            let _ = notify.write_all(&[0]);
+        });
+        Ok(Loading { file, mapping, breaks, ready, start: 0, line_ending })
+    }
+
+    pub fn line_ending(&self) -> LineEnding {
+        self.line_ending
+    }
+
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Returns how much of the file is mapped.
+    pub fn len(&self) -> usize {
+        self.mapping.len
+    }
+
+    /// Returns a file descriptor that is readable when there are more lines
+    /// to add, or the file has been read.
+    pub fn ready_fd(&self) -> RawFd {
+        self.ready.as_raw_fd()
+    }
+}
+
+impl Editor {
+    /// Adds the lines found since this was last called to the buffer being
+    /// loaded, and returns whether there were any. The lines are rendered
+    /// only once they're shown.
+    fn load_lines(&mut self) -> bool {
+        let (batch, is_done) = match self.buffer.loading {
+            Some(ref mut loading) => match loading.breaks.try_recv() {
+                Ok(batch) => {
+                    // Take the byte that was written for the batch.
+                    let _ = loading.ready.read(&mut [0]);
+                    (batch, false)
+                }
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => (vec![], true),
+            },
+            None => return false,
+        };
+        let mut loading = self.buffer.loading.take().unwrap();
+        let bytes = loading.mapping.bytes();
+        let is_crlf = loading.line_ending == LineEnding::CrLf;
+        let ends = batch.into_iter().chain(if is_done { Some(bytes.len()) } else { None });
+        for end in ends {
+            let is_cr = is_crlf && end > loading.start && bytes[end - 1] == b'\r';
+            let text = Text::Mapped(Arc::clone(&loading.mapping), loading.start, end - is_cr as usize);
+            self.buffer.lines.push(Line::new(text));
+            let line_idx = self.buffer.lines.len() - 1;
+            self.apply_filters(line_idx);
+            loading.start = end + 1;
+        }
+        self.pin_to_end();
+        let name = display_path(&self.buffer.path);
+        if is_done {
+            let mut msg = format!("Loaded {} lines of {}", self.buffer.lines.len(), name);
+            if loading.mapping.is_truncated() {
+                msg += ", which shrank while loading, so its end is missing";
+            }
+            self.new_status_msg(&msg, Duration::from_secs(3));
+        } else {
+            let percent = loading.start as u64 * 100 / bytes.len() as u64;
+            let msg = format!("Loading {}... {}%", name, percent);
+            self.new_status_msg(&msg, Duration::from_secs(60));
+            self.buffer.loading = Some(loading);
+        }
+        true
+    }
+
+    /// Saves a large file, whose lines may still be pieces of the file as it
+    /// was, so it's written next to it a line at a time and then takes its
+    /// place, and the lines become pieces of the new file. Should the file
+    /// not be able to be written next to, its lines are copied into memory
+    /// and it's overwritten instead. Returns how many bytes were written.
+    pub fn save_large(&mut self, line_ending: &[u8]) -> io::Result<usize> {
+        // Replace what a symbolic link points to, not the link.
+        let target = fs::canonicalize(&self.buffer.path).unwrap_or_else(|_| self.buffer.path.clone());
+        let name = target.file_name().map_or(OsString::new(), |name| name.to_os_string());
+        let mut temp_name = OsString::from(".");
+        temp_name.push(&name);
+        temp_name.push(".kilo-rust-save");
+        let temp = target.with_file_name(temp_name);
+        let file = match OpenOptions::new().read(true).write(true).create_new(true).open(&temp) {
+            Ok(file) => file,
+            Err(_) => {
+                for line in &mut self.buffer.lines {
+                    line.orig.to_mut();
+                }
+                let file = OpenOptions::new().write(true).create(true).truncate(true).open(&self.buffer.path)?;
+                return self.write_lines(&file, line_ending);
+            }
+        };
+        let result = self.write_lines(&file, line_ending).and_then(|len| {
+            if let Ok(meta) = fs::metadata(&target) {
+                fs::set_permissions(&temp, meta.permissions())?;
+            }
+            fs::rename(&temp, &target)?;
+            Ok(len)
+        });
+        let len = match result {
+            Ok(len) => len,
+            Err(e) => {
+                let _ = fs::remove_file(&temp);
+                return Err(e);
+            }
+        };
+        // The lines stay where they were if the new file can't be mapped, as
+        // the old one is kept for as long as it is.
+        if let Ok(mapping) = Mapping::new(&file, len) {
+            let mapping = Arc::new(mapping);
+            let mut start = 0;
+            for line in &mut self.buffer.lines {
+                let end = start + line.orig.len();
+                line.orig = Text::Mapped(Arc::clone(&mapping), start, end);
+                start = end + line_ending.len();
+            }
+        }
+        Ok(len)
+    }
+
+    /// Tells which buffers' files have been cut short while they're mapped,
+    /// if any have since this was last called, and returns whether any have.
+    /// Their text past the files' new ends is gone, and reads as zeros.
+    pub fn check_truncated(&mut self) -> bool {
+        if !IS_TRUNCATED.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        let current = self.current;
+        let mut names = vec![];
+        for idx in 0..self.buffers.len() {
+            self.swap_buffer(idx);
+            let mapping = match self.buffer.loading {
+                Some(ref loading) => Some(Arc::clone(&loading.mapping)),
+                None => self.buffer.lines.iter().find_map(|line| match line.orig {
+                    Text::Mapped(ref mapping, _, _) => Some(Arc::clone(mapping)),
+                    Text::Owned(_) => None,
+                }),
+            };
+            if mapping.is_some_and(|mapping| mapping.is_truncated()) {
+                names.push(display_path(&self.buffer.path));
+            }
+            self.swap_buffer(current);
+        }
+        if !names.is_empty() {
+            let msg = format!("{} shrank while open, so its end is missing", names.join(", "));
+            self.new_status_msg(&msg, Duration::from_secs(10));
+        }
+        true
+    }
+
+    /// Adds more lines to each buffer still being loaded, and returns
+    /// whether any were added.
+    pub fn load_more(&mut self) -> bool {
+        let current = self.current;
+        let mut is_changed = false;
+        for idx in 0..self.buffers.len() {
+            self.swap_buffer(idx);
+            is_changed |= self.load_lines();
+            self.swap_buffer(current);
+        }
+        is_changed
+    }
+
+    /// Returns the file descriptors that say when the buffers still being
+    /// loaded have more lines.
+    pub fn loading_fds(&self) -> Vec<RawFd> {
+        (0..self.buffers.len()).filter_map(|idx| {
+            let buffer = if idx == self.current { &self.buffer } else { &self.buffers[idx] };
+            buffer.loading.as_ref().map(|loading| loading.ready_fd())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::Text;
+    use buffer::Buffer;
+    use config::Config;
+    use tests::editor;
+    use Editor;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("kilo-rust-{}-{}", name, ::std::process::id()))
+    }
+
+    /// Returns an editor of the file at `path`, opened as a large file and
+    /// loaded if `is_loaded`.
+    fn open_large(path: &Path, is_loaded: bool) -> Editor {
+        let config = Config { large_file_size: 0, ..Config::default() };
+        let mut editor = editor("");
+        editor.buffer = Buffer::open(config, path).unwrap();
+        editor.configure_buffer();
+        assert!(editor.buffer.is_large);
+        while is_loaded && editor.buffer.loading.is_some() {
+            editor.load_more();
+            thread::sleep(Duration::from_millis(1));
+        }
+        editor
+    }
+
+    #[test]
+    fn saves_large_files_into_a_new_mapping() {
+        let path = temp_path("large");
+        let link = temp_path("large-link");
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let _ = fs::remove_file(&link);
+        symlink(&path, &link).unwrap();
+        let mut editor = open_large(&link, true);
+        editor.buffer.cursor.line = 1;
+        editor.insert_bytes(b"2 ");
+        editor.save();
+        let text = fs::read_to_string(&path);
+        let is_link = fs::symlink_metadata(&link).map(|meta| meta.file_type().is_symlink());
+        let leftovers: Vec<_> = fs::read_dir(env::temp_dir()).unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().ends_with(".kilo-rust-save"))
+            .collect();
+        fs::remove_file(&link).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(text.unwrap(), "one\n2 two\nthree\n");
+        assert!(is_link.unwrap());
+        assert!(leftovers.is_empty());
+        assert!(!editor.buffer.is_dirty);
+        for (line, expected) in editor.buffer.lines.iter().zip(&["one", "2 two", "three", ""]) {
+            assert!(matches!(line.orig, Text::Mapped(..)));
+            assert_eq!(&line.orig[..], expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn ignores_vi_keys_before_any_lines_are_loaded() {
+        let path = temp_path("loading");
+        fs::write(&path, "text\n").unwrap();
+        let mut editor = open_large(&path, false);
+        fs::remove_file(&path).unwrap();
+        editor.buffer.lines.clear();
+        assert!(editor.vi_handle_key("x"));
+        assert!(editor.vi_handle_key("O"));
+        assert!(editor.buffer.lines.is_empty());
+    }
+}
@@ -0,0 +1,246 @@
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use config::{Config, Profile};
+use prompt::Prompt;
+use vi::Mode;
+use Editor;
+
+/// How deep macros may play other macros, so that one that plays itself
+/// ends.
+const MAX_DEPTH: usize = 20;
+
+/// Returns whether `c` can name the register a macro is kept in.
+pub fn is_register(c: char) -> bool {
+    c.is_ascii_alphanumeric()
+}
+
+/// A macro being recorded, as the names of the keys typed.
+pub struct Recording {
+    pub register: char,
+    keys: Vec<String>,
+    // The keys of an unfinished chord or vi command, which are only recorded
+    // once it's finished. This leaves out the keys that stop the recording.
+    chord: Vec<String>,
+}
+
+impl Recording {
+    pub fn new(register: char) -> Recording {
+        Recording { register, keys: vec![], chord: vec![] }
+    }
+
+    pub fn add(&mut self, key: &str, is_finished: bool) {
+        self.chord.push(key.to_string());
+        if is_finished {
+            self.keys.append(&mut self.chord);
+        }
+    }
+}
+
+/// Sets the macro in `register`, keeping the macros ordered by register.
+pub fn set(macros: &mut Vec<(char, Vec<String>)>, register: char, keys: Vec<String>) {
+    match macros.binary_search_by_key(&register, |(r, _)| *r) {
+        Ok(i) => macros[i].1 = keys,
+        Err(i) => macros.insert(i, (register, keys)),
+    }
+}
+
+/// Writes the macros to `macros.toml` in the config directory, from where
+/// they are loaded along with the config, and returns its path.
+fn save(macros: &[(char, Vec<String>)]) -> Result<PathBuf, String> {
+    let path = match Config::dir() {
+        Some(dir) => dir.join("macros.toml"),
+        None => return Err("Can't save macros: no config directory".to_string()),
+    };
+    let mut text = "# Keyboard macros, saved by kilo-rust's save-macros command.\n[macros]\n".to_string();
+    for (register, keys) in macros {
+        let keys = keys.join(" ").replace('\\', "\\\\").replace('"', "\\\"");
+        text.push_str(&format!("{} = \"{}\"\n", register, keys));
+    }
+    path.parent().map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| File::create(&path))
+        .and_then(|mut file| file.write_all(text.as_bytes()))
+        .map_err(|e| format!("Can't save macros to {}: {}", path.display(), e))?;
+    Ok(path)
+}
+
+/// Parses what is typed in the prompt of `play-macro`: a register, which
+/// may be preceded by how many times to play the macro. Without a register,
+/// the last macro played is.
+fn parse_play(input: &str) -> Option<(usize, Option<char>)> {
+    let digits = input.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let count = if digits > 0 { input[..digits].parse().ok()? } else { 1 };
+    let mut rest = input[digits..].chars();
+    match (rest.next(), rest.next()) {
+        (None, _) => Some((count, None)),
+        (Some(c), None) if is_register(c) => Some((count, Some(c))),
+        _ => None,
+    }
+}
+
+impl Editor {
+    /// Starts recording the keys typed into a register that is prompted for,
+    /// or stops recording.
+    pub fn record_macro(&mut self) {
+        if self.recording.is_some() {
+            self.stop_recording();
+            return;
+        }
+        self.open_prompt(Prompt::new("Record macro into register: ", "macro", |editor: &mut Editor, input: String| {
+            let mut chars = input.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) if is_register(c) => editor.start_recording(c),
+                _ => editor.new_status_msg("Registers are named by a letter or digit", Duration::from_secs(3)),
+            }
+        }));
+    }
+
+    pub fn start_recording(&mut self, register: char) {
+        self.recording = Some(Recording::new(register));
+    }
+
+    pub fn stop_recording(&mut self) {
+        if let Some(recording) = self.recording.take() {
+            let msg = format!("Recorded {} keys into register {}", recording.keys.len(), recording.register);
+            set(&mut self.macros, recording.register, recording.keys);
+            self.last_macro = Some(recording.register);
+            self.new_status_msg(&msg, Duration::from_secs(3));
+        }
+    }
+
+    /// Plays a macro, prompting for its register and how many times to play
+    /// it.
+    pub fn play_macro(&mut self) {
+        self.open_prompt(Prompt::new("Play macro ([count]register): ", "macro", |editor: &mut Editor, input: String| {
+            match parse_play(&input) {
+                Some((count, register)) => editor.play_register(register, count),
+                None => editor.new_status_msg("Registers are named by a letter or digit", Duration::from_secs(3)),
+            }
+        }));
+    }
+
+    /// Plays the macro in `register`, or the last one played, `count` times,
+    /// as if its keys were typed. If text is selected, the macro is played on
+    /// each of the selected lines instead, starting at the start of the line.
+    pub fn play_register(&mut self, register: Option<char>, count: usize) {
+        let register = match register.or(self.last_macro) {
+            Some(register) => register,
+            None => {
+                self.new_status_msg("No macro has been played yet", Duration::from_secs(3));
+                return;
+            }
+        };
+        let keys = match self.macros.iter().find(|(r, _)| *r == register) {
+            Some((_, keys)) => keys.clone(),
+            None => {
+                self.new_status_msg(&format!("Register {} is empty", register), Duration::from_secs(3));
+                return;
+            }
+        };
+        if self.macro_depth == MAX_DEPTH {
+            self.new_status_msg("Macros are played too deep", Duration::from_secs(3));
+            return;
+        }
+        self.last_macro = Some(register);
+        // The whole replay is undone at once.
+        self.buffer.undo.seal();
+        self.macro_depth += 1;
+        match self.selected_lines() {
+            Some((first, last)) => {
+                // Follow the lines as the macro adds or removes some.
+                let mut line = first;
+                let mut last = last;
+                while line <= last && line < self.buffer.lines.len() {
+                    let n_lines = self.buffer.lines.len();
+                    self.buffer.cursor.line = line;
+                    self.buffer.cursor.byte = 0;
+                    self.buffer.cursor.is_at_eol = false;
+                    self.play_keys(&keys, count);
+                    let added = self.buffer.lines.len() as isize - n_lines as isize;
+                    line = (line as isize + 1 + added).max(0) as usize;
+                    last = (last as isize + added).max(0) as usize;
+                }
+            }
+            None => self.play_keys(&keys, count),
+        }
+        self.macro_depth -= 1;
+        self.buffer.undo.seal();
+    }
+
+    fn play_keys(&mut self, keys: &[String], count: usize) {
+        for _ in 0..count {
+            for key in keys {
+                self.dispatch_key(key);
+            }
+        }
+    }
+
+    /// Returns the first and last of the selected lines, if any, and clears
+    /// the selection. A region that ends at the start of a line doesn't
+    /// include it.
+    fn selected_lines(&mut self) -> Option<(usize, usize)> {
+        let ((first, _), (last, byte)) = self.selection()?;
+        if self.buffer.config.profile == Profile::Vi {
+            self.vi.mode = Mode::Normal;
+            Some((first, last))
+        } else {
+            self.mark = None;
+            Some((first, if byte == 0 && last > first { last - 1 } else { last }))
+        }
+    }
+
+    pub fn save_macros(&mut self) {
+        match save(&self.macros) {
+            Ok(path) => {
+                let msg = match self.macros.len() {
+                    1 => format!("1 macro saved to {}", path.display()),
+                    n => format!("{} macros saved to {}", n, path.display()),
+                };
+                self.new_status_msg(&msg, Duration::from_secs(3));
+            }
+            Err(e) => self.new_status_msg(&e, Duration::from_secs(5)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_play, set};
+    use tests::editor;
+    use Editor;
+
+    fn text(editor: &Editor) -> Vec<String> {
+        editor.buffer.lines.iter().map(|line| String::from_utf8_lossy(&line.orig).to_string()).collect()
+    }
+
+    #[test]
+    fn parses_counts_and_registers() {
+        assert_eq!(parse_play("a"), Some((1, Some('a'))));
+        assert_eq!(parse_play("3a"), Some((3, Some('a'))));
+        assert_eq!(parse_play("12"), Some((12, None)));
+        assert_eq!(parse_play(""), Some((1, None)));
+        assert_eq!(parse_play("3ab"), None);
+        assert_eq!(parse_play("3 a"), None);
+        assert_eq!(parse_play("-"), None);
+        assert_eq!(parse_play("99999999999999999999999a"), None);
+    }
+
+    #[test]
+    fn undoes_a_replay_at_once() {
+        let mut editor = editor("one\ntwo\nthree");
+        let keys = ["home", "-", " ", "down"].iter().map(|key| key.to_string()).collect();
+        set(&mut editor.macros, 'a', keys);
+        editor.handle_key('>');
+        editor.play_register(Some('a'), 3);
+        assert_eq!(text(&editor), vec!["- >one", "- two", "- three"]);
+        editor.undo();
+        assert_eq!(text(&editor), vec![">one", "two", "three"]);
+        editor.redo();
+        assert_eq!(text(&editor), vec!["- >one", "- two", "- three"]);
+        editor.undo();
+        editor.undo();
+        assert_eq!(text(&editor), vec!["one", "two", "three"]);
+    }
+}
@@ -1,21 +1,55 @@
 extern crate nix;
 
+mod buffer;
+mod cli;
+mod command;
+mod config;
+mod editorconfig;
+mod emacs;
+mod filetype;
+mod filter;
+mod follow;
+mod keymap;
+mod loader;
+mod macros;
+mod pager;
+mod prompt;
+mod regex;
+mod render;
+mod status;
+mod syntax;
+mod tabs;
+mod theme;
+mod undo;
+mod vi;
+mod window;
+
 use std::io;
-use std::io::prelude::*;
 use std::io::Write;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::os::unix::io::AsRawFd;
-use std::env::args;
-use std::path::Path;
+use std::env::{self, args};
+use std::path::{Path, PathBuf};
+use std::cell::{Ref, RefCell};
 use std::cmp;
+use std::mem;
+use std::process;
 use std::time::{Duration, Instant};
 
+use nix::libc;
+use nix::poll::{poll, EventFlags, PollFd};
 use nix::sys::termios;
 
-pub struct Config {
-    tab_width: i32,
-}
+use buffer::Buffer;
+use config::{Config, LineEnding, Profile, WrapMode};
+use keymap::Lookup;
+use loader::Text;
+use prompt::{History, PathCompleter, Prompt};
+use render::Rendered;
+use syntax::Highlight;
+use theme::{ColorDepth, Element, Theme};
+use window::{Layout, Rect, View, Window};
 
 /// A data type that represents where in the console window something resides.
 /// Indexing starts at 0 (even though the VT100 escape sequences expect
@@ -40,13 +74,50 @@ enum Key {
     FileHome,
     FileEnd,
     Delete,
+    // Esc by itself, rather than as the start of an escape sequence.
+    Escape,
+    // A key pressed with Alt (or Meta), which terminals send as Esc followed
+    // by the key.
+    Alt(u8),
+    // A click of the left mouse button.
+    Click(Pos),
 }
 
-fn ctrl_mask(c: char) -> char {
-    (c as u8 & 0x1f) as char
+impl Key {
+    /// Returns the name the key is bound by in the config file.
+    fn name(&self) -> String {
+        let name = match *self {
+            Key::ArrowUp => "up",
+            Key::ArrowDown => "down",
+            Key::ArrowLeft => "left",
+            Key::ArrowRight => "right",
+            Key::PageUp => "pageup",
+            Key::PageDown => "pagedown",
+            Key::LineHome => "home",
+            Key::LineEnd => "end",
+            Key::FileHome => "ctrl-home",
+            Key::FileEnd => "ctrl-end",
+            Key::Delete => "delete",
+            Key::Escape => "esc",
+            Key::Alt(b) => return format!("alt-{}", keymap::byte_key_name(b as char)),
+            // Clicks are handled as they're read rather than bound.
+            Key::Click(_) => "click",
+        };
+        name.to_string()
+    }
 }
 
-#[derive(Debug)]
+/// The most choices shown at once above a prompt.
+const MENU_HEIGHT: usize = 10;
+
+/// How long to wait for the rest of an escape sequence before taking Esc to
+/// have been pressed by itself, in milliseconds.
+const ESC_TIMEOUT_MS: i32 = 50;
+
+/// A position in the text, as the index of a line and a byte offset into it.
+type TextPos = (usize, usize);
+
+#[derive(Debug, Clone, Copy)]
 struct Cursor {
     /// The position of the cursor in the terminal window.
     pos: Pos,
@@ -56,7 +127,9 @@ struct Cursor {
     line: usize,
     /// To the same reason as above, there is no way to retrieve the actual
     /// byte in line under cursor, so the absolute offset from the line's start
-    /// needs to be stored here.
+    /// needs to be stored here. This is an offset into the line's original
+    /// representation, and it may be one past its last byte so that text can
+    /// be appended to the line.
     byte: usize,
     /// In order to be able to go up and down along the ends of lines of
     /// different lengths (including 0), this flag needs to be set to determine
@@ -67,18 +140,28 @@ struct Cursor {
 
 struct Line {
     // The original representation of the line.
-    orig: Vec<u8>,
-    // Represents how the line is rendered on screen.
-    render: Vec<u8>
+    orig: Text,
+    // What has been worked out about how the line is drawn, if anything has
+    // since it last changed.
+    rendered: RefCell<Option<Box<Rendered>>>,
+    // Whether the buffer's filters hide the line.
+    is_hidden: bool,
 }
 
-impl Line {
-    fn len(&self) -> usize {
-        self.render.len()
-    }
+/// Where the rows of a line begin in word-wrap mode, for the widths of its
+/// first row and of its continuation rows.
+#[derive(Default)]
+struct WrapRows {
+    widths: (usize, usize),
+    starts: Vec<usize>,
+    // Whether `starts` has all of the line's rows.
+    is_complete: bool,
+}
 
-    fn is_empty(&self) -> bool {
-        self.render.is_empty()
+impl Line {
+    /// Creates a line that is yet to be rendered and highlighted.
+    fn new<T: Into<Text>>(orig: T) -> Line {
+        Line { orig: orig.into(), rendered: RefCell::new(None), is_hidden: false }
     }
 }
 
@@ -91,106 +174,199 @@ struct StatusMsg {
 }
 
 struct Editor {
-    // Note that this does not always report the actual position of the cursor.
-    // Instead, it reflects the _desired_ position, i.e. what user sets. It may
-    // be that for rendering purposes the cursor is temporarily relocated but
-    // then set back to this position. This also means that when it's
-    // temporarily relocated, this field shall not be updated.
-    cursor: Cursor,
+    // The terminal, which keys are read from and the screen is drawn on, even
+    // if standard input or output are redirected.
+    tty: File,
+    // The file being edited. The other open files are in `buffers`, where
+    // this one's place is taken by whichever buffer was last switched from.
+    buffer: Buffer,
+    buffers: Vec<Buffer>,
+    // The index of `buffer` among `buffers`.
+    current: usize,
+    // The windows the screen is split into, and how. The focused window
+    // shows `buffer`, and its view is kept there rather than in `windows`.
+    windows: Vec<Window>,
+    layout: Layout,
+    focus: usize,
+    // The first tab shown on the tab bar, which is scrolled to show the tab
+    // of the buffer being edited.
+    tab_offset: usize,
+    // The size of the terminal.
+    screen_width: usize,
+    screen_height: usize,
+    // The size of the text area of the window being drawn, or otherwise of
+    // the focused window.
     window_width: usize,
     window_height: usize,
     // Used to coalesce writes into a single buffer to then flush it in one go
     // to avoid excessive IO overhead.
     write_buf: Vec<u8>,
-    // Note that there is a distinction between rows and lines. A line is the
-    // string of text until the new-line character, as stored in the file, while
-    // a row is the rendered string that fits into a single row in the window.
-    // Thus a line may wrap several rows.
-    lines: Vec<Line>,
-    // The zero-based index into `lines` of the first line to show.
-    line_offset: usize,
-    // The first character of the row in line that should be drawn. Always
-    // a multiple of `window_width`. Also zero-based.
-    line_offset_byte: usize,
-    config: Config,
-    // The path of the file currently being edited. Stored as a string since
-    // we're only printing it on the status bar.
-    path: String,
+    theme: Theme,
+    // How many colours the theme is drawn with.
+    color_depth: ColorDepth,
     // Store the status message so that it's persisted across screen redraws.
     status_msg: StatusMsg,
+    // The prompt in the message bar that keys go to instead of the text, if
+    // one is open.
+    prompt: Option<Prompt<Editor>>,
+    history: History,
+    // The keys of a chord typed so far.
+    pending_keys: Vec<String>,
+    // Whether the next key, or chord, is to be described rather than run.
+    is_describing_key: bool,
+    should_quit: bool,
+    // The config before the settings of the open file were applied to it.
+    base_config: Config,
+    // The config file given on the command line, if any.
+    config_path: Option<PathBuf>,
+    vi: vi::Vi,
+    // The other end of the region from the cursor, for the commands that act
+    // on it.
+    mark: Option<TextPos>,
+    // Killed text, the latest last.
+    kill_ring: Vec<Vec<u8>>,
+    // Where the last yank inserted text, and which kill it inserted.
+    yanked: Option<(TextPos, TextPos, usize)>,
+    // The name of the command run last, cleared when text is typed.
+    last_command: String,
+    // Whether the key being handled typed text.
+    is_typing: bool,
+    isearch: Option<emacs::Isearch>,
+    last_search: Vec<u8>,
+    // The regular expression last searched for in a pager.
+    search: Option<pager::Search>,
+    // Keyboard macros, by register.
+    macros: Vec<(char, Vec<String>)>,
+    recording: Option<macros::Recording>,
+    last_macro: Option<char>,
+    // How many macros are being played, one inside the other.
+    macro_depth: usize,
 }
 
 impl Editor {
-    fn new(config: Config, path: String) -> Editor {
+    fn new(config: Config, buffer: Buffer, tty: File) -> Editor {
         Editor {
-            cursor: Cursor { pos: Pos { row: 0, col: 0 }, line: 0, byte: 0, is_at_eol: false },
+            tty,
+            base_config: config.clone(),
+            config_path: None,
+            windows: vec![Window { buffer: 0, view: View::of(&buffer) }],
+            buffer,
+            // The place of the buffer being edited.
+            buffers: vec![Buffer::new(config, PathBuf::new())],
+            current: 0,
+            layout: Layout::Window(0),
+            focus: 0,
+            tab_offset: 0,
+            screen_width: 0,
+            screen_height: 0,
             window_width: 0,
             window_height: 0,
             write_buf: vec![],
-            lines: vec![],
-            line_offset: 0,
-            line_offset_byte: 0,
-            config: config,
-            path: path,
+            theme: Theme::builtin("default").unwrap(),
+            color_depth: ColorDepth::Ansi16,
             status_msg: StatusMsg {
                 data: String::new(),
                 timestamp: Instant::now(),
                 timeout: Duration::new(0, 0),
             },
+            prompt: None,
+            history: History::load(),
+            pending_keys: vec![],
+            is_describing_key: false,
+            should_quit: false,
+            vi: vi::Vi::new(),
+            mark: None,
+            kill_ring: vec![],
+            yanked: None,
+            last_command: String::new(),
+            is_typing: false,
+            isearch: None,
+            last_search: vec![],
+            search: None,
+            macros: vec![],
+            recording: None,
+            last_macro: None,
+            macro_depth: 0,
         }
     }
 
-    pub fn open_file(config: Config, path: &Path) -> std::io::Result<Editor> {
-        let mut file = File::open(path)?;
-        let path = path.file_name().unwrap().to_str().unwrap().to_string();
-        let mut editor = Editor::new(config, path);
-        let mut buf = vec![];
-
-        file.read_to_end(&mut buf).unwrap();
-
-        // TODO might need to match \r\n as well
-        // FIXME there's an extra empty space at the end even if there shouldn't be
-        let lines = buf.split(|b| *b == '\n' as u8);
-
-        // Try to get an esimate of the number of lines in file.
-        let size_hint = {
-            let (lower, upper) = lines.size_hint();
-            if let Some(upper) = upper { upper } else { lower }
+    /// Opens the files given on the command line in buffers of their own and
+    /// shows the first, or without any, an empty buffer that is yet to be
+    /// named. `-` reads the text from standard input instead.
+    pub fn open_files(config: Config, args: &cli::Args, tty: File) -> io::Result<Editor> {
+        let open = |file: &cli::FileArg| -> io::Result<Buffer> {
+            let buffer = if file.path == Path::new("-") {
+                Buffer::from_stdin(config.clone())
+            } else {
+                Buffer::open(config.clone(), &file.path)
+            };
+            // Say which file couldn't be opened.
+            let mut buffer = buffer.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", file.path.display(), e)))?;
+            if file.line.is_some() || file.col.is_some() {
+                buffer.go_to(file.line.unwrap_or(1), file.col.unwrap_or(1));
+            }
+            buffer.is_readonly |= args.is_readonly || args.is_viewed;
+            buffer.is_viewed = args.is_viewed;
+            Ok(buffer)
         };
-
-        if size_hint > 0 {
-            editor.lines.reserve(size_hint);
+        let buffer = match args.files.first() {
+            Some(file) => open(file)?,
+            None => Buffer::new(config.clone(), PathBuf::new()),
+        };
+        let mut editor = Editor::new(config.clone(), buffer, tty);
+        editor.config_path = args.config_path.clone();
+        for file in args.files.iter().skip(1) {
+            editor.buffers.push(open(file)?);
+        }
+        editor.configure(config);
+        if args.is_followed {
+            for idx in (0..editor.buffers.len()).rev() {
+                editor.swap_buffer(idx);
+                editor.follow();
+            }
         }
-
-        editor.lines = lines
-            .map(|line| Line {
-                orig: line.to_vec(),
-                render: editor.line_orig_to_render(&line)
-            })
-            .collect();
-
-        let dbg_lines: Vec<String> = editor.lines.iter()
-            .map(|line| String::from_utf8_lossy(&line.orig).to_string())
-            .collect();
-        log(format!("file ({} lines):\n{:?}", editor.lines.len(), dbg_lines).as_bytes());
-
         Ok(editor)
     }
 
+    /// Fills `buf` from the terminal. Unlike `io::stdin()`, this doesn't
+    /// buffer what it doesn't return, so that polling the terminal tells
+    /// whether more input is pending.
+    fn read_tty(&self, buf: &mut [u8]) -> Result<(), ()> {
+        let mut n = 0;
+        while n < buf.len() {
+            match nix::unistd::read(self.tty.as_raw_fd(), &mut buf[n..]) {
+                Ok(0) | Err(_) => return Err(()),
+                Ok(read) => n += read,
+            }
+        }
+        Ok(())
+    }
+
     pub fn run(&mut self) {
         let mut buf: [u8; 1] = [0; 1];
         self.refresh_screen();
-        self.new_status_msg("HELP: Ctrl-C to quit", Duration::from_secs(5));
-        loop {
+        // Don't hide a message issued before the editor started, such as a
+        // config error.
+        if self.status_msg.data.is_empty() {
+            let help: Vec<String> = ["save", "quit", "command-palette"].iter()
+                .filter_map(|name| {
+                    let keys = self.buffer.config.keymap.keys_for_command(name);
+                    keys.first().map(|keys| format!("{} = {}", keys, name))
+                })
+                .collect();
+            self.new_status_msg(&format!("HELP: {}", help.join(" | ")), Duration::from_secs(5));
+        }
+        while !self.should_quit {
             self.refresh_screen();
+            // Drawing may have found a mapped file to have shrunk.
+            if self.check_truncated() {
+                self.refresh_screen();
+            }
+            self.wait_for_key();
             // TODO is there a canonical way of getting a single byte from stdin?
-            if let Ok(_) = io::stdin().read_exact(&mut buf) {
-                let b = buf[0];
-                if b as char == ctrl_mask('c') {
-                    break;
-                } else {
-                    self.handle_key(b as char)
-                }
+            if self.read_tty(&mut buf).is_ok() {
+                self.handle_key(buf[0] as char);
+                self.check_follow_paused();
             } else {
                 break;
             }
@@ -198,284 +374,1035 @@ impl Editor {
     }
 
     fn handle_key(&mut self, c: char) {
-        match c {
-            '\x1b' => self.handle_esc_seq_key(),
-            _ => self.handle_input(c)
-        }
-    }
-
-    fn handle_esc_seq_key(&mut self) {
-        if let Some(key) = self.read_esc_seq_to_key() {
-            match key {
-                Key::ArrowUp => self.cursor_up(),
-                Key::ArrowDown => self.cursor_down(),
-                Key::ArrowLeft => self.cursor_left(),
-                Key::ArrowRight => self.cursor_right(),
-                Key::PageUp => self.page_up(),
-                Key::PageDown => self.page_down(),
-                Key::LineHome => {
-                    while self.cursor.byte > 0 {
-                        self.cursor_left();
-                    }
-                },
-                // FIXME this doesn't work
-                Key::LineEnd => {
-                    while self.cursor.byte + 1 < self.lines[self.cursor.line].len()
-                        && self.cursor.pos.col + 1 < self.window_width {
-                        self.cursor_right();
-                    }
-                },
-                Key::FileHome => {
+        let key = if c == '\x1b' {
+            match self.read_esc_seq_to_key() {
+                Some(Key::Click(pos)) => {
+                    self.click(pos);
+                    self.buffer.undo.seal();
+                    return;
                 }
-                Key::FileEnd => {
+                Some(key) => key.name(),
+                None => return,
+            }
+        } else {
+            keymap::byte_key_name(c)
+        };
+        let is_recording = self.recording.is_some();
+        self.is_typing = false;
+        self.dispatch_key(&key);
+        // Text typed in a row, or in one go in vi's insert mode, is undone
+        // at once.
+        if !self.is_typing && self.vi.mode != vi::Mode::Insert {
+            self.buffer.undo.seal();
+        }
+        let is_finished = self.pending_keys.is_empty() && self.vi.keys.is_empty() && !self.is_describing_key;
+        match self.recording {
+            Some(ref mut recording) if is_recording => recording.add(&key, is_finished),
+            _ => {}
+        }
+    }
+
+    /// Handles a key, typed or played from a macro, given its name.
+    fn dispatch_key(&mut self, key: &str) {
+        if self.prompt.is_some() {
+            self.handle_prompt_key(key);
+            return;
+        }
+        if self.isearch.is_some() && self.handle_isearch_key(key) {
+            return;
+        }
+        self.handle_named_key(key);
+    }
+
+    /// Handles the key with the given name: in vi's modes other than insert
+    /// mode it's a vi command, otherwise a printable character is inserted
+    /// unless it's bound or part of a chord, and other keys run what they
+    /// are bound to.
+    fn handle_named_key(&mut self, key: &str) {
+        let is_chord_pending = !self.pending_keys.is_empty() || self.is_describing_key;
+        if self.buffer.is_viewed && !is_chord_pending && self.pager_handle_key(key) {
+            return;
+        }
+        if self.buffer.config.profile == Profile::Vi && !is_chord_pending && self.vi_handle_key(key) {
+            return;
+        }
+        let is_bound = match self.buffer.config.keymap.lookup(&[key.to_string()]) {
+            Lookup::Unbound => false,
+            Lookup::Command(_) | Lookup::Prefix => true,
+        };
+        match keymap::key_byte(key) {
+            Some(b) if !is_bound && !is_chord_pending => {
+                self.insert_bytes(&[b]);
+                self.last_command.clear();
+                self.is_typing = true;
+            }
+            _ => self.run_key(key),
+        }
+    }
+
+    /// Passes a key to the open prompt, and closes it if the key confirms or
+    /// cancels it.
+    fn handle_prompt_key(&mut self, key: &str) {
+        let input = match key {
+            "esc" | "ctrl-c" | "ctrl-g" => prompt::Input::Cancel,
+            "left" => prompt::Input::Left,
+            "right" => prompt::Input::Right,
+            "up" => prompt::Input::Up,
+            "down" => prompt::Input::Down,
+            "home" | "ctrl-home" | "ctrl-a" => prompt::Input::Home,
+            "end" | "ctrl-end" | "ctrl-e" => prompt::Input::End,
+            "delete" => prompt::Input::Delete,
+            "enter" => prompt::Input::Confirm,
+            "tab" => prompt::Input::Complete,
+            "backspace" => prompt::Input::Backspace,
+            "ctrl-k" => prompt::Input::KillToEnd,
+            "ctrl-u" => prompt::Input::KillToStart,
+            _ => match keymap::key_byte(key) {
+                Some(b) => prompt::Input::Byte(b),
+                None => return,
+            },
+        };
+        let outcome = match self.prompt {
+            Some(ref mut prompt) => prompt.handle(input),
+            None => return,
+        };
+        match outcome {
+            prompt::Outcome::Pending => {}
+            prompt::Outcome::Cancel => self.prompt = None,
+            prompt::Outcome::Confirm(input) => {
+                let mut prompt = self.prompt.take().unwrap();
+                if let Err(e) = self.history.add(&prompt.history_name, &input) {
+                    log(e.as_bytes());
+                }
+                if let Some(on_confirm) = prompt.take_callback() {
+                    on_confirm(self, input);
                 }
-                _ => (),
             }
         }
     }
 
+    /// Opens a prompt in the message bar, with the entries given to prompts
+    /// of the same kind before.
+    fn open_prompt(&mut self, prompt: Prompt<Editor>) {
+        let history = self.history.entries(&prompt.history_name);
+        self.prompt = Some(prompt.with_history(history));
+    }
+
+    /// Runs the command bound to the key with the given name along with the
+    /// keys of the chord typed before it, if any. If they start a longer
+    /// chord, they are kept until it's complete.
+    fn run_key(&mut self, key: &str) {
+        self.pending_keys.push(key.to_string());
+        let command = match self.buffer.config.keymap.lookup(&self.pending_keys) {
+            Lookup::Prefix => return,
+            Lookup::Command(command) => Some(command.to_string()),
+            Lookup::Unbound => None,
+        };
+        let keys = self.pending_keys.join(" ");
+        self.pending_keys.clear();
+        if self.is_describing_key {
+            self.is_describing_key = false;
+            let msg = match command.as_ref().and_then(|name| command::find(name)) {
+                Some(command) => format!("{} runs {}: {}", keys, command.name, command.description),
+                None if command.is_some() => format!("{} runs unknown command {}", keys, command.unwrap()),
+                None if key.chars().count() == 1 => format!("{} inserts itself", keys),
+                None => format!("{} isn't bound", keys),
+            };
+            self.new_status_msg(&msg, Duration::from_secs(5));
+            return;
+        }
+        match command {
+            Some(command) => self.run_command(&command),
+            None if keys.contains(' ') => self.new_status_msg(&format!("{} isn't bound", keys), Duration::from_secs(3)),
+            None => {}
+        }
+    }
+
     fn page_down(&mut self) {
-        //let lines_left = self.lines.len() - self.cursor.line;
-        //let at_least_n_rows = cmp::min(self.window_height, lines_left);
-        let mut n_rows_left = self.window_height - 1;
-        while n_rows_left > 0 && self.cursor.line < self.lines.len() {
+        for _ in 1..self.window_height {
             self.cursor_down();
-            n_rows_left -= 1;
         }
     }
 
     fn page_up(&mut self) {
-        let mut n_rows_left = self.window_height - 1;
-        //let n_rows = cmp::min(self.window_height, self.cursor.pos.row);
-        while n_rows_left > 0 && self.cursor.line > 0 {
+        for _ in 1..self.window_height {
             self.cursor_up();
-            n_rows_left -= 1;
         }
     }
 
-    /// Moves the cursor down by one row, if possible.
-    fn cursor_down(&mut self) {
-        // Check if cursor is at the bottom of the window.
-        if self.cursor.pos.row + 1 == self.window_height {
-            self.scroll_down();
-        }
+    fn toggle_wrap_mode(&mut self) {
+        self.buffer.config.wrap_mode = match self.buffer.config.wrap_mode {
+            WrapMode::Char => WrapMode::Word,
+            WrapMode::Word => WrapMode::NoWrap,
+            WrapMode::NoWrap => WrapMode::Char,
+        };
+        // The row the window started at may not exist in the new layout.
+        self.buffer.line_offset_row = 0;
+        self.buffer.col_offset = 0;
+        let msg = match self.buffer.config.wrap_mode {
+            WrapMode::Char => "Line wrapping on",
+            WrapMode::Word => "Word wrapping on",
+            WrapMode::NoWrap => "Line wrapping off",
+        };
+        self.new_status_msg(msg, Duration::from_secs(2));
+    }
 
-        // Note that this is indexed from the beginning of the line, whereas
-        // curr_last_pos_row_offset is indexed from the beginning of the row.
-        let next_rows_len = self.curr_line_next_rows_len();
+    /// Returns the number of rows the line at `line_idx` takes up in the window.
+    fn line_n_rows(&self, line_idx: usize) -> usize {
+        match self.buffer.config.wrap_mode {
+            WrapMode::NoWrap => 1,
+            WrapMode::Word => self.word_wrap_row_starts(line_idx, |_| false).starts.len(),
+            WrapMode::Char => {
+                let width = cmp::max(self.text_width(), 1);
+                cmp::max(self.line_width(line_idx).div_ceil(width), 1)
+            }
+        }
+    }
 
-        log(format!("DOWN: cursor: {:?}, row_last_byte: {}, next_rows_len: {}, line_offset: {}, line_offset_byte: {}, line.len: {}",
-                    self.cursor, self.curr_last_pos_line_offset(), next_rows_len, self.line_offset,
-                    self.line_offset_byte, self.lines[self.cursor.line].len()).as_bytes());
+    /// Returns the start and end offsets into the rendered line at `line_idx`
+    /// of the bytes that make up its `row`th row. In no-wrap mode a line has
+    /// a single row spanning the whole line, regardless of how much of it is
+    /// visible.
+    fn row_range(&self, line_idx: usize, row: usize) -> (usize, usize) {
+        let start = self.row_start(line_idx, row);
+        let end = match self.buffer.config.wrap_mode {
+            WrapMode::NoWrap => self.line_width(line_idx),
+            WrapMode::Word => {
+                let next = self.word_wrap_row_starts(line_idx, |starts| starts.len() > row + 1).starts.get(row + 1).cloned();
+                match next {
+                    Some(next) => next,
+                    None => self.line_width_upto(line_idx, start + self.row_width(line_idx, row)),
+                }
+            }
+            WrapMode::Char => self.line_width_upto(line_idx, start + cmp::max(self.text_width(), 1)),
+        };
+        (start, end)
+    }
 
-        if next_rows_len > 0 {
-            // We're not at the end of the line, which is merely wrapped, so
-            // just go down one row staying on the same line.
-            if self.cursor.pos.row + 1 < self.window_height {
-                self.cursor.pos.row += 1;
+    /// Returns the offset into the rendered line at `line_idx` at which its
+    /// `row`th row starts.
+    fn row_start(&self, line_idx: usize, row: usize) -> usize {
+        match self.buffer.config.wrap_mode {
+            WrapMode::NoWrap => 0,
+            WrapMode::Word => {
+                let rows = self.word_wrap_row_starts(line_idx, |starts| starts.len() > row);
+                rows.starts[cmp::min(row, rows.starts.len() - 1)]
             }
+            WrapMode::Char => self.line_width_upto(line_idx, row * cmp::max(self.text_width(), 1)),
+        }
+    }
+
+    /// Returns the number of columns the text of the `row`th row of the line
+    /// at `line_idx` may take up in word-wrap mode.
+    fn row_width(&self, line_idx: usize, row: usize) -> usize {
+        let rows = self.word_wrap_row_starts(line_idx, |_| true);
+        if row == 0 { rows.widths.0 } else { rows.widths.1 }
+    }
 
-            let next_row_len = cmp::min(next_rows_len, self.window_width);
-            let col = {
-                if self.cursor.is_at_eol {
-                    next_row_len - 1
-                } else {
-                    cmp::min(self.cursor.pos.col, next_row_len - 1)
+    /// Returns the index of the row in the line at `line_idx` that contains the
+    /// rendered byte at offset `byte`.
+    fn row_of_byte(&self, line_idx: usize, byte: usize) -> usize {
+        match self.buffer.config.wrap_mode {
+            WrapMode::NoWrap => 0,
+            WrapMode::Word => {
+                // Only the rows up to the one after the byte's are needed.
+                let rows = self.word_wrap_row_starts(line_idx, |starts| starts[starts.len() - 1] > byte);
+                match rows.starts.binary_search(&byte) {
+                    Ok(row) => row,
+                    Err(row) => row - 1,
                 }
-            };
+            }
+            WrapMode::Char => {
+                let width = cmp::max(self.text_width(), 1);
+                // The end of a line that fills its last row is on that row.
+                let is_end = self.line_width_upto(line_idx, byte + 1) == byte;
+                if is_end && byte > 0 && byte.is_multiple_of(width) { byte / width - 1 } else { byte / width }
+            }
+        }
+    }
 
-            log(format!("DOWN|wrap: next_row_len: {}, col: {}", next_row_len, col).as_bytes());
-
-            self.cursor.pos.col = col;
-            self.cursor.byte = self.curr_last_pos_line_offset() + 1 + col;
-        } else if self.cursor.line + 1 < self.lines.len() {
-            // Go down one row to the next line if cursor is not already on the
-            // last line.
-            self.cursor.line += 1;
-            if self.cursor.pos.row + 1 < self.window_height {
-                self.cursor.pos.row += 1;
-            }
-
-            // Next line might be shorter than current cursor column position.
-            let col = {
-                let line = &self.lines[self.cursor.line];
-                if line.is_empty() {
-                    0
-                } else if self.cursor.is_at_eol {
-                    cmp::min(line.len(), self.window_width) - 1
-                } else {
-                    cmp::min(line.len() - 1, self.cursor.pos.col)
+    /// Returns what is drawn before the text of each continuation row of the
+    /// line at `line_idx` in word-wrap mode: the line's indentation, if
+    /// enabled, followed by the wrap indicator. The indentation is dropped if
+    /// it would take up more than half of the window.
+    fn wrap_prefix(&self, line_idx: usize) -> String {
+        let mut prefix = String::new();
+        if self.buffer.config.wrap_indent {
+            let orig = &self.buffer.lines[line_idx].orig;
+            let indent_bytes = orig.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+            let indent = self.render_offset(line_idx, indent_bytes);
+            if indent + self.buffer.config.wrap_indicator.chars().count() <= self.text_width() / 2 {
+                prefix.extend(std::iter::repeat_n(' ', indent));
+            }
+        }
+        prefix += &self.buffer.config.wrap_indicator;
+        prefix
+    }
+
+    /// Returns the number of columns taken up by the text drawn before the
+    /// `row`th row of the line at `line_idx`.
+    fn row_prefix_width(&self, line_idx: usize, row: usize) -> usize {
+        if row > 0 && self.buffer.config.wrap_mode == WrapMode::Word {
+            self.wrap_prefix(line_idx).chars().count()
+        } else {
+            0
+        }
+    }
+
+    /// Returns the offsets into the rendered line at `line_idx` at which its
+    /// rows begin in word-wrap mode, having worked them out until
+    /// `is_enough` is true of them or the line ends. A row is broken after
+    /// the last whitespace or punctuation byte that fits into it, or,
+    /// failing that, wherever the window ends. The rows are kept until the
+    /// line changes, so a long line is broken into rows bit by bit as it's
+    /// scrolled through rather than all at once.
+    fn word_wrap_row_starts<F: Fn(&[usize]) -> bool>(&self, line_idx: usize, is_enough: F) -> Ref<'_, WrapRows> {
+        let width = cmp::max(self.text_width(), 1);
+        // Continuation rows have less space because of the prefix, but always
+        // leave at least one column for the text.
+        let cont_width = cmp::max(width.saturating_sub(self.wrap_prefix(line_idx).chars().count()), 1);
+        let is_break = |b: u8| b.is_ascii_whitespace() || (b.is_ascii_punctuation() && b != b'_');
+
+        loop {
+            let start = {
+                let mut rendered = self.rendered(line_idx);
+                let rows = &mut rendered.wrap_rows;
+                if rows.widths != (width, cont_width) || rows.starts.is_empty() {
+                    *rows = WrapRows { widths: (width, cont_width), starts: vec![0], is_complete: false };
+                }
+                if rows.is_complete || is_enough(&rows.starts) {
+                    break;
                 }
+                rows.starts[rows.starts.len() - 1]
             };
+            let row_width = if start == 0 { width } else { cont_width };
+            // The row, and the byte after it that tells if the line goes on.
+            let render = self.render_cols(line_idx, start, start + row_width + 1);
+            let mut rendered = self.rendered(line_idx);
+            if render.len() <= row_width {
+                rendered.wrap_rows.is_complete = true;
+                break;
+            }
+            let mut next = (1..row_width + 1).rev()
+                .find(|end| render[end - 1].1.first().is_some_and(|&b| is_break(b)))
+                .unwrap_or(row_width);
+            // A wide character that doesn't fit goes on the next row.
+            if next > 1 && render[next].1.is_empty() {
+                next -= 1;
+            }
+            rendered.wrap_rows.starts.push(start + next);
+        }
+        Ref::map(self.buffer.lines[line_idx].rendered.borrow(), |rendered| &rendered.as_ref().unwrap().wrap_rows)
+    }
 
-            log(format!("DOWN|new-line: col: {}", col).as_bytes());
+    /// Returns whether the line at `line_idx` has a `row`th row, without
+    /// breaking the rest of the line into rows.
+    fn has_row(&self, line_idx: usize, row: usize) -> bool {
+        match self.buffer.config.wrap_mode {
+            WrapMode::Word => self.word_wrap_row_starts(line_idx, |starts| starts.len() > row).starts.len() > row,
+            WrapMode::NoWrap => row == 0,
+            WrapMode::Char => {
+                let start = row * cmp::max(self.text_width(), 1);
+                row == 0 || self.line_width_upto(line_idx, start + 1) > start
+            }
+        }
+    }
 
-            self.cursor.pos.col = col;
-            self.cursor.byte = col;
+    /// Returns the (line, row) pair of the row after the given one, skipping
+    /// the lines that filters hide, or None if it's the last row shown.
+    fn next_row(&self, (line, row): (usize, usize)) -> Option<(usize, usize)> {
+        if self.has_row(line, row + 1) {
+            Some((line, row + 1))
+        } else {
+            self.shown_line_after(line).map(|line| (line, 0))
         }
     }
 
-    /// Shifts the window down by one row, but does not affect the cursor position.
-    fn scroll_down(&mut self) {
-        // Only scroll down if there's at least one line left, or if we're on
-        // the last line but it's wrapped, so we can scroll to its next row.
-        if self.cursor.line + 1 < self.lines.len() || self.curr_line_next_rows_len() > 0 {
-            // The top row may be part of a wrapped line, so need to check if we
-            // need to advance to the next line or just adjust the byte offset
-            // from which to show the line.
-            if self.line_offset_byte + self.window_width < self.lines[self.line_offset].len() {
-                self.line_offset_byte += self.window_width;
-                self.cursor.pos.row -= 1;
-                log(format!("DOWN|scroll|wrap: line_offset: {}, line_offset_byte: {}, curr_line_next_rows_len: {}",
-                            self.line_offset, self.line_offset_byte, self.curr_line_next_rows_len()).as_bytes());
-            } else {
-                self.line_offset += 1;
-                self.line_offset_byte = 0;
-                log(format!("DOWN|scroll|new-line: line_offset: {}, line_offset_byte: {}, self.cursor.line: {}",
-                            self.line_offset, self.line_offset_byte, self.cursor.line).as_bytes());
-                self.cursor.pos.row -= 1;
-            }
+    /// Returns the (line, row) pair of the row before the given one, skipping
+    /// the lines that filters hide, or None if it's the first row shown.
+    fn prev_row(&self, (line, row): (usize, usize)) -> Option<(usize, usize)> {
+        if row > 0 {
+            Some((line, row - 1))
+        } else {
+            self.shown_line_before(line).map(|line| (line, self.line_n_rows(line) - 1))
+        }
+    }
+
+    /// Returns the (line, row) pair of the row under the cursor and the
+    /// cursor's column in that row, not counting the gutter.
+    fn cursor_row_col(&self) -> ((usize, usize), usize) {
+        let line = self.buffer.cursor.line;
+        let render = self.render_offset(line, self.buffer.cursor.byte);
+        let row = self.row_of_byte(line, render);
+        let col = self.row_prefix_width(line, row) + render - self.row_start(line, row);
+        ((line, row), col)
+    }
+
+    /// Moves the cursor down by one row, if possible.
+    fn cursor_down(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let (row, col) = self.cursor_row_col();
+        if let Some((line, row)) = self.next_row(row) {
+            self.set_cursor_on_row(line, row, col);
         }
     }
 
     /// Moves the cursor up by one row, if possible.
     fn cursor_up(&mut self) {
-        // Cursor may have reached the top of the window.
-        if self.cursor.pos.row == 0 {
-            self.scroll_up();
+        if self.buffer.lines.is_empty() {
+            return;
         }
+        let (row, col) = self.cursor_row_col();
+        if let Some((line, row)) = self.prev_row(row) {
+            self.set_cursor_on_row(line, row, col);
+        }
+    }
 
-        if self.cursor.byte >= self.window_width {
-            // Line is wrapped so we don't have to skip to the previous line,
-            // only the row.
-            if self.cursor.pos.row > 0 {
-                self.cursor.pos.row -= 1;
-            }
+    /// Places the cursor on the `row`th row of the line at `line_idx`, as
+    /// close to the column `col` as possible. The row might be shorter than
+    /// `col`, in which case the cursor goes to the row's end, as it does when
+    /// the cursor is at the end of line.
+    fn set_cursor_on_row(&mut self, line_idx: usize, row: usize, col: usize) {
+        let (start, end) = self.row_range(line_idx, row);
+        let col = col.saturating_sub(self.row_prefix_width(line_idx, row));
+        // Only on the line's last row may the cursor go past the last byte,
+        // otherwise it would be on the next row.
+        let last_col = if !self.has_row(line_idx, row + 1) {
+            end - start
+        } else {
+            (end - start).saturating_sub(1)
+        };
+        let col = if self.buffer.cursor.is_at_eol { last_col } else { cmp::min(col, last_col) };
+        self.buffer.cursor.line = line_idx;
+        self.buffer.cursor.byte = self.orig_offset(line_idx, start + col);
+    }
 
-            if self.cursor.is_at_eol {
-                // Get the total length of the previous rows and subtract one to get the last
-                // byte's offset in line of the previous row's last byte.
-                self.cursor.byte = (self.cursor.byte / self.window_width) * self.window_width - 1;
-                self.cursor.pos.col = self.cursor.byte % self.window_width;
-            } else {
-                self.cursor.byte -= self.window_width;
-            }
-        } else if self.cursor.line > 0 {
-            // Cursor is on the first row of this line, so go to the previous
-            // line.
-            self.cursor.line -= 1;
-            if self.cursor.pos.row > 0 {
-                self.cursor.pos.row -= 1;
-            }
-
-            // Previous line might be shorter than current cursor column
-            // position, in which case the cursor needs to be moved to its end,
-            // and it might be wrapping, in which case the cursor needs to be
-            // positioned on the last wrap of the line.
-            let line = &self.lines[self.cursor.line];
-            if line.is_empty() {
-                self.cursor.pos.col = 0;
-                self.cursor.byte = 0;
+    /// Moves the cursor back by a character, over all of its bytes.
+    fn cursor_left(&mut self) {
+        if self.buffer.cursor.byte > 0 {
+            let line = self.buffer.cursor.line;
+            let col = self.render_offset(line, self.buffer.cursor.byte);
+            self.buffer.cursor.byte = self.orig_offset(line, col.saturating_sub(1));
+            self.buffer.cursor.is_at_eol = false;
+        }
+    }
+
+    fn cursor_right(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let len = self.buffer.lines[self.buffer.cursor.line].orig.len();
+        if self.buffer.cursor.byte < len {
+            let line = self.buffer.cursor.line;
+            let col = self.render_offset(line, self.buffer.cursor.byte + 1);
+            self.buffer.cursor.byte = self.orig_offset(line, col);
+            self.buffer.cursor.is_at_eol = self.buffer.cursor.byte == len;
+        }
+    }
+
+    fn cursor_line_home(&mut self) {
+        self.buffer.cursor.byte = 0;
+        self.buffer.cursor.is_at_eol = false;
+    }
+
+    fn cursor_file_home(&mut self) {
+        self.buffer.cursor.line = self.first_shown_line();
+        self.cursor_line_home();
+    }
+
+    fn cursor_file_end(&mut self) {
+        self.buffer.cursor.line = self.last_shown_line();
+        self.cursor_line_end();
+    }
+
+    fn cursor_line_end(&mut self) {
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        self.buffer.cursor.byte = self.buffer.lines[self.buffer.cursor.line].orig.len();
+        self.buffer.cursor.is_at_eol = true;
+    }
+
+    /// Inserts `bytes` at the cursor and moves the cursor past them.
+    fn insert_bytes(&mut self, bytes: &[u8]) {
+        if !self.check_editable() {
+            return;
+        }
+        if self.buffer.lines.is_empty() {
+            self.buffer.lines.push(Line::new(vec![]));
+        }
+        let (line, byte) = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        self.record_change(line, 1, 1);
+        self.buffer.lines[line].orig.to_mut().splice(byte..byte, bytes.iter().cloned());
+        self.update_render_at(line, byte, 0, bytes.len());
+        self.buffer.cursor.byte += bytes.len();
+        self.buffer.is_dirty = true;
+    }
+
+    fn insert_tab(&mut self) {
+        if self.buffer.config.expand_tabs {
+            let tab_width = self.buffer.config.tab_width as usize;
+            let col = if self.buffer.lines.is_empty() {
+                0
             } else {
-                if line.len() <= self.window_width {
-                    let col = {
-                        if self.cursor.is_at_eol {
-                            line.len() - 1
-                        } else {
-                            cmp::min(line.len() - 1, self.cursor.pos.col)
-                        }
-                    };
-
-                    self.cursor.pos.col = col;
-                    self.cursor.byte = col;
-                } else {
-                    // Use integer truncation to first get the number of full
-                    // rows this line is broken up into.
-                    let last_row_first_byte = (line.len() / self.window_width) * self.window_width;
-                    let col = {
-                        let last_row_len = line.len() - last_row_first_byte;
-                        if self.cursor.is_at_eol {
-                            last_row_len - 1
-                        } else {
-                            cmp::min(last_row_len - 1, self.cursor.pos.col)
-                        }
-                    };
+                self.render_offset(self.buffer.cursor.line, self.buffer.cursor.byte)
+            };
+            self.insert_bytes(&vec![b' '; tab_width - col % tab_width]);
+        } else {
+            self.insert_bytes(b"\t");
+        }
+    }
+
+    /// Splits the line under the cursor in two at the cursor.
+    fn insert_newline(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        if self.buffer.lines.is_empty() {
+            self.buffer.lines.push(Line::new(vec![]));
+        }
+        let (line, byte) = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        self.record_change(line, 1, 2);
+        let orig = self.buffer.lines[line].orig.to_mut().split_off(byte);
+        let n_moved = orig.len();
+        self.buffer.lines.insert(line + 1, Line::new(orig));
+        self.update_render_at(line, byte, n_moved, 0);
+        self.update_render(line + 1);
+        self.buffer.cursor.line += 1;
+        self.cursor_line_home();
+        self.buffer.is_dirty = true;
+    }
+
+    /// Deletes the character before the cursor, or if the cursor is at the start of
+    /// the line, joins the line with the previous one.
+    fn delete_back(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let (line, byte) = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        if byte > 0 {
+            let start = self.orig_offset(line, self.render_offset(line, byte).saturating_sub(1));
+            self.record_change(line, 1, 1);
+            self.buffer.lines[line].orig.to_mut().drain(start..byte);
+            self.update_render_at(line, start, byte - start, 0);
+            self.buffer.cursor.byte = start;
+        } else if line > 0 {
+            self.record_change(line - 1, 2, 1);
+            let removed = self.buffer.lines.remove(line);
+            self.buffer.cursor.line -= 1;
+            self.buffer.cursor.byte = self.buffer.lines[line - 1].orig.len();
+            self.buffer.lines[line - 1].orig.to_mut().extend_from_slice(&removed.orig);
+            self.update_render_at(line - 1, self.buffer.cursor.byte, 0, removed.orig.len());
+        } else {
+            return;
+        }
+        self.buffer.cursor.is_at_eol = false;
+        self.buffer.is_dirty = true;
+    }
+
+    /// Deletes the character under the cursor, or if the cursor is at the end of
+    /// the line, joins the next line with this one.
+    fn delete_forward(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+        let (line, byte) = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        if byte < self.buffer.lines[line].orig.len() {
+            let end = self.orig_offset(line, self.render_offset(line, byte + 1));
+            self.record_change(line, 1, 1);
+            self.buffer.lines[line].orig.to_mut().drain(byte..end);
+            self.update_render_at(line, byte, end - byte, 0);
+        } else if line + 1 < self.buffer.lines.len() {
+            self.record_change(line, 2, 1);
+            let removed = self.buffer.lines.remove(line + 1);
+            self.buffer.lines[line].orig.to_mut().extend_from_slice(&removed.orig);
+            self.update_render_at(line, byte, 0, removed.orig.len());
+        } else {
+            return;
+        }
+        self.buffer.is_dirty = true;
+    }
+
+    /// Returns the start and (exclusive) end of the selected text, if any.
+    fn selection(&self) -> Option<(TextPos, TextPos)> {
+        if self.buffer.config.profile == Profile::Vi {
+            self.vi_selection()
+        } else {
+            self.region()
+        }
+    }
 
-                    self.cursor.byte = last_row_first_byte + col;
-                    self.cursor.pos.col = col;
+    fn byte_at(&self, (line, byte): TextPos) -> Option<u8> {
+        self.buffer.lines[line].orig.get(byte).cloned()
+    }
+
+    /// Returns the position after `pos`, counting the end of each line as a
+    /// position.
+    fn next_pos(&self, (line, byte): TextPos) -> Option<TextPos> {
+        if byte < self.buffer.lines[line].orig.len() {
+            Some((line, byte + 1))
+        } else if line + 1 < self.buffer.lines.len() {
+            Some((line + 1, 0))
+        } else {
+            None
+        }
+    }
+
+    fn prev_pos(&self, (line, byte): TextPos) -> Option<TextPos> {
+        if byte > 0 {
+            Some((line, byte - 1))
+        } else if line > 0 {
+            Some((line - 1, self.buffer.lines[line - 1].orig.len()))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the text between two positions, with lines joined by `\n`.
+    fn text_range(&self, start: TextPos, end: TextPos) -> Vec<u8> {
+        if start.0 == end.0 {
+            return self.buffer.lines[start.0].orig[start.1..end.1].to_vec();
+        }
+        let mut text = self.buffer.lines[start.0].orig[start.1..].to_vec();
+        for line in &self.buffer.lines[start.0 + 1..end.0] {
+            text.push(b'\n');
+            text.extend_from_slice(&line.orig);
+        }
+        text.push(b'\n');
+        text.extend(&self.buffer.lines[end.0].orig[..end.1]);
+        text
+    }
+
+    /// Deletes the text between two positions and moves the cursor to where
+    /// it was.
+    fn delete_range(&mut self, start: TextPos, end: TextPos) {
+        if !self.check_editable() {
+            return;
+        }
+        self.record_change(start.0, end.0 - start.0 + 1, 1);
+        let tail = self.buffer.lines[end.0].orig[end.1..].to_vec();
+        let (n_removed, n_inserted) = if start.0 == end.0 {
+            (end.1 - start.1, 0)
+        } else {
+            (self.buffer.lines[start.0].orig.len() - start.1, tail.len())
+        };
+        let orig = self.buffer.lines[start.0].orig.to_mut();
+        orig.truncate(start.1);
+        orig.extend(tail);
+        self.buffer.lines.drain(start.0 + 1..end.0 + 1);
+        self.update_render_at(start.0, start.1, n_removed, n_inserted);
+        self.buffer.cursor.line = start.0;
+        self.buffer.cursor.byte = start.1;
+        self.buffer.cursor.is_at_eol = false;
+        self.buffer.is_dirty = true;
+    }
+
+    /// Inserts text in which lines are separated by `\n` at the cursor and
+    /// moves the cursor past it.
+    fn insert_text(&mut self, text: &[u8]) {
+        if !self.check_editable() {
+            return;
+        }
+        for (i, part) in text.split(|b| *b == b'\n').enumerate() {
+            if i > 0 {
+                self.insert_newline();
+            }
+            self.insert_bytes(part);
+        }
+    }
+
+    fn save(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        // A scratch buffer has yet to be given a path.
+        if self.buffer.path.as_os_str().is_empty() {
+            self.save_as();
+            return;
+        }
+        if self.buffer.config.trim_trailing_whitespace {
+            for line_idx in 0..self.buffer.lines.len() {
+                let orig = &self.buffer.lines[line_idx].orig;
+                let len = orig.iter().rposition(|b| *b != b' ' && *b != b'\t').map_or(0, |i| i + 1);
+                if len < orig.len() {
+                    self.record_change(line_idx, 1, 1);
+                    self.buffer.lines[line_idx].orig.to_mut().truncate(len);
+                    self.update_render(line_idx);
                 }
             }
         }
+        // A file that ends with a new line has an empty last line.
+        let ends_with_newline = self.buffer.lines.len() > 1 && self.buffer.lines.last().unwrap().orig.is_empty();
+        match self.buffer.config.insert_final_newline {
+            Some(true) if !ends_with_newline => {
+                let last = self.buffer.lines.len();
+                self.record_change(last, 0, 1);
+                self.buffer.lines.push(Line::new(vec![]));
+                self.update_render(last);
+            }
+            Some(false) if ends_with_newline => {
+                let last = self.buffer.lines.len() - 1;
+                self.record_change(last, 1, 0);
+                self.buffer.lines.pop();
+            }
+            _ => {}
+        }
+        // Either of the above may have pulled the text from under the cursor.
+        self.buffer.cursor.line = cmp::min(self.buffer.cursor.line, self.buffer.lines.len().saturating_sub(1));
+        if let Some(line) = self.buffer.lines.get(self.buffer.cursor.line) {
+            self.buffer.cursor.byte = cmp::min(self.buffer.cursor.byte, line.orig.len());
+        }
+
+        // What the changes replaced may be pieces of the file that is about to
+        // be overwritten or replaced.
+        if self.buffer.is_large {
+            self.buffer.undo.own_text();
+        }
+        let line_ending = self.buffer.config.end_of_line.unwrap_or(self.buffer.line_ending).as_bytes();
+        let result = if self.buffer.is_large {
+            self.save_large(line_ending)
+        } else {
+            OpenOptions::new().write(true).create(true).truncate(true).open(&self.buffer.path)
+                .and_then(|file| self.write_lines(&file, line_ending))
+        };
+        match result {
+            Ok(len) => {
+                self.buffer.is_dirty = false;
+                self.buffer.is_new = false;
+                self.new_status_msg(&format!("{} bytes written to disk", len), Duration::from_secs(3));
+            }
+            Err(e) => self.new_status_msg(&format!("Can't save! I/O error: {}", e), Duration::from_secs(5)),
+        }
     }
 
-    /// Shifts the window up by one row, but does not affect the cursor position.
-    fn scroll_up(&mut self) {
-        // The top row may be part of a wrapped line, so need to check if we
-        // need to advance to the previous line or just adjust the byte offset
-        // from which to show the line.
-        if self.line_offset_byte >= self.window_width {
-            self.line_offset_byte -= self.window_width;
-            //self.cursor.pos.row += 1;
-        } else if self.line_offset > 0 {
-            self.line_offset -= 1;
-            //self.cursor.pos.row += 1;
-            // If the previous line is wrapped, it must not be drawn from its first byte.
-            let line = &self.lines[self.line_offset];
-            if line.len() > self.window_width {
-                self.line_offset_byte = (line.len() / self.window_width) * self.window_width;
-            } else {
-                self.line_offset_byte = 0;
+    /// Writes the lines of the buffer to `file`, a line at a time, with
+    /// `line_ending` between them, and returns how many bytes that took.
+    fn write_lines(&self, file: &File, line_ending: &[u8]) -> io::Result<usize> {
+        let mut writer = io::BufWriter::new(file);
+        let mut len = 0;
+        for (i, line) in self.buffer.lines.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(line_ending)?;
+                len += line_ending.len();
             }
+            writer.write_all(&line.orig)?;
+            len += line.orig.len();
         }
+        writer.flush()?;
+        Ok(len)
     }
 
-    fn cursor_left(&mut self) {
-        if self.cursor.pos.col > 0 {
-            if self.cursor.pos.col == self.curr_last_pos_row_offset() {
-                self.cursor.is_at_eol = false;
+    /// Runs the command named `name`. These are the names that keys are bound
+    /// to in the config.
+    fn run_command(&mut self, name: &str) {
+        match command::find(name) {
+            Some(command) => {
+                (command.run)(self);
+                self.last_command = command.name.to_string();
             }
-            self.cursor.pos.col -= 1;
-            self.cursor.byte -= 1;
+            None => self.new_status_msg(&format!("Unknown command: {}", name), Duration::from_secs(3)),
         }
     }
 
-    fn cursor_right(&mut self) {
-        if self.cursor.byte + 1 < self.lines[self.cursor.line].len()
-            && self.cursor.pos.col + 1 < self.window_width {
-            self.cursor.pos.col += 1;
-            self.cursor.byte += 1;
-            if self.cursor.pos.col == self.curr_last_pos_row_offset() {
-                self.cursor.is_at_eol = true;
+    /// Opens the command palette, which lists the commands that match what is
+    /// typed along with the keys they are bound to, the most recently run
+    /// ones first.
+    fn open_command_palette(&mut self) {
+        let mut names: Vec<String> = self.history.entries("command")
+            .into_iter()
+            .rev()
+            .filter(|name| command::find(name).is_some())
+            .collect();
+        for command in command::COMMANDS {
+            if !names.iter().any(|n| n == command.name) {
+                names.push(command.name.to_string());
             }
         }
+        let prompt = Prompt::new("Command: ", "command", |editor: &mut Editor, name: String| {
+            editor.run_command(name.trim());
+        });
+        self.open_prompt(prompt.with_menu(names));
+    }
+
+    fn prompt_goto_line(&mut self) {
+        let prompt = Prompt::new("Go to line: ", "goto-line", |editor: &mut Editor, line: String| {
+            match line.trim().parse::<usize>() {
+                Ok(n) if n > 0 => editor.goto_line(n - 1),
+                _ => editor.new_status_msg(&format!("Not a line number: {}", line), Duration::from_secs(3)),
+            }
+        });
+        self.open_prompt(prompt);
+    }
+
+    fn prompt_open_file(&mut self) {
+        let prompt = Prompt::new("Open: ", "path", |editor: &mut Editor, path: String| {
+            editor.open_buffer(&prompt::expand_home(path.trim()));
+        });
+        self.open_prompt(prompt.with_completer(PathCompleter));
+    }
+
+    /// Reports what the next key, or chord, is bound to.
+    fn describe_key(&mut self) {
+        self.is_describing_key = true;
+    }
+
+    fn toggle_line_numbers(&mut self) {
+        self.buffer.config.line_numbers = !self.buffer.config.line_numbers;
     }
 
-    /// Returns the position of the last byte in the row under the cursor.
-    fn curr_last_pos_row_offset(&self) -> usize {
-        if self.lines.is_empty() {
-            return 0;
+    /// Reports which syntax highlights the file and where it comes from,
+    /// along with any errors in its definition and the names of other
+    /// definitions with errors, which may be why they don't apply.
+    fn describe_syntax(&mut self) {
+        let mut msg = match self.buffer.config.filetype {
+            None => "No syntax applies to this file".to_string(),
+            Some(ref name) => match self.buffer.config.find_syntax(name) {
+                Some(def) if def.errors.is_empty() => {
+                    format!("Syntax: {} ({})", name, def.path.display())
+                }
+                Some(def) => format!(
+                    "Syntax: {} ({}) has errors: {}",
+                    name, def.path.display(), def.errors.join("; ")
+                ),
+                None if self.buffer.syntax.is_some() => format!("Syntax: {} (built-in)", name),
+                None => format!("File type {} has no syntax", name),
+            },
+        };
+        let others: Vec<String> = self.buffer.config.syntaxes.iter()
+            .filter(|def| !def.errors.is_empty() && Some(&def.name) != self.buffer.config.filetype.as_ref())
+            .map(|def| def.path.file_name().unwrap_or_default().to_string_lossy().to_string())
+            .collect();
+        if !others.is_empty() {
+            msg.push_str(&format!(" | also with errors: {}", others.join(", ")));
         }
-        let line = &self.lines[self.cursor.line];
-        if line.is_empty() {
-            0
+        self.new_status_msg(&msg, Duration::from_secs(10));
+    }
+
+    /// Moves the cursor to the start of the line at `line_idx`, or of the last
+    /// line if there are fewer lines.
+    fn goto_line(&mut self, line_idx: usize) {
+        self.buffer.cursor.line = cmp::min(line_idx, self.buffer.lines.len().saturating_sub(1));
+        self.cursor_line_home();
+    }
+
+    /// Rereads the config file. The current config is kept if the file can't
+    /// be parsed.
+    fn reload_config(&mut self) {
+        match Config::load(self.config_path.as_deref()) {
+            Ok(mut config) => {
+                config.command_line = self.base_config.command_line.clone();
+                let theme_error = config.theme_error();
+                self.configure(config);
+                match theme_error {
+                    Some(e) => self.new_status_msg(&format!("Config reloaded. {}", e), Duration::from_secs(10)),
+                    None => self.new_status_msg("Config reloaded", Duration::from_secs(3)),
+                }
+            }
+            Err(e) => self.new_status_msg(&e, Duration::from_secs(10)),
+        }
+    }
+
+    /// Applies `config`, deriving the settings of each open file from it.
+    fn configure(&mut self, config: Config) {
+        for (register, keys) in &config.macros {
+            macros::set(&mut self.macros, *register, keys.clone());
+        }
+        self.theme = config.theme();
+        self.color_depth = config.color_depth.unwrap_or_else(ColorDepth::detect);
+        // Have clicks reported, in the SGR encoding, which isn't limited to
+        // 223 rows and columns.
+        self.defer_esc_seq(if config.mouse { "?1000h" } else { "?1000l" });
+        self.defer_esc_seq("?1006h");
+        self.base_config = config;
+        for i in 0..self.buffers.len() {
+            if i != self.current {
+                mem::swap(&mut self.buffer, &mut self.buffers[i]);
+                self.configure_buffer();
+                mem::swap(&mut self.buffer, &mut self.buffers[i]);
+            }
+        }
+        self.configure_buffer();
+    }
+
+    /// Derives the settings for the file being edited from the config, which
+    /// depend on the file's path and the modelines in its first and last
+    /// five lines, and re-renders the lines as the tab width may have
+    /// changed.
+    fn configure_buffer(&mut self) {
+        let n_lines = self.buffer.lines.len();
+        let head: Vec<&[u8]> = self.buffer.lines.iter().take(5).map(|line| &line.orig[..]).collect();
+        let tail: Vec<&[u8]> = self.buffer.lines.iter()
+            .skip(cmp::max(n_lines, 10) - 5)
+            .map(|line| &line.orig[..])
+            .collect();
+        self.buffer.config = self.base_config.for_file(&self.buffer.path, &head, &tail);
+        self.buffer.syntax = match self.buffer.config.filetype {
+            Some(ref name) => match self.buffer.config.find_syntax(name) {
+                Some(def) => Some(def.syntax.clone()),
+                None => syntax::builtin(name),
+            },
+            None => None,
+        };
+        // Large files aren't highlighted.
+        if self.buffer.is_large {
+            self.buffer.syntax = None;
+        }
+        for line in &mut self.buffer.lines {
+            *line.rendered.get_mut() = None;
+        }
+        self.buffer.hl_frontier = 0;
+    }
+
+    /// Comments out the line under the cursor, or uncomments it if it's
+    /// already commented out, using the file's line comment token, or if
+    /// there is none, its block comment tokens.
+    fn toggle_comment(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        let (start, end) = match (&self.buffer.config.line_comment, &self.buffer.config.block_comment) {
+            (Some(token), _) => (token.clone(), String::new()),
+            (None, Some((start, end))) => (start.clone(), end.clone()),
+            (None, None) => {
+                self.new_status_msg("No comment syntax for this file type", Duration::from_secs(3));
+                return;
+            }
+        };
+        if self.buffer.lines.is_empty() {
+            return;
+        }
+
+        let line_idx = self.buffer.cursor.line;
+        let orig = &self.buffer.lines[line_idx].orig;
+        let indent = orig.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+        let text = &orig[indent..];
+        let (start, end) = (start.as_bytes(), end.as_bytes());
+
+        let mut new = orig[..indent].to_vec();
+        let n_removed_before_cursor;
+        if text.starts_with(start) && text.ends_with(end) && text.len() >= start.len() + end.len() {
+            // Remove the tokens, along with the spaces we'd have put around them.
+            let mut inner = &text[start.len()..text.len() - end.len()];
+            let n_removed = start.len() + if inner.starts_with(b" ") { 1 } else { 0 };
+            inner = inner.strip_prefix(b" ").unwrap_or(inner);
+            if !end.is_empty() {
+                inner = inner.strip_suffix(b" ").unwrap_or(inner);
+            }
+            new.extend(inner);
+            n_removed_before_cursor = n_removed as isize;
         } else {
-            assert!(self.window_width > 0);
-            cmp::min(line.len(), self.window_width) - 1
+            new.extend(start);
+            new.push(b' ');
+            new.extend(text);
+            if !end.is_empty() {
+                new.push(b' ');
+                new.extend(end);
+            }
+            n_removed_before_cursor = -(start.len() as isize + 1);
+        }
+
+        let new_len = new.len();
+        self.record_change(line_idx, 1, 1);
+        self.buffer.lines[line_idx].orig = Text::from(new);
+        self.update_render(line_idx);
+        if self.buffer.cursor.byte > indent {
+            let byte = self.buffer.cursor.byte as isize - n_removed_before_cursor;
+            self.buffer.cursor.byte = cmp::min(cmp::max(byte, indent as isize) as usize, new_len);
         }
+        self.buffer.is_dirty = true;
     }
 
-    /// Similary to curr_last_pos_row_offset, but returns the that position's absolute
-    /// offset from the line's start.
-    fn curr_last_pos_line_offset(&self) -> usize {
-        self.cursor.byte + self.curr_last_pos_row_offset() - self.cursor.pos.col
+    /// Returns the width of the line number gutter on the left of the window,
+    /// including the space that separates it from the text, or 0 if line
+    /// numbers are off.
+    fn gutter_width(&self) -> usize {
+        if self.buffer.config.line_numbers {
+            self.buffer.lines.len().to_string().len() + 1
+        } else {
+            0
+        }
     }
 
-    /// Returns the total number of bytes of all rows in this line after the row
-    /// under the cursor.
-    fn curr_line_next_rows_len(&self) -> usize {
-        let line_len = self.lines[self.cursor.line].len();
-        let row_last_byte = self.curr_last_pos_line_offset();
-        if row_last_byte + 1 >= line_len { 0 } else { line_len - row_last_byte - 1 }
+    /// Returns the number of columns in the window available to text.
+    fn text_width(&self) -> usize {
+        self.window_width.saturating_sub(self.gutter_width())
+    }
+
+    /// Adjusts the window's line, row and column offsets so that the cursor is
+    /// visible, and then derives the cursor's position in the window from them.
+    /// Cursor movement only ever changes the line and byte under the cursor,
+    /// so this must be called before each redraw.
+    fn scroll(&mut self) {
+        if self.buffer.lines.is_empty() || self.window_height == 0 {
+            self.buffer.cursor.pos = Pos { row: 0, col: 0 };
+            return;
+        }
+
+        let (cursor_row, col) = self.cursor_row_col();
+
+        // The layout may have changed since the last redraw (e.g. the window
+        // was resized), so the row the window starts at may no longer exist.
+        // The line the window started at may have been hidden since.
+        if !self.is_line_shown(self.buffer.line_offset) {
+            let line = self.shown_line_after(self.buffer.line_offset).unwrap_or(self.buffer.cursor.line);
+            self.buffer.line_offset = cmp::min(line, self.buffer.cursor.line);
+            self.buffer.line_offset_row = 0;
+        }
+        if !self.has_row(self.buffer.line_offset, self.buffer.line_offset_row) {
+            self.buffer.line_offset_row = self.line_n_rows(self.buffer.line_offset) - 1;
+        }
+
+        // Cursor is above the window.
+        if cursor_row < (self.buffer.line_offset, self.buffer.line_offset_row) {
+            self.buffer.line_offset = cursor_row.0;
+            self.buffer.line_offset_row = cursor_row.1;
+        }
+
+        // Count the rows between the top of the window and the cursor, but
+        // don't bother counting past the bottom of the window.
+        let mut top = (self.buffer.line_offset, self.buffer.line_offset_row);
+        let mut n_rows_above_cursor = 0;
+        while top < cursor_row && n_rows_above_cursor < self.window_height {
+            top = self.next_row(top).unwrap();
+            n_rows_above_cursor += 1;
+        }
+
+        // Cursor is below the window, so walk back from it until it's on the
+        // window's last row.
+        if n_rows_above_cursor == self.window_height {
+            let mut top = cursor_row;
+            for _ in 1..self.window_height {
+                top = self.prev_row(top).unwrap();
+            }
+            self.buffer.line_offset = top.0;
+            self.buffer.line_offset_row = top.1;
+            n_rows_above_cursor = self.window_height - 1;
+        }
+
+        self.buffer.cursor.pos.row = n_rows_above_cursor;
+
+        if self.buffer.config.wrap_mode == WrapMode::NoWrap {
+            // Keep at least this many columns between the cursor and the
+            // edges of the window, unless the window is too narrow for it.
+            let width = self.text_width();
+            let margin = cmp::min(self.buffer.config.h_scroll_margin, width.saturating_sub(1) / 2);
+            if col < self.buffer.col_offset + margin {
+                self.buffer.col_offset = col.saturating_sub(margin);
+            } else if col + margin >= self.buffer.col_offset + width {
+                self.buffer.col_offset = col + margin + 1 - width;
+            }
+        } else {
+            self.buffer.col_offset = 0;
+        }
+        self.buffer.cursor.pos.col = self.gutter_width() + col - self.buffer.col_offset;
     }
 
     /// This function is called after encountering a \x1b escape character from
@@ -483,21 +1410,35 @@ impl Editor {
     /// an optional Key value, or None, if no valid (or implemented) sequence
     /// was deteced.
     fn read_esc_seq_to_key(&mut self) -> Option<Key> {
-        let mut buf: [u8; 3] = [0; 3];
-        if let Err(_) = io::stdin().read_exact(&mut buf[..2]) {
+        let mut buf: [u8; 5] = [0; 5];
+        // Terminals send escape sequences in one go, so if nothing follows
+        // the Esc right away, it was pressed by itself.
+        let mut fds = [PollFd::new(self.tty.as_raw_fd(), EventFlags::POLLIN)];
+        match poll(&mut fds, ESC_TIMEOUT_MS) {
+            Ok(0) => return Some(Key::Escape),
+            Ok(_) => {}
+            Err(_) => return None,
+        }
+        if self.read_tty(&mut buf[..1]).is_err() {
+            return None;
+        }
+        if buf[0] != b'[' && buf[0] != b'O' {
+            return Some(Key::Alt(buf[0]));
+        }
+        if self.read_tty(&mut buf[1..2]).is_err() {
             return None;
         }
 
         let c = buf[0] as char;
         if c == '[' {
             let c = buf[1] as char;
-            if c >= '0' && c <= '9' {
-                if let Err(_) = io::stdin().read_exact(&mut buf[2..3]) {
+            if c.is_ascii_digit() {
+                if self.read_tty(&mut buf[2..3]).is_err() {
                     return None;
                 }
 
                 let c = buf[2] as char;
-                return if c == '~' {
+                if c == '~' {
                     let c = buf[1] as char;
                     match c {
                         '1' | '7' => Some(Key::LineHome),
@@ -507,7 +1448,17 @@ impl Editor {
                         '6' => Some(Key::PageDown),
                         _ =>  None
                     }
-                } else { None };
+                } else if c == ';' {
+                    // A modifier follows, e.g. \x1b[1;5H for Ctrl-Home.
+                    if self.read_tty(&mut buf[3..5]).is_err() {
+                        return None;
+                    }
+                    match (buf[3] as char, buf[4] as char) {
+                        ('5', 'H') => Some(Key::FileHome),
+                        ('5', 'F') => Some(Key::FileEnd),
+                        _ => None
+                    }
+                } else { None }
             } else {
                 let c = buf[1] as char;
                 match c {
@@ -516,6 +1467,7 @@ impl Editor {
                     'C' => Some(Key::ArrowRight),
                     'D' => Some(Key::ArrowLeft),
                     'H' => Some(Key::LineHome),
+                    '<' => self.read_mouse_event(),
                     _ => None
                 }
             }
@@ -531,7 +1483,25 @@ impl Editor {
         }
     }
 
-    fn handle_input(&mut self, _c: char) {
+    /// Reads the rest of a mouse event in SGR encoding, e.g. \x1b[<0;12;3M
+    /// for pressing the left button at column 12 on row 3. Only such presses
+    /// are made into keys.
+    fn read_mouse_event(&mut self) -> Option<Key> {
+        let mut seq = String::new();
+        let mut buf: [u8; 1] = [0; 1];
+        // The numbers are short, so give up on anything longer.
+        while seq.len() < 16 {
+            self.read_tty(&mut buf).ok()?;
+            match buf[0] {
+                b'M' | b'm' => break,
+                b => seq.push(b as char),
+            }
+        }
+        let numbers: Vec<usize> = seq.split(';').filter_map(|n| n.parse().ok()).collect();
+        match numbers[..] {
+            [0, col, row] if buf[0] == b'M' && col > 0 && row > 0 => Some(Key::Click(Pos { row: row - 1, col: col - 1 })),
+            _ => None,
+        }
     }
 
     fn refresh_screen(&mut self) {
@@ -540,180 +1510,385 @@ impl Editor {
         self.update_window_size();
         // Hide cursor while redrawing to avoid glitching.
         self.hide_cursor();
-        self.move_cursor(Pos { row: 0, col: 0 });
-        // Append text to write buffer while clearing old data.
-        self.build_rows();
-        self.build_status_bar();
-        self.update_status_msg();
-        // (Rust giving me crap for directly passing self.cursor.pos.)
-        let cursor = self.cursor.pos;
-        // Move cursor back to its original position.
+        if self.has_tab_bar() {
+            self.build_tab_bar();
+        }
+        // Append text to write buffer while clearing old data. The focused
+        // window is drawn last, so that the sizes of its text area are left
+        // for the commands to go by.
+        let (rects, separators) = self.window_rects();
+        let mut focused = rects[0].1;
+        for (idx, rect) in rects {
+            if idx == self.focus {
+                focused = rect;
+            } else {
+                self.draw_unfocused_window(idx, rect);
+            }
+        }
+        self.draw_window(focused, true);
+        for separator in separators {
+            self.build_separator(separator);
+        }
+        let prompt_col = self.update_status_msg();
+        self.build_menu();
+        // Move cursor back to its original position, or into the prompt.
+        let cursor = match prompt_col {
+            Some(col) => Pos { row: self.screen_height - 1, col },
+            None => Pos {
+                row: focused.top + self.buffer.cursor.pos.row,
+                col: focused.left + self.buffer.cursor.pos.col,
+            },
+        };
         self.move_cursor(cursor);
         self.show_cursor();
-        self.defer_esc_seq("?25h");
+        if self.buffer.config.profile == Profile::Vi {
+            // Set the cursor's shape for the mode (DECSCUSR).
+            let shape = self.vi_cursor_shape();
+            self.defer_esc_seq(&format!("{} q", shape));
+        }
         self.flush_write_buf();
     }
 
-    fn line_orig_to_render(&self, line: &[u8]) -> Vec<u8> {
-        let mut render = vec![];
-        for (pos, b) in line.iter().enumerate() {
-            if *b as char == '\t' {
-                let mut i = pos + 1;
-                render.push(' ' as u8);
-                while i % self.config.tab_width as usize != 0 {
-                    render.push(' ' as u8);
-                    i += 1;
-                }
-            } else {
-                render.push(*b);
-            }
-        }
-        render
+    /// Draws the buffer being edited and its status bar into `rect`, scrolled
+    /// so that the cursor is visible.
+    fn draw_window(&mut self, rect: Rect, is_focused: bool) {
+        self.window_width = rect.width;
+        self.window_height = rect.height.saturating_sub(1);
+        self.scroll();
+        self.build_rows(rect);
+        self.build_status_bar(rect, is_focused);
     }
 
-    fn build_rows(&mut self) {
-        let mut n_rows_drawn = 0;
-        for line in self.lines.iter().skip(self.line_offset) {
-            if n_rows_drawn == self.window_height {
-                break;
-            }
-
-            // The line might be longer than the width of our window, so it needs
-            // to be split accross rows and wrapped. Count how many bytes are left in
-            // the row to draw.
-            let (mut n_bytes_left, mut offset) = {
-                if n_rows_drawn == 0 {
-                    // This is the first line to draw which may not be drawn
-                    // from its first byte if window begins after a wrap.
-                    (line.len() - self.line_offset_byte, self.line_offset_byte)
-                } else {
-                    (line.len(), 0)
-                }
-            };
+    fn build_rows(&mut self, rect: Rect) {
+        let mut next_row = if self.buffer.lines.is_empty() {
+            None
+        } else {
+            Some((self.buffer.line_offset, self.buffer.line_offset_row))
+        };
 
-            // It's an empty line.
-            if n_bytes_left == 0 {
-                // Clear row.
-                self.write_buf.extend("\x1b[K".as_bytes());
-                n_rows_drawn += 1;
-                if n_rows_drawn < self.window_height {
-                    self.write_buf.extend("\r\n".as_bytes());
-                } else {
-                    self.write_buf.extend(" ".as_bytes());
+        for i in 0..self.window_height {
+            self.move_cursor(Pos { row: rect.top + i, col: rect.left });
+            // Clearing the row fills it with the current background colour.
+            self.write_style(Element::Text(Highlight::Normal));
+            self.clear_cols(rect.width);
+            // There may not be enough text to fill all the rows of the window,
+            // so fill the rest with '~'s.
+            match next_row {
+                Some((line, row)) => {
+                    let gutter_width = self.gutter_width();
+                    if gutter_width > 0 {
+                        // Only number the first row of each line.
+                        let gutter = if row == 0 {
+                            format!("{:>1$} ", line + 1, gutter_width - 1)
+                        } else {
+                            " ".repeat(gutter_width)
+                        };
+                        self.write_style(Element::Gutter);
+                        self.write_buf.extend(gutter.as_bytes());
+                        self.write_style(Element::Text(Highlight::Normal));
+                    }
+                    match self.buffer.config.wrap_mode {
+                        WrapMode::NoWrap => self.build_unwrapped_row(line),
+                        WrapMode::Char | WrapMode::Word => {
+                            if self.row_prefix_width(line, row) > 0 {
+                                let prefix = self.wrap_prefix(line);
+                                self.write_buf.extend(prefix.as_bytes());
+                            }
+                            let (start, end) = self.row_range(line, row);
+                            self.build_highlighted(line, start, end);
+                        }
+                    }
+                    next_row = self.next_row((line, row));
                 }
-            } else {
-                // Split up line into rows.
-                while n_bytes_left > 0 && n_rows_drawn < self.window_height {
-                    let end = offset + cmp::min(self.window_width, n_bytes_left);
-                    let row = &line.render[offset..end];
-
-                    assert!(row.len() > 0);
-                    //log(format!("bytes left: {}, offset: {}, row.len: {}",
-                            //n_bytes_left, offset, row.len()).as_bytes());
-
-                    // Clear row.
-                    // TODO we should use self.clear_row but can't due to ownership
-                    self.write_buf.extend("\x1b[K".as_bytes());
-                    self.write_buf.extend(row);
-                    self.write_buf.extend("\r\n".as_bytes());
-
-                    offset += row.len();
-                    n_bytes_left -= row.len();
-                    n_rows_drawn += 1;
+                None => {
+                    self.write_style(Element::Filler);
+                    self.write_buf.extend(b"~");
                 }
             }
         }
+    }
 
-        log(format!("window height: {}, rows drawn: {}",
-                    self.window_height, n_rows_drawn).as_bytes());
-        // There may not be enough text to fill all the rows of the window, so
-        // fill the rest with '~'s.
-        let n_empty_rows = self.window_height - n_rows_drawn;
-        if n_empty_rows > 0 {
-            for _ in 1..(n_empty_rows) {
-                self.write_buf.extend("~\r\n".as_bytes());
-                self.clear_row();
-            }
+    /// Draws the part of the line at `line_idx` that is visible through the
+    /// window's horizontal scroll, marking the edges past which the line
+    /// continues with '«' and '»'.
+    fn build_unwrapped_row(&mut self, line_idx: usize) {
+        let col_offset = self.buffer.col_offset;
+        let width = self.text_width();
+        // Only as much of the line is looked at as tells whether it goes on
+        // past the window.
+        let line_width = self.line_width_upto(line_idx, col_offset + width + 1);
+        let start = cmp::min(col_offset, line_width);
+        let end = cmp::min(col_offset + width, line_width);
+
+        let continues_left = col_offset > 0 && line_width > 0;
+        let continues_right = line_width > col_offset + width;
+        // The markers take the place of the first and last visible columns.
+        let hi = if continues_right { end - 1 } else { end };
+        let lo = if continues_left { cmp::min(start + 1, hi) } else { start };
+
+        if continues_left {
+            self.write_buf.extend("«".as_bytes());
+        }
+        self.build_highlighted(line_idx, lo, hi);
+        if continues_right {
+            self.write_buf.extend("»".as_bytes());
         }
     }
 
-    fn build_status_bar(&mut self) {
-        // TODO also count escape sequences
-        self.write_buf.reserve(self.window_width);
-
-        // Invert colors.
-        self.defer_esc_seq("1m");
-        // Make text bold.
-        self.defer_esc_seq("7m");
-
-        let sep = " | ";
-        let line_count = {
-            let mut buf = self.lines.len().to_string();
-            if self.lines.len() == 1 {
-                buf += " line";
-            } else {
-                buf += " lines";
-            }
-            buf
+    /// Draws the bytes between `start` and `end` of the rendered line at
+    /// `line_idx`, switching styles wherever the highlighting changes. The
+    /// style of normal text must be in effect before and is after.
+    fn build_highlighted(&mut self, line_idx: usize, start: usize, end: usize) {
+        let cols: Vec<(usize, Vec<u8>)> = self.render_cols(line_idx, start, end).into_iter()
+            .map(|(byte, drawn)| (byte, drawn.to_vec()))
+            .collect();
+        // The part of the original line that is selected.
+        let (sel_start, sel_end) = match self.selection() {
+            Some(((l1, b1), (l2, b2))) if l1 <= line_idx && line_idx <= l2 => (
+                if line_idx == l1 { b1 } else { 0 },
+                if line_idx == l2 { b2 } else { usize::MAX },
+            ),
+            _ => (0, 0),
         };
-        let cursor_pos = {
-            let mut buf = self.cursor.line.to_string();
-            buf += ":";
-            buf += &self.cursor.pos.col.to_string()[..];
-            buf
+        // The part of the original line that a search is at.
+        let found = self.isearch.as_ref().and_then(|isearch| isearch.found).or_else(|| self.search_match());
+        let (match_start, match_end) = match found {
+            Some(((line, b1), (_, b2))) if line == line_idx => (b1, b2),
+            _ => (0, 0),
         };
-        let (n_used_bytes, n_path_bytes) = {
-            // NOTE: count separators as well: one separator between path and
-            // cursor position, and one between the latter and line count.
-            let mut n_used_bytes = cursor_pos.len() + line_count.len() + sep.len() * 1;
-            let n_path_bytes = cmp::min(self.window_width - n_used_bytes, self.path.len());
-            n_used_bytes += n_path_bytes;
-            (n_used_bytes, n_path_bytes)
+        let (first, last) = match (cols.first(), cols.last()) {
+            (Some(&(first, _)), Some(&(last, _))) => (first, last + 1),
+            _ => (0, 0),
         };
+        let hl = self.line_hl(line_idx, first, last);
+        let mut current = Element::Text(Highlight::Normal);
+        for (byte, drawn) in cols {
+            let element = if match_start <= byte && byte < match_end {
+                Element::SearchMatch
+            } else if sel_start <= byte && byte < sel_end {
+                Element::Selection
+            } else {
+                Element::Text(hl[byte - first])
+            };
+            if element != current {
+                self.write_style(element);
+                current = element;
+            }
+            self.write_buf.extend(drawn);
+        }
+        if current != Element::Text(Highlight::Normal) {
+            self.write_style(Element::Text(Highlight::Normal));
+        }
+    }
 
-        self.write_buf.extend(self.path.as_bytes().iter().take(n_path_bytes));
-        // Fill up empty space.
-        //self.write_buf.extend(std::iter::repeat(' ' as u8).take(self.window_width - n_used_bytes));
-        for _ in 0..self.window_width - n_used_bytes {
-            self.write_buf.push(' ' as u8);
+    /// Switches to the theme's style for `element`.
+    fn write_style(&mut self, element: Element) {
+        let esc_seq = self.theme.style(element).esc_seq(self.color_depth);
+        self.write_buf.extend(esc_seq.as_bytes());
+    }
+
+    /// Draws the status bar as described by the `status_format` setting on
+    /// the bottom row of `rect`.
+    fn build_status_bar(&mut self, rect: Rect, is_focused: bool) {
+        let mut left = String::new();
+        let mut right = String::new();
+        let mut is_right = false;
+        for piece in &self.buffer.config.status_format {
+            let text = match *piece {
+                status::Piece::Text(ref text) => text.clone(),
+                status::Piece::Segment(segment) => self.status_segment(segment),
+                status::Piece::Align => {
+                    is_right = true;
+                    continue;
+                }
+            };
+            if is_right { right.push_str(&text) } else { left.push_str(&text) }
         }
-        self.write_buf.extend(cursor_pos.as_bytes().iter());
-        self.write_buf.extend(sep.as_bytes().iter());
-        self.write_buf.extend(line_count.as_bytes().iter());
+        let status = status::layout(&left, &right, rect.width);
+
+        self.move_cursor(Pos { row: rect.top + self.window_height, col: rect.left });
+        self.write_style(if is_focused { Element::StatusBar } else { Element::InactiveStatusBar });
+        self.write_buf.extend(status.as_bytes());
+        self.defer_esc_seq("m");
+    }
 
-        log(format!("status bar buffer: {:?}", &self.write_buf[self.write_buf.len() - self.window_width..]).as_bytes());
-        // Revert invert colors.
+    /// Draws the column between two windows side by side.
+    fn build_separator(&mut self, rect: Rect) {
+        self.write_style(Element::InactiveStatusBar);
+        for row in rect.top..rect.top + rect.height {
+            self.move_cursor(Pos { row, col: rect.left });
+            self.write_buf.extend("│".as_bytes());
+        }
         self.defer_esc_seq("m");
     }
 
+    fn status_segment(&self, segment: status::Segment) -> String {
+        use status::Segment;
+        let Cursor { line, byte, .. } = self.buffer.cursor;
+        match segment {
+            Segment::Path => display_path(&self.buffer.path),
+            Segment::AbsPath => editorconfig::absolute(&self.buffer.path).display().to_string(),
+            Segment::Name => self.buffer.path.file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            Segment::Modified => {
+                let readonly = if self.buffer.is_readonly { " [RO]" } else { "" };
+                let new = if self.buffer.is_new { " [New File]" } else { "" };
+                let filtered = if self.buffer.filters.is_empty() { "" } else { " [Filtered]" };
+                let modified = if self.buffer.is_dirty { "[+]" } else { "" };
+                format!("{}{}{}{}", readonly, new, filtered, modified)
+            }
+            Segment::FileType => self.buffer.config.filetype.clone().unwrap_or_else(|| "text".to_string()),
+            Segment::Encoding => self.buffer.encoding.to_string(),
+            Segment::LineEnding => match self.buffer.config.end_of_line.unwrap_or(self.buffer.line_ending) {
+                LineEnding::Lf => "LF",
+                LineEnding::CrLf => "CRLF",
+                LineEnding::Cr => "CR",
+            }.to_string(),
+            Segment::Line => (line + 1).to_string(),
+            Segment::Col if line < self.buffer.lines.len() => (self.char_offset(line, byte) + 1).to_string(),
+            Segment::Col => "1".to_string(),
+            Segment::Byte => (byte + 1).to_string(),
+            Segment::Percent => {
+                let last_line = cmp::max(self.buffer.lines.len(), 2) - 1;
+                format!("{}%", line * 100 / last_line)
+            }
+            Segment::Lines => match self.buffer.lines.len() {
+                1 => "1 line".to_string(),
+                n => format!("{} lines", n),
+            },
+            Segment::Selection => match self.selection() {
+                Some((start, end)) => format!("{} selected", self.text_range(start, end).len()),
+                None => String::new(),
+            },
+            Segment::Mode => match self.recording {
+                Some(ref recording) if self.vi_mode_name().is_empty() => format!("recording @{}", recording.register),
+                Some(ref recording) => format!("{} recording @{}", self.vi_mode_name(), recording.register),
+                None => self.vi_mode_name().to_string(),
+            },
+        }
+    }
+
     fn new_status_msg(&mut self, msg: &str, timeout: Duration) {
         //let len = cmp::min(self.window_width, msg.len());
         //self.write_buf.extend(msg.as_bytes().iter().take(len));
         self.status_msg = StatusMsg {
             data: msg.to_string(),
             timestamp: Instant::now(),
-            timeout: timeout,
+            timeout,
         };
-        self.write_status_msg();
     }
 
-    fn update_status_msg(&mut self) {
+    /// Draws the message bar, returning the column of the cursor if a prompt
+    /// is open in it.
+    fn update_status_msg(&mut self) -> Option<usize> {
         let now = Instant::now();
-        if now.duration_since(self.status_msg.timestamp) <= self.status_msg.timeout {
-            self.write_status_msg();
-        } else {
+        if now.duration_since(self.status_msg.timestamp) > self.status_msg.timeout {
             self.status_msg.data.clear();
         }
+        // Draw the message bar even if empty, to clear the previous message.
+        self.write_status_msg()
+    }
+
+    fn write_status_msg(&mut self) -> Option<usize> {
+        self.move_cursor(Pos { row: self.screen_height - 1, col: 0 });
+        self.write_style(Element::MessageBar);
+        self.clear_row();
+        let mut prompt_col = None;
+        if self.prompt.is_some() {
+            prompt_col = self.build_prompt();
+        } else if !self.vi.keys.is_empty() {
+            // Show the keys of an unfinished vi command.
+            let msg: String = self.vi.keys.concat().chars().take(self.screen_width).collect();
+            self.write_buf.extend(msg.as_bytes());
+        } else if let Some(msg) = self.isearch_msg() {
+            let msg: String = msg.chars().take(self.screen_width).collect();
+            self.write_buf.extend(msg.as_bytes());
+        } else if self.is_describing_key || !self.pending_keys.is_empty() {
+            // Show the keys of an unfinished chord, so that it's clear the
+            // editor is waiting for the rest.
+            let mut msg = if self.is_describing_key { "Describe key: ".to_string() } else { String::new() };
+            if !self.pending_keys.is_empty() {
+                msg.push_str(&format!("{}-", self.pending_keys.join(" ")));
+            }
+            let msg: String = msg.chars().take(self.screen_width).collect();
+            self.write_buf.extend(msg.as_bytes());
+        } else {
+            let len = cmp::min(self.screen_width, self.status_msg.data.len());
+            self.write_buf.extend(self.status_msg.data.as_bytes().iter().take(len));
+        }
+        self.defer_esc_seq("m");
+        prompt_col
     }
 
-    fn write_status_msg(&mut self) {
-        let len = cmp::min(self.window_width, self.status_msg.data.len());
-        self.write_buf.extend(self.status_msg.data.as_bytes().iter().take(len));
+    /// Draws the open prompt in the message bar, followed by the completions
+    /// being cycled through, and returns the column of the cursor in it. If
+    /// the input is too long, only as much of it as fits before the cursor is
+    /// shown.
+    fn build_prompt(&mut self) -> Option<usize> {
+        let (label, before, after, completions) = match self.prompt {
+            Some(ref prompt) => (
+                prompt.label.clone(),
+                String::from_utf8_lossy(&prompt.input[..prompt.cursor]).to_string(),
+                String::from_utf8_lossy(&prompt.input[prompt.cursor..]).to_string(),
+                prompt.completions.clone(),
+            ),
+            None => return None,
+        };
+        let width = self.screen_width;
+        let n_label = cmp::min(label.chars().count(), width.saturating_sub(1));
+        let room = width - n_label;
+        let n_before = before.chars().count();
+        let skip = (n_before + 1).saturating_sub(room);
+        let mut line: String = label.chars().take(n_label).collect();
+        line.extend(before.chars().skip(skip));
+        line.push_str(&after);
+        if !completions.is_empty() {
+            line.push_str(&format!("  {{{}}}", completions.join(" | ")));
+        }
+        let line: String = line.chars().take(width).collect();
+        self.write_buf.extend(line.as_bytes());
+        Some(n_label + n_before - skip)
+    }
+
+    /// Draws the choices of the open prompt's menu, if it has one, over the
+    /// bottom rows of the text. Commands are shown with their descriptions
+    /// and the keys bound to them.
+    fn build_menu(&mut self) {
+        let (choices, selected) = match self.prompt.as_ref().and_then(|p| p.menu.as_ref()) {
+            Some(menu) => {
+                let choices: Vec<String> = menu.matches.iter().map(|&i| menu.choices[i].clone()).collect();
+                (choices, menu.selected)
+            }
+            None => return,
+        };
+        // The menu covers the rows above the bottom status bar.
+        let height = self.screen_height.saturating_sub(2);
+        let n_rows = cmp::min(MENU_HEIGHT, height);
+        // Scroll the selected choice into view.
+        let first = (selected + 1).saturating_sub(n_rows);
+        let shown = &choices[first..cmp::min(first + n_rows, choices.len())];
+        let name_width = shown.iter().map(|c| c.chars().count()).max().unwrap_or(0);
+        let top = height - shown.len();
+        for (i, choice) in shown.iter().enumerate() {
+            let (left, right) = match command::find(choice) {
+                Some(command) => (
+                    format!(" {:<2$}  {}", command.name, command.description, name_width),
+                    format!("{} ", self.buffer.config.keymap.keys_for_command(command.name).join(", ")),
+                ),
+                None => (format!(" {}", choice), String::new()),
+            };
+            let row = status::layout(&left, &right, self.screen_width);
+            self.move_cursor(Pos { row: top + i, col: 0 });
+            self.write_style(if first + i == selected { Element::Selection } else { Element::MessageBar });
+            self.write_buf.extend(row.as_bytes());
+            self.defer_esc_seq("m");
+        }
     }
 
     fn flush_write_buf(&mut self) {
-        io::stdout().write(&self.write_buf).unwrap();
-        io::stdout().flush().unwrap();
+        self.tty.write_all(&self.write_buf).unwrap();
+        self.tty.flush().unwrap();
         // Does not alter its capacity.
         self.write_buf.clear();
     }
@@ -738,6 +1913,11 @@ impl Editor {
         self.defer_esc_seq("K");
     }
 
+    /// Clears `n` columns from the cursor, leaving it where it is.
+    fn clear_cols(&mut self, n: usize) {
+        self.defer_esc_seq(&format!("{}X", n));
+    }
+
     /// Appends the specified escape sequence to the write buffer which needs to
     /// be manually flushed for the sequence to take effect.
     fn defer_esc_seq(&mut self, cmd: &str) {
@@ -746,25 +1926,34 @@ impl Editor {
 
     /// Immeadiately sends the specified escape sequence to the terminal.
     fn send_esc_seq(&mut self, cmd: &str) {
-        println!("\x1b[{}", cmd);
+        writeln!(self.tty, "\x1b[{}", cmd).unwrap();
     }
 
     fn update_window_size(&mut self) {
+        // Ask the terminal driver, which can't get mixed up with keys typed
+        // meanwhile, the way a reply to a query read from the terminal can.
+        let mut size: libc::winsize = unsafe { mem::zeroed() };
+        let is_known = unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } == 0;
+        if is_known && size.ws_row > 0 && size.ws_col > 0 {
+            self.screen_width = size.ws_col as usize;
+            self.screen_height = size.ws_row as usize;
+            return;
+        }
         // Move cursor as far right and down as we can (set_cursor_pos not used
         // on purpose as it uses a different escape sequence which does not
         // ensure that it won't move the cursor beyond the confines of the
         // window while this does).
         self.send_esc_seq("999C");
         self.send_esc_seq("999B");
-        let bottom_right_corner = self.cursor_pos();
-        self.window_width = bottom_right_corner.col + 1;
-        // NOTE: subtract 2 from the result: 1 for the status bar and 1 for the
-        // status message bar (only subtract one since the + 1 hasn't been added
-        // to begin with).
-        self.window_height = bottom_right_corner.row - 1;
+        if let Some(bottom_right_corner) = self.cursor_pos() {
+            self.screen_width = bottom_right_corner.col + 1;
+            self.screen_height = bottom_right_corner.row + 1;
+        }
     }
 
-    fn cursor_pos(&mut self) -> Pos {
+    /// Asks the terminal where the cursor is. Returns `None` if the reply
+    /// can't be made sense of.
+    fn cursor_pos(&mut self) -> Option<Pos> {
         // Query cursor position.
         self.send_esc_seq("6n");
 
@@ -773,37 +1962,24 @@ impl Editor {
         // So if we generously assume each number to be 3 digits long, 10
         // bytes should be enough to allocate only once.
         let mut response = String::with_capacity(10);
-        for r in io::stdin().bytes() {
-            match r {
-                Ok(c) => {
-                    if c == 'R' as u8 {
-                        break;
-                    } else {
-                        response.push(c as char);
-                    }
-                }
-                Err(_) => (),
+        let mut buf: [u8; 1] = [0; 1];
+        while self.read_tty(&mut buf).is_ok() {
+            if buf[0] == b'R' {
+                break;
+            } else {
+                response.push(buf[0] as char);
             }
         }
 
-        // Sometimes we receive a [6~ (which as far as I can tell is not a
-        // valid escape sequence), so skip to the first \x1b character.
-        let esc_pos = response.find('\x1b').unwrap();
-        let response = &response[esc_pos + 1..];
-        let row_pos = response.find(char::is_numeric).unwrap();
-        let semicolon_pos = response.find(';').unwrap();
-        assert!(row_pos < semicolon_pos);
-        let row: usize = response[row_pos..semicolon_pos].parse().unwrap();
-
-        // Skip the first integer.
-        assert!(semicolon_pos < response.len());
-        let response = &response[semicolon_pos..];
-
-        let col_pos = response.find(char::is_numeric).unwrap();
-        assert!(col_pos < response.len());
-        let col: usize = response[col_pos..].parse().unwrap();
-
-        Pos { col: col - 1, row: row - 1 }
+        // Keys typed meanwhile may come before the response, so skip to the
+        // last \x1b character.
+        let esc_pos = response.rfind('\x1b')?;
+        let (row, col) = response[esc_pos + 1..].trim_start_matches('[').split_once(';')?;
+        let (row, col): (usize, usize) = (row.parse().ok()?, col.parse().ok()?);
+        if row == 0 || col == 0 {
+            return None;
+        }
+        Some(Pos { col: col - 1, row: row - 1 })
     }
 }
 
@@ -811,6 +1987,13 @@ impl Drop for Editor {
     fn drop(&mut self) {
         // Restore user's screen.
         self.clear_screen();
+        self.defer_esc_seq("?1000l");
+        self.defer_esc_seq("?1006l");
+        if self.buffer.config.profile == Profile::Vi {
+            // And the terminal's default cursor shape.
+            self.defer_esc_seq("0 q");
+        }
+        self.flush_write_buf();
     }
 }
 
@@ -823,45 +2006,142 @@ fn init_log() {
         .unwrap();
 }
 
+/// Returns `path` relative to the working directory if it's under it, or
+/// else relative to the home directory (as `~/...`) if it's under that.
+fn display_path(path: &Path) -> String {
+    if path.as_os_str().is_empty() {
+        return "[No Name]".to_string();
+    }
+    let path = editorconfig::absolute(path);
+    if let Some(rel) = env::current_dir().ok().and_then(|dir| path.strip_prefix(dir).ok().map(Path::to_path_buf)) {
+        return rel.display().to_string();
+    }
+    match env::var_os("HOME").map(PathBuf::from) {
+        Some(ref home) if path.starts_with(home) => {
+            format!("~/{}", path.strip_prefix(home).unwrap().display())
+        }
+        _ => path.display().to_string(),
+    }
+}
+
 fn log(buf: &[u8]) {
     let mut file = OpenOptions::new()
-        .write(true)
         .append(true)
         .open("/tmp/kilo-rust.log")
         .unwrap();
-    file.write("\n>>NEW LOG ENTRY\n".as_bytes()).unwrap();
-    file.write(&buf).unwrap();
-    file.write("\n".as_bytes()).unwrap();
+    file.write_all(b"\n>>NEW LOG ENTRY\n").unwrap();
+    file.write_all(buf).unwrap();
+    file.write_all(b"\n").unwrap();
     file.flush().unwrap();
 }
 
 fn main() {
+    // Bad arguments are reported before the terminal is taken over.
+    let args = match cli::parse(args().skip(1)) {
+        Ok(cli::Command::Edit(args)) => args,
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            return;
+        }
+        Ok(cli::Command::Version) => {
+            println!("kilo-rust {}", env!("CARGO_PKG_VERSION"));
+            return;
+        }
+        Err(e) => {
+            eprintln!("kilo-rust: {}\nTry 'kilo-rust --help' for more information.", e);
+            process::exit(2);
+        }
+    };
+    // Keys are read from the terminal rather than standard input, which may
+    // be piped text to edit, and the screen is drawn on it rather than on
+    // standard output, which may be redirected.
+    let tty = match OpenOptions::new().read(true).write(true).open("/dev/tty") {
+        Ok(tty) => tty,
+        Err(e) => {
+            eprintln!("kilo-rust: can't open the terminal: {}", e);
+            process::exit(1);
+        }
+    };
+    let tty_fd = tty.as_raw_fd();
+
     init_log();
-    // Save the current terminal config before entering raw mode with the
-    // instantiation of the editor so that we can restore it on drop.
-    let orig_termios = termios::tcgetattr(io::stdin().as_raw_fd()).unwrap();
+    // A broken config file shouldn't keep the user from editing, so fall back
+    // to the defaults and report the error once the editor is up.
+    let (mut config, config_err) = match Config::load(args.config_path.as_deref()) {
+        Ok(config) => {
+            let theme_error = config.theme_error();
+            (config, theme_error)
+        }
+        Err(e) => (Config::default(), Some(e)),
+    };
+    config.command_line = args.overrides.clone();
+
+    let mut editor = match Editor::open_files(config, &args, tty) {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("kilo-rust: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(e) = config_err {
+        editor.new_status_msg(&e, Duration::from_secs(10));
+    }
+
+    // Save the current terminal config before entering raw mode so that we
+    // can restore it once the editor is dropped.
+    let orig_termios = termios::tcgetattr(tty_fd).unwrap();
     let mut raw_termios = orig_termios.clone();
 
     termios::cfmakeraw(&mut raw_termios);
     termios::tcsetattr(
-        io::stdin().as_raw_fd(),
+        tty_fd,
         termios::SetArg::TCSANOW,
         &raw_termios,
     ).unwrap();
 
-    let config = Config { tab_width: 4 };
+    editor.run();
 
-    let args: Vec<String> = args().collect();
-    if args.len() > 1 {
-        Editor::open_file(config, Path::new(&args[1])).unwrap().run();
-    } else {
-        // TODO report error or ask for a file name
-    }
-
-    // Restore the original termios config.
+    // Restore the original termios config. The editor restores the screen
+    // when it's dropped, after this.
     termios::tcsetattr(
-        io::stdin().as_raw_fd(),
+        tty_fd,
         termios::SetArg::TCSANOW,
         &orig_termios,
     ).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::path::PathBuf;
+
+    use buffer::Buffer;
+    use config::Config;
+    use {Editor, Line};
+
+    /// Returns an editor of `text`, as the file at `path`, that draws an
+    /// 80 by 24 screen on nothing.
+    pub fn editor_at(config: Config, path: &str, text: &str) -> Editor {
+        let mut buffer = Buffer::new(config.clone(), PathBuf::from(path));
+        buffer.lines = text.split('\n').map(|line| Line::new(line.as_bytes().to_vec())).collect();
+        let tty = OpenOptions::new().write(true).open("/dev/null").unwrap();
+        let mut editor = Editor::new(config, buffer, tty);
+        editor.screen_width = 80;
+        editor.screen_height = 24;
+        editor.window_width = 80;
+        editor.window_height = 22;
+        editor.configure_buffer();
+        editor
+    }
+
+    pub fn editor(text: &str) -> Editor {
+        editor_at(Config::default(), "", text)
+    }
+
+    /// Confirms the open prompt with `input`, leaving its history be.
+    pub fn confirm(editor: &mut Editor, input: &str) {
+        let mut prompt = editor.prompt.take().expect("no prompt is open");
+        let on_confirm = prompt.take_callback().unwrap();
+        on_confirm(editor, input.to_string());
+    }
+}
@@ -0,0 +1,263 @@
+use std::time::Duration;
+
+use prompt::Prompt;
+use regex::Regex;
+use {Editor, TextPos};
+
+/// The search last typed, which `n` and `N` repeat.
+pub struct Search {
+    pattern: String,
+    regex: Regex,
+    is_backward: bool,
+    // The match last moved to, which is highlighted while the cursor is at
+    // its start.
+    pub found: Option<(TextPos, TextPos)>,
+}
+
+impl Editor {
+    /// Handles a key the way less does, if it's one of its keys, in a buffer
+    /// that is being viewed. Returns whether the key was handled.
+    pub fn pager_handle_key(&mut self, key: &str) -> bool {
+        let command: fn(&mut Editor) = match key {
+            "space" | "f" => Editor::pager_page_down,
+            "b" => Editor::pager_page_up,
+            "j" | "enter" => Editor::cursor_down,
+            "k" => Editor::cursor_up,
+            "g" | "<" => Editor::cursor_file_home,
+            "G" | ">" => Editor::cursor_file_end,
+            "&" => Editor::filter_lines,
+            "/" => Editor::search_forward,
+            "?" => Editor::search_backward,
+            "n" => Editor::search_next,
+            "N" => Editor::search_previous,
+            "F" => Editor::follow,
+            "q" => Editor::quit,
+            _ => return false,
+        };
+        command(self);
+        true
+    }
+
+    /// Scrolls down a window, keeping the row that was at the bottom in sight
+    /// at the top.
+    fn pager_page_down(&mut self) {
+        self.scroll();
+        for _ in self.buffer.cursor.pos.row + 1..self.window_height {
+            self.cursor_down();
+        }
+        self.page_down();
+    }
+
+    fn pager_page_up(&mut self) {
+        self.scroll();
+        for _ in 0..self.buffer.cursor.pos.row {
+            self.cursor_up();
+        }
+        self.page_up();
+    }
+
+    /// Returns `false`, and says why, if the buffer is read-only or still
+    /// loading, so that it mustn't be edited or saved.
+    pub fn check_editable(&mut self) -> bool {
+        if self.buffer.loading.is_some() {
+            self.new_status_msg("The file is still loading", Duration::from_secs(3));
+            return false;
+        }
+        if self.buffer.is_readonly {
+            self.new_status_msg("The file is read-only", Duration::from_secs(3));
+        }
+        !self.buffer.is_readonly
+    }
+
+    pub fn toggle_readonly(&mut self) {
+        self.buffer.is_readonly = !self.buffer.is_readonly;
+        // Keys type text again once the file can be edited, and it's no
+        // longer followed.
+        if !self.buffer.is_readonly {
+            self.buffer.is_viewed = false;
+            self.buffer.follow = None;
+        }
+        let msg = if self.buffer.is_readonly { "The file is now read-only" } else { "The file can now be edited" };
+        self.new_status_msg(msg, Duration::from_secs(3));
+    }
+
+    pub fn search_forward(&mut self) {
+        self.prompt_search(false);
+    }
+
+    pub fn search_backward(&mut self) {
+        self.prompt_search(true);
+    }
+
+    /// Asks for a regular expression and moves to its next match. An empty
+    /// one repeats the last search in the new direction.
+    fn prompt_search(&mut self, is_backward: bool) {
+        let label = if is_backward { "?" } else { "/" };
+        let prompt = Prompt::new(label, "search", move |editor: &mut Editor, pattern: String| {
+            if pattern.is_empty() {
+                match editor.search {
+                    Some(ref mut search) => search.is_backward = is_backward,
+                    None => return editor.new_status_msg("No previous search", Duration::from_secs(3)),
+                }
+            } else {
+                match Regex::new(&pattern) {
+                    Ok(regex) => editor.search = Some(Search { pattern, regex, is_backward, found: None }),
+                    Err(e) => return editor.new_status_msg(&e, Duration::from_secs(5)),
+                }
+            }
+            editor.repeat_search(false);
+        });
+        self.open_prompt(prompt);
+    }
+
+    pub fn search_next(&mut self) {
+        self.repeat_search(false);
+    }
+
+    pub fn search_previous(&mut self) {
+        self.repeat_search(true);
+    }
+
+    /// Moves to the next match of the last search, or with `is_reversed`, the
+    /// one the other way, wrapping around the ends of the file.
+    fn repeat_search(&mut self, is_reversed: bool) {
+        let (is_backward, pattern) = match self.search {
+            Some(ref search) => (search.is_backward != is_reversed, search.pattern.clone()),
+            None => return self.new_status_msg("No previous search", Duration::from_secs(3)),
+        };
+        let from = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        let found = self.find_match(from, is_backward);
+        if let Some(search) = self.search.as_mut() {
+            search.found = found;
+        }
+        match found {
+            Some((start, _)) => {
+                let is_wrapped = if is_backward { start >= from } else { start <= from };
+                self.buffer.cursor.line = start.0;
+                self.buffer.cursor.byte = start.1;
+                self.buffer.cursor.is_at_eol = false;
+                if is_wrapped {
+                    self.new_status_msg("Search wrapped", Duration::from_secs(2));
+                }
+            }
+            None => self.new_status_msg(&format!("Pattern not found: {}", pattern), Duration::from_secs(3)),
+        }
+    }
+
+    /// Returns the first match of the last search after `from`, or before it
+    /// if `is_backward`, wrapping around the ends of the file.
+    fn find_match(&self, from: TextPos, is_backward: bool) -> Option<(TextPos, TextPos)> {
+        let search = self.search.as_ref()?;
+        let n = self.buffer.lines.len();
+        if n == 0 {
+            return None;
+        }
+        // The line the search starts on is searched again last, for matches
+        // on the other side of the cursor.
+        (0..=n).find_map(|i| {
+            let line_idx = if is_backward { (from.0 + 2 * n - i) % n } else { (from.0 + i) % n };
+            let orig = &self.buffer.lines[line_idx].orig;
+            // Matches in the lines that filters hide are skipped.
+            if self.buffer.lines[line_idx].is_hidden {
+                return None;
+            }
+            // The bytes a match may start at.
+            let (lo, hi) = match (i == 0, i == n) {
+                (true, _) => if is_backward { (0, from.1.checked_sub(1)?) } else { (from.1 + 1, orig.len()) },
+                (_, true) => if is_backward { (from.1, orig.len()) } else { (0, from.1) },
+                _ => (0, orig.len()),
+            };
+            let found = if is_backward {
+                let mut last = None;
+                let mut byte = lo;
+                while let Some((start, end)) = search.regex.find(orig, byte).filter(|&(start, _)| start <= hi) {
+                    last = Some((start, end));
+                    byte = start + 1;
+                }
+                last
+            } else {
+                search.regex.find(orig, lo).filter(|&(start, _)| start <= hi)
+            };
+            found.map(|(start, end)| ((line_idx, start), (line_idx, end)))
+        })
+    }
+
+    /// Returns the match of the last search that the cursor is at, if any.
+    pub fn search_match(&self) -> Option<(TextPos, TextPos)> {
+        let cursor = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        self.search.as_ref().and_then(|search| search.found).filter(|&(start, _)| start == cursor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Search;
+    use regex::Regex;
+    use tests::editor;
+    use Editor;
+
+    fn searching(text: &str, pattern: &str) -> Editor {
+        let mut editor = editor(text);
+        let regex = Regex::new(pattern).unwrap();
+        editor.search = Some(Search { pattern: pattern.to_string(), regex, is_backward: false, found: None });
+        editor
+    }
+
+    fn starts(editor: &Editor, from: (usize, usize), is_backward: bool) -> Option<(usize, usize)> {
+        editor.find_match(from, is_backward).map(|(start, _)| start)
+    }
+
+    #[test]
+    fn finds_matches_forward_and_wraps() {
+        let editor = searching("ab ab\nxx\nab", "ab");
+        assert_eq!(starts(&editor, (0, 0), false), Some((0, 3)));
+        assert_eq!(starts(&editor, (0, 3), false), Some((2, 0)));
+        assert_eq!(starts(&editor, (1, 1), false), Some((2, 0)));
+        assert_eq!(starts(&editor, (2, 0), false), Some((0, 0)));
+        // From the end of a line.
+        assert_eq!(starts(&editor, (0, 5), false), Some((2, 0)));
+        assert_eq!(editor.find_match((0, 0), false), Some(((0, 3), (0, 5))));
+    }
+
+    #[test]
+    fn finds_matches_backward_and_wraps() {
+        let editor = searching("ab ab\nxx\nab", "ab");
+        assert_eq!(starts(&editor, (0, 4), false), Some((2, 0)));
+        assert_eq!(starts(&editor, (0, 4), true), Some((0, 3)));
+        assert_eq!(starts(&editor, (0, 3), true), Some((0, 0)));
+        assert_eq!(starts(&editor, (0, 0), true), Some((2, 0)));
+        assert_eq!(starts(&editor, (2, 0), true), Some((0, 3)));
+    }
+
+    #[test]
+    fn finds_a_lone_match_by_wrapping_around_to_it() {
+        let editor = searching("xx\nab\nxx", "ab");
+        assert_eq!(starts(&editor, (1, 0), false), Some((1, 0)));
+        assert_eq!(starts(&editor, (1, 0), true), Some((1, 0)));
+        assert_eq!(starts(&searching("xx\nxx", "ab"), (0, 0), false), None);
+        assert_eq!(starts(&searching("", "ab"), (0, 0), true), None);
+    }
+
+    #[test]
+    fn skips_lines_filters_hide() {
+        let mut editor = searching("a 1\nb 1\na 2\nb 2", "1|2");
+        editor.add_filter("b".to_string(), false);
+        assert_eq!(editor.buffer.cursor.line, 1);
+        assert_eq!(starts(&editor, (1, 0), false), Some((1, 2)));
+        assert_eq!(starts(&editor, (1, 2), false), Some((3, 2)));
+        assert_eq!(starts(&editor, (3, 2), false), Some((1, 2)));
+    }
+
+    #[test]
+    fn says_when_the_search_wraps() {
+        let mut editor = searching("ab\nab", "ab");
+        editor.buffer.cursor.line = 1;
+        editor.search_next();
+        assert_eq!((editor.buffer.cursor.line, editor.buffer.cursor.byte), (0, 0));
+        assert_eq!(editor.status_msg.data, "Search wrapped");
+        assert!(editor.search_match().is_some());
+        editor.search_next();
+        assert_eq!(editor.buffer.cursor.line, 1);
+        assert_eq!(editor.status_msg.data, "Search wrapped");
+    }
+}
@@ -0,0 +1,486 @@
+use std::cmp;
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+/// The keys a prompt understands, decoded from the input by the editor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Input {
+    Byte(u8),
+    Left,
+    Right,
+    Home,
+    End,
+    /// The previous entry in the prompt's history.
+    Up,
+    /// The next entry in the prompt's history.
+    Down,
+    Backspace,
+    Delete,
+    KillToStart,
+    KillToEnd,
+    Complete,
+    Confirm,
+    Cancel,
+}
+
+/// What a prompt wants done after handling an input.
+pub enum Outcome {
+    Pending,
+    Confirm(String),
+    Cancel,
+}
+
+/// Provides the completions of a prompt's input.
+pub trait Completer {
+    /// Returns what `input` may be completed to, in the order to cycle
+    /// through them. Each is the whole input, not just what's added to it.
+    fn complete(&self, input: &str) -> Vec<String>;
+}
+
+/// Completes file paths, relative to the working directory unless they are
+/// absolute or start with `~/`. Directories are completed with a trailing
+/// slash, and hidden files are only offered if asked for with a leading dot.
+pub struct PathCompleter;
+
+impl Completer for PathCompleter {
+    fn complete(&self, input: &str) -> Vec<String> {
+        let (dir, prefix) = match input.rfind('/') {
+            Some(i) => (&input[..i + 1], &input[i + 1..]),
+            None => ("", input),
+        };
+        let entries = match fs::read_dir(expand_home(if dir.is_empty() { "." } else { dir })) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut paths: Vec<String> = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().to_string();
+                if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+                    return None;
+                }
+                let is_dir = e.path().is_dir();
+                Some(format!("{}{}{}", dir, name, if is_dir { "/" } else { "" }))
+            })
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+/// Replaces a leading `~/` with the home directory.
+pub fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// A list of choices shown with a prompt, narrowed down to those that
+/// fuzzily match the input, best first. Up and down then select a choice
+/// rather than browse the history, and confirming the prompt picks it.
+pub struct Menu {
+    pub choices: Vec<String>,
+    // The indices of the choices that match the input.
+    pub matches: Vec<usize>,
+    // The index into `matches` of the selected choice.
+    pub selected: usize,
+}
+
+impl Menu {
+    fn filter(&mut self, input: &str) {
+        let mut scored: Vec<(i32, usize)> = self.choices.iter()
+            .enumerate()
+            .filter_map(|(i, c)| fuzzy_score(input, c).map(|score| (score, i)))
+            .collect();
+        // Sorting is stable, so choices that score the same keep their order.
+        scored.sort_by_key(|&(score, _)| -score);
+        self.matches = scored.into_iter().map(|(_, i)| i).collect();
+        self.selected = 0;
+    }
+
+    /// Returns the selected choice, if any choice matches.
+    pub fn selection(&self) -> Option<&str> {
+        self.matches.get(self.selected).map(|&i| self.choices[i].as_str())
+    }
+}
+
+/// Scores how well `pattern` fuzzily matches `s`, if it does at all: the
+/// characters of `pattern` other than spaces must appear in `s` in order,
+/// ignoring case. Runs of consecutive characters and characters that start
+/// words score higher, as do shorter strings, and the best way of matching
+/// counts.
+pub fn fuzzy_score(pattern: &str, s: &str) -> Option<i32> {
+    let pattern: Vec<u8> = pattern.bytes().filter(|b| *b != b' ').map(|b| b.to_ascii_lowercase()).collect();
+    if pattern.is_empty() {
+        return Some(0);
+    }
+    let s = s.as_bytes();
+    let word_start = |j: usize| j == 0 || !s[j - 1].is_ascii_alphanumeric();
+    // The best score of matching the pattern so far with its last character
+    // matched at each position of `s`.
+    let mut best: Vec<Option<i32>> = vec![None; s.len()];
+    for (i, &p) in pattern.iter().enumerate() {
+        let mut next = vec![None; s.len()];
+        for j in 0..s.len() {
+            if s[j].to_ascii_lowercase() != p {
+                continue;
+            }
+            let before = if i == 0 {
+                Some(0)
+            } else {
+                (0..j).filter_map(|k| best[k].map(|score| score + if k + 1 == j { 5 } else { 0 })).max()
+            };
+            next[j] = before.map(|score| score + if word_start(j) { 3 } else { 0 });
+        }
+        best = next;
+    }
+    best.into_iter().flatten().max().map(|score| score * 100 - s.len() as i32)
+}
+
+/// What is run with the input a prompt is confirmed with.
+pub type Callback<T> = Box<dyn FnOnce(&mut T, String)>;
+
+/// A line of text being entered in the message bar, with its own history
+/// and completion. `T` is what the callback it's confirmed with acts on.
+pub struct Prompt<T> {
+    pub label: String,
+    // The name the history of the prompt is kept under, shared by all
+    // prompts that ask for the same kind of thing.
+    pub history_name: String,
+    pub input: Vec<u8>,
+    // The byte offset of the cursor into `input`.
+    pub cursor: usize,
+    // The earlier entries of the prompt, oldest first, and which of them is
+    // being shown, if any.
+    history: Vec<String>,
+    history_idx: Option<usize>,
+    // What was typed before browsing the history, to get back to.
+    draft: Vec<u8>,
+    completer: Option<Box<dyn Completer>>,
+    // The completions being cycled through with repeated tabs, and which of
+    // them is shown.
+    pub completions: Vec<String>,
+    completion_idx: Option<usize>,
+    pub menu: Option<Menu>,
+    on_confirm: Option<Callback<T>>,
+}
+
+impl<T> Prompt<T> {
+    pub fn new<F>(label: &str, history_name: &str, on_confirm: F) -> Prompt<T>
+    where
+        F: FnOnce(&mut T, String) + 'static,
+    {
+        Prompt {
+            label: label.to_string(),
+            history_name: history_name.to_string(),
+            input: vec![],
+            cursor: 0,
+            history: vec![],
+            history_idx: None,
+            draft: vec![],
+            completer: None,
+            completions: vec![],
+            completion_idx: None,
+            menu: None,
+            on_confirm: Some(Box::new(on_confirm)),
+        }
+    }
+
+    pub fn with_completer<C: Completer + 'static>(mut self, completer: C) -> Prompt<T> {
+        self.completer = Some(Box::new(completer));
+        self
+    }
+
+    pub fn with_menu(mut self, choices: Vec<String>) -> Prompt<T> {
+        let mut menu = Menu { choices, matches: vec![], selected: 0 };
+        menu.filter("");
+        self.menu = Some(menu);
+        self
+    }
+
+    pub fn with_history(mut self, history: Vec<String>) -> Prompt<T> {
+        self.history = history;
+        self
+    }
+
+    /// Returns the callback to run with the confirmed input. It can only be
+    /// taken once.
+    pub fn take_callback(&mut self) -> Option<Callback<T>> {
+        self.on_confirm.take()
+    }
+
+    pub fn handle(&mut self, input: Input) -> Outcome {
+        if input != Input::Complete {
+            self.completions.clear();
+            self.completion_idx = None;
+        }
+        match input {
+            Input::Byte(b) => {
+                self.input.insert(self.cursor, b);
+                self.cursor += 1;
+            }
+            Input::Left => self.cursor = self.prev_char_boundary(),
+            Input::Right => self.cursor = self.next_char_boundary(),
+            Input::Home => self.cursor = 0,
+            Input::End => self.cursor = self.input.len(),
+            Input::Up | Input::Down if self.menu.is_some() => {
+                let menu = self.menu.as_mut().unwrap();
+                let n = menu.matches.len();
+                if n > 0 {
+                    menu.selected = if input == Input::Up { (menu.selected + n - 1) % n } else { (menu.selected + 1) % n };
+                }
+                return Outcome::Pending;
+            }
+            Input::Up => self.browse_history(true),
+            Input::Down => self.browse_history(false),
+            Input::Backspace => {
+                let start = self.prev_char_boundary();
+                self.input.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Input::Delete => {
+                let end = self.next_char_boundary();
+                self.input.drain(self.cursor..end);
+            }
+            Input::KillToStart => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            Input::KillToEnd => self.input.truncate(self.cursor),
+            Input::Complete => self.complete(),
+            Input::Confirm => {
+                let input = match self.menu.as_ref().and_then(|m| m.selection()) {
+                    Some(choice) => choice.to_string(),
+                    None => String::from_utf8_lossy(&self.input).to_string(),
+                };
+                return Outcome::Confirm(input);
+            }
+            Input::Cancel => return Outcome::Cancel,
+        }
+        if let Some(ref mut menu) = self.menu {
+            menu.filter(&String::from_utf8_lossy(&self.input));
+        }
+        Outcome::Pending
+    }
+
+    fn prev_char_boundary(&self) -> usize {
+        let mut i = self.cursor.saturating_sub(1);
+        while i > 0 && self.input[i] & 0xc0 == 0x80 {
+            i -= 1;
+        }
+        i
+    }
+
+    fn next_char_boundary(&self) -> usize {
+        let mut i = cmp::min(self.cursor + 1, self.input.len());
+        while i < self.input.len() && self.input[i] & 0xc0 == 0x80 {
+            i += 1;
+        }
+        i
+    }
+
+    fn set_input(&mut self, input: Vec<u8>) {
+        self.input = input;
+        self.cursor = self.input.len();
+    }
+
+    fn browse_history(&mut self, is_back: bool) {
+        let idx = match (self.history_idx, is_back) {
+            (None, true) if !self.history.is_empty() => {
+                self.draft = self.input.clone();
+                Some(self.history.len() - 1)
+            }
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < self.history.len() => Some(i + 1),
+            // Going past the newest entry gets back to what was typed.
+            (Some(_), false) => {
+                let draft = self.draft.clone();
+                self.set_input(draft);
+                None
+            }
+            (None, _) => None,
+        };
+        if let Some(i) = idx {
+            let entry = self.history[i].as_bytes().to_vec();
+            self.set_input(entry);
+        }
+        self.history_idx = idx;
+    }
+
+    /// Completes the input as far as all completions agree, and if that adds
+    /// nothing, cycles through the completions on each call.
+    fn complete(&mut self) {
+        if let Some(choice) = self.menu.as_ref().and_then(|m| m.selection()) {
+            let choice = choice.as_bytes().to_vec();
+            self.set_input(choice);
+            return;
+        }
+        if let Some(i) = self.completion_idx {
+            let i = (i + 1) % self.completions.len();
+            let completion = self.completions[i].as_bytes().to_vec();
+            self.set_input(completion);
+            self.completion_idx = Some(i);
+            return;
+        }
+        let completions = match self.completer {
+            Some(ref completer) => completer.complete(&String::from_utf8_lossy(&self.input)),
+            None => return,
+        };
+        match completions.len() {
+            0 => {}
+            1 => self.set_input(completions[0].as_bytes().to_vec()),
+            _ => {
+                let common = common_prefix(&completions);
+                if common.len() > self.input.len() {
+                    self.set_input(common.into_bytes());
+                } else {
+                    self.set_input(completions[0].as_bytes().to_vec());
+                    self.completion_idx = Some(0);
+                }
+                // Keep them around to show them.
+                self.completions = completions;
+            }
+        }
+    }
+}
+
+/// Returns the longest prefix all of `strings` share.
+fn common_prefix(strings: &[String]) -> String {
+    let first = match strings.first() {
+        Some(first) => first,
+        None => return String::new(),
+    };
+    let mut len = first.len();
+    for s in &strings[1..] {
+        len = first.bytes().zip(s.bytes()).take(len).take_while(|(a, b)| a == b).count();
+    }
+    // Don't cut a character in half.
+    while !first.is_char_boundary(len) {
+        len -= 1;
+    }
+    first[..len].to_string()
+}
+
+/// The entries of all prompts, which are kept across sessions in the data
+/// directory.
+pub struct History {
+    // Pairs of prompt history names and entries, oldest first.
+    entries: Vec<(String, String)>,
+}
+
+/// How many entries are kept for each prompt.
+const HISTORY_LEN: usize = 100;
+
+impl History {
+    /// Returns where the history is kept: `$XDG_DATA_HOME/kilo-rust/history`,
+    /// falling back to `~/.local/share/kilo-rust/history`.
+    fn path() -> Option<PathBuf> {
+        let dir = match env::var_os("XDG_DATA_HOME") {
+            Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".local").join("share"),
+        };
+        Some(dir.join("kilo-rust").join("history"))
+    }
+
+    /// Loads the history, which is empty if it can't be read. Each line of
+    /// the file is a prompt's history name and an entry, separated by a tab.
+    pub fn load() -> History {
+        let mut text = String::new();
+        if let Some(path) = History::path() {
+            if let Ok(mut file) = File::open(path) {
+                let _ = file.read_to_string(&mut text);
+            }
+        }
+        let entries = text.lines()
+            .filter_map(|line| {
+                let i = line.find('\t')?;
+                Some((line[..i].to_string(), line[i + 1..].to_string()))
+            })
+            .collect();
+        History { entries }
+    }
+
+    /// Returns the entries of the prompt with the given history name, oldest
+    /// first.
+    pub fn entries(&self, name: &str) -> Vec<String> {
+        self.entries.iter().filter(|(n, _)| n == name).map(|(_, e)| e.clone()).collect()
+    }
+
+    /// Adds an entry to the history of a prompt, replacing any earlier one
+    /// that is the same, and writes the history to disk.
+    pub fn add(&mut self, name: &str, entry: &str) -> Result<(), String> {
+        if entry.is_empty() || entry.contains('\n') {
+            return Ok(());
+        }
+        self.entries.retain(|(n, e)| !(n == name && e == entry));
+        self.entries.push((name.to_string(), entry.to_string()));
+        let n_entries = self.entries.iter().filter(|(n, _)| n == name).count();
+        if n_entries > HISTORY_LEN {
+            let oldest = self.entries.iter().position(|(n, _)| n == name).unwrap();
+            self.entries.remove(oldest);
+        }
+        self.save()
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let path = match History::path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for (name, entry) in &self.entries {
+            text.push_str(&format!("{}\t{}\n", name, entry));
+        }
+        path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| File::create(&path))
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|e| format!("Can't save prompt history to {}: {}", path.display(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{common_prefix, fuzzy_score};
+
+    fn prefix(strings: &[&str]) -> String {
+        common_prefix(&strings.iter().map(|s| s.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn finds_the_common_prefix() {
+        assert_eq!(prefix(&[]), "");
+        assert_eq!(prefix(&["main.rs"]), "main.rs");
+        assert_eq!(prefix(&["main.rs", "main.c", "mainly"]), "main");
+        assert_eq!(prefix(&["src/", "Cargo.toml"]), "");
+        assert_eq!(prefix(&["ab", "abc"]), "ab");
+    }
+
+    #[test]
+    fn keeps_characters_whole() {
+        assert_eq!(prefix(&["éa", "éb"]), "é");
+        // "é" and "è" share their first byte.
+        assert_eq!(prefix(&["é", "è"]), "");
+    }
+
+    #[test]
+    fn matches_characters_in_order_ignoring_case() {
+        assert!(fuzzy_score("gtl", "Go to line").is_some());
+        assert!(fuzzy_score("GO TO", "go to line").is_some());
+        assert!(fuzzy_score("ltg", "go to line").is_none());
+        assert!(fuzzy_score("x", "").is_none());
+        assert_eq!(fuzzy_score("", "anything"), Some(0));
+        assert_eq!(fuzzy_score("  ", "anything"), Some(0));
+    }
+
+    #[test]
+    fn scores_word_starts_and_runs_higher() {
+        assert!(fuzzy_score("gl", "go-line") > fuzzy_score("gl", "goal"));
+        assert!(fuzzy_score("de", "delete") > fuzzy_score("de", "dxxxxe"));
+        assert!(fuzzy_score("save", "save") > fuzzy_score("save", "save-as"));
+        // The run at the end beats taking the first 'a'.
+        assert_eq!(fuzzy_score("ab", "a_ab"), Some(796));
+    }
+}
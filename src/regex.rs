@@ -0,0 +1,526 @@
+use std::cmp;
+
+/// A small regular expression engine for the token rules of syntax
+/// definitions, searches and filters. It works on bytes and supports
+/// literals, `.`, classes (`[a-z_]`, `[^0-9]`), the escapes `\d`, `\w`, `\s`
+/// (and their negations `\D`, `\W`, `\S`), `\b`, the anchors `^` and `$`,
+/// groups with `|`, and the quantifiers `*`, `+`, `?` and `{m,n}`, which may
+/// be made lazy with `?`.
+///
+/// A pattern is compiled into a program that is run over the text once,
+/// following every way the pattern could match side by side, so matching
+/// takes time linear in the length of the text however the pattern repeats.
+/// Of the ways a pattern matches, the one a backtracking engine would find
+/// first wins.
+#[derive(Clone)]
+pub struct Regex {
+    program: Vec<Inst>,
+}
+
+#[derive(Clone)]
+enum Node {
+    Byte(u8),
+    Any,
+    // Inclusive byte ranges, and whether the class is negated.
+    Class(Vec<(u8, u8)>, bool),
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    Group(Vec<Vec<Node>>),
+    Repeat {
+        node: Box<Node>,
+        min: usize,
+        max: Option<usize>,
+        greedy: bool,
+    },
+}
+
+#[derive(Clone)]
+enum Inst {
+    // Matches a byte, or with an anchor or `\b`, a position, and goes on to
+    // the next instruction.
+    Node(Node),
+    // Goes on to both instructions, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+/// How many instructions a pattern may compile into, which counted
+/// repetitions multiply.
+const MAX_PROGRAM_LEN: usize = 10_000;
+
+impl Regex {
+    /// Compiles `pattern`, or describes why it's invalid.
+    pub fn new(pattern: &str) -> Result<Regex, String> {
+        let mut p = Parser { s: pattern.as_bytes(), pos: 0 };
+        let alternatives = p.alternatives()?;
+        if p.pos < p.s.len() {
+            // Only an unmatched closing parenthesis stops the parser early.
+            return Err(format!("unmatched ')' in regex '{}'", pattern));
+        }
+        let mut program = vec![];
+        compile_alternatives(&alternatives, &mut program)?;
+        program.push(Inst::Match);
+        Ok(Regex { program })
+    }
+
+    /// Returns a regex that matches `text` as it is.
+    pub fn literal(text: &[u8]) -> Regex {
+        let mut program: Vec<Inst> = text.iter().map(|b| Inst::Node(Node::Byte(*b))).collect();
+        program.push(Inst::Match);
+        Regex { program }
+    }
+
+    /// Returns the end of the match that starts at `start` in `text` and
+    /// only takes in bytes before `limit`, if there is one, so that what
+    /// comes after `limit` doesn't change the match. `text` is the whole
+    /// line, so that anchors and word boundaries can look around the match.
+    pub fn match_within(&self, text: &[u8], start: usize, limit: usize) -> Option<usize> {
+        self.run(text, start, cmp::min(limit, text.len()), true).map(|(_, end)| end)
+    }
+
+    /// Returns the start and end of the first match in `text` that starts
+    /// at or after `start`.
+    pub fn find(&self, text: &[u8], start: usize) -> Option<(usize, usize)> {
+        self.run(text, start, text.len(), false)
+    }
+
+    /// Runs the program over `text` from `start` to `limit`, starting a match
+    /// at each byte after `start` too unless `is_anchored`, and returns the
+    /// first match.
+    fn run(&self, text: &[u8], start: usize, limit: usize, is_anchored: bool) -> Option<(usize, usize)> {
+        // The instructions each way of matching is at, in order of
+        // preference, along with where its match started.
+        let mut threads = vec![];
+        let mut next = vec![];
+        // The position each instruction was last added at, so that no
+        // instruction is added twice at one position.
+        let mut added = vec![usize::MAX; self.program.len()];
+        let mut stack = vec![];
+        let mut found = None;
+        for i in start..=limit {
+            // Matches that start later are preferred less, and aren't looked
+            // for once one was found.
+            if found.is_none() && (i == start || !is_anchored) {
+                self.add_thread(&mut threads, &mut added, &mut stack, text, i, 0, i);
+            }
+            if threads.is_empty() && (found.is_some() || is_anchored) {
+                break;
+            }
+            for &(pc, match_start) in &threads {
+                match self.program[pc] {
+                    Inst::Match => {
+                        // The ways preferred less than this one are dropped.
+                        found = Some((match_start, i));
+                        break;
+                    }
+                    Inst::Node(ref node) => {
+                        if i < limit && match_one(node, text, i).is_some() {
+                            self.add_thread(&mut next, &mut added, &mut stack, text, i + 1, pc + 1, match_start);
+                        }
+                    }
+                    Inst::Split(..) | Inst::Jump(_) => unreachable!(),
+                }
+            }
+            threads.clear();
+            ::std::mem::swap(&mut threads, &mut next);
+        }
+        found
+    }
+
+    /// Adds the instructions that `pc` leads to at position `i` without
+    /// matching a byte to `threads`, in order of preference.
+    #[allow(clippy::too_many_arguments)]
+    fn add_thread(
+        &self,
+        threads: &mut Vec<(usize, usize)>,
+        added: &mut [usize],
+        stack: &mut Vec<usize>,
+        text: &[u8],
+        i: usize,
+        pc: usize,
+        match_start: usize,
+    ) {
+        stack.push(pc);
+        while let Some(pc) = stack.pop() {
+            if added[pc] == i {
+                continue;
+            }
+            added[pc] = i;
+            match self.program[pc] {
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Node(ref node) if is_assertion(node) => {
+                    if match_one(node, text, i).is_some() {
+                        stack.push(pc + 1);
+                    }
+                }
+                Inst::Node(_) | Inst::Match => threads.push((pc, match_start)),
+            }
+        }
+    }
+}
+
+fn compile_alternatives(alternatives: &[Vec<Node>], program: &mut Vec<Inst>) -> Result<(), String> {
+    let mut jumps = vec![];
+    for (idx, seq) in alternatives.iter().enumerate() {
+        let is_last = idx + 1 == alternatives.len();
+        let split = program.len();
+        if !is_last {
+            program.push(Inst::Split(split + 1, 0));
+        }
+        for node in seq {
+            compile_node(node, program)?;
+        }
+        if !is_last {
+            jumps.push(program.len());
+            program.push(Inst::Jump(0));
+            program[split] = Inst::Split(split + 1, program.len());
+        }
+    }
+    for jump in jumps {
+        program[jump] = Inst::Jump(program.len());
+    }
+    Ok(())
+}
+
+fn compile_node(node: &Node, program: &mut Vec<Inst>) -> Result<(), String> {
+    if program.len() > MAX_PROGRAM_LEN {
+        return Err("regex is too large".to_string());
+    }
+    // Greedy repetitions prefer matching once more to stopping, lazy ones
+    // the other way around.
+    let split = |more: usize, stop: usize, greedy: bool| {
+        if greedy { Inst::Split(more, stop) } else { Inst::Split(stop, more) }
+    };
+    match *node {
+        Node::Group(ref alternatives) => compile_alternatives(alternatives, program)?,
+        Node::Repeat { ref node, min, max, greedy } => {
+            for _ in 0..min {
+                compile_node(node, program)?;
+            }
+            match max {
+                None => {
+                    let start = program.len();
+                    program.push(Inst::Jump(0));
+                    compile_node(node, program)?;
+                    program.push(Inst::Jump(start));
+                    program[start] = split(start + 1, program.len(), greedy);
+                }
+                Some(max) => {
+                    // Each optional repetition is only tried after the one
+                    // before it matched.
+                    let mut splits = vec![];
+                    for _ in min..max {
+                        splits.push(program.len());
+                        program.push(Inst::Jump(0));
+                        compile_node(node, program)?;
+                    }
+                    for start in splits {
+                        program[start] = split(start + 1, program.len(), greedy);
+                    }
+                }
+            }
+        }
+        ref node => program.push(Inst::Node(node.clone())),
+    }
+    Ok(())
+}
+
+fn is_assertion(node: &Node) -> bool {
+    matches!(*node, Node::LineStart | Node::LineEnd | Node::WordBoundary)
+}
+
+/// Matches a node that isn't a group or a repetition, returning where the
+/// match ends.
+fn match_one(node: &Node, text: &[u8], i: usize) -> Option<usize> {
+    let is_word = |i: usize| text.get(i).is_some_and(|b| b.is_ascii_alphanumeric() || *b == b'_');
+    let matches = match *node {
+        Node::LineStart => return if i == 0 { Some(i) } else { None },
+        Node::LineEnd => return if i == text.len() { Some(i) } else { None },
+        Node::WordBoundary => {
+            let before = i > 0 && is_word(i - 1);
+            return if before != is_word(i) { Some(i) } else { None };
+        }
+        Node::Byte(b) => text.get(i) == Some(&b),
+        Node::Any => i < text.len(),
+        Node::Class(ref ranges, negated) => match text.get(i) {
+            Some(&b) => ranges.iter().any(|&(lo, hi)| lo <= b && b <= hi) != negated,
+            None => false,
+        },
+        Node::Group(_) | Node::Repeat { .. } => unreachable!(),
+    };
+    if matches { Some(i + 1) } else { None }
+}
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).cloned()
+    }
+
+    /// Parses `|`-separated sequences up to the end of the pattern or an
+    /// unmatched `)`.
+    fn alternatives(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.peek() == Some(b'|') {
+            self.pos += 1;
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut seq = vec![];
+        while let Some(c) = self.peek() {
+            if c == b'|' || c == b')' {
+                break;
+            }
+            let atom = self.atom()?;
+            seq.push(self.quantified(atom)?);
+        }
+        Ok(seq)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        let c = self.peek().unwrap();
+        self.pos += 1;
+        let node = match c {
+            b'.' => Node::Any,
+            b'^' => Node::LineStart,
+            b'$' => Node::LineEnd,
+            b'(' => {
+                // Non-capturing groups are the only kind there is anyway.
+                if self.s[self.pos..].starts_with(b"?:") {
+                    self.pos += 2;
+                }
+                let alternatives = self.alternatives()?;
+                if self.peek() != Some(b')') {
+                    return Err("unmatched '(' in regex".to_string());
+                }
+                self.pos += 1;
+                Node::Group(alternatives)
+            }
+            b'[' => self.class()?,
+            b'\\' => self.escape()?,
+            b'*' | b'+' | b'?' | b'{' => {
+                return Err(format!("'{}' doesn't follow anything to repeat in regex", c as char));
+            }
+            _ => Node::Byte(c),
+        };
+        Ok(node)
+    }
+
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.peek().ok_or("regex ends with '\\'")?;
+        self.pos += 1;
+        let node = match c {
+            b'b' => Node::WordBoundary,
+            b'n' => Node::Byte(b'\n'),
+            b't' => Node::Byte(b'\t'),
+            _ => match class_escape(c) {
+                Some((ranges, negated)) => Node::Class(ranges, negated),
+                None if c.is_ascii_alphanumeric() => {
+                    return Err(format!("unknown escape '\\{}' in regex", c as char));
+                }
+                None => Node::Byte(c),
+            },
+        };
+        Ok(node)
+    }
+
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some(b'^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = vec![];
+        let mut is_first = true;
+        loop {
+            let c = self.peek().ok_or("unmatched '[' in regex")?;
+            self.pos += 1;
+            // A `]` right after the opening bracket is taken literally.
+            if c == b']' && !is_first {
+                break;
+            }
+            is_first = false;
+            let lo = if c == b'\\' {
+                let e = self.peek().ok_or("unmatched '[' in regex")?;
+                self.pos += 1;
+                match class_escape(e) {
+                    Some((escaped, false)) => {
+                        ranges.extend(escaped);
+                        continue;
+                    }
+                    Some((_, true)) => return Err("negated escapes can't be used in a class".to_string()),
+                    None => match e {
+                        b'n' => b'\n',
+                        b't' => b'\t',
+                        _ => e,
+                    },
+                }
+            } else {
+                c
+            };
+            let is_range = self.peek() == Some(b'-') && self.s.get(self.pos + 1).is_some_and(|b| *b != b']');
+            if is_range {
+                let hi = self.s[self.pos + 1];
+                self.pos += 2;
+                if hi < lo {
+                    return Err(format!("invalid range '{}-{}' in regex", lo as char, hi as char));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+        Ok(Node::Class(ranges, negated))
+    }
+
+    fn quantified(&mut self, atom: Node) -> Result<Node, String> {
+        let (min, max) = match self.peek() {
+            Some(b'*') => (0, None),
+            Some(b'+') => (1, None),
+            Some(b'?') => (0, Some(1)),
+            Some(b'{') => {
+                let close = match self.s[self.pos..].iter().position(|b| *b == b'}') {
+                    Some(i) => self.pos + i,
+                    None => return Err("unmatched '{' in regex".to_string()),
+                };
+                let bounds = String::from_utf8_lossy(&self.s[self.pos + 1..close]).to_string();
+                let (min, max) = parse_bounds(&bounds)
+                    .ok_or_else(|| format!("invalid repetition '{{{}}}' in regex", bounds))?;
+                // Leave the parser on the closing brace, like on the others.
+                self.pos = close;
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if let Node::LineStart | Node::LineEnd | Node::WordBoundary = atom {
+            return Err("anchors can't be repeated in regex".to_string());
+        }
+        let greedy = self.peek() != Some(b'?');
+        if !greedy {
+            self.pos += 1;
+        }
+        Ok(Node::Repeat { node: Box::new(atom), min, max, greedy })
+    }
+}
+
+/// Returns the ranges that `\d`, `\w` or `\s` (or their upper case,
+/// negated forms) stand for.
+fn class_escape(c: u8) -> Option<(Vec<(u8, u8)>, bool)> {
+    let ranges = match c.to_ascii_lowercase() {
+        b'd' => vec![(b'0', b'9')],
+        b'w' => vec![(b'a', b'z'), (b'A', b'Z'), (b'0', b'9'), (b'_', b'_')],
+        b's' => vec![(b' ', b' '), (b'\t', b'\t'), (b'\r', b'\r'), (b'\n', b'\n')],
+        _ => return None,
+    };
+    Some((ranges, c.is_ascii_uppercase()))
+}
+
+/// Parses the inside of `{n}`, `{m,}` or `{m,n}`.
+fn parse_bounds(s: &str) -> Option<(usize, Option<usize>)> {
+    match s.find(',') {
+        None => {
+            let n = s.trim().parse().ok()?;
+            Some((n, Some(n)))
+        }
+        Some(i) => {
+            let min = s[..i].trim().parse().ok()?;
+            let max = s[i + 1..].trim();
+            if max.is_empty() {
+                Some((min, None))
+            } else {
+                let max = max.parse().ok()?;
+                if max < min { None } else { Some((min, Some(max))) }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regex;
+
+    fn match_at(pattern: &str, text: &str, start: usize) -> Option<usize> {
+        Regex::new(pattern).unwrap().match_within(text.as_bytes(), start, text.len())
+    }
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        Regex::new(pattern).unwrap().find(text.as_bytes(), 0)
+    }
+
+    #[test]
+    fn matches_literals_and_classes() {
+        assert_eq!(match_at("abc", "abcd", 0), Some(3));
+        assert_eq!(match_at("abc", "xabc", 0), None);
+        assert_eq!(match_at("[a-c]+", "cabx", 0), Some(3));
+        assert_eq!(match_at("[^0-9]", "5", 0), None);
+        assert_eq!(match_at(r"\d+\.\d*", "3.14;", 0), Some(4));
+        assert_eq!(match_at(r"\w+\s\W", "ab_1 -", 0), Some(6));
+    }
+
+    #[test]
+    fn prefers_what_a_backtracking_engine_would() {
+        assert_eq!(match_at("a*", "aaab", 0), Some(3));
+        assert_eq!(match_at("a*?", "aaab", 0), Some(0));
+        assert_eq!(match_at("a+?b", "aaab", 0), Some(4));
+        assert_eq!(match_at("a|ab", "ab", 0), Some(1));
+        assert_eq!(match_at("(ab|a)b*", "abbb", 0), Some(4));
+        assert_eq!(match_at(r#""(\\.|[^"])*""#, r#""a\"b" c"#, 0), Some(6));
+        assert_eq!(match_at("a{2,3}", "aaaa", 0), Some(3));
+        assert_eq!(match_at("a{2,3}?", "aaaa", 0), Some(2));
+        assert_eq!(match_at("a{2}", "a", 0), None);
+        assert_eq!(match_at("(a*)*b", "aab", 0), Some(3));
+    }
+
+    #[test]
+    fn anchors_look_around_the_start() {
+        assert_eq!(match_at("^a", "aa", 1), None);
+        assert_eq!(match_at("a$", "aa", 1), Some(2));
+        assert_eq!(match_at(r"\bif\b", "if x", 0), Some(2));
+        assert_eq!(match_at(r"\bif\b", "elif", 2), None);
+    }
+
+    #[test]
+    fn finds_the_leftmost_match() {
+        assert_eq!(find("b+", "abbcb"), Some((1, 3)));
+        assert_eq!(find(r"\bx", "ax x"), Some((3, 4)));
+        assert_eq!(find("z", "abc"), None);
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+        assert_eq!(Regex::new("c").unwrap().find(b"cac", 1), Some((2, 3)));
+        assert_eq!(Regex::literal(b"a.b").find(b"axb a.b", 0), Some((4, 7)));
+    }
+
+    #[test]
+    fn stops_matching_at_the_limit() {
+        let regex = Regex::new("a+").unwrap();
+        assert_eq!(regex.match_within(b"aaaa", 1, 3), Some(3));
+        assert_eq!(regex.match_within(b"aaaa", 1, 1), None);
+        assert_eq!(Regex::new("a+$").unwrap().match_within(b"aaaa", 0, 2), None);
+    }
+
+    #[test]
+    fn matches_long_lines_without_overflowing() {
+        let line = format!("a{}b", "x".repeat(1_000_000));
+        assert_eq!(match_at("a.*b", &line, 0), Some(line.len()));
+        assert_eq!(find("x*b$", &line), Some((1, line.len())));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        for pattern in &["(a", "a)", "[a", "*a", "a{2", "a{3,1}", "[z-a]", r"\q", "^*", "(a{100}){100}{100}"] {
+            assert!(Regex::new(pattern).is_err(), "{}", pattern);
+        }
+    }
+}
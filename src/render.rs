@@ -0,0 +1,710 @@
+use std::cell::RefMut;
+use std::cmp;
+use std::iter;
+use std::str;
+
+use syntax::{Highlight, State, LOOKAHEAD};
+use {Editor, WrapRows};
+
+/// How far apart, in bytes, the points in a line are that working out how
+/// the line is drawn can be picked up from.
+const CHECKPOINT_INTERVAL: usize = 1 << 12;
+
+/// What has been worked out about how a line is drawn, as far into the line
+/// as it's been needed since the line last changed, so that a long line is
+/// only rendered and highlighted as far as it's shown.
+pub struct Rendered {
+    // The tab width the columns were counted with.
+    tab_width: usize,
+    // Where the line is drawn at every `CHECKPOINT_INTERVAL` bytes after its
+    // start.
+    cols: Vec<ColMark>,
+    pub wrap_rows: WrapRows,
+    // How the line is highlighted, once the state it starts in is known.
+    hl: Option<Highlighting>,
+}
+
+/// A point in a line: a byte's offset, the column it's drawn at, and the
+/// number of characters before it.
+#[derive(Clone, Copy, Default)]
+struct ColMark {
+    byte: usize,
+    col: usize,
+    chars: usize,
+}
+
+impl ColMark {
+    /// Returns the point after the byte at this one in `orig`. A character
+    /// takes up its columns at its first byte, and the rest take up none.
+    fn after(self, orig: &[u8], tab_width: usize) -> ColMark {
+        let b = orig[self.byte];
+        let col = if b == b'\t' { self.col + tab_width - self.col % tab_width } else { self.col + char_at(orig, self.byte).0 };
+        let chars = if b & 0xc0 == 0x80 { self.chars } else { self.chars + 1 };
+        ColMark { byte: self.byte + 1, col, chars }
+    }
+}
+
+/// Returns the number of columns the character starting at offset `byte` in
+/// `orig` takes up, and its length. The bytes that go on a character take up
+/// none, and any other that isn't part of a valid UTF-8 character is drawn
+/// by itself in one column.
+fn char_at(orig: &[u8], byte: usize) -> (usize, usize) {
+    let b = orig[byte];
+    if b < 0x80 {
+        return (1, 1);
+    }
+    if b & 0xc0 == 0x80 {
+        let lead = (byte.saturating_sub(3)..byte).rev().find(|&i| orig[i] & 0xc0 != 0x80);
+        let goes_on = lead.is_some_and(|lead| orig[lead] >= 0xc0 && char_at(orig, lead).1 > byte - lead);
+        return (if goes_on { 0 } else { 1 }, 1);
+    }
+    let end = cmp::min(byte + 4, orig.len());
+    let valid = match str::from_utf8(&orig[byte..end]) {
+        Ok(s) => s,
+        Err(e) => str::from_utf8(&orig[byte..byte + e.valid_up_to()]).unwrap(),
+    };
+    match valid.chars().next() {
+        Some(c) => (if is_wide(c) { 2 } else { 1 }, c.len_utf8()),
+        None => (1, 1),
+    }
+}
+
+/// Returns whether terminals draw `c` two columns wide, as they do the
+/// characters of East Asian scripts and most emoji.
+fn is_wide(c: char) -> bool {
+    matches!(c as u32,
+        0x1100..=0x115f | 0x2e80..=0x303e | 0x3041..=0x33ff | 0x3400..=0x4dbf | 0x4e00..=0x9fff |
+        0xa000..=0xa4cf | 0xac00..=0xd7a3 | 0xf900..=0xfaff | 0xfe30..=0xfe4f | 0xff00..=0xff60 |
+        0xffe0..=0xffe6 | 0x1f300..=0x1f64f | 0x1f900..=0x1f9ff | 0x20000..=0x3fffd)
+}
+
+struct Highlighting {
+    // The state the highlighter starts the line in.
+    start: State,
+    // The class of each byte as far as the line has been highlighted.
+    hl: Vec<Highlight>,
+    // Where tokens start, every `CHECKPOINT_INTERVAL` bytes or so, along with
+    // the state there, the last of which is how far the line has been
+    // highlighted. The start of the line comes before them.
+    marks: Vec<(usize, State)>,
+    // The marks from before the line last changed that are past the change,
+    // moved along with the text. If highlighting the changed part gets to
+    // one of them in the same state, the rest is as it was, and is in `hl`.
+    old_marks: Vec<(usize, State)>,
+}
+
+impl Highlighting {
+    fn new(start: State) -> Highlighting {
+        Highlighting { start, hl: vec![], marks: vec![], old_marks: vec![] }
+    }
+
+    fn last_mark(&self) -> (usize, State) {
+        self.marks.last().cloned().unwrap_or((0, self.start))
+    }
+}
+
+impl Editor {
+    /// Returns what has been worked out about how the line at `line_idx` is
+    /// drawn, starting over if the tab width has changed since.
+    pub fn rendered(&self, line_idx: usize) -> RefMut<'_, Rendered> {
+        let tab_width = self.buffer.config.tab_width as usize;
+        let rendered = self.buffer.lines[line_idx].rendered.borrow_mut();
+        RefMut::map(rendered, |rendered| {
+            let rendered = rendered.get_or_insert_with(|| {
+                Box::new(Rendered { tab_width, cols: vec![], wrap_rows: WrapRows::default(), hl: None })
+            });
+            if rendered.tab_width != tab_width {
+                rendered.tab_width = tab_width;
+                rendered.cols.clear();
+                rendered.wrap_rows = WrapRows::default();
+            }
+            &mut **rendered
+        })
+    }
+
+    /// Returns the last point in the line at `line_idx`, of those every
+    /// `CHECKPOINT_INTERVAL` bytes, that `is_past` isn't true of, working out
+    /// more of them if needed. `is_past` must stay true further on.
+    fn last_mark_before<F: Fn(&ColMark) -> bool>(&self, line_idx: usize, is_past: F) -> ColMark {
+        let orig = &self.buffer.lines[line_idx].orig;
+        let mut rendered = self.rendered(line_idx);
+        let tab_width = rendered.tab_width;
+        loop {
+            let last = rendered.cols.last().cloned().unwrap_or_default();
+            if is_past(&last) || last.byte + CHECKPOINT_INTERVAL > orig.len() {
+                break;
+            }
+            let next = (0..CHECKPOINT_INTERVAL).fold(last, |mark, _| mark.after(orig, tab_width));
+            rendered.cols.push(next);
+        }
+        match rendered.cols.partition_point(|mark| !is_past(mark)) {
+            0 => ColMark::default(),
+            n => rendered.cols[n - 1],
+        }
+    }
+
+    /// Returns the point in the line at `line_idx` at offset `byte`.
+    fn mark_at(&self, line_idx: usize, byte: usize) -> ColMark {
+        let orig = &self.buffer.lines[line_idx].orig;
+        let tab_width = self.buffer.config.tab_width as usize;
+        let mark = self.last_mark_before(line_idx, |mark| mark.byte > byte);
+        (mark.byte..byte).fold(mark, |mark, _| mark.after(orig, tab_width))
+    }
+
+    /// Returns the offset into the rendered line at `line_idx` at which the
+    /// byte at offset `byte` in the original line is drawn.
+    pub fn render_offset(&self, line_idx: usize, byte: usize) -> usize {
+        self.mark_at(line_idx, byte).col
+    }
+
+    /// Returns the number of characters before the byte at offset `byte` in
+    /// the line at `line_idx`.
+    pub fn char_offset(&self, line_idx: usize, byte: usize) -> usize {
+        self.mark_at(line_idx, byte).chars
+    }
+
+    /// The inverse of `render_offset`: returns the offset of the byte in the
+    /// original line at `line_idx` that is drawn at offset `render` in the
+    /// rendered line (so all of a tab's columns map to the tab), or the
+    /// original line's length if `render` is past its end.
+    pub fn orig_offset(&self, line_idx: usize, render: usize) -> usize {
+        let orig = &self.buffer.lines[line_idx].orig;
+        let tab_width = self.buffer.config.tab_width as usize;
+        let mut mark = self.last_mark_before(line_idx, |mark| mark.col > render);
+        while mark.byte < orig.len() {
+            let next = mark.after(orig, tab_width);
+            if render < next.col {
+                return mark.byte;
+            }
+            mark = next;
+        }
+        orig.len()
+    }
+
+    /// Returns the length of the rendered line at `line_idx`.
+    pub fn line_width(&self, line_idx: usize) -> usize {
+        self.render_offset(line_idx, self.buffer.lines[line_idx].orig.len())
+    }
+
+    /// Returns the length of the rendered line at `line_idx`, or `limit` if
+    /// it's longer, without going through any more of the line than that.
+    pub fn line_width_upto(&self, line_idx: usize, limit: usize) -> usize {
+        if self.orig_offset(line_idx, limit) < self.buffer.lines[line_idx].orig.len() {
+            limit
+        } else {
+            self.line_width(line_idx)
+        }
+    }
+
+    /// Returns what is drawn in each column between `start` and `end` of the
+    /// rendered line at `line_idx`, along with the offset of the byte in the
+    /// original line that it's part of. A character is drawn in the first of
+    /// its columns, so nothing is in the second of a wide one, and one that
+    /// doesn't fit between `start` and `end` is drawn as spaces.
+    pub fn render_cols(&self, line_idx: usize, start: usize, end: usize) -> Vec<(usize, &[u8])> {
+        let orig = &self.buffer.lines[line_idx].orig;
+        let tab_width = self.buffer.config.tab_width as usize;
+        let mut mark = self.last_mark_before(line_idx, |mark| mark.col > start);
+        let mut cols = vec![];
+        while mark.byte < orig.len() && mark.col < end {
+            let next = mark.after(orig, tab_width);
+            let n = cmp::min(next.col, end).saturating_sub(cmp::max(mark.col, start));
+            let (_, len) = char_at(orig, mark.byte);
+            if orig[mark.byte] == b'\t' || n < next.col - mark.col {
+                cols.extend(iter::repeat_n((mark.byte, &b" "[..]), n));
+            } else if n > 0 {
+                cols.push((mark.byte, &orig[mark.byte..mark.byte + len]));
+                cols.extend(iter::repeat_n((mark.byte, &b""[..]), n - 1));
+            }
+            mark = next;
+        }
+        cols
+    }
+
+    /// Forgets how the line at `line_idx` is drawn after it has changed as a
+    /// whole, and works it out again as it's needed.
+    pub fn update_render(&mut self, line_idx: usize) {
+        *self.buffer.lines[line_idx].rendered.get_mut() = None;
+        self.update_syntax(line_idx);
+        self.apply_filters(line_idx);
+    }
+
+    /// Forgets how the line at `line_idx` is drawn from where `removed` bytes
+    /// at offset `byte` were replaced with `inserted` ones, keeping what comes
+    /// before the change, and the highlighting of what comes after it in case
+    /// highlighting the change ends up where it did before.
+    pub fn update_render_at(&mut self, line_idx: usize, byte: usize, removed: usize, inserted: usize) {
+        if self.buffer.lines[line_idx].rendered.get_mut().is_none() {
+            return self.update_render(line_idx);
+        }
+        {
+            let mut rendered = self.rendered(line_idx);
+            let n_kept = rendered.cols.partition_point(|mark| mark.byte <= byte);
+            rendered.cols.truncate(n_kept);
+            if let Some(ref mut hl) = rendered.hl {
+                // Only the tokens that start far enough before the change
+                // for it not to have made a difference are kept, and those
+                // after it that don't look back into it.
+                let moved = hl.marks.iter().chain(hl.old_marks.iter())
+                    .filter(|&&(pos, _)| pos > byte + removed)
+                    .map(|&(pos, state)| (pos - removed + inserted, state))
+                    .collect();
+                let n_kept = hl.marks.partition_point(|&(pos, _)| pos + LOOKAHEAD <= byte);
+                hl.marks.truncate(n_kept);
+                hl.old_marks = moved;
+                if byte < hl.hl.len() {
+                    let end = cmp::min(byte + removed, hl.hl.len());
+                    hl.hl.splice(byte..end, iter::repeat_n(Highlight::Normal, inserted));
+                }
+            }
+        }
+        // The rows before the one the change is in were broken where they
+        // were without looking as far as the change.
+        let col = self.render_offset(line_idx, byte);
+        {
+            let mut rendered = self.rendered(line_idx);
+            let rows = &mut rendered.wrap_rows;
+            while rows.starts.len() > 1 {
+                let start = rows.starts[rows.starts.len() - 2];
+                let width = if start == 0 { rows.widths.0 } else { rows.widths.1 };
+                if start + width < col {
+                    break;
+                }
+                rows.starts.pop();
+            }
+            rows.is_complete = false;
+        }
+        self.update_syntax(line_idx);
+        self.apply_filters(line_idx);
+    }
+
+    /// Has the lines from `line_idx` on highlighted again as they're needed,
+    /// from the state the lines above leave the highlighter in, after lines
+    /// above them have been added or removed.
+    pub fn update_syntax(&mut self, line_idx: usize) {
+        self.buffer.hl_frontier = cmp::min(self.buffer.hl_frontier, line_idx);
+    }
+
+    /// Highlights the line at `line_idx` at least as far as offset `until`,
+    /// having first highlighted as much of the lines above it as tells what
+    /// state it starts in, which is all of those that have changed.
+    pub fn highlight_to(&mut self, line_idx: usize, until: usize) {
+        if self.buffer.syntax.is_none() {
+            return;
+        }
+        let mut frontier = cmp::min(self.buffer.hl_frontier, self.buffer.lines.len());
+        if !self.is_highlighted(line_idx) {
+            frontier = cmp::min(frontier, line_idx);
+        }
+        // The lines may have been replaced as a whole.
+        while frontier > 0 && !self.is_highlighted(frontier - 1) {
+            frontier -= 1;
+        }
+        while frontier <= line_idx {
+            let state = if frontier == 0 { State::Normal } else { self.end_state(frontier - 1) };
+            let mut rendered = self.rendered(frontier);
+            if rendered.hl.as_ref().is_none_or(|hl| hl.start != state) {
+                rendered.hl = Some(Highlighting::new(state));
+            }
+            frontier += 1;
+        }
+        self.buffer.hl_frontier = frontier;
+        self.resume_highlight(line_idx, until);
+    }
+
+    fn is_highlighted(&self, line_idx: usize) -> bool {
+        self.buffer.lines[line_idx].rendered.borrow().as_ref().is_some_and(|rendered| rendered.hl.is_some())
+    }
+
+    /// Returns the state the line at `line_idx` leaves the highlighter in,
+    /// which must have been highlighted from the right state.
+    fn end_state(&self, line_idx: usize) -> State {
+        self.resume_highlight(line_idx, usize::MAX);
+        let rendered = self.rendered(line_idx);
+        rendered.hl.as_ref().map_or(State::Normal, |hl| hl.last_mark().1)
+    }
+
+    /// Highlights the line at `line_idx` from where it's been highlighted to,
+    /// until a token starts at or after `until` or the line ends.
+    fn resume_highlight(&self, line_idx: usize, until: usize) {
+        let syntax = match self.buffer.syntax {
+            Some(ref syntax) => syntax,
+            None => return,
+        };
+        let orig = &self.buffer.lines[line_idx].orig;
+        let until = cmp::min(until, orig.len());
+        let mut rendered = self.rendered(line_idx);
+        let hl = match rendered.hl {
+            Some(ref mut hl) => hl,
+            None => return,
+        };
+        loop {
+            let (pos, state) = hl.last_mark();
+            if pos >= until {
+                break;
+            }
+            let target = match hl.old_marks.first() {
+                Some(&(old_pos, _)) => cmp::min(old_pos, pos + CHECKPOINT_INTERVAL),
+                None => pos + CHECKPOINT_INTERVAL,
+            };
+            let (end, state) = syntax.highlight(orig, pos, state, target, &mut hl.hl);
+            let n_passed = hl.old_marks.partition_point(|&(old_pos, _)| old_pos < end);
+            hl.old_marks.drain(..n_passed);
+            if let Some(&(old_pos, old_state)) = hl.old_marks.first() {
+                if old_pos == end && old_state == state {
+                    // The rest of the line is highlighted as it was.
+                    hl.marks.append(&mut hl.old_marks);
+                    continue;
+                }
+                if old_pos == end {
+                    hl.old_marks.remove(0);
+                }
+            }
+            // Keep the marks about as far apart as they're meant to be.
+            let n = hl.marks.len();
+            let before_last = if n >= 2 { hl.marks[n - 2].0 } else { 0 };
+            if n >= 1 && pos - before_last < CHECKPOINT_INTERVAL {
+                hl.marks.pop();
+            }
+            hl.marks.push((end, state));
+            if hl.old_marks.is_empty() {
+                hl.hl.truncate(end);
+            }
+        }
+    }
+
+    /// Returns the class of each byte from `start` to `end` of the line at
+    /// `line_idx`, highlighting as much of it as needed.
+    pub fn line_hl(&mut self, line_idx: usize, start: usize, end: usize) -> Vec<Highlight> {
+        self.highlight_to(line_idx, end);
+        let rendered = self.buffer.lines[line_idx].rendered.get_mut();
+        let hl = rendered.as_ref().and_then(|rendered| rendered.hl.as_ref()).map_or(&[][..], |hl| &hl.hl[..]);
+        (start..end).map(|i| hl.get(i).cloned().unwrap_or(Highlight::Normal)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::{Config, WrapMode};
+    use syntax::{Highlight, State, LOOKAHEAD};
+    use tests::{editor, editor_at};
+    use window::Rect;
+    use Editor;
+
+    /// A line long enough to be highlighted from several checkpoints.
+    fn long_line() -> String {
+        "let x = 1; ".repeat(6000)
+    }
+
+    fn rust(text: &str) -> Editor {
+        editor_at(Config::default(), "a.rs", text)
+    }
+
+    fn highlight_all(editor: &mut Editor) -> Vec<Vec<Highlight>> {
+        (0..editor.buffer.lines.len())
+            .map(|i| {
+                let len = editor.buffer.lines[i].orig.len();
+                editor.line_hl(i, 0, len)
+            })
+            .collect()
+    }
+
+    fn marks(editor: &Editor, line_idx: usize) -> Vec<(usize, State)> {
+        let rendered = editor.rendered(line_idx);
+        rendered.hl.as_ref().map_or(vec![], |hl| hl.marks.clone())
+    }
+
+    fn text(editor: &Editor) -> String {
+        let lines: Vec<_> = editor.buffer.lines.iter().map(|line| String::from_utf8_lossy(&line.orig)).collect();
+        lines.join("\n")
+    }
+
+    /// Checks that the editor highlights its text as it would if it had been
+    /// opened with it.
+    fn assert_highlighted_afresh(editor: &mut Editor) {
+        let expected = highlight_all(&mut rust(&text(editor)));
+        assert!(highlight_all(editor) == expected, "highlighting differs from a full rehighlight");
+    }
+
+    #[test]
+    fn resumes_from_a_checkpoint_after_closing_a_comment() {
+        let line = long_line();
+        let mut editor = rust(&format!("/*\n{}\nfn f() {{}}\n*/", line));
+        let hl = highlight_all(&mut editor);
+        assert!(hl[1].iter().all(|&hl| hl == Highlight::Comment));
+        assert_eq!(hl[2][0], Highlight::Comment);
+        let old_marks = marks(&editor, 1);
+
+        let byte = 50000;
+        editor.buffer.cursor.line = 1;
+        editor.buffer.cursor.byte = byte;
+        editor.insert_text(b"*/");
+        let kept = marks(&editor, 1);
+        assert!(!kept.is_empty());
+        assert!(kept.iter().all(|&(pos, _)| pos + LOOKAHEAD <= byte));
+        assert_eq!(&old_marks[..kept.len()], &kept[..]);
+
+        assert_highlighted_afresh(&mut editor);
+        let hl = highlight_all(&mut editor);
+        assert_eq!(hl[1][byte - 1], Highlight::Comment);
+        assert_eq!(hl[2][0], Highlight::Keyword);
+
+        editor.delete_back();
+        editor.delete_back();
+        assert_highlighted_afresh(&mut editor);
+        assert_eq!(editor.line_hl(2, 0, 1), vec![Highlight::Comment]);
+    }
+
+    #[test]
+    fn resumes_from_a_checkpoint_after_closing_a_string() {
+        let line = long_line();
+        let mut editor = rust(&format!("let s = \"\n{}\n\";\nlet y = 2;", line));
+        let hl = highlight_all(&mut editor);
+        assert!(hl[1].iter().all(|&hl| hl == Highlight::String));
+        assert_eq!(hl[3][0], Highlight::Keyword);
+
+        let byte = 40007;
+        editor.buffer.cursor.line = 1;
+        editor.buffer.cursor.byte = byte;
+        editor.insert_text(b"\"");
+        assert!(!marks(&editor, 1).is_empty());
+        assert_highlighted_afresh(&mut editor);
+        let hl = highlight_all(&mut editor);
+        assert_eq!(hl[1][byte + 2], Highlight::Keyword);
+        assert_eq!(hl[3][0], Highlight::String);
+
+        editor.buffer.cursor.byte = byte + 1;
+        editor.delete_back();
+        assert_highlighted_afresh(&mut editor);
+    }
+
+    #[test]
+    fn picks_up_the_old_highlighting_when_an_edit_makes_no_difference_to_it() {
+        let line = long_line();
+        let mut editor = rust(&format!("/*\n{}\n*/", line));
+        highlight_all(&mut editor);
+        let old_marks = marks(&editor, 1);
+
+        let byte = 30000;
+        editor.buffer.cursor.line = 1;
+        editor.buffer.cursor.byte = byte;
+        editor.insert_text(b"abc");
+        editor.line_hl(1, byte, byte + 10000);
+        // Highlighting past the edit got to a mark from before it in the
+        // same state, so the rest of the line wasn't highlighted again.
+        let new_marks = marks(&editor, 1);
+        let &(end, state) = new_marks.last().unwrap();
+        assert_eq!((end, state), (line.len() + 3, State::Comment(1)));
+        assert_eq!(old_marks.last().unwrap().0 + 3, end);
+        assert_highlighted_afresh(&mut editor);
+    }
+
+    #[test]
+    fn highlights_lines_after_a_change_to_the_state_they_start_in() {
+        let mut editor = rust("let a = 1;\nlet b = 2;\nlet c = 3;");
+        highlight_all(&mut editor);
+        editor.buffer.cursor.line = 0;
+        editor.buffer.cursor.byte = 0;
+        editor.insert_text(b"/* ");
+        assert_eq!(editor.line_hl(2, 0, 3), vec![Highlight::Comment; 3]);
+        assert_highlighted_afresh(&mut editor);
+        editor.delete_range((0, 0), (0, 3));
+        assert_eq!(editor.line_hl(2, 0, 3), vec![Highlight::Keyword; 3]);
+        assert_highlighted_afresh(&mut editor);
+    }
+
+    /// Returns an editor of `text` whose window has `width` columns for it.
+    fn laid_out(text: &str, wrap_mode: WrapMode, width: usize) -> Editor {
+        let mut editor = editor(text);
+        editor.buffer.config.wrap_mode = wrap_mode;
+        editor.buffer.config.tab_width = 4;
+        editor.window_width = width;
+        editor
+    }
+
+    fn drawn(editor: &Editor, line_idx: usize, start: usize, end: usize) -> String {
+        let cols = editor.render_cols(line_idx, start, end);
+        String::from_utf8(cols.iter().flat_map(|&(_, drawn)| drawn.iter().cloned()).collect()).unwrap()
+    }
+
+    /// Returns the text drawn in each of the window's rows, without styles.
+    fn screen(editor: &mut Editor) -> Vec<String> {
+        editor.scroll();
+        editor.write_buf.clear();
+        let rect = Rect { top: 0, left: 0, width: editor.window_width, height: editor.window_height + 1 };
+        editor.build_rows(rect);
+        let text = String::from_utf8(editor.write_buf.clone()).unwrap();
+        let mut rows = vec![];
+        // Each row starts with the cursor being moved to it.
+        for part in text.split('\x1b').skip(1) {
+            let end = part.find(|c: char| c.is_ascii_alphabetic()).unwrap();
+            if part[end..].starts_with('H') {
+                rows.push(String::new());
+            }
+            rows.last_mut().unwrap().push_str(&part[end + 1..]);
+        }
+        rows
+    }
+
+    #[test]
+    fn counts_the_columns_of_tabs_and_wide_characters() {
+        let editor = laid_out("\tab\u{65e5}\u{672c}\u{8a9e}x", WrapMode::NoWrap, 20);
+        let cols: Vec<usize> = [0, 1, 3, 4, 6, 9, 12, 13].iter().map(|&b| editor.render_offset(0, b)).collect();
+        assert_eq!(cols, vec![0, 4, 6, 8, 8, 10, 12, 13]);
+        assert_eq!(editor.line_width(0), 13);
+        assert_eq!(editor.char_offset(0, 9), 5);
+        // All of a character's columns are its first byte's.
+        assert_eq!(editor.orig_offset(0, 2), 0);
+        assert_eq!(editor.orig_offset(0, 7), 3);
+        assert_eq!(editor.orig_offset(0, 8), 6);
+        assert_eq!(editor.orig_offset(0, 13), 13);
+    }
+
+    #[test]
+    fn draws_wide_characters_cut_off_by_the_edge_as_spaces() {
+        let editor = laid_out("\tab\u{65e5}\u{672c}\u{8a9e}x", WrapMode::NoWrap, 20);
+        assert_eq!(drawn(&editor, 0, 2, 10), "  ab\u{65e5}\u{672c}");
+        assert_eq!(drawn(&editor, 0, 2, 9), "  ab\u{65e5} ");
+        assert_eq!(drawn(&editor, 0, 7, 11), " \u{672c} ");
+        assert_eq!(editor.render_cols(0, 7, 11).len(), 4);
+        // A byte that isn't part of a character takes up a column.
+        let mut editor = laid_out("a\u{e9}", WrapMode::NoWrap, 20);
+        editor.buffer.lines[0].orig.to_mut().insert(1, 0x80);
+        editor.update_render(0);
+        assert_eq!(editor.line_width(0), 3);
+    }
+
+    #[test]
+    fn moves_over_and_deletes_whole_characters() {
+        let mut editor = laid_out("\u{e9}\u{65e5}\tx", WrapMode::NoWrap, 20);
+        let mut stops = vec![editor.buffer.cursor.byte];
+        for _ in 0..4 {
+            editor.cursor_right();
+            stops.push(editor.buffer.cursor.byte);
+        }
+        assert_eq!(stops, vec![0, 2, 5, 6, 7]);
+        for _ in 0..3 {
+            editor.cursor_left();
+        }
+        assert_eq!(editor.buffer.cursor.byte, 2);
+        editor.delete_forward();
+        assert_eq!(&editor.buffer.lines[0].orig[..], "\u{e9}\tx".as_bytes());
+        editor.delete_back();
+        assert_eq!(&editor.buffer.lines[0].orig[..], b"\tx");
+        assert_eq!(editor.buffer.cursor.byte, 0);
+    }
+
+    #[test]
+    fn scrolls_horizontally_to_keep_the_cursor_away_from_the_edges() {
+        let mut editor = laid_out(&"0123456789".repeat(5), WrapMode::NoWrap, 20);
+        editor.buffer.config.h_scroll_margin = 3;
+        editor.buffer.cursor.byte = 30;
+        editor.scroll();
+        assert_eq!(editor.buffer.col_offset, 14);
+        assert_eq!(editor.buffer.cursor.pos.col, 16);
+        editor.buffer.cursor.byte = 20;
+        editor.scroll();
+        assert_eq!(editor.buffer.col_offset, 14);
+        editor.buffer.cursor.byte = 5;
+        editor.scroll();
+        assert_eq!((editor.buffer.col_offset, editor.buffer.cursor.pos.col), (2, 3));
+        editor.buffer.cursor.byte = 0;
+        editor.scroll();
+        assert_eq!(editor.buffer.col_offset, 0);
+    }
+
+    #[test]
+    fn scrolls_by_columns_past_tabs_and_wide_characters() {
+        let mut editor = laid_out("\t\t\u{65e5}\u{672c}\u{8a9e}\u{65e5}\u{672c}x", WrapMode::NoWrap, 10);
+        editor.buffer.config.h_scroll_margin = 2;
+        editor.buffer.cursor.byte = 17;
+        editor.scroll();
+        // The cursor is at column 18, on the window's eighth.
+        assert_eq!((editor.buffer.col_offset, editor.buffer.cursor.pos.col), (11, 7));
+        assert_eq!(screen(&mut editor)[0], "\u{ab}\u{8a9e}\u{65e5}\u{672c}x");
+        editor.buffer.cursor.byte = 8;
+        assert_eq!(screen(&mut editor)[0], "\u{ab} \u{8a9e}\u{65e5}\u{672c}x");
+        assert_eq!(editor.buffer.col_offset, 10);
+        editor.buffer.cursor.byte = 2;
+        assert_eq!(screen(&mut editor)[0], "\u{ab} \u{65e5}\u{672c}\u{8a9e} \u{bb}");
+        assert_eq!(editor.buffer.col_offset, 6);
+    }
+
+    #[test]
+    fn marks_where_a_line_goes_on_past_the_window() {
+        let mut editor = laid_out("abcdefghijklmn\nab", WrapMode::NoWrap, 8);
+        editor.buffer.config.h_scroll_margin = 0;
+        assert_eq!(screen(&mut editor)[..2], ["abcdefg\u{bb}", "ab"]);
+        editor.buffer.cursor.byte = 10;
+        editor.scroll();
+        assert_eq!(editor.buffer.col_offset, 3);
+        assert_eq!(screen(&mut editor)[..2], ["\u{ab}efghij\u{bb}", "\u{ab}"]);
+        editor.buffer.cursor.byte = 14;
+        assert_eq!(screen(&mut editor)[0], "\u{ab}ijklmn");
+    }
+
+    #[test]
+    fn breaks_words_after_spaces_and_punctuation() {
+        let mut editor = laid_out("the quick brown fox, jumped\nabcdefghijklmnop", WrapMode::Word, 10);
+        assert_eq!(screen(&mut editor)[..4], ["the quick ", "\u{21aa}brown ", "\u{21aa}fox, ", "\u{21aa}jumped"]);
+        editor.window_width = 6;
+        assert_eq!(screen(&mut editor)[..2], ["the ", "\u{21aa}quick"]);
+        // A word too long for a row is broken where the row ends.
+        assert_eq!(screen(&mut editor)[8..11], ["abcdef", "\u{21aa}ghijk", "\u{21aa}lmnop"]);
+    }
+
+    #[test]
+    fn indents_continuation_rows_as_the_line_is() {
+        let mut editor = laid_out("  aaaa bbbb cccc\n      dddd eeee", WrapMode::Word, 10);
+        assert_eq!(screen(&mut editor)[..3], ["  aaaa ", "  \u{21aa}bbbb ", "  \u{21aa}cccc"]);
+        // Unless the indentation takes up more than half of the window.
+        assert_eq!(screen(&mut editor)[3..5], ["      ", "\u{21aa}dddd eeee"]);
+        editor.buffer.config.wrap_indent = false;
+        editor.buffer.config.wrap_indicator = "> ".to_string();
+        editor.update_render(0);
+        assert_eq!(screen(&mut editor)[..3], ["  aaaa ", "> bbbb ", "> cccc"]);
+    }
+
+    #[test]
+    fn wraps_words_with_tabs_and_wide_characters() {
+        let mut editor = laid_out("ab\tcd ef\n\u{65e5}\u{672c}\u{8a9e}\u{65e5}\u{672c}\u{8a9e}", WrapMode::Word, 7);
+        assert_eq!(screen(&mut editor)[..2], ["ab  cd ", "\u{21aa}ef"]);
+        // A wide character that doesn't fit on a row goes on the next one.
+        editor.window_width = 5;
+        assert_eq!(screen(&mut editor)[3..6], ["\u{65e5}\u{672c}", "\u{21aa}\u{8a9e}\u{65e5}", "\u{21aa}\u{672c}\u{8a9e}"]);
+    }
+
+    #[test]
+    fn wraps_tabs_and_wide_characters_where_the_window_ends() {
+        let mut editor = laid_out("a\tb\u{65e5}\u{672c}c", WrapMode::Char, 4);
+        // A wide character the window's edge cuts in two is drawn as spaces.
+        assert_eq!(screen(&mut editor)[..4], ["a   ", "b\u{65e5} ", " c", "~"]);
+        assert_eq!(editor.line_n_rows(0), 3);
+        editor.buffer.cursor.byte = 6;
+        editor.scroll();
+        assert_eq!((editor.buffer.cursor.pos.row, editor.buffer.cursor.pos.col), (1, 3));
+        editor.buffer.cursor.byte = 9;
+        editor.scroll();
+        assert_eq!((editor.buffer.cursor.pos.row, editor.buffer.cursor.pos.col), (2, 1));
+    }
+
+    #[test]
+    fn moves_the_cursor_between_wrapped_rows() {
+        let mut editor = laid_out("\u{65e5}\u{672c}\u{8a9e}\u{65e5}\u{672c}\u{8a9e}\nab", WrapMode::Word, 5);
+        editor.buffer.cursor.byte = 3;
+        let mut stops = vec![];
+        for _ in 0..3 {
+            editor.cursor_down();
+            editor.scroll();
+            stops.push((editor.buffer.cursor.line, editor.buffer.cursor.byte, editor.buffer.cursor.pos.col));
+        }
+        assert_eq!(stops, vec![(0, 6, 1), (0, 12, 1), (1, 1, 1)]);
+        editor.cursor_up();
+        editor.cursor_up();
+        editor.scroll();
+        assert_eq!((editor.buffer.cursor.byte, editor.buffer.cursor.pos.row), (6, 1));
+        editor.buffer.cursor.byte = 9;
+        editor.scroll();
+        assert_eq!((editor.buffer.cursor.pos.row, editor.buffer.cursor.pos.col), (1, 3));
+    }
+}
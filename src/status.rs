@@ -0,0 +1,185 @@
+/// Something the status bar shows about the file or the editor.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Segment {
+    /// The path of the file relative to the working directory, or from the
+    /// home directory (as `~/...`) if it's not under the working directory.
+    Path,
+    AbsPath,
+    /// The file name alone.
+    Name,
+    /// `[+]` if the file has unsaved changes.
+    Modified,
+    FileType,
+    Encoding,
+    LineEnding,
+    /// The 1-based line of the cursor.
+    Line,
+    /// The 1-based column of the cursor, counted in characters.
+    Col,
+    /// The 1-based column of the cursor, counted in bytes.
+    Byte,
+    /// How far through the file the cursor is.
+    Percent,
+    /// The number of lines in the file.
+    Lines,
+    /// The number of characters selected.
+    Selection,
+    Mode,
+}
+
+const SEGMENTS: &[(&str, Segment)] = &[
+    ("path", Segment::Path),
+    ("abspath", Segment::AbsPath),
+    ("name", Segment::Name),
+    ("modified", Segment::Modified),
+    ("filetype", Segment::FileType),
+    ("encoding", Segment::Encoding),
+    ("eol", Segment::LineEnding),
+    ("line", Segment::Line),
+    ("col", Segment::Col),
+    ("byte", Segment::Byte),
+    ("percent", Segment::Percent),
+    ("lines", Segment::Lines),
+    ("selection", Segment::Selection),
+    ("mode", Segment::Mode),
+];
+
+/// A piece of a parsed status format.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Piece {
+    Text(String),
+    Segment(Segment),
+    /// What follows is aligned to the right.
+    Align,
+}
+
+/// Parses a status format, in which segments are named in braces (e.g.
+/// `{line}`), `{=}` aligns the rest to the right, and `{{` and `}}` stand for
+/// literal braces.
+pub fn parse(format: &str) -> Result<Vec<Piece>, String> {
+    let mut pieces = vec![];
+    let mut text = String::new();
+    let mut chars = format.chars().peekable();
+    let mut is_aligned = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut is_closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        is_closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !is_closed {
+                    return Err("unmatched '{' in status format (use '{{')".to_string());
+                }
+                if !text.is_empty() {
+                    pieces.push(Piece::Text(text.clone()));
+                    text.clear();
+                }
+                if name == "=" {
+                    if is_aligned {
+                        return Err("status format may only have one {=}".to_string());
+                    }
+                    is_aligned = true;
+                    pieces.push(Piece::Align);
+                    continue;
+                }
+                match SEGMENTS.iter().find(|(n, _)| *n == name) {
+                    Some((_, segment)) => pieces.push(Piece::Segment(*segment)),
+                    None => return Err(format!("unknown status segment {{{}}}", name)),
+                }
+            }
+            '}' => return Err("unmatched '}' in status format (use '}}')".to_string()),
+            _ => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        pieces.push(Piece::Text(text));
+    }
+    Ok(pieces)
+}
+
+/// Lays out the left and right parts of the status bar in `width` columns.
+/// The right part, which tends to be short and to hold the cursor position,
+/// is kept whole if it fits; the left part gets what's left and is cut
+/// short with an ellipsis if it doesn't fit.
+pub fn layout(left: &str, right: &str, width: usize) -> String {
+    let right = truncate(right, width);
+    let n_right = right.chars().count();
+    // Keep the two parts apart if both are shown.
+    let gap = if n_right > 0 && n_right < width { 1 } else { 0 };
+    let left = truncate(left, width - n_right - gap);
+    let n_left = left.chars().count();
+    format!("{}{}{}", left, " ".repeat(width - n_left - n_right), right)
+}
+
+/// Cuts `s` short to `width` characters, marking the cut with an ellipsis.
+fn truncate(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        s.to_string()
+    } else if width == 0 {
+        String::new()
+    } else {
+        let mut s: String = s.chars().take(width - 1).collect();
+        s.push('…');
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{layout, parse, Piece, Segment};
+
+    #[test]
+    fn parses_segments_and_text() {
+        assert_eq!(parse("{name}{modified} L{line}{=}{{{col}}}"), Ok(vec![
+            Piece::Segment(Segment::Name),
+            Piece::Segment(Segment::Modified),
+            Piece::Text(" L".to_string()),
+            Piece::Segment(Segment::Line),
+            Piece::Align,
+            Piece::Text("{".to_string()),
+            Piece::Segment(Segment::Col),
+            Piece::Text("}".to_string()),
+        ]));
+        assert_eq!(parse(""), Ok(vec![]));
+        assert_eq!(parse("é"), Ok(vec![Piece::Text("é".to_string())]));
+    }
+
+    #[test]
+    fn rejects_bad_formats() {
+        assert_eq!(parse("{line"), Err("unmatched '{' in status format (use '{{')".to_string()));
+        assert_eq!(parse("line}"), Err("unmatched '}' in status format (use '}}')".to_string()));
+        assert_eq!(parse("{=}{=}"), Err("status format may only have one {=}".to_string()));
+        assert_eq!(parse("{row}"), Err("unknown status segment {row}".to_string()));
+    }
+
+    #[test]
+    fn lays_out_both_parts() {
+        assert_eq!(layout("a.rs", "1:1", 10), "a.rs   1:1");
+        assert_eq!(layout("", "", 3), "   ");
+        assert_eq!(layout("a.rs", "", 6), "a.rs  ");
+    }
+
+    #[test]
+    fn cuts_the_left_part_short_first() {
+        assert_eq!(layout("long_name.rs", "1:1", 10), "long_… 1:1");
+        assert_eq!(layout("long_name.rs", "1:1", 4), " 1:1");
+        assert_eq!(layout("long_name.rs", "1:1", 5), "… 1:1");
+        assert_eq!(layout("ab", "12345", 3), "12…");
+        assert_eq!(layout("ab", "12345", 0), "");
+        assert_eq!(layout("ééé", "", 2), "é…");
+    }
+}
@@ -0,0 +1,646 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use config::{glob_match, toml};
+use regex::Regex;
+
+/// The class of token a byte of a line belongs to, which determines how the
+/// theme colours it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Highlight {
+    Normal,
+    Comment,
+    Keyword,
+    Type,
+    String,
+    Number,
+    Heading,
+    Emphasis,
+}
+
+impl Highlight {
+    pub fn from_name(name: &str) -> Option<Highlight> {
+        match name {
+            "normal" => Some(Highlight::Normal),
+            "comment" => Some(Highlight::Comment),
+            "keyword" => Some(Highlight::Keyword),
+            "type" => Some(Highlight::Type),
+            "string" => Some(Highlight::String),
+            "number" => Some(Highlight::Number),
+            "heading" => Some(Highlight::Heading),
+            "emphasis" => Some(Highlight::Emphasis),
+            _ => None,
+        }
+    }
+}
+
+/// The state a line leaves the highlighter in, which the next line starts
+/// with. This is how constructs that span several lines are highlighted.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Normal,
+    /// Inside a block comment, nested this deep.
+    Comment(usize),
+    /// Inside a multi-line string opened by the delimiter at this index of
+    /// `Syntax::strings`.
+    String(usize),
+    /// The rest of the line is highlighted as this, as after a line comment.
+    /// This is only ever the state part way through a line.
+    Rest(Highlight),
+}
+
+/// A string-like construct: anything between a start and an end delimiter.
+#[derive(Clone)]
+pub struct Delimiter {
+    pub start: String,
+    pub end: String,
+    pub hl: Highlight,
+    // Whether the construct may span several lines. Single line ones that
+    // aren't closed on the line they're opened on aren't highlighted.
+    pub multiline: bool,
+    // The byte that makes the byte after it not count as the end delimiter.
+    pub escape: Option<u8>,
+}
+
+/// How much of a line a `LineRule` highlights.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Extent {
+    Prefix,
+    /// The prefix and the word that follows it.
+    Word,
+    Line,
+}
+
+/// Highlights lines that start with `prefix`, not counting indentation.
+#[derive(Clone)]
+pub struct LineRule {
+    pub prefix: String,
+    pub hl: Highlight,
+    pub extent: Extent,
+}
+
+/// Highlights whatever matches `regex`.
+#[derive(Clone)]
+pub struct TokenRule {
+    pub regex: Regex,
+    pub hl: Highlight,
+}
+
+/// Describes how to highlight a language.
+#[derive(Clone)]
+pub struct Syntax {
+    pub keywords: Vec<String>,
+    // Highlighted as types, but otherwise just another list of keywords.
+    pub types: Vec<String>,
+    pub line_comment: Option<String>,
+    pub block_comment: Option<(String, String)>,
+    pub nested_comments: bool,
+    // If several delimiters match, the one with the longest start wins.
+    pub strings: Vec<Delimiter>,
+    pub numbers: bool,
+    // What numbers look like, if not as in most programming languages.
+    pub number_regex: Option<Regex>,
+    // Tried in order; at most one applies to a line.
+    pub line_rules: Vec<LineRule>,
+    // Tried in order wherever a comment or string doesn't start.
+    pub token_rules: Vec<TokenRule>,
+}
+
+/// Returns the built-in syntax for the language with the given name, as in
+/// `filetype::Language::name`.
+pub fn builtin(name: &str) -> Option<Syntax> {
+    let syntax = match name {
+        "rust" => Syntax {
+            keywords: words("as async await break const continue crate dyn else enum extern fn for if impl \
+                             in let loop match mod move mut pub ref return self Self static struct super \
+                             trait type unsafe use where while true false"),
+            types: words("bool char str u8 u16 u32 u64 u128 usize i8 i16 i32 i64 i128 isize f32 f64 \
+                          String Vec Option Result Box Some None Ok Err"),
+            line_comment: Some("//".to_string()),
+            block_comment: Some(("/*".to_string(), "*/".to_string())),
+            nested_comments: true,
+            strings: vec![delim("\"", "\"", Highlight::String, true, Some(b'\\'))],
+            line_rules: vec![rule("#", Highlight::Type, Extent::Line)],
+            ..Syntax::new()
+        },
+        "c" => Syntax {
+            keywords: words("auto break case const continue default do else enum extern for goto if \
+                             inline register restrict return sizeof static struct switch typedef union \
+                             volatile while NULL true false"),
+            types: words("char double float int long short signed unsigned void bool size_t ssize_t \
+                          int8_t int16_t int32_t int64_t uint8_t uint16_t uint32_t uint64_t"),
+            line_comment: Some("//".to_string()),
+            block_comment: Some(("/*".to_string(), "*/".to_string())),
+            strings: vec![
+                delim("\"", "\"", Highlight::String, false, Some(b'\\')),
+                delim("'", "'", Highlight::String, false, Some(b'\\')),
+            ],
+            line_rules: vec![rule("#", Highlight::Keyword, Extent::Word)],
+            ..Syntax::new()
+        },
+        "python" => Syntax {
+            keywords: words("and as assert async await break class continue def del elif else except \
+                             finally for from global if import in is lambda nonlocal not or pass raise \
+                             return try while with yield True False None self"),
+            types: words("int float str bytes bool list dict set tuple object type"),
+            line_comment: Some("#".to_string()),
+            strings: vec![
+                delim("\"\"\"", "\"\"\"", Highlight::String, true, Some(b'\\')),
+                delim("'''", "'''", Highlight::String, true, Some(b'\\')),
+                delim("\"", "\"", Highlight::String, false, Some(b'\\')),
+                delim("'", "'", Highlight::String, false, Some(b'\\')),
+            ],
+            line_rules: vec![rule("@", Highlight::Type, Extent::Word)],
+            ..Syntax::new()
+        },
+        "sh" => Syntax {
+            keywords: words("if then else elif fi case esac for while until do done in function \
+                             return break continue local export readonly set unset shift exit"),
+            types: words("echo printf read cd test eval exec source trap"),
+            line_comment: Some("#".to_string()),
+            strings: vec![
+                delim("\"", "\"", Highlight::String, true, Some(b'\\')),
+                delim("'", "'", Highlight::String, true, None),
+            ],
+            ..Syntax::new()
+        },
+        "json" => Syntax {
+            keywords: words("true false null"),
+            strings: vec![delim("\"", "\"", Highlight::String, false, Some(b'\\'))],
+            ..Syntax::new()
+        },
+        "toml" => Syntax {
+            keywords: words("true false"),
+            line_comment: Some("#".to_string()),
+            strings: vec![
+                delim("\"\"\"", "\"\"\"", Highlight::String, true, Some(b'\\')),
+                delim("'''", "'''", Highlight::String, true, None),
+                delim("\"", "\"", Highlight::String, false, Some(b'\\')),
+                delim("'", "'", Highlight::String, false, None),
+            ],
+            line_rules: vec![rule("[", Highlight::Heading, Extent::Line)],
+            ..Syntax::new()
+        },
+        "markdown" => Syntax {
+            strings: vec![
+                delim("```", "```", Highlight::String, true, None),
+                delim("`", "`", Highlight::String, false, None),
+                delim("**", "**", Highlight::Emphasis, false, Some(b'\\')),
+                delim("*", "*", Highlight::Emphasis, false, Some(b'\\')),
+            ],
+            numbers: false,
+            line_rules: vec![
+                rule("#", Highlight::Heading, Extent::Line),
+                rule(">", Highlight::Comment, Extent::Line),
+                rule("- ", Highlight::Keyword, Extent::Prefix),
+                rule("* ", Highlight::Keyword, Extent::Prefix),
+                rule("+ ", Highlight::Keyword, Extent::Prefix),
+            ],
+            ..Syntax::new()
+        },
+        _ => return None,
+    };
+    Some(syntax)
+}
+
+/// How far past where a token starts the highlighter looks to tell what
+/// the token is, so that the highlighting of a line up to some point only
+/// depends on what is a little past it, and a long line can be highlighted
+/// only as far as it's shown. A single line string closed further away than
+/// this isn't highlighted.
+pub const LOOKAHEAD: usize = 1 << 14;
+
+fn words(s: &str) -> Vec<String> {
+    s.split_whitespace().map(|w| w.to_string()).collect()
+}
+
+fn delim(start: &str, end: &str, hl: Highlight, multiline: bool, escape: Option<u8>) -> Delimiter {
+    Delimiter { start: start.to_string(), end: end.to_string(), hl, multiline, escape }
+}
+
+fn rule(prefix: &str, hl: Highlight, extent: Extent) -> LineRule {
+    LineRule { prefix: prefix.to_string(), hl, extent }
+}
+
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+impl Syntax {
+    /// Returns a syntax that highlights nothing but numbers.
+    pub fn new() -> Syntax {
+        Syntax {
+            keywords: vec![],
+            types: vec![],
+            line_comment: None,
+            block_comment: None,
+            nested_comments: false,
+            strings: vec![],
+            numbers: true,
+            number_regex: None,
+            line_rules: vec![],
+            token_rules: vec![],
+        }
+    }
+
+    /// Highlights `line` from `start`, where a token starts with the
+    /// highlighter in `state` (0 and the state the previous line left it in,
+    /// to begin with), until a token starts at or after `until` or the line
+    /// ends. Sets the class of each byte it goes over in `hl`, which is
+    /// lengthened as needed, and returns where it stopped and the state there,
+    /// which is the state for the next line if it's the end of the line.
+    pub fn highlight(&self, line: &[u8], start: usize, mut state: State, until: usize, hl: &mut Vec<Highlight>) -> (usize, State) {
+        let mut i = start;
+
+        if i == 0 && state == State::Normal {
+            // The indentation goes with whatever rule applies after it.
+            let indent = line.iter().take_while(|b| **b == b' ' || **b == b'\t').count();
+            fill(hl, 0, indent, Highlight::Normal);
+            i = indent;
+            if let Some(rule) = self.line_rules.iter().find(|r| line[indent..].starts_with(r.prefix.as_bytes())) {
+                let end = indent + rule.prefix.len();
+                match rule.extent {
+                    Extent::Prefix => {
+                        fill(hl, indent, end, rule.hl);
+                        i = end;
+                    }
+                    Extent::Word => {
+                        let end = end + line[end..].iter().take_while(|b| is_word_byte(**b)).count();
+                        fill(hl, indent, end, rule.hl);
+                        i = end;
+                    }
+                    Extent::Line => state = State::Rest(rule.hl),
+                }
+            }
+        }
+
+        while i < line.len() && i < until {
+            let rest = &line[i..];
+            // What a token may be told apart by.
+            let limit = cmp::min(line.len(), i + LOOKAHEAD);
+            match state {
+                State::Rest(class) => {
+                    let end = cmp::min(line.len(), until);
+                    fill(hl, i, end, class);
+                    i = end;
+                    continue;
+                }
+                State::Comment(depth) => {
+                    let (start, end) = match self.block_comment {
+                        Some((ref start, ref end)) => (start.as_bytes(), end.as_bytes()),
+                        None => (&b""[..], &b""[..]),
+                    };
+                    if self.nested_comments && !start.is_empty() && rest.starts_with(start) {
+                        fill(hl, i, i + start.len(), Highlight::Comment);
+                        i += start.len();
+                        state = State::Comment(depth + 1);
+                    } else if end.is_empty() || rest.starts_with(end) {
+                        fill(hl, i, i + end.len(), Highlight::Comment);
+                        i += end.len();
+                        state = if depth > 1 { State::Comment(depth - 1) } else { State::Normal };
+                    } else {
+                        fill(hl, i, i + 1, Highlight::Comment);
+                        i += 1;
+                    }
+                    continue;
+                }
+                State::String(idx) => {
+                    let d = &self.strings[idx];
+                    if Some(rest[0]) == d.escape && rest.len() > 1 {
+                        fill(hl, i, i + 2, d.hl);
+                        i += 2;
+                    } else if rest.starts_with(d.end.as_bytes()) {
+                        fill(hl, i, i + d.end.len(), d.hl);
+                        i += d.end.len();
+                        state = State::Normal;
+                    } else {
+                        fill(hl, i, i + 1, d.hl);
+                        i += 1;
+                    }
+                    continue;
+                }
+                State::Normal => {}
+            }
+
+            if let Some(ref token) = self.line_comment {
+                if rest.starts_with(token.as_bytes()) {
+                    state = State::Rest(Highlight::Comment);
+                    continue;
+                }
+            }
+
+            if let Some((ref start, _)) = self.block_comment {
+                if rest.starts_with(start.as_bytes()) {
+                    fill(hl, i, i + start.len(), Highlight::Comment);
+                    i += start.len();
+                    state = State::Comment(1);
+                    continue;
+                }
+            }
+
+            let string = self.strings.iter().enumerate()
+                .filter(|&(_, d)| rest.starts_with(d.start.as_bytes()))
+                .max_by_key(|&(_, d)| d.start.len());
+            if let Some((idx, d)) = string {
+                if d.multiline {
+                    fill(hl, i, i + d.start.len(), d.hl);
+                    i += d.start.len();
+                    state = State::String(idx);
+                    continue;
+                }
+                if let Some(len) = closed_len(&line[i..limit], d) {
+                    fill(hl, i, i + len, d.hl);
+                    i += len;
+                    continue;
+                }
+            }
+
+            let token = self.token_rules.iter()
+                .filter_map(|rule| Some((rule.regex.match_within(line, i, limit)?, rule.hl)))
+                .find(|&(end, _)| end > i);
+            if let Some((end, class)) = token {
+                fill(hl, i, end, class);
+                i = end;
+                continue;
+            }
+
+            let follows_word = i > 0 && is_word_byte(line[i - 1]);
+            if !follows_word && is_word_byte(rest[0]) {
+                if self.numbers {
+                    let len = match self.number_regex {
+                        Some(ref regex) => regex.match_within(line, i, limit).map_or(0, |end| end - i),
+                        None if rest[0].is_ascii_digit() => number_len(rest),
+                        None => 0,
+                    };
+                    if len > 0 {
+                        fill(hl, i, i + len, Highlight::Number);
+                        i += len;
+                        continue;
+                    }
+                }
+                let len = rest.iter().take_while(|b| is_word_byte(**b)).count();
+                let word = &rest[..len];
+                let class = if self.keywords.iter().any(|k| k.as_bytes() == word) {
+                    Highlight::Keyword
+                } else if self.types.iter().any(|k| k.as_bytes() == word) {
+                    Highlight::Type
+                } else {
+                    Highlight::Normal
+                };
+                fill(hl, i, i + len, class);
+                i += len;
+                continue;
+            }
+
+            fill(hl, i, i + 1, Highlight::Normal);
+            i += 1;
+        }
+
+        if i >= line.len() {
+            i = line.len();
+            if let State::Rest(_) = state {
+                state = State::Normal;
+            }
+        }
+        (i, state)
+    }
+}
+
+/// Sets the class of the bytes from `start` to `end`, lengthening `hl` to
+/// reach `end` if it's shorter.
+fn fill(hl: &mut Vec<Highlight>, start: usize, end: usize, class: Highlight) {
+    if hl.len() < end {
+        hl.resize(end, Highlight::Normal);
+    }
+    for h in &mut hl[start..end] {
+        *h = class;
+    }
+}
+
+/// Returns the length of the single line construct that `s` starts with, if
+/// it's closed before the end of `s`.
+fn closed_len(s: &[u8], d: &Delimiter) -> Option<usize> {
+    let mut i = d.start.len();
+    while i < s.len() {
+        if Some(s[i]) == d.escape {
+            i += 2;
+        } else if s[i..].starts_with(d.end.as_bytes()) {
+            return Some(i + d.end.len());
+        } else {
+            i += 1;
+        }
+    }
+    None
+}
+
+/// Returns the length of the number that `s` starts with, including any
+/// fractional part, exponent and type suffix (e.g. 0x1f, 1.5e3, 10u32).
+fn number_len(s: &[u8]) -> usize {
+    let mut i = 0;
+    while i < s.len() {
+        let b = s[i];
+        let is_fraction_dot = b == b'.' && s.get(i + 1).is_some_and(|b| b.is_ascii_digit());
+        let is_exponent_sign = (b == b'+' || b == b'-') && i > 0 && (s[i - 1] == b'e' || s[i - 1] == b'E')
+            && !s.starts_with(b"0x");
+        if is_word_byte(b) || is_fraction_dot || is_exponent_sign {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    i
+}
+
+/// A syntax loaded from a definition file in the config directory.
+#[derive(Clone)]
+pub struct Definition {
+    pub name: String,
+    pub path: PathBuf,
+    // Glob patterns matched against the file name, or the whole path if they
+    // contain a slash.
+    pub patterns: Vec<String>,
+    pub syntax: Syntax,
+    // What was wrong with the definition. Whatever could be understood of it
+    // is used regardless.
+    pub errors: Vec<String>,
+}
+
+impl Definition {
+    /// Returns whether the definition applies to the file at `path`.
+    pub fn matches(&self, path: &Path) -> bool {
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let full = path.to_string_lossy();
+        self.patterns.iter().any(|pattern| {
+            let subject = if pattern.contains('/') { &full[..] } else { &name[..] };
+            glob_match(pattern.as_bytes(), subject.as_bytes())
+        })
+    }
+
+    fn apply_entry(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        let syntax = &mut self.syntax;
+        match key {
+            "name" => self.name = value.str(key)?.to_lowercase(),
+            "files" => self.patterns = value.strs(key)?,
+            "keywords" => syntax.keywords = value.strs(key)?,
+            "types" => syntax.types = value.strs(key)?,
+            "line_comment" => syntax.line_comment = Some(value.str(key)?),
+            "block_comment" => {
+                let delims = value.strs(key)?;
+                if delims.len() != 2 {
+                    return Err(format!("{} must have a start and an end", key));
+                }
+                syntax.block_comment = Some((delims[0].clone(), delims[1].clone()));
+            }
+            "nested_comments" => syntax.nested_comments = value.bool(key)?,
+            "numbers" => syntax.numbers = value.bool(key)?,
+            "number_regex" => syntax.number_regex = Some(Regex::new(&value.str(key)?)?),
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Loads the definitions in the `.toml` files in `dir`, in the order of their
+/// file names. A definition is named after its file unless it says otherwise.
+pub fn load_dir(dir: &Path) -> Vec<Definition> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return vec![],
+    };
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+    paths.iter().map(|path| load(path)).collect()
+}
+
+fn load(path: &Path) -> Definition {
+    let mut def = Definition {
+        name: path.file_stem().map(|s| s.to_string_lossy().to_lowercase()).unwrap_or_default(),
+        path: path.to_path_buf(),
+        patterns: vec![],
+        syntax: Syntax::new(),
+        errors: vec![],
+    };
+    let mut text = String::new();
+    if let Err(e) = File::open(path).and_then(|mut file| file.read_to_string(&mut text)) {
+        def.errors.push(e.to_string());
+        return def;
+    }
+    let entries = match toml::parse(&text) {
+        Ok(entries) => entries,
+        Err(e) => {
+            def.errors.push(format!("line {}", e));
+            return def;
+        }
+    };
+
+    // The `[string.<label>]` and `[token.<label>]` tables, by label, in the
+    // order they first appear in.
+    let mut strings: Vec<(String, usize, StringDef)> = vec![];
+    let mut tokens: Vec<(String, usize, TokenDef)> = vec![];
+    for entry in &entries {
+        let table: Vec<&str> = entry.table.iter().map(|s| s.as_str()).collect();
+        let result = match table.as_slice() {
+            [] => def.apply_entry(&entry.key, &entry.value),
+            ["string", label] => table_entry(&mut strings, label, entry.line).apply_entry(&entry.key, &entry.value),
+            ["token", label] => table_entry(&mut tokens, label, entry.line).apply_entry(&entry.key, &entry.value),
+            _ => Err(format!("unknown table [{}]", entry.table.join("."))),
+        };
+        if let Err(e) = result {
+            def.errors.push(format!("line {}: {}", entry.line, e));
+        }
+    }
+
+    for (label, line, string) in strings {
+        match (string.start, string.end) {
+            (Some(start), end) => {
+                let end = end.unwrap_or_else(|| start.clone());
+                def.syntax.strings.push(delim(&start, &end, string.hl, string.multiline, string.escape));
+            }
+            _ => def.errors.push(format!("line {}: [string.{}] has no start", line, label)),
+        }
+    }
+    for (label, line, token) in tokens {
+        match (token.regex, token.hl) {
+            (Some(regex), Some(hl)) => def.syntax.token_rules.push(TokenRule { regex, hl }),
+            (None, _) => def.errors.push(format!("line {}: [token.{}] has no regex", line, label)),
+            (_, None) => def.errors.push(format!("line {}: [token.{}] has no class", line, label)),
+        }
+    }
+    def
+}
+
+struct StringDef {
+    start: Option<String>,
+    end: Option<String>,
+    hl: Highlight,
+    multiline: bool,
+    escape: Option<u8>,
+}
+
+impl Default for StringDef {
+    fn default() -> StringDef {
+        StringDef { start: None, end: None, hl: Highlight::String, multiline: false, escape: None }
+    }
+}
+
+impl StringDef {
+    fn apply_entry(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        match key {
+            "start" => self.start = Some(non_empty(key, value.str(key)?)?),
+            "end" => self.end = Some(non_empty(key, value.str(key)?)?),
+            "class" => self.hl = class(key, value)?,
+            "multiline" => self.multiline = value.bool(key)?,
+            "escape" => {
+                let escape = value.str(key)?;
+                if escape.len() != 1 {
+                    return Err(format!("{} must be a single character", key));
+                }
+                self.escape = Some(escape.as_bytes()[0]);
+            }
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct TokenDef {
+    regex: Option<Regex>,
+    hl: Option<Highlight>,
+}
+
+impl TokenDef {
+    fn apply_entry(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        match key {
+            "regex" => self.regex = Some(Regex::new(&value.str(key)?)?),
+            "class" => self.hl = Some(class(key, value)?),
+            _ => return Err(format!("unknown setting '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Returns the definition of the table with the given label, adding it if
+/// it's not there yet.
+fn table_entry<'a, T: Default>(tables: &'a mut Vec<(String, usize, T)>, label: &str, line: usize) -> &'a mut T {
+    match tables.iter().position(|(l, _, _)| l == label) {
+        Some(i) => &mut tables[i].2,
+        None => {
+            tables.push((label.to_string(), line, T::default()));
+            &mut tables.last_mut().unwrap().2
+        }
+    }
+}
+
+fn class(key: &str, value: &toml::Value) -> Result<Highlight, String> {
+    let name = value.str(key)?;
+    Highlight::from_name(&name).ok_or_else(|| {
+        format!("{} must be one of normal, comment, keyword, type, string, number, heading or emphasis", key)
+    })
+}
+
+fn non_empty(key: &str, s: String) -> Result<String, String> {
+    if s.is_empty() { Err(format!("{} must not be empty", key)) } else { Ok(s) }
+}
@@ -0,0 +1,123 @@
+use config::TabBar;
+use theme::Element;
+use {Editor, Pos};
+
+impl Editor {
+    /// Returns whether the tab bar is shown, on the top row of the screen.
+    pub fn has_tab_bar(&self) -> bool {
+        match self.base_config.tab_bar {
+            TabBar::Auto => self.buffers.len() > 1,
+            TabBar::Always => true,
+            TabBar::Never => false,
+        }
+    }
+
+    fn tab_label(&self, idx: usize) -> String {
+        format!(" {} ", self.buffer_name(idx))
+    }
+
+    /// Scrolls the tab bar so that the tab of the buffer being edited is
+    /// shown, along with as many of the tabs before it as fit.
+    fn scroll_tabs(&mut self) {
+        if self.current < self.tab_offset || self.tab_offset >= self.buffers.len() {
+            self.tab_offset = self.current;
+        }
+        let widths: Vec<usize> = (0..self.buffers.len()).map(|i| self.tab_label(i).chars().count()).collect();
+        let (current, width) = (self.current, self.screen_width);
+        // Tabs cut off on either side leave a column for a marker.
+        let fits = |offset: usize| {
+            let left = if offset > 0 { 1 } else { 0 };
+            let right = if current + 1 < widths.len() { 1 } else { 0 };
+            left + widths[offset..=current].iter().sum::<usize>() + right <= width
+        };
+        while self.tab_offset < self.current && !fits(self.tab_offset) {
+            self.tab_offset += 1;
+        }
+    }
+
+    /// Returns the tabs shown on the tab bar, as the column each starts at,
+    /// the buffer it's for and its label, which may be cut short, and
+    /// whether there are more tabs than fit after them.
+    fn tabs_shown(&self) -> (Vec<(usize, usize, String)>, bool) {
+        let n = self.buffers.len();
+        let mut col = if self.tab_offset > 0 { 1 } else { 0 };
+        let mut tabs = vec![];
+        for idx in self.tab_offset..n {
+            let label = self.tab_label(idx);
+            let room = self.screen_width.saturating_sub(col + if idx + 1 < n { 1 } else { 0 });
+            let width = label.chars().count();
+            if width > room && !tabs.is_empty() {
+                return (tabs, true);
+            }
+            // The only tab shown is cut short if it's too wide.
+            let label: String = label.chars().take(room).collect();
+            tabs.push((col, idx, label));
+            col += width;
+        }
+        (tabs, false)
+    }
+
+    pub fn build_tab_bar(&mut self) {
+        self.scroll_tabs();
+        let (tabs, is_more) = self.tabs_shown();
+        self.move_cursor(Pos { row: 0, col: 0 });
+        self.write_style(Element::TabBar);
+        self.clear_row();
+        if self.tab_offset > 0 {
+            self.write_buf.extend(b"<");
+        }
+        for (col, idx, label) in tabs {
+            self.move_cursor(Pos { row: 0, col });
+            self.write_style(if idx == self.current { Element::ActiveTab } else { Element::TabBar });
+            self.write_buf.extend(label.as_bytes());
+        }
+        if is_more {
+            self.move_cursor(Pos { row: 0, col: self.screen_width - 1 });
+            self.write_style(Element::TabBar);
+            self.write_buf.extend(b">");
+        }
+        self.defer_esc_seq("m");
+    }
+
+    /// Handles a click of the left mouse button at `pos`. Clicking a tab
+    /// switches to its buffer.
+    pub fn click(&mut self, pos: Pos) {
+        if !self.has_tab_bar() || pos.row != 0 {
+            return;
+        }
+        let (tabs, _) = self.tabs_shown();
+        let tab = tabs.iter().find(|(col, _, label)| *col <= pos.col && pos.col < col + label.chars().count());
+        if let Some(&(_, idx, _)) = tab {
+            self.switch_buffer(idx);
+        }
+    }
+
+    pub fn move_tab_left(&mut self) {
+        if self.current > 0 {
+            let idx = self.current - 1;
+            self.swap_tabs(idx);
+        }
+    }
+
+    pub fn move_tab_right(&mut self) {
+        if self.current + 1 < self.buffers.len() {
+            let idx = self.current + 1;
+            self.swap_tabs(idx);
+        }
+    }
+
+    /// Swaps the buffer being edited with the one at `idx` in the order of
+    /// the tabs.
+    fn swap_tabs(&mut self, idx: usize) {
+        let current = self.current;
+        self.buffers.swap(current, idx);
+        self.current = idx;
+        for window in &mut self.windows {
+            if window.buffer == current {
+                window.buffer = idx;
+            } else if window.buffer == idx {
+                window.buffer = current;
+            }
+        }
+    }
+}
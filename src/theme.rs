@@ -0,0 +1,398 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use config::toml;
+use syntax::Highlight;
+
+/// How many colours the terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorDepth {
+    Ansi16,
+    Indexed256,
+    TrueColor,
+}
+
+impl ColorDepth {
+    pub fn from_name(name: &str) -> Option<ColorDepth> {
+        match name {
+            "16" => Some(ColorDepth::Ansi16),
+            "256" => Some(ColorDepth::Indexed256),
+            "truecolor" => Some(ColorDepth::TrueColor),
+            _ => None,
+        }
+    }
+
+    /// Guesses the terminal's colour depth from the `COLORTERM` and `TERM`
+    /// environment variables, assuming the 16 basic colours if they don't
+    /// say otherwise.
+    pub fn detect() -> ColorDepth {
+        ColorDepth::from_env(&env::var("COLORTERM").unwrap_or_default(), &env::var("TERM").unwrap_or_default())
+    }
+
+    fn from_env(colorterm: &str, term: &str) -> ColorDepth {
+        if colorterm == "truecolor" || colorterm == "24bit" || term.ends_with("-direct") {
+            ColorDepth::TrueColor
+        } else if term.contains("256color") {
+            ColorDepth::Indexed256
+        } else {
+            ColorDepth::Ansi16
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Color {
+    /// One of the 16 basic colours, the bright ones being 8 to 15.
+    Ansi(u8),
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+const COLOR_NAMES: &[&str] = &[
+    "black", "red", "green", "yellow", "blue", "magenta", "cyan", "white",
+    "bright_black", "bright_red", "bright_green", "bright_yellow",
+    "bright_blue", "bright_magenta", "bright_cyan", "bright_white",
+];
+
+// What the 16 basic colours usually look like (xterm's defaults), for finding
+// the one closest to a colour the terminal can't show.
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255),
+];
+
+// The levels of each component in the 6x6x6 colour cube of the 256 colours.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+impl Color {
+    /// Parses a colour name, such as "red" or "bright_blue", a colour index
+    /// between 0 and 255, or an RGB colour in `#rrggbb` form.
+    fn parse(s: &str) -> Option<Color> {
+        if let Some(hex) = s.strip_prefix('#') {
+            if hex.len() != 6 || !hex.is_ascii() {
+                return None;
+            }
+            let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+            return Some(Color::Rgb(component(0)?, component(2)?, component(4)?));
+        }
+        if let Some(i) = COLOR_NAMES.iter().position(|name| *name == s) {
+            return Some(Color::Ansi(i as u8));
+        }
+        s.parse().ok().map(Color::Indexed)
+    }
+
+    fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Color::Ansi(n) => ANSI_RGB[n as usize % 16],
+            Color::Indexed(n) if n < 16 => ANSI_RGB[n as usize],
+            Color::Indexed(n) if n < 232 => {
+                let n = n as usize - 16;
+                (CUBE_LEVELS[n / 36], CUBE_LEVELS[n / 6 % 6], CUBE_LEVELS[n % 6])
+            }
+            Color::Indexed(n) => {
+                let gray = 8 + 10 * (n - 232);
+                (gray, gray, gray)
+            }
+            Color::Rgb(r, g, b) => (r, g, b),
+        }
+    }
+
+    /// Returns the closest colour that can be shown at `depth`.
+    fn downgrade(self, depth: ColorDepth) -> Color {
+        match (self, depth) {
+            (Color::Ansi(_), _) | (_, ColorDepth::TrueColor) => self,
+            (Color::Indexed(_), ColorDepth::Indexed256) => self,
+            (Color::Indexed(n), ColorDepth::Ansi16) if n < 16 => Color::Ansi(n),
+            (Color::Rgb(r, g, b), ColorDepth::Indexed256) => Color::Indexed(nearest_indexed(r, g, b)),
+            (_, ColorDepth::Ansi16) => {
+                let rgb = self.rgb();
+                let i = (0..16).min_by_key(|i| distance(ANSI_RGB[*i], rgb)).unwrap();
+                Color::Ansi(i as u8)
+            }
+        }
+    }
+
+    /// Returns the SGR parameters that select the colour as the foreground,
+    /// or as the background if `is_bg`.
+    fn sgr(self, is_bg: bool) -> String {
+        let base = if is_bg { 40 } else { 30 };
+        match self {
+            Color::Ansi(n) if n < 8 => (base + n as u32).to_string(),
+            Color::Ansi(n) => (base + 60 + n as u32 - 8).to_string(),
+            Color::Indexed(n) => format!("{};5;{}", base + 8, n),
+            Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
+        }
+    }
+}
+
+fn distance((r1, g1, b1): (u8, u8, u8), (r2, g2, b2): (u8, u8, u8)) -> u32 {
+    let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2) as u32;
+    d(r1, r2) + d(g1, g2) + d(b1, b2)
+}
+
+/// Returns the index of the colour in the cube or the grayscale ramp of the
+/// 256 colours that is closest to the given one.
+fn nearest_indexed(r: u8, g: u8, b: u8) -> u8 {
+    let level = |v: u8| (0..6).min_by_key(|i| (CUBE_LEVELS[*i] as i32 - v as i32).abs()).unwrap();
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+    let avg = (r as u32 + g as u32 + b as u32) / 3;
+    let gray = 232 + (avg.saturating_sub(3) / 10).min(23) as usize;
+    let candidates = [cube as u8, gray as u8];
+    *candidates.iter().min_by_key(|n| distance(Color::Indexed(**n).rgb(), (r, g, b))).unwrap()
+}
+
+/// How to draw something. Unset colours are the terminal's defaults.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Style {
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub reverse: bool,
+}
+
+impl Style {
+    /// Returns the escape sequence that switches to the style, using no more
+    /// colours than `depth` allows.
+    pub fn esc_seq(&self, depth: ColorDepth) -> String {
+        // Always start from scratch so that nothing of the previous style
+        // carries over.
+        let mut params = vec!["0".to_string()];
+        let flags = [(self.bold, "1"), (self.italic, "3"), (self.underline, "4"), (self.reverse, "7")];
+        params.extend(flags.iter().filter(|(on, _)| *on).map(|(_, p)| p.to_string()));
+        if let Some(fg) = self.fg {
+            params.push(fg.downgrade(depth).sgr(false));
+        }
+        if let Some(bg) = self.bg {
+            params.push(bg.downgrade(depth).sgr(true));
+        }
+        format!("\x1b[{}m", params.join(";"))
+    }
+
+    fn apply_entry(&mut self, key: &str, value: &toml::Value) -> Result<(), String> {
+        match key {
+            "fg" | "bg" => {
+                let name = value.str(key)?;
+                let color = match name.as_str() {
+                    "default" => None,
+                    _ => Some(Color::parse(&name).ok_or_else(|| format!("invalid colour '{}'", name))?),
+                };
+                if key == "fg" { self.fg = color } else { self.bg = color }
+            }
+            "bold" => self.bold = value.bool(key)?,
+            "italic" => self.italic = value.bool(key)?,
+            "underline" => self.underline = value.bool(key)?,
+            "reverse" => self.reverse = value.bool(key)?,
+            _ => return Err(format!("unknown style attribute '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+/// The things a theme gives a style to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Element {
+    /// Text highlighted as the given token class.
+    Text(Highlight),
+    StatusBar,
+    /// The status bars of the windows other than the focused one, and the
+    /// separators between side by side windows.
+    InactiveStatusBar,
+    /// The tab bar, and the tabs of the files other than the one being
+    /// edited.
+    TabBar,
+    ActiveTab,
+    MessageBar,
+    Selection,
+    SearchMatch,
+    Gutter,
+    /// The `~` that fills the rows past the end of the file.
+    Filler,
+}
+
+impl Element {
+    fn from_name(name: &str) -> Option<Element> {
+        match name {
+            "status_bar" => Some(Element::StatusBar),
+            "status_bar_inactive" => Some(Element::InactiveStatusBar),
+            "tab_bar" => Some(Element::TabBar),
+            "tab_active" => Some(Element::ActiveTab),
+            "message_bar" => Some(Element::MessageBar),
+            "selection" => Some(Element::Selection),
+            "search_match" => Some(Element::SearchMatch),
+            "gutter" => Some(Element::Gutter),
+            "filler" => Some(Element::Filler),
+            _ => Highlight::from_name(name).map(Element::Text),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Theme {
+    pub name: String,
+    styles: Vec<(Element, Style)>,
+}
+
+const BUILTIN: &[(&str, &str)] = &[
+    ("default", include_str!("themes/default.toml")),
+    ("gruvbox", include_str!("themes/gruvbox.toml")),
+];
+
+impl Theme {
+    /// Returns the style of `element`. Colours the theme doesn't give it are
+    /// those of normal text.
+    pub fn style(&self, element: Element) -> Style {
+        let style = |element| self.styles.iter().find(|(e, _)| *e == element).map(|(_, s)| *s);
+        let normal = style(Element::Text(Highlight::Normal)).unwrap_or_default();
+        match style(element) {
+            Some(style) => Style { fg: style.fg.or(normal.fg), bg: style.bg.or(normal.bg), ..style },
+            None => normal,
+        }
+    }
+
+    /// Returns the built-in theme with the given name.
+    pub fn builtin(name: &str) -> Option<Theme> {
+        let (_, text) = BUILTIN.iter().find(|(n, _)| *n == name)?;
+        // The built-in themes are known to be valid.
+        Some(Theme::parse(name, text, &[]).unwrap())
+    }
+
+    /// Parses a theme file. Themes are based on the default theme, or on the
+    /// theme named by the `inherits` key at the top of the file, which must
+    /// be built in or among `others`.
+    fn parse(name: &str, text: &str, others: &[Theme]) -> Result<Theme, String> {
+        let mut theme = Theme { name: name.to_string(), styles: vec![] };
+        let entries = toml::parse(text)?;
+        let inherits = entries.iter().find(|e| e.table.is_empty() && e.key == "inherits");
+        if name != "default" || inherits.is_some() {
+            let base = match inherits {
+                Some(entry) => entry.value.str("inherits").map_err(|e| format!("{}: {}", entry.line, e))?,
+                None => "default".to_string(),
+            };
+            let base = others.iter().find(|t| t.name == base).cloned()
+                .or_else(|| Theme::builtin(&base))
+                .ok_or_else(|| format!("{}: unknown theme '{}'", inherits.map_or(0, |e| e.line), base))?;
+            theme.styles = base.styles;
+        }
+
+        for entry in &entries {
+            let result = match entry.table.as_slice() {
+                [] if entry.key == "inherits" => Ok(()),
+                [] => Err(format!("unknown setting '{}'", entry.key)),
+                [element] => match Element::from_name(element) {
+                    Some(element) => theme.style_entry(element).apply_entry(&entry.key, &entry.value),
+                    None => Err(format!("unknown element [{}]", element)),
+                },
+                _ => Err(format!("unknown table [{}]", entry.table.join("."))),
+            };
+            result.map_err(|e| format!("{}: {}", entry.line, e))?;
+        }
+        Ok(theme)
+    }
+
+    /// Returns the style of `element`, adding a default one if the theme
+    /// doesn't style it yet.
+    fn style_entry(&mut self, element: Element) -> &mut Style {
+        match self.styles.iter().position(|(e, _)| *e == element) {
+            Some(i) => &mut self.styles[i].1,
+            None => {
+                self.styles.push((element, Style::default()));
+                &mut self.styles.last_mut().unwrap().1
+            }
+        }
+    }
+}
+
+/// Returns whether there is a built-in theme with the given name.
+pub fn is_builtin(name: &str) -> bool {
+    BUILTIN.iter().any(|(n, _)| *n == name)
+}
+
+/// Loads the themes in the `.toml` files in `dir`, each named after its file,
+/// in the order of their file names so that themes may inherit from those
+/// before them. A file that can't be loaded is skipped, and returned by the
+/// name of its theme along with where and why it couldn't be.
+pub fn load_dir(dir: &Path) -> (Vec<Theme>, Vec<(String, String)>) {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return (vec![], vec![]),
+    };
+    paths.retain(|p| p.extension().is_some_and(|ext| ext == "toml"));
+    paths.sort();
+
+    let mut themes = vec![];
+    let mut errors = vec![];
+    for path in paths {
+        let name = path.file_stem().map(|s| s.to_string_lossy().to_string()).unwrap_or_default();
+        let mut text = String::new();
+        let theme = File::open(&path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| format!("{}: {}", path.display(), e))
+            .and_then(|_| Theme::parse(&name, &text, &themes).map_err(|e| format!("{}:{}", path.display(), e)));
+        match theme {
+            Ok(theme) => themes.push(theme),
+            Err(e) => errors.push((name, e)),
+        }
+    }
+    (themes, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{nearest_indexed, Color, ColorDepth};
+
+    #[test]
+    fn detects_the_color_depth() {
+        assert_eq!(ColorDepth::from_env("truecolor", "xterm-256color"), ColorDepth::TrueColor);
+        assert_eq!(ColorDepth::from_env("24bit", ""), ColorDepth::TrueColor);
+        assert_eq!(ColorDepth::from_env("", "xterm-direct"), ColorDepth::TrueColor);
+        assert_eq!(ColorDepth::from_env("", "screen-256color"), ColorDepth::Indexed256);
+        assert_eq!(ColorDepth::from_env("yes", "xterm"), ColorDepth::Ansi16);
+        assert_eq!(ColorDepth::from_env("", ""), ColorDepth::Ansi16);
+    }
+
+    #[test]
+    fn finds_the_nearest_of_the_256_colors() {
+        assert_eq!(nearest_indexed(0, 0, 0), 16);
+        assert_eq!(nearest_indexed(255, 255, 255), 231);
+        assert_eq!(nearest_indexed(255, 0, 0), 196);
+        assert_eq!(nearest_indexed(0x87, 0xaf, 0xff), 111);
+        assert_eq!(nearest_indexed(0x80, 0x80, 0x80), 244);
+        assert_eq!(nearest_indexed(0x12, 0x12, 0x12), 233);
+        assert_eq!(nearest_indexed(0xee, 0xee, 0xee), 255);
+    }
+
+    #[test]
+    fn downgrades_truecolor_to_256_colors() {
+        let depth = ColorDepth::Indexed256;
+        assert_eq!(Color::Rgb(0xfb, 0x49, 0x34).downgrade(depth), Color::Indexed(203));
+        assert_eq!(Color::Rgb(0x28, 0x28, 0x28).downgrade(depth), Color::Indexed(235));
+        assert_eq!(Color::Indexed(167).downgrade(depth), Color::Indexed(167));
+        assert_eq!(Color::Ansi(9).downgrade(depth), Color::Ansi(9));
+        assert_eq!(Color::Rgb(1, 2, 3).downgrade(ColorDepth::TrueColor), Color::Rgb(1, 2, 3));
+    }
+
+    #[test]
+    fn downgrades_to_16_colors() {
+        let depth = ColorDepth::Ansi16;
+        assert_eq!(Color::Indexed(3).downgrade(depth), Color::Ansi(3));
+        assert_eq!(Color::Indexed(196).downgrade(depth), Color::Ansi(9));
+        assert_eq!(Color::Indexed(234).downgrade(depth), Color::Ansi(0));
+        assert_eq!(Color::Indexed(250).downgrade(depth), Color::Ansi(7));
+        assert_eq!(Color::Rgb(0, 0, 200).downgrade(depth), Color::Ansi(4));
+        assert_eq!(Color::Rgb(250, 250, 250).downgrade(depth), Color::Ansi(15));
+    }
+
+    #[test]
+    fn writes_sgr_parameters() {
+        assert_eq!(Color::Ansi(1).sgr(false), "31");
+        assert_eq!(Color::Ansi(9).sgr(true), "101");
+        assert_eq!(Color::Indexed(200).sgr(false), "38;5;200");
+        assert_eq!(Color::Rgb(1, 2, 3).sgr(true), "48;2;1;2;3");
+    }
+}
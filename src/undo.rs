@@ -0,0 +1,139 @@
+use std::cmp;
+use std::time::Duration;
+
+use loader::Text;
+use {Editor, Line};
+
+/// A change to the lines of a buffer: `n_new` lines from `line` on took the
+/// place of the lines in `old`.
+struct Change {
+    line: usize,
+    old: Vec<Text>,
+    n_new: usize,
+}
+
+/// The changes that are undone, or redone, in one go, in the order they
+/// were made, and where the cursor goes back to when they are.
+struct Step {
+    changes: Vec<Change>,
+    cursor: (usize, usize),
+}
+
+/// The changes made to a buffer that can be undone, the latest last, and
+/// those undone since that can be redone.
+#[derive(Default)]
+pub struct Undo {
+    done: Vec<Step>,
+    undone: Vec<Step>,
+    // Whether changes are added to the last step rather than starting one.
+    is_open: bool,
+}
+
+impl Undo {
+    /// Has the next change start a step of its own.
+    pub fn seal(&mut self) {
+        self.is_open = false;
+    }
+
+    /// Copies the text of the changes that are pieces of a mapped file, which
+    /// is about to be overwritten.
+    pub fn own_text(&mut self) {
+        let steps = self.done.iter_mut().chain(self.undone.iter_mut());
+        for change in steps.flat_map(|step| step.changes.iter_mut()) {
+            for text in &mut change.old {
+                if let Text::Mapped(..) = *text {
+                    *text = Text::Owned(text.to_vec());
+                }
+            }
+        }
+    }
+}
+
+impl Editor {
+    /// Records that the `n_old` lines from `line_idx` on are about to be
+    /// replaced by `n_new` lines, so that this can be undone. This must come
+    /// before every change to the lines of the buffer being edited, except
+    /// for giving a buffer without any lines the empty line it stands for.
+    pub fn record_change(&mut self, line_idx: usize, n_old: usize, n_new: usize) {
+        let cursor = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        let undo = &mut self.buffer.undo;
+        undo.undone.clear();
+        if !undo.is_open {
+            undo.done.push(Step { changes: vec![], cursor });
+            undo.is_open = true;
+        }
+        let step = undo.done.last_mut().unwrap();
+        // Changes to the lines a change of the same step made, such as the
+        // characters of a word typed one by one, only add to it.
+        if let Some(last) = step.changes.last_mut() {
+            if last.line <= line_idx && line_idx + n_old <= last.line + last.n_new {
+                last.n_new = last.n_new - n_old + n_new;
+                return;
+            }
+        }
+        let old = self.buffer.lines[line_idx..line_idx + n_old].iter().map(|line| line.orig.clone()).collect();
+        step.changes.push(Change { line: line_idx, old, n_new });
+    }
+
+    /// Undoes the last step of changes that hasn't been.
+    pub fn undo(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        match self.buffer.undo.done.pop() {
+            Some(step) => {
+                let step = self.revert(step);
+                self.buffer.undo.undone.push(step);
+            }
+            None => self.new_status_msg("Nothing to undo", Duration::from_secs(3)),
+        }
+    }
+
+    /// Makes the last step of changes undone again, unless others have been
+    /// made since.
+    pub fn redo(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        match self.buffer.undo.undone.pop() {
+            Some(step) => {
+                let step = self.revert(step);
+                self.buffer.undo.done.push(step);
+            }
+            None => self.new_status_msg("Nothing to redo", Duration::from_secs(3)),
+        }
+    }
+
+    /// Puts back the lines the changes of `step` replaced, latest first, and
+    /// returns the step that reverts that in turn.
+    fn revert(&mut self, step: Step) -> Step {
+        self.buffer.undo.seal();
+        let cursor = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        let mut changes = vec![];
+        for change in step.changes.into_iter().rev() {
+            let n_old = change.old.len();
+            let end = change.line + change.n_new;
+            let new = change.old.into_iter().map(Line::new);
+            let old = self.buffer.lines.splice(change.line..end, new).map(|line| line.orig).collect();
+            // Pushing lines down may change how the lines below start.
+            self.update_syntax(change.line);
+            for line_idx in change.line..change.line + n_old {
+                self.update_render(line_idx);
+            }
+            changes.push(Change { line: change.line, old, n_new: n_old });
+        }
+        let (line, byte) = step.cursor;
+        self.buffer.cursor.line = cmp::min(line, self.buffer.lines.len().saturating_sub(1));
+        self.buffer.cursor.byte = self.buffer.lines.get(self.buffer.cursor.line).map_or(0, |line| cmp::min(byte, line.orig.len()));
+        self.buffer.cursor.is_at_eol = false;
+        self.mark = None;
+        self.buffer.is_dirty = true;
+        Step { changes, cursor }
+    }
+
+    /// Forgets the changes made to the buffer being edited, as its lines
+    /// have been replaced as a whole.
+    pub fn clear_undo(&mut self) {
+        self.buffer.undo = Undo::default();
+    }
+}
@@ -0,0 +1,1164 @@
+use std::cmp;
+use std::mem;
+use std::time::Duration;
+
+use config::Profile;
+use keymap;
+use macros;
+use prompt::{self, Prompt};
+use regex::Regex;
+use {display_path, Editor, Line, TextPos};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Normal,
+    Insert,
+    /// Selects characters.
+    Visual,
+    /// Selects whole lines.
+    VisualLine,
+}
+
+/// The state of vi's modal editing.
+pub struct Vi {
+    pub mode: Mode,
+    // The keys of the command being typed.
+    pub keys: Vec<String>,
+    // The text last deleted or yanked, and whether it's whole lines.
+    register: Vec<u8>,
+    is_register_linewise: bool,
+    // The keys of the last change, which `.` repeats, and of the change
+    // being made, which goes on until insert mode is left.
+    last_change: Vec<String>,
+    change: Option<Vec<String>>,
+    is_repeating: bool,
+    // Where visual mode was entered.
+    pub anchor: TextPos,
+    // The last `f`, `t`, `F` or `T` and the byte it looked for, which `;` and
+    // `,` repeat.
+    last_find: Option<(u8, u8)>,
+}
+
+impl Vi {
+    pub fn new() -> Vi {
+        Vi {
+            mode: Mode::Normal,
+            keys: vec![],
+            register: vec![],
+            is_register_linewise: false,
+            last_change: vec![],
+            change: None,
+            is_repeating: false,
+            anchor: (0, 0),
+            last_find: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motion {
+    Left,
+    Right,
+    Down,
+    Up,
+    WordStart,
+    WordBack,
+    WordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FileStart,
+    FileEnd,
+    // One of `f`, `t`, `F` or `T`, and the byte to look for.
+    Find(u8, u8),
+    RepeatFind,
+    RepeatFindBack,
+}
+
+/// Whether the text a motion moves over includes the character it ends on,
+/// or consists of whole lines.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Extent {
+    Exclusive,
+    Inclusive,
+    Linewise,
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Move(Motion),
+    // An operator (`d`, `c` or `y`) and the motion whose text it acts on.
+    Operate(u8, Motion),
+    // An operator typed twice, which acts on whole lines.
+    OperateLines(u8),
+    Replace(u8),
+    // `q` and the register to record a macro into.
+    Record(char),
+    // `@` and the register of the macro to play, or none for `@@`.
+    Play(Option<char>),
+    // A command of a single key, such as `i` or `p`.
+    Action(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Parse<T> {
+    Incomplete,
+    Invalid,
+    Done(T),
+}
+
+/// The single-key commands of normal mode, and those of visual mode.
+const ACTIONS: &[&str] = &["i", "a", "I", "A", "o", "O", "x", "X", "D", "C", "s", "p", "P", "J", "u", "ctrl-r", "v", "V", ".", ":", "esc"];
+const VISUAL_ACTIONS: &[&str] = &["d", "x", "c", "s", "y", "o", "v", "V", ":", "esc"];
+
+/// The single-key commands that change the text, which `.` repeats.
+const CHANGES: &[&str] = &["i", "a", "I", "A", "o", "O", "x", "X", "D", "C", "s", "p", "P", "J"];
+
+/// Parses a count, which can't start with `0` since that is a motion.
+fn parse_count(keys: &[String], i: &mut usize) -> Option<usize> {
+    let mut count: Option<usize> = None;
+    while let Some(key) = keys.get(*i) {
+        match key.parse::<usize>() {
+            Ok(d) if key.len() == 1 && (d > 0 || count.is_some()) => {
+                count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(d));
+                *i += 1;
+            }
+            _ => break,
+        }
+    }
+    count
+}
+
+fn parse_motion(keys: &[String]) -> Parse<Motion> {
+    let motion = match keys[0].as_str() {
+        "h" | "left" | "backspace" => Motion::Left,
+        "l" | "right" | "space" => Motion::Right,
+        "j" | "down" | "enter" => Motion::Down,
+        "k" | "up" => Motion::Up,
+        "w" => Motion::WordStart,
+        "b" => Motion::WordBack,
+        "e" => Motion::WordEnd,
+        "0" | "home" => Motion::LineStart,
+        "^" => Motion::FirstNonBlank,
+        "$" | "end" => Motion::LineEnd,
+        "G" => Motion::FileEnd,
+        ";" => Motion::RepeatFind,
+        "," => Motion::RepeatFindBack,
+        "g" => match keys.get(1).map(|k| k.as_str()) {
+            None => return Parse::Incomplete,
+            Some("g") => Motion::FileStart,
+            Some(_) => return Parse::Invalid,
+        },
+        "f" | "t" | "F" | "T" => match keys.get(1) {
+            None => return Parse::Incomplete,
+            Some(key) => match keymap::key_byte(key) {
+                Some(b) => Motion::Find(keys[0].as_bytes()[0], b),
+                None => return Parse::Invalid,
+            },
+        },
+        _ => return Parse::Invalid,
+    };
+    Parse::Done(motion)
+}
+
+/// Parses the keys of a normal or visual mode command, along with its count.
+fn parse(keys: &[String], is_visual: bool) -> Parse<(Option<usize>, Command)> {
+    let mut i = 0;
+    let count = parse_count(keys, &mut i);
+    let key = match keys.get(i) {
+        Some(key) => key.as_str(),
+        None => return Parse::Incomplete,
+    };
+    if !is_visual && (key == "d" || key == "c" || key == "y") {
+        let op = key.as_bytes()[0];
+        i += 1;
+        // A count may also come between the operator and the motion, and the
+        // two multiply.
+        let count = match (count, parse_count(keys, &mut i)) {
+            (Some(a), Some(b)) => Some(a.saturating_mul(b)),
+            (a, b) => a.or(b),
+        };
+        return match keys.get(i) {
+            None => Parse::Incomplete,
+            Some(k) if *k == key => Parse::Done((count, Command::OperateLines(op))),
+            Some(_) => match parse_motion(&keys[i..]) {
+                Parse::Done(motion) => Parse::Done((count, Command::Operate(op, motion))),
+                Parse::Incomplete => Parse::Incomplete,
+                Parse::Invalid => Parse::Invalid,
+            },
+        };
+    }
+    match parse_motion(&keys[i..]) {
+        Parse::Done(motion) => return Parse::Done((count, Command::Move(motion))),
+        Parse::Incomplete => return Parse::Incomplete,
+        Parse::Invalid => {}
+    }
+    if key == "r" && !is_visual {
+        return match keys.get(i + 1) {
+            None => Parse::Incomplete,
+            Some(k) => match keymap::key_byte(k) {
+                Some(b) => Parse::Done((count, Command::Replace(b))),
+                None => Parse::Invalid,
+            },
+        };
+    }
+    if (key == "q" && !is_visual) || key == "@" {
+        let mut chars = match keys.get(i + 1) {
+            None => return Parse::Incomplete,
+            Some(k) => k.chars(),
+        };
+        return match (chars.next(), chars.next()) {
+            (Some('@'), None) if key == "@" => Parse::Done((count, Command::Play(None))),
+            (Some(c), None) if macros::is_register(c) => Parse::Done((count, match key {
+                "q" => Command::Record(c),
+                _ => Command::Play(Some(c)),
+            })),
+            _ => Parse::Invalid,
+        };
+    }
+    let actions = if is_visual { VISUAL_ACTIONS } else { ACTIONS };
+    if actions.contains(&key) {
+        Parse::Done((count, Command::Action(key.to_string())))
+    } else {
+        Parse::Invalid
+    }
+}
+
+/// Classifies a byte for word motions: blanks (and the ends of lines), word
+/// characters and other, punctuation-like, characters.
+fn class(b: Option<u8>) -> u8 {
+    match b {
+        None | Some(b' ') | Some(b'\t') => 0,
+        Some(b) if b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80 => 2,
+        Some(_) => 1,
+    }
+}
+
+/// Splits the argument of `:s`, such as `/a/b/g`, into the pattern, the
+/// replacement and the flags. The first character is the delimiter, which
+/// can be escaped with a backslash.
+fn split_substitution(arg: &str) -> Option<(String, String, String)> {
+    let mut chars = arg.chars();
+    let delim = chars.next()?;
+    let mut parts = vec![String::new()];
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(c) if c == delim => parts.last_mut().unwrap().push(c),
+                Some(c) => {
+                    parts.last_mut().unwrap().push('\\');
+                    parts.last_mut().unwrap().push(c);
+                }
+                None => parts.last_mut().unwrap().push('\\'),
+            }
+        } else if c == delim && parts.len() < 3 {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    parts.resize(3, String::new());
+    let flags = parts.pop().unwrap();
+    let replacement = parts.pop().unwrap();
+    Some((parts.pop().unwrap(), replacement, flags))
+}
+
+/// Expands the replacement of `:s` for a match: `&` stands for the match,
+/// and `\&` and `\\` for a literal `&` and backslash.
+fn expand_replacement(replacement: &str, matched: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut bytes = replacement.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'&' => out.extend(matched),
+            b'\\' => match bytes.next() {
+                Some(b'n') => out.push(b'\n'),
+                Some(b't') => out.push(b'\t'),
+                Some(b) => out.push(b),
+                None => out.push(b'\\'),
+            },
+            _ => out.push(b),
+        }
+    }
+    out
+}
+
+impl Editor {
+    /// Handles a key in vi's normal or visual mode, returning false for keys
+    /// that vi doesn't use, which go to the key bindings instead. In insert
+    /// mode only Esc is handled, which returns to normal mode.
+    pub fn vi_handle_key(&mut self, key: &str) -> bool {
+        if self.vi.mode == Mode::Insert {
+            if let Some(ref mut change) = self.vi.change {
+                change.push(key.to_string());
+            }
+            if key == "esc" {
+                self.vi_leave_insert();
+                return true;
+            }
+            return false;
+        }
+        if self.buffer.lines.is_empty() {
+            // A large file has no lines until the first of them are loaded,
+            // and until then there is nothing for keys to act on.
+            if self.buffer.loading.is_some() {
+                return true;
+            }
+            self.buffer.lines.push(Line::new(vec![]));
+        }
+        self.vi.keys.push(key.to_string());
+        if self.recording.is_some() && self.vi.keys == ["q"] {
+            self.vi.keys.clear();
+            self.stop_recording();
+            return true;
+        }
+        let is_visual = self.vi.mode != Mode::Normal;
+        match parse(&self.vi.keys, is_visual) {
+            Parse::Incomplete => true,
+            Parse::Invalid => {
+                let keys = mem::take(&mut self.vi.keys);
+                // A key that starts no command, such as Ctrl-S, runs what
+                // it's bound to, but a printable one mustn't be inserted.
+                keys.len() > 1 || keymap::key_byte(key).is_some() || key == "tab"
+            }
+            Parse::Done((count, command)) => {
+                let keys = mem::take(&mut self.vi.keys);
+                let is_change = match command {
+                    Command::Operate(op, _) | Command::OperateLines(op) => op != b'y',
+                    Command::Replace(_) => true,
+                    Command::Action(ref action) => !is_visual && CHANGES.contains(&action.as_str()),
+                    Command::Move(_) | Command::Record(_) | Command::Play(_) => false,
+                };
+                if is_change && !self.vi.is_repeating {
+                    self.vi.change = Some(keys);
+                }
+                self.vi_run(count, command);
+                if self.vi.mode != Mode::Insert {
+                    if let Some(change) = self.vi.change.take() {
+                        self.vi.last_change = change;
+                    }
+                    self.vi_clamp_cursor();
+                }
+                true
+            }
+        }
+    }
+
+    /// Returns the name of the vi mode the editor is in, or nothing if vi
+    /// isn't used.
+    pub fn vi_mode_name(&self) -> &'static str {
+        if self.buffer.config.profile != Profile::Vi {
+            return "";
+        }
+        if self.prompt.is_some() {
+            return "COMMAND";
+        }
+        match self.vi.mode {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Visual => "VISUAL",
+            Mode::VisualLine => "V-LINE",
+        }
+    }
+
+    /// Returns the DECSCUSR parameter of the cursor shape for the vi mode:
+    /// a bar to insert, an underline while a command is being typed, and a
+    /// block otherwise.
+    pub fn vi_cursor_shape(&self) -> u8 {
+        if self.prompt.is_some() || self.vi.mode == Mode::Insert {
+            6
+        } else if !self.vi.keys.is_empty() {
+            4
+        } else {
+            2
+        }
+    }
+
+    /// Returns the start and (exclusive) end of the text selected in visual
+    /// mode.
+    pub fn vi_selection(&self) -> Option<(TextPos, TextPos)> {
+        let cursor = (self.buffer.cursor.line, self.buffer.cursor.byte);
+        let (start, end) = (cmp::min(self.vi.anchor, cursor), cmp::max(self.vi.anchor, cursor));
+        match self.vi.mode {
+            Mode::Visual => Some((start, (end.0, cmp::min(end.1 + 1, self.vi_len(end.0))))),
+            Mode::VisualLine => Some(((start.0, 0), (end.0, self.vi_len(end.0)))),
+            Mode::Normal | Mode::Insert => None,
+        }
+    }
+
+    fn vi_run(&mut self, count: Option<usize>, command: Command) {
+        let n = count.unwrap_or(1);
+        match command {
+            Command::Move(motion) => {
+                if let Some(((line, byte), _)) = self.vi_motion_target(motion, count, false) {
+                    self.buffer.cursor.line = line;
+                    self.buffer.cursor.byte = byte;
+                    match motion {
+                        Motion::LineEnd => self.buffer.cursor.is_at_eol = true,
+                        Motion::Down | Motion::Up => {}
+                        _ => self.buffer.cursor.is_at_eol = false,
+                    }
+                }
+            }
+            Command::Operate(op, motion) => {
+                // Like `ce`, `cw` leaves the blanks after the word alone.
+                let motion = if op == b'c' && motion == Motion::WordStart && class(self.byte_at(self.vi_cursor())) != 0 {
+                    Motion::WordEnd
+                } else {
+                    motion
+                };
+                if let Some((target, extent)) = self.vi_motion_target(motion, count, true) {
+                    let cursor = self.vi_cursor();
+                    self.vi_operate(op, cursor, target, extent);
+                }
+            }
+            Command::OperateLines(op) => {
+                let line = self.buffer.cursor.line;
+                let last = cmp::min(line + n - 1, self.buffer.lines.len() - 1);
+                self.vi_operate(op, (line, 0), (last, 0), Extent::Linewise);
+            }
+            Command::Replace(b) => {
+                if !self.check_editable() {
+                    return;
+                }
+                let (line, byte) = self.vi_cursor();
+                if byte + n <= self.vi_len(line) {
+                    self.record_change(line, 1, 1);
+                    for b_ref in &mut self.buffer.lines[line].orig.to_mut()[byte..byte + n] {
+                        *b_ref = b;
+                    }
+                    self.update_render_at(line, byte, n, n);
+                    self.buffer.cursor.byte = byte + n - 1;
+                    self.buffer.is_dirty = true;
+                }
+            }
+            Command::Record(register) => self.start_recording(register),
+            Command::Play(register) => self.play_register(register, n),
+            Command::Action(ref action) if self.vi.mode == Mode::Normal => self.vi_action(action, count),
+            Command::Action(ref action) => self.vi_visual_action(action),
+        }
+    }
+
+    fn vi_action(&mut self, action: &str, count: Option<usize>) {
+        let n = count.unwrap_or(1);
+        let cursor = self.vi_cursor();
+        let len = self.vi_len(cursor.0);
+        match action {
+            "i" => self.vi.mode = Mode::Insert,
+            "a" => {
+                self.buffer.cursor.byte = cmp::min(cursor.1 + 1, len);
+                self.vi.mode = Mode::Insert;
+            }
+            "I" => {
+                self.buffer.cursor.byte = self.vi_first_nonblank(cursor.0);
+                self.vi.mode = Mode::Insert;
+            }
+            "A" => {
+                self.buffer.cursor.byte = len;
+                self.vi.mode = Mode::Insert;
+            }
+            // Opening a line can't be done in a read-only or loading buffer,
+            // and the line it would have opened isn't there to move to.
+            "o" | "O" if !self.check_editable() => {}
+            "o" => {
+                self.buffer.cursor.byte = len;
+                self.insert_newline();
+                self.vi.mode = Mode::Insert;
+            }
+            "O" => {
+                self.buffer.cursor.byte = 0;
+                self.insert_newline();
+                self.buffer.cursor.line -= 1;
+                self.vi.mode = Mode::Insert;
+            }
+            "x" if len > 0 => self.vi_operate(b'd', cursor, (cursor.0, cmp::min(cursor.1 + n, len)), Extent::Exclusive),
+            "X" => self.vi_operate(b'd', cursor, (cursor.0, cursor.1.saturating_sub(n)), Extent::Exclusive),
+            "D" => self.vi_operate(b'd', cursor, (cursor.0, len), Extent::Exclusive),
+            "C" => self.vi_operate(b'c', cursor, (cursor.0, len), Extent::Exclusive),
+            "s" => self.vi_operate(b'c', cursor, (cursor.0, cmp::min(cursor.1 + n, len)), Extent::Exclusive),
+            "p" | "P" => self.vi_put(action == "P", n),
+            "J" => {
+                for _ in 0..cmp::max(n.saturating_sub(1), 1) {
+                    self.vi_join_line();
+                }
+            }
+            "u" => {
+                for _ in 0..n {
+                    self.undo();
+                }
+            }
+            "ctrl-r" => {
+                for _ in 0..n {
+                    self.redo();
+                }
+            }
+            "v" | "V" => {
+                self.vi.anchor = cursor;
+                self.vi.mode = if action == "v" { Mode::Visual } else { Mode::VisualLine };
+            }
+            "." => self.vi_repeat(count),
+            ":" => self.vi_open_command_line(),
+            _ => {}
+        }
+    }
+
+    fn vi_visual_action(&mut self, action: &str) {
+        let (start, end) = match self.vi_selection() {
+            Some(selection) => selection,
+            None => return,
+        };
+        let extent = if self.vi.mode == Mode::VisualLine { Extent::Linewise } else { Extent::Exclusive };
+        let mode = self.vi.mode;
+        self.vi.mode = Mode::Normal;
+        match action {
+            "d" | "x" => self.vi_operate(b'd', start, end, extent),
+            "c" | "s" => self.vi_operate(b'c', start, end, extent),
+            "y" => self.vi_operate(b'y', start, end, extent),
+            "o" => {
+                let cursor = self.vi_cursor();
+                self.buffer.cursor.line = self.vi.anchor.0;
+                self.buffer.cursor.byte = self.vi.anchor.1;
+                self.vi.anchor = cursor;
+                self.vi.mode = mode;
+            }
+            // Switch between the two kinds of visual mode, or leave it.
+            "v" if mode == Mode::VisualLine => self.vi.mode = Mode::Visual,
+            "V" if mode == Mode::Visual => self.vi.mode = Mode::VisualLine,
+            ":" => self.vi_open_command_line(),
+            _ => {}
+        }
+    }
+
+    /// Acts on the text from `from` to `to` with the operator `op`: `d`
+    /// deletes it, `c` deletes it and enters insert mode, and `y` copies it.
+    /// Both are kept in the register.
+    fn vi_operate(&mut self, op: u8, from: TextPos, to: TextPos, extent: Extent) {
+        if op != b'y' && !self.check_editable() {
+            return;
+        }
+        let (start, mut end) = (cmp::min(from, to), cmp::max(from, to));
+        if extent == Extent::Linewise {
+            // An empty last line is what's left after the file's final line
+            // break, so it's only acted on by itself.
+            let last = self.buffer.lines.len() - 1;
+            if end.0 == last && start.0 < last && self.vi_len(last) == 0 {
+                end.0 -= 1;
+            }
+            let text = self.text_range((start.0, 0), (end.0, self.vi_len(end.0)));
+            self.vi.register = text;
+            self.vi.is_register_linewise = true;
+            match op {
+                b'd' => {
+                    self.record_change(start.0, end.0 - start.0 + 1, 0);
+                    self.buffer.lines.drain(start.0..end.0 + 1);
+                    if self.buffer.lines.is_empty() {
+                        self.record_change(0, 0, 1);
+                        self.buffer.lines.push(Line::new(vec![]));
+                    }
+                    let line = cmp::min(start.0, self.buffer.lines.len() - 1);
+                    if start.0 < self.buffer.lines.len() {
+                        self.update_syntax(start.0);
+                    }
+                    self.buffer.cursor.line = line;
+                    self.buffer.cursor.byte = self.vi_first_nonblank(line);
+                    self.buffer.is_dirty = true;
+                }
+                b'c' => {
+                    self.delete_range((start.0, 0), (end.0, self.vi_len(end.0)));
+                    self.vi.mode = Mode::Insert;
+                }
+                _ => self.buffer.cursor.line = start.0,
+            }
+            return;
+        }
+        let end = if extent == Extent::Inclusive {
+            (end.0, cmp::min(end.1 + 1, self.vi_len(end.0)))
+        } else {
+            end
+        };
+        self.vi.register = self.text_range(start, end);
+        self.vi.is_register_linewise = false;
+        match op {
+            b'd' => self.delete_range(start, end),
+            b'c' => {
+                self.delete_range(start, end);
+                self.vi.mode = Mode::Insert;
+            }
+            _ => {
+                self.buffer.cursor.line = start.0;
+                self.buffer.cursor.byte = start.1;
+            }
+        }
+    }
+
+    /// Puts the text in the register `count` times after the cursor, or
+    /// before it. Whole lines go below or above the cursor's line.
+    fn vi_put(&mut self, is_before: bool, count: usize) {
+        if !self.check_editable() {
+            return;
+        }
+        if self.vi.register.is_empty() && !self.vi.is_register_linewise {
+            return;
+        }
+        if self.vi.is_register_linewise {
+            let at = if is_before { self.buffer.cursor.line } else { self.buffer.cursor.line + 1 };
+            let register = self.vi.register.clone();
+            let lines: Vec<&[u8]> = register.split(|b| *b == b'\n').collect();
+            self.record_change(at, 0, count * lines.len());
+            for i in 0..count * lines.len() {
+                self.buffer.lines.insert(at + i, Line::new(lines[i % lines.len()].to_vec()));
+                self.update_render(at + i);
+            }
+            self.buffer.cursor.line = at;
+            self.buffer.cursor.byte = self.vi_first_nonblank(at);
+            self.buffer.is_dirty = true;
+        } else {
+            if !is_before && self.vi_len(self.buffer.cursor.line) > 0 {
+                self.buffer.cursor.byte += 1;
+            }
+            let text = self.vi.register.repeat(count);
+            self.insert_text(&text);
+            // Leave the cursor on the last character put.
+            self.buffer.cursor.byte = self.buffer.cursor.byte.saturating_sub(1);
+        }
+    }
+
+    /// Joins the next line to the cursor's, with a space between them in
+    /// place of the next line's indentation.
+    fn vi_join_line(&mut self) {
+        if !self.check_editable() {
+            return;
+        }
+        let line = self.buffer.cursor.line;
+        if line + 1 >= self.buffer.lines.len() {
+            return;
+        }
+        self.record_change(line, 2, 1);
+        let next = self.buffer.lines.remove(line + 1);
+        let start = next.orig.iter().position(|b| *b != b' ' && *b != b'\t').unwrap_or(next.orig.len());
+        let orig = self.buffer.lines[line].orig.to_mut();
+        while let Some(b' ') | Some(b'\t') = orig.last() {
+            orig.pop();
+        }
+        self.buffer.cursor.byte = orig.len();
+        if !orig.is_empty() && start < next.orig.len() && next.orig[start] != b')' {
+            orig.push(b' ');
+        }
+        orig.extend_from_slice(&next.orig[start..]);
+        self.update_render(line);
+        self.buffer.is_dirty = true;
+    }
+
+    /// Repeats the last change, with a different count if one is given.
+    fn vi_repeat(&mut self, count: Option<usize>) {
+        let mut keys = self.vi.last_change.clone();
+        if let Some(count) = count {
+            let mut i = 0;
+            parse_count(&keys, &mut i);
+            keys.drain(..i);
+            for (j, c) in count.to_string().chars().enumerate() {
+                keys.insert(j, c.to_string());
+            }
+        }
+        self.vi.is_repeating = true;
+        for key in keys {
+            self.handle_named_key(&key);
+        }
+        self.vi.is_repeating = false;
+    }
+
+    fn vi_leave_insert(&mut self) {
+        self.vi.mode = Mode::Normal;
+        if let Some(change) = self.vi.change.take() {
+            self.vi.last_change = change;
+        }
+        self.buffer.cursor.byte = self.buffer.cursor.byte.saturating_sub(1);
+        self.buffer.cursor.is_at_eol = false;
+        self.vi_clamp_cursor();
+    }
+
+    /// Keeps the cursor on a character in normal mode, rather than past the
+    /// end of the line.
+    fn vi_clamp_cursor(&mut self) {
+        if self.vi.mode == Mode::Insert || self.buffer.lines.is_empty() {
+            return;
+        }
+        self.buffer.cursor.line = cmp::min(self.buffer.cursor.line, self.buffer.lines.len() - 1);
+        let len = self.vi_len(self.buffer.cursor.line);
+        self.buffer.cursor.byte = cmp::min(self.buffer.cursor.byte, len.saturating_sub(1));
+    }
+
+    fn vi_cursor(&self) -> TextPos {
+        (self.buffer.cursor.line, self.buffer.cursor.byte)
+    }
+
+    fn vi_len(&self, line: usize) -> usize {
+        self.buffer.lines[line].orig.len()
+    }
+
+    fn vi_first_nonblank(&self, line: usize) -> usize {
+        let orig = &self.buffer.lines[line].orig;
+        orig.iter().position(|b| *b != b' ' && *b != b'\t').unwrap_or(orig.len())
+    }
+
+    fn vi_is_empty_line(&self, pos: TextPos) -> bool {
+        pos.1 == 0 && self.vi_len(pos.0) == 0
+    }
+
+    /// Returns the start of the next word, stopping at empty lines.
+    fn vi_word_start(&self, start: TextPos) -> TextPos {
+        let mut pos = start;
+        let c = class(self.byte_at(pos));
+        if c != 0 {
+            while class(self.byte_at(pos)) == c {
+                match self.next_pos(pos) {
+                    Some(next) => pos = next,
+                    None => return pos,
+                }
+            }
+        }
+        while class(self.byte_at(pos)) == 0 && !(pos != start && self.vi_is_empty_line(pos)) {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => break,
+            }
+        }
+        pos
+    }
+
+    /// Returns the end of the word the cursor is in, or of the next one if
+    /// it's already at the end of one.
+    fn vi_word_end(&self, start: TextPos) -> TextPos {
+        let mut pos = match self.next_pos(start) {
+            Some(next) => next,
+            None => return start,
+        };
+        while class(self.byte_at(pos)) == 0 {
+            match self.next_pos(pos) {
+                Some(next) => pos = next,
+                None => return pos,
+            }
+        }
+        let c = class(self.byte_at(pos));
+        while let Some(next) = self.next_pos(pos) {
+            if class(self.byte_at(next)) != c {
+                break;
+            }
+            pos = next;
+        }
+        pos
+    }
+
+    /// Returns the start of the word the cursor is in, or of the previous
+    /// one if it's already at the start of one, stopping at empty lines.
+    fn vi_word_back(&self, start: TextPos) -> TextPos {
+        let mut pos = match self.prev_pos(start) {
+            Some(prev) => prev,
+            None => return start,
+        };
+        while class(self.byte_at(pos)) == 0 && !self.vi_is_empty_line(pos) {
+            match self.prev_pos(pos) {
+                Some(prev) => pos = prev,
+                None => return pos,
+            }
+        }
+        let c = class(self.byte_at(pos));
+        if c == 0 {
+            return pos;
+        }
+        while let Some(prev) = self.prev_pos(pos) {
+            if class(self.byte_at(prev)) != c {
+                break;
+            }
+            pos = prev;
+        }
+        pos
+    }
+
+    /// Returns where a motion repeated `count` times takes the cursor, and
+    /// how much of the text it covers for an operator, or nothing if it
+    /// can't move.
+    fn vi_motion_target(&mut self, motion: Motion, count: Option<usize>, is_operator: bool) -> Option<(TextPos, Extent)> {
+        let n = count.unwrap_or(1);
+        let cursor = self.vi_cursor();
+        let (line, byte) = cursor;
+        let len = self.vi_len(line);
+        let last_line = self.buffer.lines.len() - 1;
+        let target = match motion {
+            Motion::Left => ((line, byte.saturating_sub(n)), Extent::Exclusive),
+            Motion::Right => ((line, cmp::min(byte + n, len)), Extent::Exclusive),
+            Motion::Down | Motion::Up => {
+                let target = if motion == Motion::Down {
+                    self.shown_lines_down(line, n)
+                } else {
+                    self.shown_lines_up(line, n)
+                };
+                if target == line && !is_operator {
+                    return None;
+                }
+                let byte = if self.buffer.cursor.is_at_eol {
+                    self.vi_len(target)
+                } else {
+                    let col = self.render_offset(line, byte);
+                    self.orig_offset(target, col)
+                };
+                ((target, byte), Extent::Linewise)
+            }
+            Motion::WordStart => {
+                let mut pos = cursor;
+                for _ in 0..n {
+                    pos = self.vi_word_start(pos);
+                }
+                if is_operator && pos.0 > line {
+                    // An operator stops at the end of the last word moved
+                    // over rather than going on to the next line.
+                    let mut end = pos;
+                    while let Some(prev) = self.prev_pos(end) {
+                        if prev < cursor || class(self.byte_at(prev)) != 0 {
+                            break;
+                        }
+                        end = prev;
+                    }
+                    pos = end;
+                }
+                (pos, Extent::Exclusive)
+            }
+            Motion::WordEnd => {
+                let mut pos = cursor;
+                for _ in 0..n {
+                    pos = self.vi_word_end(pos);
+                }
+                (pos, Extent::Inclusive)
+            }
+            Motion::WordBack => {
+                let mut pos = cursor;
+                for _ in 0..n {
+                    pos = self.vi_word_back(pos);
+                }
+                (pos, Extent::Exclusive)
+            }
+            Motion::LineStart => ((line, 0), Extent::Exclusive),
+            Motion::FirstNonBlank => ((line, self.vi_first_nonblank(line)), Extent::Exclusive),
+            Motion::LineEnd => {
+                let target = cmp::min(line + n - 1, last_line);
+                ((target, self.vi_len(target)), Extent::Exclusive)
+            }
+            Motion::FileStart | Motion::FileEnd => {
+                let target = match count {
+                    Some(n) => cmp::min(n.saturating_sub(1), last_line),
+                    None if motion == Motion::FileStart => self.first_shown_line(),
+                    None => self.last_shown_line(),
+                };
+                ((target, self.vi_first_nonblank(target)), Extent::Linewise)
+            }
+            Motion::Find(kind, b) => {
+                self.vi.last_find = Some((kind, b));
+                return self.vi_find(kind, b, n, false);
+            }
+            Motion::RepeatFind | Motion::RepeatFindBack => {
+                let (kind, b) = self.vi.last_find?;
+                let kind = if motion == Motion::RepeatFind {
+                    kind
+                } else {
+                    match kind {
+                        b'f' => b'F',
+                        b'F' => b'f',
+                        b't' => b'T',
+                        _ => b't',
+                    }
+                };
+                return self.vi_find(kind, b, n, true);
+            }
+        };
+        Some(target)
+    }
+
+    /// Finds the `n`th occurrence of `b` in the cursor's line after it (`f`
+    /// and `t`) or before it (`F` and `T`). `t` and `T` stop just short of
+    /// it, which a repeated search skips over so as not to get stuck.
+    fn vi_find(&self, kind: u8, b: u8, n: usize, is_repeat: bool) -> Option<(TextPos, Extent)> {
+        let (line, byte) = self.vi_cursor();
+        let orig = &self.buffer.lines[line].orig;
+        let skip = if is_repeat && (kind == b't' || kind == b'T') { 1 } else { 0 };
+        let found = if kind == b'f' || kind == b't' {
+            let from = byte + 1 + skip;
+            let i = orig.iter().enumerate().skip(from).filter(|(_, c)| **c == b).nth(n - 1)?.0;
+            if kind == b'f' { i } else { i - 1 }
+        } else {
+            let until = byte.saturating_sub(skip);
+            let i = orig[..until].iter().enumerate().rev().filter(|(_, c)| **c == b).nth(n - 1)?.0;
+            if kind == b'F' { i } else { i + 1 }
+        };
+        let extent = if kind == b'f' || kind == b't' { Extent::Inclusive } else { Extent::Exclusive };
+        Some(((line, found), extent))
+    }
+
+    fn vi_open_command_line(&mut self) {
+        let prompt = Prompt::new(":", "ex", |editor: &mut Editor, command: String| {
+            editor.vi_ex(command.trim());
+        });
+        self.open_prompt(prompt);
+    }
+
+    /// Runs a command-line command: `:w`, `:q`, `:q!`, `:wq`, `:x`, a line
+    /// number, or a substitution on the cursor's line (`:s/a/b/g`) or all
+    /// lines (`:%s/a/b/g`). While the screen is split, `:q` closes the
+    /// window rather than quitting.
+    fn vi_ex(&mut self, command: &str) {
+        let is_split = self.windows.len() > 1;
+        match command {
+            "w" => self.save(),
+            "q" | "q!" if is_split => self.close_window(),
+            "q" => match self.dirty_buffers().first() {
+                Some(&idx) => {
+                    let msg = format!("No write since last change to {} (add ! to override)", display_path(&self.buffer_at(idx).path));
+                    self.new_status_msg(&msg, Duration::from_secs(3));
+                }
+                None => self.should_quit = true,
+            },
+            "q!" => self.should_quit = true,
+            "wq" | "x" => {
+                self.save();
+                if !self.buffer.is_dirty {
+                    if is_split { self.close_window() } else { self.quit() }
+                }
+            }
+            "sp" | "split" => self.split_below(),
+            "vs" | "vsplit" => self.split_right(),
+            "clo" | "close" => self.close_window(),
+            "on" | "only" => self.close_other_windows(),
+            "bn" | "bnext" => self.next_buffer(),
+            "bp" | "bprevious" => self.previous_buffer(),
+            "bd" | "bdelete" => self.close_buffer(),
+            _ if command.starts_with("sav ") || command.starts_with("saveas ") => {
+                let path = command.split_once(' ').unwrap().1.trim();
+                self.save_as_path(prompt::expand_home(path));
+            }
+            _ if command.starts_with("e ") => self.open_buffer(&prompt::expand_home(command[2..].trim())),
+            _ => {
+                if let Ok(n) = command.parse::<usize>() {
+                    let line = cmp::min(n.saturating_sub(1), self.buffer.lines.len().saturating_sub(1));
+                    self.buffer.cursor.line = line;
+                    self.buffer.cursor.byte = self.vi_first_nonblank(line);
+                    return;
+                }
+                let (lines, arg) = match command.strip_prefix("%s") {
+                    Some(arg) => (0..self.buffer.lines.len(), arg),
+                    None => (self.buffer.cursor.line..self.buffer.cursor.line + 1, command.strip_prefix('s').unwrap_or("")),
+                };
+                if arg.starts_with(|c: char| !c.is_alphanumeric() && c != ' ' && c != '\\') && !self.buffer.lines.is_empty() {
+                    self.vi_substitute(lines, arg);
+                } else {
+                    self.new_status_msg(&format!("Not an editor command: {}", command), Duration::from_secs(3));
+                }
+            }
+        }
+    }
+
+    fn vi_substitute(&mut self, lines: ::std::ops::Range<usize>, arg: &str) {
+        if !self.check_editable() {
+            return;
+        }
+        let (pattern, replacement, flags) = split_substitution(arg).unwrap();
+        let regex = match Regex::new(&pattern) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.new_status_msg(&format!("Invalid pattern: {}", e), Duration::from_secs(3));
+                return;
+            }
+        };
+        let is_global = flags.contains('g');
+        let (mut n_subs, mut n_lines) = (0, 0);
+        let (mut line_idx, mut end) = (lines.start, lines.end);
+        while line_idx < end {
+            let text = self.buffer.lines[line_idx].orig.clone();
+            let mut out = vec![];
+            let mut n = 0;
+            // How much of the line has been copied to `out`.
+            let mut copied = 0;
+            let mut i = 0;
+            while n == 0 || is_global {
+                let (start, end) = match regex.find(&text, i) {
+                    Some(found) => found,
+                    None => break,
+                };
+                out.extend_from_slice(&text[copied..start]);
+                out.extend(expand_replacement(&replacement, &text[start..end]));
+                n += 1;
+                copied = end;
+                // An empty match is followed by the byte after it, where the
+                // next match is looked for after.
+                i = end;
+                if end == start {
+                    if start < text.len() {
+                        out.push(text[start]);
+                    }
+                    copied = start + 1;
+                    i = start + 1;
+                }
+            }
+            out.extend_from_slice(&text[cmp::min(copied, text.len())..]);
+            if n > 0 {
+                n_subs += n;
+                n_lines += 1;
+                // The replacement may have line breaks in it.
+                self.buffer.cursor.line = line_idx;
+                self.delete_range((line_idx, 0), (line_idx, text.len()));
+                self.insert_text(&out);
+                self.buffer.cursor.line = line_idx;
+                self.buffer.cursor.byte = self.vi_first_nonblank(line_idx);
+                // Skip over the lines the replacement broke the line into.
+                let n_breaks = out.iter().filter(|b| **b == b'\n').count();
+                line_idx += n_breaks;
+                end += n_breaks;
+            }
+            line_idx += 1;
+        }
+        let msg = if n_subs == 0 {
+            format!("Pattern not found: {}", pattern)
+        } else {
+            format!("{} substitutions on {} lines", n_subs, n_lines)
+        };
+        self.new_status_msg(&msg, Duration::from_secs(3));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{expand_replacement, parse, split_substitution, Command, Motion, Parse};
+    use buffer::Buffer;
+    use config::Config;
+    use tests::editor;
+
+    fn parse_keys(keys: &str, is_visual: bool) -> Parse<(Option<usize>, Command)> {
+        let keys: Vec<String> = keys.split(' ').map(|key| key.to_string()).collect();
+        parse(&keys, is_visual)
+    }
+
+    fn done(count: Option<usize>, command: Command) -> Parse<(Option<usize>, Command)> {
+        Parse::Done((count, command))
+    }
+
+    fn split(arg: &str) -> Option<(String, String, String)> {
+        split_substitution(arg)
+    }
+
+    fn parts(pattern: &str, replacement: &str, flags: &str) -> Option<(String, String, String)> {
+        Some((pattern.to_string(), replacement.to_string(), flags.to_string()))
+    }
+
+    fn expand(replacement: &str, matched: &str) -> String {
+        String::from_utf8(expand_replacement(replacement, matched.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn parses_motions_and_counts() {
+        assert_eq!(parse_keys("w", false), done(None, Command::Move(Motion::WordStart)));
+        assert_eq!(parse_keys("1 2 j", false), done(Some(12), Command::Move(Motion::Down)));
+        assert_eq!(parse_keys("1 0 $", false), done(Some(10), Command::Move(Motion::LineEnd)));
+        // A lone 0 is a motion rather than a count.
+        assert_eq!(parse_keys("0", false), done(None, Command::Move(Motion::LineStart)));
+        assert_eq!(parse_keys("g", false), Parse::Incomplete);
+        assert_eq!(parse_keys("g g", false), done(None, Command::Move(Motion::FileStart)));
+        assert_eq!(parse_keys("g x", false), Parse::Invalid);
+        assert_eq!(parse_keys("3", false), Parse::Incomplete);
+        assert_eq!(parse_keys("f", false), Parse::Incomplete);
+        assert_eq!(parse_keys("t ;", false), done(None, Command::Move(Motion::Find(b't', b';'))));
+        assert_eq!(parse_keys("F space", false), done(None, Command::Move(Motion::Find(b'F', b' '))));
+        assert_eq!(parse_keys("f left", false), Parse::Invalid);
+        assert_eq!(parse_keys("Z", false), Parse::Invalid);
+    }
+
+    #[test]
+    fn parses_operators() {
+        assert_eq!(parse_keys("d w", false), done(None, Command::Operate(b'd', Motion::WordStart)));
+        assert_eq!(parse_keys("d d", false), done(None, Command::OperateLines(b'd')));
+        assert_eq!(parse_keys("2 c 3 w", false), done(Some(6), Command::Operate(b'c', Motion::WordStart)));
+        assert_eq!(parse_keys("y 2 y", false), done(Some(2), Command::OperateLines(b'y')));
+        assert_eq!(parse_keys("d", false), Parse::Incomplete);
+        assert_eq!(parse_keys("d 2", false), Parse::Incomplete);
+        assert_eq!(parse_keys("d f", false), Parse::Incomplete);
+        assert_eq!(parse_keys("d c", false), Parse::Invalid);
+        assert_eq!(parse_keys("9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 d 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 9 w", false),
+            done(Some(usize::MAX), Command::Operate(b'd', Motion::WordStart)));
+    }
+
+    #[test]
+    fn parses_other_commands() {
+        assert_eq!(parse_keys("r x", false), done(None, Command::Replace(b'x')));
+        assert_eq!(parse_keys("r", false), Parse::Incomplete);
+        assert_eq!(parse_keys("q a", false), done(None, Command::Record('a')));
+        assert_eq!(parse_keys("2 @ a", false), done(Some(2), Command::Play(Some('a'))));
+        assert_eq!(parse_keys("@ @", false), done(None, Command::Play(None)));
+        assert_eq!(parse_keys("q @", false), Parse::Invalid);
+        assert_eq!(parse_keys("3 x", false), done(Some(3), Command::Action("x".to_string())));
+        assert_eq!(parse_keys("ctrl-r", false), done(None, Command::Action("ctrl-r".to_string())));
+        // Visual mode has operators of its own, which act on the selection.
+        assert_eq!(parse_keys("d", true), done(None, Command::Action("d".to_string())));
+        assert_eq!(parse_keys("j", true), done(None, Command::Move(Motion::Down)));
+        assert_eq!(parse_keys("q a", true), Parse::Invalid);
+        assert_eq!(parse_keys("i", true), Parse::Invalid);
+    }
+
+    #[test]
+    fn splits_substitutions() {
+        assert_eq!(split("/a/b/g"), parts("a", "b", "g"));
+        assert_eq!(split("/a/b"), parts("a", "b", ""));
+        assert_eq!(split("/a"), parts("a", "", ""));
+        assert_eq!(split("#a/b#c#"), parts("a/b", "c", ""));
+        assert_eq!(split(r"/a\/b/c\/d/"), parts("a/b", "c/d", ""));
+        // Other escapes are left for the pattern and the replacement.
+        assert_eq!(split(r"/\d+/\n&/"), parts(r"\d+", r"\n&", ""));
+        assert_eq!(split("/a/b/g/x"), parts("a", "b", "g/x"));
+        assert_eq!(split("/a\\"), parts("a\\", "", ""));
+        assert_eq!(split(""), None);
+    }
+
+    #[test]
+    fn expands_replacements() {
+        assert_eq!(expand("<&>", "ab"), "<ab>");
+        assert_eq!(expand("&&", "x"), "xx");
+        assert_eq!(expand(r"\&", "x"), "&");
+        assert_eq!(expand(r"a\nb\t", "x"), "a\nb\t");
+        assert_eq!(expand(r"\\", "x"), "\\");
+        assert_eq!(expand(r"\q", "x"), "q");
+        assert_eq!(expand("end\\", "x"), "end\\");
+    }
+
+    #[test]
+    fn substitutes_across_lines() {
+        let mut global = editor("a-b-a\nb\nab");
+        global.vi_ex("%s/a/<&>/g");
+        assert_eq!(global.status_msg.data, "3 substitutions on 2 lines");
+        assert_eq!(&global.buffer.lines[0].orig[..], b"<a>-b-<a>");
+        assert_eq!(&global.buffer.lines[2].orig[..], b"<a>b");
+        let mut split = editor("a b\nc");
+        split.vi_ex("s/ /\\n/");
+        let lines: Vec<&[u8]> = split.buffer.lines.iter().map(|line| &line.orig[..]).collect();
+        assert_eq!(lines, vec![&b"a"[..], b"b", b"c"]);
+    }
+
+    #[test]
+    fn doesnt_substitute_in_read_only_files() {
+        let mut editor = editor("aaa");
+        editor.buffer.is_readonly = true;
+        editor.vi_ex("s/a/b/g");
+        assert_eq!(&editor.buffer.lines[0].orig[..], b"aaa");
+        assert_eq!(editor.status_msg.data, "The file is read-only");
+    }
+
+    #[test]
+    fn keeps_from_quitting_with_unsaved_changes() {
+        let mut editor = editor("shown");
+        let mut other = Buffer::new(Config::default(), PathBuf::from("other.txt"));
+        other.is_dirty = true;
+        editor.buffers.push(other);
+        editor.vi_ex("q");
+        assert!(!editor.should_quit);
+        assert_eq!(editor.status_msg.data, "No write since last change to other.txt (add ! to override)");
+        editor.vi_ex("q!");
+        assert!(editor.should_quit);
+    }
+}
@@ -0,0 +1,420 @@
+use std::cmp;
+use std::mem;
+use std::time::Duration;
+
+use buffer::Buffer;
+use vi::Mode;
+use {Cursor, Editor};
+
+/// How a split arranges its two parts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Orientation {
+    /// One above the other.
+    Horizontal,
+    /// Side by side, with a column between them.
+    Vertical,
+}
+
+/// The part of the screen a window takes up, including its status bar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub top: usize,
+    pub left: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    fn contains(&self, row: usize, col: usize) -> bool {
+        self.top <= row && row < self.top + self.height && self.left <= col && col < self.left + self.width
+    }
+}
+
+/// Where a window is scrolled to and its cursor, kept while another window
+/// has the focus.
+#[derive(Clone, Copy)]
+pub struct View {
+    cursor: Cursor,
+    line_offset: usize,
+    line_offset_row: usize,
+    col_offset: usize,
+}
+
+impl View {
+    pub fn of(buffer: &Buffer) -> View {
+        View {
+            cursor: buffer.cursor,
+            line_offset: buffer.line_offset,
+            line_offset_row: buffer.line_offset_row,
+            col_offset: buffer.col_offset,
+        }
+    }
+
+    /// Puts the view back in `buffer`, keeping it within the text, which may
+    /// have been changed through another window since.
+    pub fn restore(self, buffer: &mut Buffer) {
+        let last_line = buffer.lines.len().saturating_sub(1);
+        buffer.cursor = self.cursor;
+        buffer.cursor.line = cmp::min(self.cursor.line, last_line);
+        buffer.cursor.byte = cmp::min(self.cursor.byte, buffer.lines.get(buffer.cursor.line).map_or(0, |l| l.orig.len()));
+        buffer.line_offset = cmp::min(self.line_offset, last_line);
+        buffer.line_offset_row = self.line_offset_row;
+        buffer.col_offset = self.col_offset;
+    }
+}
+
+/// A view onto one of the open buffers.
+pub struct Window {
+    pub buffer: usize,
+    pub view: View,
+}
+
+/// How the screen is divided between the windows, which are referred to by
+/// their index.
+pub enum Layout {
+    Window(usize),
+    Split {
+        orientation: Orientation,
+        // The share of the split's rows or columns that the first part gets.
+        ratio: f64,
+        first: Box<Layout>,
+        second: Box<Layout>,
+    },
+}
+
+/// Divides `rect` between the two parts of a split. Each part keeps at
+/// least a row of text and its status bar, or a column, if there's room.
+fn split_rect(orientation: Orientation, ratio: f64, rect: Rect) -> (Rect, Rect) {
+    match orientation {
+        Orientation::Horizontal => {
+            let height = clamp((ratio * rect.height as f64).round() as usize, 2, rect.height.saturating_sub(2));
+            (
+                Rect { height, ..rect },
+                Rect { top: rect.top + height, height: rect.height - height, ..rect },
+            )
+        }
+        Orientation::Vertical => {
+            // Leave a column for the separator.
+            let avail = rect.width.saturating_sub(1);
+            let width = clamp((ratio * avail as f64).round() as usize, 1, avail.saturating_sub(1));
+            (
+                Rect { width, ..rect },
+                Rect { left: rect.left + width + 1, width: avail - width, ..rect },
+            )
+        }
+    }
+}
+
+fn clamp(n: usize, min: usize, max: usize) -> usize {
+    cmp::max(cmp::min(n, max), cmp::min(min, max))
+}
+
+impl Layout {
+    /// Adds the windows in the layout, in order, along with the part of
+    /// `rect` each takes up, to `rects`, and the columns that separate side
+    /// by side windows to `separators`.
+    pub fn rects(&self, rect: Rect, rects: &mut Vec<(usize, Rect)>, separators: &mut Vec<Rect>) {
+        match *self {
+            Layout::Window(idx) => rects.push((idx, rect)),
+            Layout::Split { orientation, ratio, ref first, ref second } => {
+                let (a, b) = split_rect(orientation, ratio, rect);
+                first.rects(a, rects, separators);
+                if orientation == Orientation::Vertical {
+                    separators.push(Rect { left: a.left + a.width, width: 1, ..rect });
+                }
+                second.rects(b, rects, separators);
+            }
+        }
+    }
+
+    /// Splits the window at `idx` in two, the second part of which is the
+    /// window at `new`.
+    fn split(&mut self, idx: usize, new: usize, orientation: Orientation) {
+        match *self {
+            Layout::Window(i) if i == idx => {
+                *self = Layout::Split {
+                    orientation,
+                    ratio: 0.5,
+                    first: Box::new(Layout::Window(idx)),
+                    second: Box::new(Layout::Window(new)),
+                };
+            }
+            Layout::Window(_) => {}
+            Layout::Split { ref mut first, ref mut second, .. } => {
+                first.split(idx, new, orientation);
+                second.split(idx, new, orientation);
+            }
+        }
+    }
+
+    /// Returns the first window in the layout.
+    fn first_window(&self) -> usize {
+        match *self {
+            Layout::Window(idx) => idx,
+            Layout::Split { ref first, .. } => first.first_window(),
+        }
+    }
+
+    /// Removes the window at `idx`, whose part goes to the other part of its
+    /// split, and returns the first window of that part.
+    fn remove(&mut self, idx: usize) -> Option<usize> {
+        let other = match *self {
+            Layout::Window(_) => return None,
+            Layout::Split { ref mut first, ref mut second, .. } => {
+                if first.is_window(idx) {
+                    mem::replace(&mut **second, Layout::Window(0))
+                } else if second.is_window(idx) {
+                    mem::replace(&mut **first, Layout::Window(0))
+                } else {
+                    return first.remove(idx).or_else(|| second.remove(idx));
+                }
+            }
+        };
+        *self = other;
+        Some(self.first_window())
+    }
+
+    fn is_window(&self, idx: usize) -> bool {
+        match *self {
+            Layout::Window(i) => i == idx,
+            Layout::Split { .. } => false,
+        }
+    }
+
+    /// Renumbers the windows after `idx` once the window at `idx` is gone.
+    fn renumber_after(&mut self, idx: usize) {
+        match *self {
+            Layout::Window(ref mut i) => {
+                if *i > idx {
+                    *i -= 1;
+                }
+            }
+            Layout::Split { ref mut first, ref mut second, .. } => {
+                first.renumber_after(idx);
+                second.renumber_after(idx);
+            }
+        }
+    }
+
+    /// Moves the edge of the window at `idx` by `delta` rows or columns,
+    /// along the innermost split with the given orientation, whose part of
+    /// the screen is `rect`. Returns whether there is such a split.
+    fn resize(&mut self, idx: usize, delta: isize, orientation: Orientation, rect: Rect) -> bool {
+        let (o, ratio, first, second) = match *self {
+            Layout::Window(_) => return false,
+            Layout::Split { orientation, ref mut ratio, ref mut first, ref mut second } => (orientation, ratio, first, second),
+        };
+        let (a, b) = split_rect(o, *ratio, rect);
+        if first.resize(idx, delta, orientation, a) || second.resize(idx, delta, orientation, b) {
+            return true;
+        }
+        let in_first = first.contains(idx);
+        if o != orientation || !(in_first || second.contains(idx)) {
+            return false;
+        }
+        // Growing the first part moves the edge forward, and growing the
+        // second moves it back.
+        let (size, total) = match o {
+            Orientation::Horizontal => (a.height as isize, rect.height as isize),
+            Orientation::Vertical => (a.width as isize, rect.width as isize - 1),
+        };
+        let size = size + if in_first { delta } else { -delta };
+        if total > 0 {
+            *ratio = (size as f64 / total as f64).clamp(0.0, 1.0);
+        }
+        true
+    }
+
+    fn contains(&self, idx: usize) -> bool {
+        match *self {
+            Layout::Window(i) => i == idx,
+            Layout::Split { ref first, ref second, .. } => first.contains(idx) || second.contains(idx),
+        }
+    }
+}
+
+impl Editor {
+    /// Returns the windows along with the part of the screen each takes up,
+    /// in the order they're laid out in, and the separators between them.
+    pub fn window_rects(&self) -> (Vec<(usize, Rect)>, Vec<Rect>) {
+        let (mut rects, mut separators) = (vec![], vec![]);
+        self.layout.rects(self.windows_area(), &mut rects, &mut separators);
+        (rects, separators)
+    }
+
+    /// Returns the part of the screen the windows are laid out in, which is
+    /// all of it but the tab bar and the message bar.
+    fn windows_area(&self) -> Rect {
+        let top = if self.has_tab_bar() { 1 } else { 0 };
+        Rect { top, left: 0, width: self.screen_width, height: self.screen_height.saturating_sub(top + 1) }
+    }
+
+    fn focused_rect(&self) -> Rect {
+        let (rects, _) = self.window_rects();
+        rects.into_iter().find(|(idx, _)| *idx == self.focus).map(|(_, rect)| rect).unwrap()
+    }
+
+    /// Moves the focus to the window at `idx`, whose buffer and view become
+    /// the ones being edited.
+    fn focus_window(&mut self, idx: usize) {
+        if idx == self.focus {
+            return;
+        }
+        self.windows[self.focus].view = View::of(&self.buffer);
+        self.focus = idx;
+        let buffer = self.windows[idx].buffer;
+        self.swap_buffer(buffer);
+        self.windows[idx].view.restore(&mut self.buffer);
+        self.forget_positions();
+    }
+
+    /// Draws the window at `idx`, which doesn't have the focus, into `rect`
+    /// by making its buffer and view the current ones for as long as it takes.
+    pub fn draw_unfocused_window(&mut self, idx: usize, rect: Rect) {
+        let current = self.current;
+        // What is selected or searched for is only shown in the focused window.
+        let mark = self.mark.take();
+        let isearch = self.isearch.take();
+        let mode = self.vi.mode;
+        self.vi.mode = Mode::Normal;
+
+        let buffer = self.windows[idx].buffer;
+        self.swap_buffer(buffer);
+        // The buffer's own view is that of the focused window, or the one it
+        // was last edited with.
+        let own = View::of(&self.buffer);
+        self.windows[idx].view.restore(&mut self.buffer);
+        self.pin_to_end();
+        self.draw_window(rect, false);
+        self.windows[idx].view = View::of(&self.buffer);
+        own.restore(&mut self.buffer);
+        self.swap_buffer(current);
+
+        self.mark = mark;
+        self.isearch = isearch;
+        self.vi.mode = mode;
+    }
+
+    fn split_window(&mut self, orientation: Orientation) {
+        let rect = self.focused_rect();
+        let is_room = match orientation {
+            Orientation::Horizontal => rect.height >= 4,
+            Orientation::Vertical => rect.width >= 3,
+        };
+        if !is_room {
+            self.new_status_msg("Not enough room to split the window", Duration::from_secs(3));
+            return;
+        }
+        let new = self.windows.len();
+        self.windows.push(Window { buffer: self.current, view: View::of(&self.buffer) });
+        self.layout.split(self.focus, new, orientation);
+    }
+
+    /// Splits the window into two, one above the other, onto the same buffer.
+    pub fn split_below(&mut self) {
+        self.split_window(Orientation::Horizontal);
+    }
+
+    /// Splits the window into two side by side, onto the same buffer.
+    pub fn split_right(&mut self) {
+        self.split_window(Orientation::Vertical);
+    }
+
+    /// Closes the focused window, giving its place to its neighbour.
+    pub fn close_window(&mut self) {
+        let closed = self.focus;
+        let next = match self.layout.remove(closed) {
+            Some(next) => next,
+            None => {
+                self.new_status_msg("Can't close the only window", Duration::from_secs(3));
+                return;
+            }
+        };
+        self.layout.renumber_after(closed);
+        self.windows.remove(closed);
+        let next = if next > closed { next - 1 } else { next };
+        // The closed window's view is simply dropped.
+        self.focus = next;
+        let buffer = self.windows[next].buffer;
+        self.swap_buffer(buffer);
+        self.windows[next].view.restore(&mut self.buffer);
+        self.forget_positions();
+    }
+
+    /// Closes all windows but the focused one.
+    pub fn close_other_windows(&mut self) {
+        let window = self.windows.swap_remove(self.focus);
+        self.windows = vec![window];
+        self.focus = 0;
+        self.layout = Layout::Window(0);
+    }
+
+    /// Moves the focus to the next window, in the order they're laid out.
+    pub fn next_window(&mut self) {
+        let (rects, _) = self.window_rects();
+        let i = rects.iter().position(|(idx, _)| *idx == self.focus).unwrap();
+        self.focus_window(rects[(i + 1) % rects.len()].0);
+    }
+
+    /// Moves the focus to the window next to the focused one in the given
+    /// direction, the one beside the cursor if there are several.
+    fn focus_towards(&mut self, d_row: isize, d_col: isize) {
+        let (rects, _) = self.window_rects();
+        let rect = self.focused_rect();
+        let cursor = self.buffer.cursor.pos;
+        // Step just past the window's edge, and past the separator when
+        // going sideways.
+        let row = match d_row {
+            -1 => rect.top as isize - 1,
+            1 => (rect.top + rect.height) as isize,
+            _ => (rect.top + cursor.row) as isize,
+        };
+        let col = match d_col {
+            -1 => rect.left as isize - 2,
+            1 => (rect.left + rect.width + 1) as isize,
+            _ => (rect.left + cursor.col) as isize,
+        };
+        if row < 0 || col < 0 {
+            return;
+        }
+        if let Some(&(idx, _)) = rects.iter().find(|(_, r)| r.contains(row as usize, col as usize)) {
+            self.focus_window(idx);
+        }
+    }
+
+    pub fn focus_up(&mut self) {
+        self.focus_towards(-1, 0);
+    }
+
+    pub fn focus_down(&mut self) {
+        self.focus_towards(1, 0);
+    }
+
+    pub fn focus_left(&mut self) {
+        self.focus_towards(0, -1);
+    }
+
+    pub fn focus_right(&mut self) {
+        self.focus_towards(0, 1);
+    }
+
+    /// Makes the focused window bigger or smaller by `delta` rows, or if it
+    /// isn't above or below another window, by `delta` columns.
+    fn resize_window(&mut self, delta: isize) {
+        let area = self.windows_area();
+        let focus = self.focus;
+        if !self.layout.resize(focus, delta, Orientation::Horizontal, area)
+            && !self.layout.resize(focus, delta, Orientation::Vertical, area)
+        {
+            self.new_status_msg("There's only one window", Duration::from_secs(3));
+        }
+    }
+
+    pub fn grow_window(&mut self) {
+        self.resize_window(1);
+    }
+
+    pub fn shrink_window(&mut self) {
+        self.resize_window(-1);
+    }
+}